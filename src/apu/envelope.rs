@@ -0,0 +1,67 @@
+// Volume envelope shared by the square and noise channels (NRx2)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Envelope {
+    pub register: u8,
+    pub volume: u8,
+    pub timer: u8,
+}
+
+impl Envelope {
+    pub fn dac_enabled(&self) -> bool {
+        self.register & 0xF8 != 0
+    }
+
+    fn period(&self) -> u8 {
+        self.register & 0x07
+    }
+
+    pub fn trigger(&mut self) {
+        self.volume = self.register >> 4;
+        self.timer = self.period();
+    }
+
+    pub fn clock(&mut self) {
+        if self.period() == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period();
+            if self.register & 0x08 != 0 && self.volume < 15 {
+                self.volume += 1;
+            } else if self.register & 0x08 == 0 && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+// Length counter, when enabled it silences the channel once it reaches 0
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LengthCounter {
+    pub counter: u16,
+    pub enabled: bool,
+}
+
+impl LengthCounter {
+    pub fn load(&mut self, max: u16, value: u8) {
+        self.counter = max - value as u16;
+    }
+
+    pub fn trigger(&mut self, max: u16) {
+        if self.counter == 0 {
+            self.counter = max;
+        }
+    }
+
+    // Returns true when the channel should be disabled
+    pub fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+}
@@ -0,0 +1,269 @@
+pub mod envelope;
+pub mod noise_channel;
+pub mod square_channel;
+pub mod wave_channel;
+
+use self::noise_channel::NoiseChannel;
+use self::square_channel::SquareChannel;
+use self::wave_channel::WaveChannel;
+
+pub const CPU_CLOCK_HZ: u32 = 4_194_304;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+// The frame sequencer clocks length, sweep and envelope units at 512Hz
+const FRAME_SEQUENCER_PERIOD: u32 = 8192;
+
+pub struct Apu {
+    pub powered: bool,
+    pub channel1: SquareChannel,
+    pub channel2: SquareChannel,
+    pub channel3: WaveChannel,
+    pub channel4: NoiseChannel,
+    pub nr50: u8,
+    pub nr51: u8,
+    pub frame_sequencer: u32,
+    pub frame_sequencer_step: u8,
+    pub sample_rate: u32,
+    pub sample_counter: u32,
+    accumulated: (f32, f32),
+    accumulated_count: u32,
+    capacitor: (f32, f32),
+    // Interleaved left/right 16 bit samples waiting to be drained
+    pub samples: Vec<i16>,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Apu::new()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        Apu {
+            powered: true,
+            channel1: SquareChannel::new(true),
+            channel2: SquareChannel::new(false),
+            channel3: WaveChannel::new(),
+            channel4: NoiseChannel::new(),
+            nr50: 0x77,
+            nr51: 0xF3,
+            frame_sequencer: 0,
+            frame_sequencer_step: 0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_counter: 0,
+            accumulated: (0.0, 0.0),
+            accumulated_count: 0,
+            capacitor: (0.0, 0.0),
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0xFF10..=0xFF14 => self.channel1.read(address - 0xFF10),
+            0xFF16..=0xFF19 => self.channel2.read(address - 0xFF15),
+            0xFF1A..=0xFF1E => self.channel3.read(address - 0xFF1A),
+            0xFF20..=0xFF23 => self.channel4.read(address - 0xFF1F),
+            0xFF24 => self.nr50,
+            0xFF25 => self.nr51,
+            0xFF26 => {
+                (if self.powered { 0x80 } else { 0 })
+                    | 0x70
+                    | (if self.channel1.enabled { 0x01 } else { 0 })
+                    | (if self.channel2.enabled { 0x02 } else { 0 })
+                    | (if self.channel3.enabled { 0x04 } else { 0 })
+                    | (if self.channel4.enabled { 0x08 } else { 0 })
+            }
+            0xFF30..=0xFF3F => self.channel3.wave_ram[(address - 0xFF30) as usize],
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        if address == 0xFF26 {
+            let powered = value & 0x80 != 0;
+            if self.powered && !powered {
+                self.power_off();
+            } else if !self.powered && powered {
+                self.frame_sequencer_step = 0;
+            }
+            self.powered = powered;
+            return;
+        }
+        if let 0xFF30..=0xFF3F = address {
+            self.channel3.wave_ram[(address - 0xFF30) as usize] = value;
+            return;
+        }
+        if !self.powered {
+            return;
+        }
+        match address {
+            0xFF10..=0xFF14 => self.channel1.write(address - 0xFF10, value),
+            0xFF16..=0xFF19 => self.channel2.write(address - 0xFF15, value),
+            0xFF1A..=0xFF1E => self.channel3.write(address - 0xFF1A, value),
+            0xFF20..=0xFF23 => self.channel4.write(address - 0xFF1F, value),
+            0xFF24 => self.nr50 = value,
+            0xFF25 => self.nr51 = value,
+            _ => {}
+        }
+    }
+
+    // Powering off clears every register except wave RAM
+    fn power_off(&mut self) {
+        let wave_ram = self.channel3.wave_ram;
+        self.channel1 = SquareChannel::new(true);
+        self.channel2 = SquareChannel::new(false);
+        self.channel3 = WaveChannel::new();
+        self.channel3.wave_ram = wave_ram;
+        self.channel4 = NoiseChannel::new();
+        self.nr50 = 0;
+        self.nr51 = 0;
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        for _ in 0..cycles / 4 {
+            if self.powered {
+                self.frame_sequencer += 4;
+                if self.frame_sequencer >= FRAME_SEQUENCER_PERIOD {
+                    self.frame_sequencer -= FRAME_SEQUENCER_PERIOD;
+                    self.clock_frame_sequencer();
+                }
+                self.channel1.step(4);
+                self.channel2.step(4);
+                self.channel3.step(4);
+                self.channel4.step(4);
+            }
+
+            let (left, right) = self.mix();
+            self.accumulated.0 += left;
+            self.accumulated.1 += right;
+            self.accumulated_count += 1;
+
+            self.sample_counter += self.sample_rate * 4;
+            if self.sample_counter >= CPU_CLOCK_HZ {
+                self.sample_counter -= CPU_CLOCK_HZ;
+                self.emit_sample();
+            }
+        }
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        let step = self.frame_sequencer_step;
+        if step.is_multiple_of(2) {
+            self.channel1.clock_length();
+            self.channel2.clock_length();
+            self.channel3.clock_length();
+            self.channel4.clock_length();
+        }
+        if step == 2 || step == 6 {
+            self.channel1.clock_sweep();
+        }
+        if step == 7 {
+            self.channel1.envelope.clock();
+            self.channel2.envelope.clock();
+            self.channel4.envelope.clock();
+        }
+        self.frame_sequencer_step = (step + 1) & 0x07;
+    }
+
+    // Each DAC maps the digital 0-15 level onto -1.0..1.0
+    fn dac(enabled: bool, level: u8) -> f32 {
+        if enabled {
+            1.0 - level as f32 / 7.5
+        } else {
+            0.0
+        }
+    }
+
+    fn mix(&self) -> (f32, f32) {
+        if !self.powered {
+            return (0.0, 0.0);
+        }
+        let outputs = [
+            Apu::dac(self.channel1.dac_enabled(), self.channel1.output()),
+            Apu::dac(self.channel2.dac_enabled(), self.channel2.output()),
+            Apu::dac(self.channel3.dac_enabled, self.channel3.output()),
+            Apu::dac(self.channel4.dac_enabled(), self.channel4.output()),
+        ];
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (index, output) in outputs.iter().enumerate() {
+            if self.nr51 & (0x10 << index) != 0 {
+                left += output;
+            }
+            if self.nr51 & (0x01 << index) != 0 {
+                right += output;
+            }
+        }
+        let left_volume = ((self.nr50 >> 4) & 0x07) as f32 + 1.0;
+        let right_volume = (self.nr50 & 0x07) as f32 + 1.0;
+        (left * left_volume / 32.0, right * right_volume / 32.0)
+    }
+
+    fn emit_sample(&mut self) {
+        let count = self.accumulated_count.max(1) as f32;
+        let (left, right) = (self.accumulated.0 / count, self.accumulated.1 / count);
+        self.accumulated = (0.0, 0.0);
+        self.accumulated_count = 0;
+
+        // The output stage has a capacitor that removes the DC offset
+        let charge = 0.999_958f32.powf(CPU_CLOCK_HZ as f32 / self.sample_rate as f32);
+        let left_out = left - self.capacitor.0;
+        self.capacitor.0 = left - left_out * charge;
+        let right_out = right - self.capacitor.1;
+        self.capacitor.1 = right - right_out * charge;
+
+        self.samples.push(Apu::to_i16(left_out));
+        self.samples.push(Apu::to_i16(right_out));
+    }
+
+    fn to_i16(value: f32) -> i16 {
+        (value.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+    }
+}
+
+#[cfg(test)]
+mod apu_tests {
+    use super::*;
+    #[test]
+    fn produces_samples_at_sample_rate() {
+        let mut apu = Apu::new();
+        apu.step(CPU_CLOCK_HZ);
+        assert_eq!(apu.take_samples().len(), DEFAULT_SAMPLE_RATE as usize * 2);
+        assert!(apu.samples.is_empty());
+    }
+    #[test]
+    fn trigger_enables_channel() {
+        let mut apu = Apu::new();
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF14, 0x80);
+        assert_eq!(apu.read(0xFF26) & 0x01, 0x01);
+        // Turning the DAC off disables the channel
+        apu.write(0xFF12, 0x00);
+        assert_eq!(apu.read(0xFF26) & 0x01, 0x00);
+    }
+    #[test]
+    fn length_counter_expires() {
+        let mut apu = Apu::new();
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF16, 0x3F);
+        apu.write(0xFF19, 0xC0);
+        apu.step(FRAME_SEQUENCER_PERIOD * 2);
+        assert_eq!(apu.read(0xFF26) & 0x02, 0x00);
+    }
+    #[test]
+    fn power_off_clears_registers() {
+        let mut apu = Apu::new();
+        apu.write(0xFF30, 0x12);
+        apu.write(0xFF26, 0x00);
+        assert_eq!(apu.read(0xFF25), 0x00);
+        assert_eq!(apu.read(0xFF30), 0x12);
+        apu.write(0xFF25, 0xFF);
+        assert_eq!(apu.read(0xFF25), 0x00);
+    }
+}
@@ -0,0 +1,94 @@
+use super::envelope::{Envelope, LengthCounter};
+
+const DIVISORS: [i32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Channel 4 outputs the inverted low bit of a linear feedback shift register
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NoiseChannel {
+    pub enabled: bool,
+    pub polynomial: u8,
+    pub timer: i32,
+    pub lfsr: u16,
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+}
+
+impl NoiseChannel {
+    pub fn new() -> NoiseChannel {
+        NoiseChannel {
+            lfsr: 0x7FFF,
+            ..NoiseChannel::default()
+        }
+    }
+
+    fn period(&self) -> i32 {
+        DIVISORS[(self.polynomial & 0x07) as usize] << (self.polynomial >> 4)
+    }
+
+    pub fn read(&self, register: u16) -> u8 {
+        match register {
+            2 => self.envelope.register,
+            3 => self.polynomial,
+            4 => if self.length.enabled { 0xFF } else { 0xBF },
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            1 => self.length.load(64, value & 0x3F),
+            2 => {
+                self.envelope.register = value;
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.polynomial = value,
+            4 => {
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger(64);
+        self.timer = self.period();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        self.timer -= cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 0x01;
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            // 7 bit mode also feeds back into bit 6
+            if self.polynomial & 0x08 != 0 {
+                self.lfsr = (self.lfsr & !0x40) | (feedback << 6);
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 0x01 != 0 {
+            return 0;
+        }
+        self.envelope.volume
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+}
@@ -0,0 +1,148 @@
+use super::envelope::{Envelope, LengthCounter};
+
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+// Channels 1 and 2. Only channel 1 has the frequency sweep unit.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SquareChannel {
+    pub has_sweep: bool,
+    pub enabled: bool,
+    pub sweep: u8,
+    pub duty: u8,
+    pub frequency: u16,
+    pub timer: i32,
+    pub duty_step: u8,
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    pub sweep_timer: u8,
+    pub sweep_enabled: bool,
+    pub shadow_frequency: u16,
+}
+
+impl SquareChannel {
+    pub fn new(has_sweep: bool) -> SquareChannel {
+        SquareChannel {
+            has_sweep,
+            ..SquareChannel::default()
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 4
+    }
+
+    pub fn read(&self, register: u16) -> u8 {
+        match register {
+            0 if self.has_sweep => self.sweep | 0x80,
+            1 => (self.duty << 6) | 0x3F,
+            2 => self.envelope.register,
+            4 => if self.length.enabled { 0xFF } else { 0xBF },
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 if self.has_sweep => self.sweep = value & 0x7F,
+            1 => {
+                self.duty = value >> 6;
+                self.length.load(64, value & 0x3F);
+            }
+            2 => {
+                self.envelope.register = value;
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger(64);
+        self.timer = self.period();
+        self.envelope.trigger();
+        if self.has_sweep {
+            let period = (self.sweep >> 4) & 0x07;
+            let shift = self.sweep & 0x07;
+            self.shadow_frequency = self.frequency;
+            self.sweep_timer = if period == 0 { 8 } else { period };
+            self.sweep_enabled = period != 0 || shift != 0;
+            if shift != 0 {
+                self.calculate_sweep();
+            }
+        }
+    }
+
+    fn calculate_sweep(&mut self) -> u16 {
+        let delta = self.shadow_frequency >> (self.sweep & 0x07);
+        let frequency = if self.sweep & 0x08 != 0 {
+            self.shadow_frequency.wrapping_sub(delta)
+        } else {
+            self.shadow_frequency + delta
+        };
+        if frequency > 2047 {
+            self.enabled = false;
+        }
+        frequency
+    }
+
+    pub fn clock_sweep(&mut self) {
+        if self.sweep_timer > 0 {
+            self.sweep_timer -= 1;
+        }
+        if self.sweep_timer != 0 {
+            return;
+        }
+        let period = (self.sweep >> 4) & 0x07;
+        self.sweep_timer = if period == 0 { 8 } else { period };
+        if self.sweep_enabled && period != 0 {
+            let frequency = self.calculate_sweep();
+            if frequency <= 2047 && self.sweep & 0x07 != 0 {
+                self.frequency = frequency;
+                self.shadow_frequency = frequency;
+                self.calculate_sweep();
+            }
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        self.timer -= cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.duty_step = (self.duty_step + 1) & 0x07;
+        }
+    }
+
+    // Digital output 0-15
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        DUTY_PATTERNS[self.duty as usize][self.duty_step as usize] * self.envelope.volume
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+}
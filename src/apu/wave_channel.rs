@@ -0,0 +1,91 @@
+use super::envelope::LengthCounter;
+
+// Channel 3 plays back 32 4-bit samples from wave RAM (0xFF30-0xFF3F)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WaveChannel {
+    pub enabled: bool,
+    pub dac_enabled: bool,
+    pub volume_code: u8,
+    pub frequency: u16,
+    pub timer: i32,
+    pub position: u8,
+    pub sample_buffer: u8,
+    pub length: LengthCounter,
+    pub wave_ram: [u8; 16],
+}
+
+impl WaveChannel {
+    pub fn new() -> WaveChannel {
+        WaveChannel::default()
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 2
+    }
+
+    pub fn read(&self, register: u16) -> u8 {
+        match register {
+            0 => if self.dac_enabled { 0xFF } else { 0x7F },
+            2 => (self.volume_code << 5) | 0x9F,
+            4 => if self.length.enabled { 0xFF } else { 0xBF },
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.dac_enabled = value & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(256, value),
+            2 => self.volume_code = (value >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger(256);
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        self.timer -= cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.position = (self.position + 1) & 0x1F;
+            let byte = self.wave_ram[self.position as usize / 2];
+            self.sample_buffer = if self.position.is_multiple_of(2) { byte >> 4 } else { byte & 0x0F };
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        match self.volume_code {
+            0 => 0,
+            1 => self.sample_buffer,
+            2 => self.sample_buffer >> 1,
+            _ => self.sample_buffer >> 2,
+        }
+    }
+}
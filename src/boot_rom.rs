@@ -0,0 +1,65 @@
+use std::fmt;
+
+pub const DMG_BOOT_ROM_SIZE: usize = 0x100;
+pub const CGB_BOOT_ROM_SIZE: usize = 0x900;
+
+#[derive(Debug, PartialEq)]
+pub enum BootRomError {
+    WrongSize(usize),
+}
+
+impl fmt::Display for BootRomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BootRomError::WrongSize(size) => write!(
+                f,
+                "boot ROM is {} bytes, expected {} (DMG/SGB) or {} (CGB)",
+                size, DMG_BOOT_ROM_SIZE, CGB_BOOT_ROM_SIZE
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BootRomError {}
+
+// Overlays the cartridge until 0xFF50 is written. The CGB boot ROM is
+// split around the cartridge header at 0x0100-0x01FF.
+#[derive(Clone)]
+pub struct BootRom {
+    data: Vec<u8>,
+}
+
+impl BootRom {
+    pub fn new(data: Vec<u8>) -> Result<BootRom, BootRomError> {
+        match data.len() {
+            DMG_BOOT_ROM_SIZE | CGB_BOOT_ROM_SIZE => Ok(BootRom { data }),
+            size => Err(BootRomError::WrongSize(size)),
+        }
+    }
+
+    // None where the cartridge shows through
+    pub fn read(&self, address: u16) -> Option<u8> {
+        let address = address as usize;
+        if (0x0100..0x0200).contains(&address) {
+            return None;
+        }
+        self.data.get(address).copied()
+    }
+}
+
+#[cfg(test)]
+mod boot_rom_tests {
+    use super::*;
+    #[test]
+    fn rejects_odd_sizes() {
+        assert_eq!(BootRom::new(vec![0; 0x200]).err(), Some(BootRomError::WrongSize(0x200)));
+    }
+    #[test]
+    fn cgb_boot_rom_skips_header() {
+        let boot_rom = BootRom::new(vec![0xAA; CGB_BOOT_ROM_SIZE]).unwrap();
+        assert_eq!(boot_rom.read(0x00FF), Some(0xAA));
+        assert_eq!(boot_rom.read(0x0150), None);
+        assert_eq!(boot_rom.read(0x0200), Some(0xAA));
+        assert_eq!(boot_rom.read(0x0900), None);
+    }
+}
@@ -0,0 +1,281 @@
+use std::fmt;
+
+use crate::cpu::CPU;
+use crate::debugger::instruction_length;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameKind {
+    Call,
+    Rst,
+    Interrupt,
+}
+
+// One entry on the shadow stack. `sp` is where the return address was
+// pushed, the frame is over once SP climbs back above it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    // The call or rst, or the instruction an interrupt came in before
+    pub call_site: u16,
+    pub call_bank: u16,
+    pub target: u16,
+    pub target_bank: u16,
+    pub return_address: u16,
+    pub sp: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImbalanceKind {
+    // The return address on the stack was changed
+    WrongReturnAddress { returned_to: u16 },
+    // Something pushed in the function was never popped, so the return
+    // read it instead of the return address
+    DataLeftOnStack,
+    // SP went above the return address without a return, from a pop or
+    // by writing SP directly
+    FrameDiscarded { new_sp: u16 },
+}
+
+// Code that did not keep its stack balanced, found at `pc`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Imbalance {
+    pub kind: ImbalanceKind,
+    pub pc: u16,
+    pub bank: u16,
+    pub frame: Frame,
+}
+
+impl fmt::Display for Imbalance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let call = format!("the call at ${:04X} in bank {}", self.frame.call_site, self.frame.call_bank);
+        match self.kind {
+            ImbalanceKind::WrongReturnAddress { returned_to } => write!(
+                f,
+                "return at ${:04X} went to ${:04X}, {} expected ${:04X}",
+                self.pc, returned_to, call, self.frame.return_address
+            ),
+            ImbalanceKind::DataLeftOnStack => write!(
+                f,
+                "return at ${:04X} with data left on the stack above the return address of {}",
+                self.pc, call
+            ),
+            ImbalanceKind::FrameDiscarded { new_sp } => write!(
+                f,
+                "SP moved to ${:04X} at ${:04X} without returning from {}",
+                new_sp, self.pc, call
+            ),
+        }
+    }
+}
+
+// CALL, conditional CALLs and RST
+pub fn is_call(opcode: u8) -> bool {
+    matches!(opcode, 0xCD | 0xC4 | 0xCC | 0xD4 | 0xDC) || opcode & 0xC7 == 0xC7
+}
+
+pub fn is_return(opcode: u8) -> bool {
+    matches!(opcode, 0xC9 | 0xD9 | 0xC0 | 0xC8 | 0xD0 | 0xD8)
+}
+
+// Follows calls, rsts and interrupts into functions and returns out of
+// them by watching PC and SP around each step. Anything already on the
+// stack when tracking starts is unknown, so returns past the bottom
+// frame are ignored.
+pub struct CallStack {
+    pub frames: Vec<Frame>,
+    // Found since the owner last took them
+    pub imbalances: Vec<Imbalance>,
+}
+
+impl CallStack {
+    pub fn new() -> CallStack {
+        CallStack {
+            frames: Vec::new(),
+            imbalances: Vec::new(),
+        }
+    }
+
+    // Steps the machine, returning the cycles taken like CPU::step
+    pub fn step(&mut self, cpu: &mut CPU) -> u32 {
+        let (pc, sp) = (cpu.pc, cpu.sp);
+        let bank = cpu.bus.bank_at(pc);
+        let executes = cpu.executes_next();
+        let opcode = cpu.bus.peek(pc);
+        let length = instruction_length(&cpu.bus, pc);
+        let cycles = cpu.step();
+        let pushed = cpu.sp == sp.wrapping_sub(2);
+        let kind = match opcode {
+            _ if !executes => FrameKind::Interrupt,
+            0xCD | 0xC4 | 0xCC | 0xD4 | 0xDC => FrameKind::Call,
+            _ => FrameKind::Rst,
+        };
+        if pushed && (!executes || is_call(opcode)) {
+            self.frames.push(Frame {
+                kind,
+                call_site: pc,
+                call_bank: bank,
+                target: cpu.pc,
+                target_bank: cpu.bus.bank_at(cpu.pc),
+                return_address: if executes { pc.wrapping_add(length) } else { pc },
+                sp: cpu.sp,
+            });
+        } else if executes && is_return(opcode) && cpu.sp == sp.wrapping_add(2) {
+            self.leave(pc, bank, sp, cpu.pc);
+        }
+        self.unwind(pc, bank, cpu.sp);
+        cycles
+    }
+
+    fn leave(&mut self, pc: u16, bank: u16, sp: u16, returned_to: u16) {
+        let frame = match self.frames.last() {
+            Some(frame) => *frame,
+            None => return,
+        };
+        if frame.sp != sp {
+            // The frame stays, its return address is still further up
+            self.imbalances.push(Imbalance { kind: ImbalanceKind::DataLeftOnStack, pc, bank, frame });
+            return;
+        }
+        self.frames.pop();
+        if returned_to != frame.return_address {
+            let kind = ImbalanceKind::WrongReturnAddress { returned_to };
+            self.imbalances.push(Imbalance { kind, pc, bank, frame });
+        }
+    }
+
+    // Drops frames whose return address SP has moved past
+    fn unwind(&mut self, pc: u16, bank: u16, sp: u16) {
+        while let Some(&frame) = self.frames.last() {
+            if frame.sp >= sp {
+                break;
+            }
+            self.frames.pop();
+            let kind = ImbalanceKind::FrameDiscarded { new_sp: sp };
+            self.imbalances.push(Imbalance { kind, pc, bank, frame });
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.imbalances.clear();
+    }
+}
+
+impl Default for CallStack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod call_stack_tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+
+    fn machine(code: &[(u16, &[u8])]) -> CPU {
+        let mut rom = vec![0; 0x8000];
+        for (address, bytes) in code {
+            let start = *address as usize;
+            rom[start..start + bytes.len()].copy_from_slice(bytes);
+        }
+        let mut cpu = CPU::with_cartridge(Cartridge::new(rom).unwrap());
+        cpu.sp = 0xDFFF;
+        cpu
+    }
+
+    fn run(stack: &mut CallStack, cpu: &mut CPU, steps: usize) {
+        for _ in 0..steps {
+            stack.step(cpu);
+        }
+    }
+
+    #[test]
+    fn follows_calls_and_returns() {
+        let mut cpu = machine(&[
+            (0x0100, &[0xCD, 0x00, 0x02, 0x00]), // call $0200, nop
+            (0x0200, &[0xEF, 0xC9]),             // rst $28, ret
+            (0x0028, &[0xC9]),                   // ret
+        ]);
+        let mut stack = CallStack::new();
+        run(&mut stack, &mut cpu, 2);
+        assert_eq!(cpu.pc, 0x0028);
+        let kinds: Vec<FrameKind> = stack.frames.iter().map(|frame| frame.kind).collect();
+        assert_eq!(kinds, vec![FrameKind::Call, FrameKind::Rst]);
+        assert_eq!(stack.frames[0].return_address, 0x0103);
+        assert_eq!(stack.frames[1].call_site, 0x0200);
+        assert_eq!(stack.frames[1].sp, 0xDFFB);
+        run(&mut stack, &mut cpu, 1);
+        assert_eq!(stack.frames.len(), 1);
+        run(&mut stack, &mut cpu, 1);
+        assert_eq!(cpu.pc, 0x0103);
+        assert!(stack.frames.is_empty());
+        assert!(stack.imbalances.is_empty());
+    }
+    #[test]
+    fn follows_interrupts() {
+        let mut cpu = machine(&[(0x0100, &[0xFB, 0x00, 0x00]), (0x0050, &[0xD9])]);
+        cpu.bus.interrupt_enable = 0x04;
+        let mut stack = CallStack::new();
+        run(&mut stack, &mut cpu, 2);
+        cpu.bus.interrupt_flag = 0x04;
+        run(&mut stack, &mut cpu, 1);
+        assert_eq!(cpu.pc, 0x0050);
+        assert_eq!(stack.frames[0].kind, FrameKind::Interrupt);
+        assert_eq!(stack.frames[0].return_address, 0x0102);
+        run(&mut stack, &mut cpu, 1);
+        assert_eq!(cpu.pc, 0x0102);
+        assert!(stack.frames.is_empty());
+        assert!(stack.imbalances.is_empty());
+    }
+    #[test]
+    fn reports_imbalances() {
+        let mut cpu = machine(&[
+            (0x0100, &[0xCD, 0x00, 0x02]), // call $0200
+            (0x0200, &[0xC5, 0xC9]),       // push bc, ret
+            (0x0300, &[0xCD, 0x00, 0x04]), // call $0400
+            (0x0400, &[0xE1, 0xE9]),       // pop hl, jp hl
+        ]);
+        cpu.registers.set_bc(0x0300);
+        let mut stack = CallStack::new();
+        run(&mut stack, &mut cpu, 3);
+        assert_eq!(cpu.pc, 0x0300);
+        assert_eq!(stack.imbalances[0].kind, ImbalanceKind::DataLeftOnStack);
+        assert_eq!(stack.imbalances[0].pc, 0x0201);
+        assert_eq!(stack.frames.len(), 1);
+        run(&mut stack, &mut cpu, 2);
+        assert_eq!(stack.imbalances[1].kind, ImbalanceKind::FrameDiscarded { new_sp: 0xDFFD });
+        assert_eq!(stack.imbalances[1].frame.call_site, 0x0300);
+        assert_eq!(
+            stack.imbalances[1].to_string(),
+            "SP moved to $DFFD at $0400 without returning from the call at $0300 in bank 0"
+        );
+        assert_eq!(stack.frames.len(), 1);
+    }
+    #[test]
+    fn reports_changed_return_addresses() {
+        let mut cpu = machine(&[
+            (0x0100, &[0xCD, 0x00, 0x02]), // call $0200
+            (0x0200, &[0xE1, 0x23, 0xE5, 0xC9]), // pop hl, inc hl, push hl, ret
+        ]);
+        let mut stack = CallStack::new();
+        run(&mut stack, &mut cpu, 5);
+        assert_eq!(cpu.pc, 0x0104);
+        assert!(stack.frames.is_empty());
+        // The pop already let go of the frame
+        assert!(matches!(stack.imbalances[0].kind, ImbalanceKind::FrameDiscarded { .. }));
+        stack.clear();
+        stack.frames.push(Frame {
+            kind: FrameKind::Call,
+            call_site: 0x0100,
+            call_bank: 0,
+            target: 0x0200,
+            target_bank: 0,
+            return_address: 0x0103,
+            sp: 0xDFFD,
+        });
+        cpu.pc = 0x0203;
+        cpu.sp = 0xDFFD;
+        run(&mut stack, &mut cpu, 1);
+        assert_eq!(stack.imbalances[0].kind, ImbalanceKind::WrongReturnAddress { returned_to: 0x0104 });
+    }
+}
@@ -0,0 +1,58 @@
+// The cartridge header lives at 0x0100-0x014F of bank 0
+#[derive(Clone, Debug, PartialEq)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub cgb_flag: u8,
+    pub sgb_flag: u8,
+    pub cartridge_type: u8,
+    pub rom_size: usize,
+    pub ram_size: usize,
+    pub old_licensee_code: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> CartridgeHeader {
+        // CGB cartridges shorten the title to make room for the
+        // manufacturer code and CGB flag
+        let cgb_flag = rom[0x143];
+        let title_end = if cgb_flag & 0x80 != 0 { 0x143 } else { 0x144 };
+        let title = rom[0x134..title_end]
+            .iter()
+            .take_while(|&&byte| byte != 0)
+            .map(|&byte| byte as char)
+            .collect();
+
+        CartridgeHeader {
+            title,
+            cgb_flag,
+            sgb_flag: rom[0x146],
+            cartridge_type: rom[0x147],
+            rom_size: 0x8000 << (rom[0x148] & 0x0F),
+            ram_size: match rom[0x149] {
+                0x01 => 0x800,
+                0x02 => 0x2000,
+                0x03 => 0x8000,
+                0x04 => 0x20000,
+                0x05 => 0x10000,
+                _ => 0,
+            },
+            old_licensee_code: rom[0x14B],
+            header_checksum: rom[0x14D],
+            global_checksum: (rom[0x14E] as u16) << 8 | rom[0x14F] as u16,
+        }
+    }
+
+    // 0x80 marks CGB enhanced games, 0xC0 CGB only ones
+    pub fn supports_cgb(&self) -> bool {
+        self.cgb_flag & 0x80 != 0
+    }
+
+    // Checksum of 0x0134-0x014C that the boot ROM verifies
+    pub fn compute_header_checksum(rom: &[u8]) -> u8 {
+        rom[0x134..=0x14C]
+            .iter()
+            .fold(0u8, |checksum, &byte| checksum.wrapping_sub(byte).wrapping_sub(1))
+    }
+}
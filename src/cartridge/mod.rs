@@ -0,0 +1,307 @@
+pub mod header;
+pub mod rtc;
+
+use std::fmt;
+
+use self::header::CartridgeHeader;
+use self::rtc::Rtc;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+#[derive(Debug, PartialEq)]
+pub enum CartridgeError {
+    TooSmall(usize),
+    UnsupportedType(u8),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::TooSmall(size) => {
+                write!(f, "ROM is {} bytes, too small to contain a cartridge header", size)
+            }
+            CartridgeError::UnsupportedType(kind) => {
+                write!(f, "unsupported cartridge type 0x{:02X}", kind)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MbcKind {
+    None,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+}
+
+pub struct Cartridge {
+    pub header: CartridgeHeader,
+    pub kind: MbcKind,
+    rom: Vec<u8>,
+    pub ram: Vec<u8>,
+    pub rtc: Option<Rtc>,
+    pub ram_enabled: bool,
+    pub rom_bank: u16,
+    pub ram_bank: u8,
+    // MBC1 banking mode, selects whether the upper bank bits apply to
+    // the 0x0000-0x3FFF region and RAM
+    pub banking_mode: bool,
+}
+
+impl Cartridge {
+    pub fn new(rom: Vec<u8>) -> Result<Cartridge, CartridgeError> {
+        if rom.len() < 0x150 {
+            return Err(CartridgeError::TooSmall(rom.len()));
+        }
+        let header = CartridgeHeader::parse(&rom);
+        let (kind, has_rtc) = match header.cartridge_type {
+            0x00 | 0x08 | 0x09 => (MbcKind::None, false),
+            0x01..=0x03 => (MbcKind::Mbc1, false),
+            0x05 | 0x06 => (MbcKind::Mbc2, false),
+            0x0F | 0x10 => (MbcKind::Mbc3, true),
+            0x11..=0x13 => (MbcKind::Mbc3, false),
+            0x19..=0x1E => (MbcKind::Mbc5, false),
+            other => return Err(CartridgeError::UnsupportedType(other)),
+        };
+        // MBC2 has 512 half-byte cells built into the controller
+        let ram_size = if kind == MbcKind::Mbc2 { 0x200 } else { header.ram_size };
+        Ok(Cartridge {
+            header,
+            kind,
+            rom,
+            ram: vec![0; ram_size],
+            rtc: if has_rtc { Some(Rtc::new()) } else { None },
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            banking_mode: false,
+        })
+    }
+
+    // Builds a cartridge around a ROM image that has no meaningful
+    // header, such as the memory map assembled for a GBS rip. The image
+    // still has to cover the header area.
+    pub fn with_mapper(rom: Vec<u8>, kind: MbcKind, ram_size: usize) -> Cartridge {
+        Cartridge {
+            header: CartridgeHeader::parse(&rom),
+            kind,
+            rom,
+            ram: vec![0; ram_size],
+            rtc: None,
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            banking_mode: false,
+        }
+    }
+
+    // A 32KiB ROM-only cartridge filled with zeroes, which is what the
+    // CPU sees when no game is inserted
+    pub fn empty() -> Cartridge {
+        Cartridge::new(vec![0; 0x8000]).unwrap()
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub fn rom_bank_count(&self) -> usize {
+        self.rom.len().div_ceil(ROM_BANK_SIZE)
+    }
+
+    // The bank currently mapped at 0x4000-0x7FFF
+    pub fn current_rom_bank(&self) -> usize {
+        self.high_bank()
+    }
+
+    // The bank read at a 0x0000-0x7FFF address
+    pub fn rom_bank_at(&self, address: u16) -> usize {
+        match address {
+            0x0000..=0x3FFF => self.low_bank(),
+            _ => self.high_bank(),
+        }
+    }
+
+    // The bank mapped at 0xA000-0xBFFF
+    pub fn current_ram_bank(&self) -> usize {
+        match self.kind {
+            MbcKind::Mbc1 if !self.banking_mode => 0,
+            MbcKind::None | MbcKind::Mbc2 => 0,
+            _ => self.ram_bank as usize,
+        }
+    }
+
+    fn low_bank(&self) -> usize {
+        match self.kind {
+            MbcKind::Mbc1 if self.banking_mode => ((self.ram_bank as usize) << 5) % self.rom_bank_count(),
+            _ => 0,
+        }
+    }
+
+    fn high_bank(&self) -> usize {
+        let bank = match self.kind {
+            MbcKind::None => 1,
+            MbcKind::Mbc1 => (self.rom_bank as usize & 0x1F) | ((self.ram_bank as usize & 0x3) << 5),
+            _ => self.rom_bank as usize,
+        };
+        bank % self.rom_bank_count().max(1)
+    }
+
+    fn ram_offset(&self, address: u16) -> usize {
+        self.current_ram_bank() * RAM_BANK_SIZE + (address as usize - 0xA000)
+    }
+
+    pub fn read_rom(&self, address: u16) -> u8 {
+        let offset = match address {
+            0x0000..=0x3FFF => self.low_bank() * ROM_BANK_SIZE + address as usize,
+            _ => self.high_bank() * ROM_BANK_SIZE + (address as usize - 0x4000),
+        };
+        self.rom.get(offset).copied().unwrap_or(0xFF)
+    }
+
+    pub fn write_rom(&mut self, address: u16, value: u8) {
+        match self.kind {
+            MbcKind::None => {}
+            MbcKind::Mbc1 => match address {
+                0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+                0x2000..=0x3FFF => {
+                    let bank = value & 0x1F;
+                    self.rom_bank = if bank == 0 { 1 } else { bank as u16 };
+                }
+                0x4000..=0x5FFF => self.ram_bank = value & 0x03,
+                _ => self.banking_mode = value & 0x01 != 0,
+            },
+            MbcKind::Mbc2 => {
+                if address < 0x4000 {
+                    // Address bit 8 selects between RAM enable and ROM bank
+                    if address & 0x0100 == 0 {
+                        self.ram_enabled = value & 0x0F == 0x0A;
+                    } else {
+                        let bank = value & 0x0F;
+                        self.rom_bank = if bank == 0 { 1 } else { bank as u16 };
+                    }
+                }
+            }
+            MbcKind::Mbc3 => match address {
+                0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+                0x2000..=0x3FFF => {
+                    let bank = value & 0x7F;
+                    self.rom_bank = if bank == 0 { 1 } else { bank as u16 };
+                }
+                0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+                _ => {
+                    if let Some(rtc) = self.rtc.as_mut() {
+                        rtc.write_latch(value);
+                    }
+                }
+            },
+            MbcKind::Mbc5 => match address {
+                0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+                0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | value as u16,
+                0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | ((value as u16 & 0x01) << 8),
+                0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+                _ => {}
+            },
+        }
+    }
+
+    pub fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled && self.kind != MbcKind::None {
+            return 0xFF;
+        }
+        if self.kind == MbcKind::Mbc3 && self.ram_bank >= 0x08 {
+            return match self.rtc.as_ref() {
+                Some(rtc) => rtc.read(self.ram_bank),
+                None => 0xFF,
+            };
+        }
+        if self.kind == MbcKind::Mbc2 {
+            let offset = (address as usize - 0xA000) & 0x1FF;
+            return self.ram[offset] | 0xF0;
+        }
+        let offset = self.ram_offset(address);
+        self.ram.get(offset).copied().unwrap_or(0xFF)
+    }
+
+    pub fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled && self.kind != MbcKind::None {
+            return;
+        }
+        if self.kind == MbcKind::Mbc3 && self.ram_bank >= 0x08 {
+            if let Some(rtc) = self.rtc.as_mut() {
+                rtc.write(self.ram_bank, value);
+            }
+            return;
+        }
+        if self.kind == MbcKind::Mbc2 {
+            let offset = (address as usize - 0xA000) & 0x1FF;
+            self.ram[offset] = value & 0x0F;
+            return;
+        }
+        let offset = self.ram_offset(address);
+        if let Some(cell) = self.ram.get_mut(offset) {
+            *cell = value;
+        }
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.step(cycles);
+        }
+    }
+}
+
+#[cfg(test)]
+mod cartridge_tests {
+    use super::*;
+
+    fn rom_with_type(cartridge_type: u8, banks: usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+        }
+        rom[0x147] = cartridge_type;
+        rom[0x149] = 0x03;
+        rom
+    }
+
+    #[test]
+    fn rejects_short_rom() {
+        assert_eq!(Cartridge::new(vec![0; 0x100]).err(), Some(CartridgeError::TooSmall(0x100)));
+    }
+    #[test]
+    fn rejects_unknown_mapper() {
+        let rom = rom_with_type(0xFC, 2);
+        assert_eq!(Cartridge::new(rom).err(), Some(CartridgeError::UnsupportedType(0xFC)));
+    }
+    #[test]
+    fn mbc1_bank_zero_maps_to_one() {
+        let mut cartridge = Cartridge::new(rom_with_type(0x01, 8)).unwrap();
+        cartridge.write_rom(0x2000, 0x00);
+        assert_eq!(cartridge.read_rom(0x4000), 1);
+        cartridge.write_rom(0x2000, 0x05);
+        assert_eq!(cartridge.read_rom(0x4000), 5);
+        assert_eq!(cartridge.read_rom(0x0000), 0);
+    }
+    #[test]
+    fn mbc1_ram_requires_enable() {
+        let mut cartridge = Cartridge::new(rom_with_type(0x03, 2)).unwrap();
+        cartridge.write_ram(0xA000, 0x42);
+        assert_eq!(cartridge.read_ram(0xA000), 0xFF);
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_ram(0xA000, 0x42);
+        assert_eq!(cartridge.read_ram(0xA000), 0x42);
+    }
+    #[test]
+    fn mbc5_ninth_bank_bit() {
+        let mut cartridge = Cartridge::new(rom_with_type(0x19, 0x102)).unwrap();
+        cartridge.write_rom(0x2000, 0x01);
+        cartridge.write_rom(0x3000, 0x01);
+        assert_eq!(cartridge.current_rom_bank(), 0x101);
+    }
+}
@@ -0,0 +1,105 @@
+const CYCLES_PER_SECOND: u32 = 4_194_304;
+
+// MBC3 real time clock. It advances with emulated time rather than the
+// host clock so runs stay deterministic.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RtcRegisters {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    pub days: u16,
+    pub halted: bool,
+    pub day_carry: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Rtc {
+    pub live: RtcRegisters,
+    pub latched: RtcRegisters,
+    pub cycles: u32,
+    pub latch_armed: bool,
+}
+
+impl Rtc {
+    pub fn new() -> Rtc {
+        Rtc::default()
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        if self.live.halted {
+            return;
+        }
+        self.cycles += cycles;
+        while self.cycles >= CYCLES_PER_SECOND {
+            self.cycles -= CYCLES_PER_SECOND;
+            self.tick_second();
+        }
+    }
+
+    fn tick_second(&mut self) {
+        let time = &mut self.live;
+        time.seconds = (time.seconds + 1) & 0x3F;
+        if time.seconds != 60 {
+            return;
+        }
+        time.seconds = 0;
+        time.minutes = (time.minutes + 1) & 0x3F;
+        if time.minutes != 60 {
+            return;
+        }
+        time.minutes = 0;
+        time.hours = (time.hours + 1) & 0x1F;
+        if time.hours != 24 {
+            return;
+        }
+        time.hours = 0;
+        time.days += 1;
+        if time.days > 0x1FF {
+            time.days = 0;
+            time.day_carry = true;
+        }
+    }
+
+    // Writing 0x00 then 0x01 copies the live registers into the latch
+    pub fn write_latch(&mut self, value: u8) {
+        if value == 0x01 && self.latch_armed {
+            self.latched = self.live;
+        }
+        self.latch_armed = value == 0x00;
+    }
+
+    pub fn read(&self, register: u8) -> u8 {
+        let time = &self.latched;
+        match register {
+            0x08 => time.seconds,
+            0x09 => time.minutes,
+            0x0A => time.hours,
+            0x0B => time.days as u8,
+            0x0C => {
+                ((time.days >> 8) as u8 & 0x01)
+                    | if time.halted { 0x40 } else { 0 }
+                    | if time.day_carry { 0x80 } else { 0 }
+            }
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, register: u8, value: u8) {
+        let time = &mut self.live;
+        match register {
+            0x08 => {
+                time.seconds = value & 0x3F;
+                self.cycles = 0;
+            }
+            0x09 => time.minutes = value & 0x3F,
+            0x0A => time.hours = value & 0x1F,
+            0x0B => time.days = (time.days & 0x100) | value as u16,
+            0x0C => {
+                time.days = (time.days & 0xFF) | ((value as u16 & 0x01) << 8);
+                time.halted = value & 0x40 != 0;
+                time.day_carry = value & 0x80 != 0;
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,372 @@
+// Colorization the CGB boot ROM applies to DMG cartridges. Nintendo
+// titles are looked up by a checksum of their title, everything else
+// gets the default palette. Holding a button combination during the
+// boot logo overrides the choice.
+
+// Raw palettes from the boot ROM, four RGB555 colors each
+const PALETTES: [[u16; 4]; 30] = [
+    [0x7FFF, 0x32BF, 0x00D0, 0x0000],
+    [0x639F, 0x4279, 0x15B0, 0x04CB],
+    [0x7FFF, 0x6E31, 0x454A, 0x0000],
+    [0x7FFF, 0x1BEF, 0x0200, 0x0000],
+    [0x7FFF, 0x421F, 0x1CF2, 0x0000],
+    [0x7FFF, 0x5294, 0x294A, 0x0000],
+    [0x7FFF, 0x03FF, 0x012F, 0x0000],
+    [0x7FFF, 0x03EF, 0x01D6, 0x0000],
+    [0x7FFF, 0x42B5, 0x3DC8, 0x0000],
+    [0x7E74, 0x03FF, 0x0180, 0x0000],
+    [0x67FF, 0x77AC, 0x1A13, 0x2D6B],
+    [0x7ED6, 0x4BFF, 0x2175, 0x0000],
+    [0x53FF, 0x4A5F, 0x7E52, 0x0000],
+    [0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0],
+    [0x03ED, 0x7FFF, 0x255F, 0x0000],
+    [0x036A, 0x021F, 0x03FF, 0x7FFF],
+    [0x7FFF, 0x01DF, 0x0112, 0x0000],
+    [0x231F, 0x035F, 0x00F2, 0x0009],
+    [0x7FFF, 0x03EA, 0x011F, 0x0000],
+    [0x299F, 0x001A, 0x000C, 0x0000],
+    [0x7FFF, 0x027F, 0x001F, 0x0000],
+    [0x7FFF, 0x03E0, 0x0206, 0x0120],
+    [0x7FFF, 0x7EEB, 0x001F, 0x7C00],
+    [0x7FFF, 0x3FFF, 0x7E00, 0x001F],
+    [0x7FFF, 0x03FF, 0x001F, 0x0000],
+    [0x03FF, 0x001F, 0x000C, 0x0000],
+    [0x7FFF, 0x033F, 0x0193, 0x0000],
+    [0x0000, 0x4200, 0x037F, 0x7FFF],
+    [0x7FFF, 0x7E8C, 0x7C00, 0x0000],
+    [0x7FFF, 0x1BEF, 0x6180, 0x0000],
+];
+
+// OBJ0, OBJ1 and BG as color offsets into PALETTES. A few entries start
+// mid palette, which the boot ROM's offsets allow.
+const COMBINATIONS: [(usize, usize, usize); 51] = [
+    (4 * 4, 4 * 4, 29 * 4),
+    (18 * 4, 18 * 4, 18 * 4),
+    (20 * 4, 20 * 4, 20 * 4),
+    (24 * 4, 24 * 4, 24 * 4),
+    (9 * 4, 9 * 4, 9 * 4),
+    (0, 0, 0),
+    (27 * 4, 27 * 4, 27 * 4),
+    (5 * 4, 5 * 4, 5 * 4),
+    (12 * 4, 12 * 4, 12 * 4),
+    (26 * 4, 26 * 4, 26 * 4),
+    (16 * 4, 8 * 4, 8 * 4),
+    (4 * 4, 28 * 4, 28 * 4),
+    (4 * 4, 2 * 4, 2 * 4),
+    (3 * 4, 4 * 4, 4 * 4),
+    (4 * 4, 29 * 4, 29 * 4),
+    (28 * 4, 4 * 4, 28 * 4),
+    (2 * 4, 17 * 4, 2 * 4),
+    (16 * 4, 16 * 4, 8 * 4),
+    (4 * 4, 4 * 4, 7 * 4),
+    (4 * 4, 4 * 4, 18 * 4),
+    (4 * 4, 4 * 4, 20 * 4),
+    (19 * 4, 19 * 4, 9 * 4),
+    (4 * 4 - 1, 4 * 4 - 1, 11 * 4),
+    (17 * 4, 17 * 4, 2 * 4),
+    (4 * 4, 4 * 4, 2 * 4),
+    (4 * 4, 4 * 4, 3 * 4),
+    (28 * 4, 28 * 4, 0),
+    (3 * 4, 3 * 4, 0),
+    (0, 0, 4),
+    (18 * 4, 22 * 4, 18 * 4),
+    (20 * 4, 22 * 4, 20 * 4),
+    (24 * 4, 22 * 4, 24 * 4),
+    (16 * 4, 22 * 4, 8 * 4),
+    (17 * 4, 4 * 4, 13 * 4),
+    (28 * 4 - 1, 0, 14 * 4),
+    (28 * 4 - 1, 4 * 4, 15 * 4),
+    (19 * 4, 22 * 4, 9 * 4),
+    (16 * 4, 28 * 4, 10 * 4),
+    (4 * 4, 23 * 4, 28 * 4),
+    (17 * 4, 22 * 4, 2 * 4),
+    (4 * 4, 0, 2 * 4),
+    (4 * 4, 28 * 4, 3 * 4),
+    (28 * 4, 3 * 4, 0),
+    (3 * 4, 28 * 4, 4 * 4),
+    (21 * 4, 28 * 4, 4 * 4),
+    (3 * 4, 28 * 4, 0),
+    (25 * 4, 3 * 4, 28 * 4),
+    (0, 28 * 4, 8 * 4),
+    (4 * 4, 3 * 4, 28 * 4),
+    (28 * 4, 3 * 4, 6 * 4),
+    (4 * 4, 28 * 4, 29 * 4),
+];
+
+// Title checksums and the combination each one selects. Entries from
+// FIRST_DUPLICATE on share a checksum with another title and also have
+// to match the title's 4th letter.
+const TITLE_CHECKSUMS: [(u8, usize); 94] = [
+    (0x00, 0),
+    (0x88, 4),
+    (0x16, 5),
+    (0x36, 35),
+    (0xD1, 34),
+    (0xDB, 3),
+    (0xF2, 31),
+    (0x3C, 15),
+    (0x8C, 10),
+    (0x92, 5),
+    (0x3D, 19),
+    (0x5C, 36),
+    (0x58, 7),
+    (0xC9, 37),
+    (0x3E, 30),
+    (0x70, 44),
+    (0x1D, 21),
+    (0x59, 32),
+    (0x69, 31),
+    (0x19, 20),
+    (0x35, 5),
+    (0xA8, 33),
+    (0x14, 13),
+    (0xAA, 14),
+    (0x75, 5),
+    (0x95, 29),
+    (0x99, 5),
+    (0x34, 18),
+    (0x6F, 9),
+    (0x15, 3),
+    (0xFF, 2),
+    (0x97, 26),
+    (0x4B, 25),
+    (0x90, 25),
+    (0x17, 41),
+    (0x10, 42),
+    (0x39, 26),
+    (0xF7, 45),
+    (0xF6, 42),
+    (0xA2, 45),
+    (0x49, 36),
+    (0x4E, 38),
+    (0x43, 26),
+    (0x68, 42),
+    (0xE0, 30),
+    (0x8B, 41),
+    (0xF0, 34),
+    (0xCE, 34),
+    (0x0C, 5),
+    (0x29, 42),
+    (0xE8, 6),
+    (0xB7, 5),
+    (0x86, 33),
+    (0x9A, 25),
+    (0x52, 42),
+    (0x01, 42),
+    (0x9D, 40),
+    (0x71, 2),
+    (0x9C, 16),
+    (0xBD, 25),
+    (0x5D, 42),
+    (0x6D, 42),
+    (0x67, 5),
+    (0x3F, 0),
+    (0x6B, 39),
+    (0xB3, 36),
+    (0x46, 22),
+    (0x28, 25),
+    (0xA5, 6),
+    (0xC6, 32),
+    (0xD3, 12),
+    (0x27, 36),
+    (0x61, 11),
+    (0x18, 39),
+    (0x66, 18),
+    (0x6A, 39),
+    (0xBF, 24),
+    (0x0D, 31),
+    (0xF4, 50),
+    (0xB3, 17),
+    (0x46, 46),
+    (0x28, 6),
+    (0xA5, 27),
+    (0xC6, 0),
+    (0xD3, 47),
+    (0x27, 41),
+    (0x61, 41),
+    (0x18, 0),
+    (0x66, 0),
+    (0x6A, 19),
+    (0xBF, 34),
+    (0x0D, 23),
+    (0xF4, 18),
+    (0xB3, 29),
+];
+
+const FIRST_DUPLICATE: usize = 65;
+const FOURTH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ButtonCombo {
+    Up,
+    UpA,
+    UpB,
+    Left,
+    LeftA,
+    LeftB,
+    Down,
+    DownA,
+    DownB,
+    Right,
+    RightA,
+    RightB,
+}
+
+impl ButtonCombo {
+    pub const ALL: [ButtonCombo; 12] = [
+        ButtonCombo::Up,
+        ButtonCombo::UpA,
+        ButtonCombo::UpB,
+        ButtonCombo::Left,
+        ButtonCombo::LeftA,
+        ButtonCombo::LeftB,
+        ButtonCombo::Down,
+        ButtonCombo::DownA,
+        ButtonCombo::DownB,
+        ButtonCombo::Right,
+        ButtonCombo::RightA,
+        ButtonCombo::RightB,
+    ];
+
+    // Names like "up", "left+a" or "down+b"
+    pub fn from_name(name: &str) -> Option<ButtonCombo> {
+        ButtonCombo::ALL.iter().copied().find(|combo| combo.name() == name.to_lowercase())
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ButtonCombo::Up => "up",
+            ButtonCombo::UpA => "up+a",
+            ButtonCombo::UpB => "up+b",
+            ButtonCombo::Left => "left",
+            ButtonCombo::LeftA => "left+a",
+            ButtonCombo::LeftB => "left+b",
+            ButtonCombo::Down => "down",
+            ButtonCombo::DownA => "down+a",
+            ButtonCombo::DownB => "down+b",
+            ButtonCombo::Right => "right",
+            ButtonCombo::RightA => "right+a",
+            ButtonCombo::RightB => "right+b",
+        }
+    }
+
+    fn combination(self) -> usize {
+        match self {
+            ButtonCombo::Up => 5,
+            ButtonCombo::UpA => 43,
+            ButtonCombo::UpB => 28,
+            ButtonCombo::Left => 48,
+            ButtonCombo::LeftA => 40,
+            ButtonCombo::LeftB => 7,
+            ButtonCombo::Down => 8,
+            ButtonCombo::DownA => 3,
+            ButtonCombo::DownB => 49,
+            ButtonCombo::Right => 1,
+            ButtonCombo::RightA => 0,
+            ButtonCombo::RightB => 6,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CompatPalette {
+    pub bg: [u16; 4],
+    pub obj0: [u16; 4],
+    pub obj1: [u16; 4],
+}
+
+// Sum of the 16 title bytes, only computed for Nintendo licensed titles
+pub fn nintendo_title_checksum(rom: &[u8]) -> Option<u8> {
+    let nintendo = rom[0x14B] == 0x01 || (rom[0x14B] == 0x33 && &rom[0x144..0x146] == b"01");
+    if !nintendo {
+        return None;
+    }
+    Some(rom[0x134..0x144].iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)))
+}
+
+fn colors(offset: usize) -> [u16; 4] {
+    let mut colors = [0; 4];
+    for (index, color) in colors.iter_mut().enumerate() {
+        let position = offset + index;
+        *color = PALETTES[position / 4][position % 4];
+    }
+    colors
+}
+
+impl CompatPalette {
+    fn from_combination(index: usize) -> CompatPalette {
+        let (obj0, obj1, bg) = COMBINATIONS[index];
+        CompatPalette {
+            bg: colors(bg),
+            obj0: colors(obj0),
+            obj1: colors(obj1),
+        }
+    }
+
+    pub fn for_combo(combo: ButtonCombo) -> CompatPalette {
+        CompatPalette::from_combination(combo.combination())
+    }
+
+    // Picks the palette the boot ROM would for a DMG cartridge
+    pub fn for_rom(rom: &[u8]) -> CompatPalette {
+        // Only titles licensed by Nintendo get a custom palette
+        let checksum = match nintendo_title_checksum(rom) {
+            Some(checksum) => checksum,
+            None => return CompatPalette::from_combination(0),
+        };
+        let fourth_letter = rom[0x137];
+        let combination = TITLE_CHECKSUMS
+            .iter()
+            .enumerate()
+            .find(|&(index, &(entry, _))| {
+                entry == checksum
+                    && (index < FIRST_DUPLICATE || FOURTH_LETTERS[index - FIRST_DUPLICATE] == fourth_letter)
+            })
+            .map_or(0, |(_, &(_, combination))| combination);
+        CompatPalette::from_combination(combination)
+    }
+}
+
+#[cfg(test)]
+mod compat_palette_tests {
+    use super::*;
+
+    fn rom_with_title(title: &[u8], licensee: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        rom[0x14B] = licensee;
+        rom
+    }
+
+    #[test]
+    fn unlicensed_titles_get_default() {
+        let rom = rom_with_title(b"TETRIS", 0x00);
+        assert_eq!(CompatPalette::for_rom(&rom), CompatPalette::for_combo(ButtonCombo::RightA));
+    }
+    #[test]
+    fn title_checksum_lookup() {
+        let rom = rom_with_title(b"TETRIS", 0x01);
+        assert_eq!(CompatPalette::for_rom(&rom), CompatPalette::for_combo(ButtonCombo::DownA));
+    }
+    #[test]
+    fn duplicate_checksums_use_fourth_letter() {
+        // POKEMON BLUE shares 0x61 with VEGAS STAKES
+        let blue = CompatPalette::for_rom(&rom_with_title(b"POKEMON BLUE", 0x01));
+        assert_eq!(blue.bg, [0x7FFF, 0x7E8C, 0x7C00, 0x0000]);
+        // Same checksum, but neither title's 4th letter
+        let mut rom = rom_with_title(b"POKFLON BLUE", 0x33);
+        rom[0x144..0x146].copy_from_slice(b"01");
+        assert_eq!(CompatPalette::for_rom(&rom), CompatPalette::for_combo(ButtonCombo::RightA));
+    }
+    #[test]
+    fn combos_by_name() {
+        assert_eq!(ButtonCombo::from_name("Left+B"), Some(ButtonCombo::LeftB));
+        assert_eq!(ButtonCombo::from_name("select"), None);
+        let gray = CompatPalette::for_combo(ButtonCombo::LeftB);
+        assert_eq!(gray.bg, [0x7FFF, 0x5294, 0x294A, 0x0000]);
+    }
+    #[test]
+    fn offsets_can_straddle_palettes() {
+        assert_eq!(CompatPalette::from_combination(22).obj0, [0x0000, 0x7FFF, 0x421F, 0x1CF2]);
+    }
+}
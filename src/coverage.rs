@@ -0,0 +1,227 @@
+use std::cell::RefCell;
+
+use crate::cartridge::ROM_BANK_SIZE;
+use crate::cpu::CPU;
+use crate::debugger::instruction_length;
+use crate::memory_bus::{Access, AccessKind};
+
+// How each ROM byte has been used, several can apply to one byte
+pub const OPCODE: u8 = 0x01;
+pub const OPERAND: u8 = 0x02;
+pub const DATA: u8 = 0x04;
+
+// Bytes per row of the text map
+const ROW_BYTES: usize = 64;
+
+// Marks ROM bytes as they are executed or read. Record it after every
+// step with the accesses the step made, it works out the instruction
+// from the state it saw the time before.
+pub struct Coverage {
+    pub usage: Vec<u8>,
+    // The ROM offsets of the instruction about to run, None while the
+    // next step services an interrupt or stays halted
+    pending: Option<Vec<usize>>,
+}
+
+impl Coverage {
+    // Starts logging bus accesses on the machine, which stays on
+    pub fn attach(cpu: &mut CPU) -> Coverage {
+        cpu.bus.access_log = Some(RefCell::new(Vec::new()));
+        let mut coverage = Coverage {
+            usage: vec![0; cpu.bus.cartridge.rom().len()],
+            pending: None,
+        };
+        coverage.prepare(cpu);
+        coverage
+    }
+
+    fn prepare(&mut self, cpu: &CPU) {
+        self.pending = if cpu.executes_next() {
+            let length = instruction_length(&cpu.bus, cpu.pc);
+            Some((0..length).filter_map(|offset| cpu.bus.rom_offset(cpu.pc.wrapping_add(offset))).collect())
+        } else {
+            None
+        };
+    }
+
+    pub fn record(&mut self, cpu: &CPU, accesses: &[Access]) {
+        let instruction = self.pending.take().unwrap_or_default();
+        // The 0xCB prefix and the opcode after it are both opcode bytes
+        let prefixed = instruction.first().map(|&offset| cpu.bus.cartridge.rom()[offset]) == Some(0xCB);
+        let opcode_bytes = if prefixed { 2 } else { 1 };
+        for (index, &offset) in instruction.iter().enumerate() {
+            self.usage[offset] |= if index < opcode_bytes { OPCODE } else { OPERAND };
+        }
+        for access in accesses.iter().filter(|access| access.kind == AccessKind::Read) {
+            if let Some(offset) = cpu.bus.rom_offset(access.address) {
+                if !instruction.contains(&offset) {
+                    self.usage[offset] |= DATA;
+                }
+            }
+        }
+        self.prepare(cpu);
+    }
+
+    // Bytes with any of the usage bits set, in the bank or everywhere
+    pub fn count(&self, bank: Option<usize>, usage: u8) -> usize {
+        let bytes = match bank {
+            Some(bank) => self.bank(bank),
+            None => &self.usage[..],
+        };
+        bytes.iter().filter(|&&byte| byte & usage != 0).count()
+    }
+
+    fn bank(&self, bank: usize) -> &[u8] {
+        let start = (bank * ROM_BANK_SIZE).min(self.usage.len());
+        &self.usage[start..(start + ROM_BANK_SIZE).min(self.usage.len())]
+    }
+
+    fn bank_count(&self) -> usize {
+        self.usage.len().div_ceil(ROM_BANK_SIZE)
+    }
+
+    fn summary(&self, bank: Option<usize>) -> String {
+        let size = match bank {
+            Some(bank) => self.bank(bank).len(),
+            None => self.usage.len(),
+        };
+        let used = self.count(bank, OPCODE | OPERAND | DATA);
+        format!(
+            "{} of {} bytes used ({:.1}%), {} opcode, {} operand, {} data",
+            used,
+            size,
+            used as f64 * 100.0 / size.max(1) as f64,
+            self.count(bank, OPCODE),
+            self.count(bank, OPERAND),
+            self.count(bank, DATA)
+        )
+    }
+
+    // A character per byte, 64 to a row with the bank's address on the
+    // left. Rows nothing touched are left out.
+    pub fn text(&self) -> String {
+        let mut out = format!(
+            "; {}\n; . unused  C opcode  o operand  d data  * more than one\n",
+            self.summary(None)
+        );
+        for bank in 0..self.bank_count() {
+            out.push_str(&format!("\nbank {:02X}: {}\n", bank, self.summary(Some(bank))));
+            let base = if bank == 0 { 0 } else { ROM_BANK_SIZE };
+            for (row, bytes) in self.bank(bank).chunks(ROW_BYTES).enumerate() {
+                if bytes.iter().all(|&byte| byte == 0) {
+                    continue;
+                }
+                let map: String = bytes.iter().map(|&byte| symbol(byte)).collect();
+                out.push_str(&format!("{:04X} {}\n", base + row * ROW_BYTES, map));
+            }
+        }
+        out
+    }
+
+    // Per bank counts and the runs of bytes used the same way
+    pub fn json(&self) -> String {
+        let mut banks = Vec::new();
+        for bank in 0..self.bank_count() {
+            let base = if bank == 0 { 0 } else { ROM_BANK_SIZE };
+            let bytes = self.bank(bank);
+            let mut ranges = Vec::new();
+            let mut start = 0;
+            while start < bytes.len() {
+                let usage = bytes[start];
+                let length = bytes[start..].iter().take_while(|&&byte| byte == usage).count();
+                if usage != 0 {
+                    let kinds: Vec<&str> = [(OPCODE, "\"opcode\""), (OPERAND, "\"operand\""), (DATA, "\"data\"")]
+                        .iter()
+                        .filter(|(bit, _)| usage & bit != 0)
+                        .map(|(_, name)| *name)
+                        .collect();
+                    ranges.push(format!(
+                        "{{\"start\": {}, \"end\": {}, \"usage\": [{}]}}",
+                        base + start,
+                        base + start + length,
+                        kinds.join(", ")
+                    ));
+                }
+                start += length;
+            }
+            banks.push(format!(
+                "    {{\"bank\": {}, \"size\": {}, \"used\": {}, \"opcode\": {}, \"operand\": {}, \"data\": {}, \
+                 \"ranges\": [{}]}}",
+                bank,
+                bytes.len(),
+                self.count(Some(bank), OPCODE | OPERAND | DATA),
+                self.count(Some(bank), OPCODE),
+                self.count(Some(bank), OPERAND),
+                self.count(Some(bank), DATA),
+                ranges.join(", ")
+            ));
+        }
+        format!(
+            "{{\n  \"rom_size\": {},\n  \"used\": {},\n  \"banks\": [\n{}\n  ]\n}}\n",
+            self.usage.len(),
+            self.count(None, OPCODE | OPERAND | DATA),
+            banks.join(",\n")
+        )
+    }
+}
+
+fn symbol(usage: u8) -> char {
+    match usage {
+        0 => '.',
+        OPCODE => 'C',
+        OPERAND => 'o',
+        DATA => 'd',
+        _ => '*',
+    }
+}
+
+#[cfg(test)]
+mod coverage_tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+
+    // Reads a table byte, swaps it with a prefixed instruction and loops
+    fn machine() -> CPU {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x10A].copy_from_slice(&[
+            0xFA, 0x00, 0x20, // ld a, [$2000]
+            0xCB, 0x37, // swap a
+            0xC3, 0x00, 0x01, // jp $0100
+            0x00, 0x00,
+        ]);
+        CPU::with_cartridge(Cartridge::new(rom).unwrap())
+    }
+
+    #[test]
+    fn marks_opcodes_operands_and_data() {
+        let mut cpu = machine();
+        let mut coverage = Coverage::attach(&mut cpu);
+        for _ in 0..6 {
+            cpu.step();
+            coverage.record(&cpu, &cpu.bus.take_accesses());
+        }
+        assert_eq!(&coverage.usage[0x100..0x109], &[1, 2, 2, 1, 1, 1, 2, 2, 0]);
+        assert_eq!(coverage.usage[0x2000], DATA);
+        assert_eq!(coverage.count(None, OPCODE), 4);
+        assert_eq!(coverage.count(Some(1), OPCODE | OPERAND | DATA), 0);
+        let text = coverage.text();
+        assert!(text.starts_with("; 9 of 32768 bytes used (0.0%), 4 opcode, 4 operand, 1 data\n"), "{}", text);
+        assert!(text.contains("\n0100 CooCCCoo....."), "{}", text);
+        assert!(text.contains("\n2000 d....."), "{}", text);
+        assert!(text.contains("\nbank 01: 0 of 16384 bytes used"), "{}", text);
+    }
+    #[test]
+    fn exports_json_ranges() {
+        let mut cpu = machine();
+        let mut coverage = Coverage::attach(&mut cpu);
+        for _ in 0..3 {
+            cpu.step();
+            coverage.record(&cpu, &cpu.bus.take_accesses());
+        }
+        let json = coverage.json();
+        assert!(json.contains("\"rom_size\": 32768"), "{}", json);
+        assert!(json.contains("{\"start\": 256, \"end\": 257, \"usage\": [\"opcode\"]}"), "{}", json);
+        assert!(json.contains("{\"start\": 8192, \"end\": 8193, \"usage\": [\"data\"]}"), "{}", json);
+        assert!(json.contains("{\"bank\": 1, \"size\": 16384, \"used\": 0, "), "{}", json);
+    }
+}
@@ -1,3 +1,4 @@
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct FlagsRegister {
     pub zero: bool,
     pub subtract: bool,
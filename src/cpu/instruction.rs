@@ -1,15 +1,484 @@
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Instruction {
+    // Arithmetic and logic
     ADD(ArthimeticTarget),
     ADC(ArthimeticTarget),
     ADDHL(ADDHLTarget),
+    ADDSP,
     SUB(ArthimeticTarget),
-    SBC(ArthimeticTarget)
+    SBC(ArthimeticTarget),
+    AND(ArthimeticTarget),
+    XOR(ArthimeticTarget),
+    OR(ArthimeticTarget),
+    CP(ArthimeticTarget),
+    INC(IncDecTarget),
+    DEC(IncDecTarget),
+    DAA,
+    CPL,
+    SCF,
+    CCF,
+    RLCA,
+    RRCA,
+    RLA,
+    RRA,
+
+    // CB prefixed
+    RLC(PrefixTarget),
+    RRC(PrefixTarget),
+    RL(PrefixTarget),
+    RR(PrefixTarget),
+    SLA(PrefixTarget),
+    SRA(PrefixTarget),
+    SWAP(PrefixTarget),
+    SRL(PrefixTarget),
+    BIT(PrefixTarget, BitPosition),
+    RES(PrefixTarget, BitPosition),
+    SET(PrefixTarget, BitPosition),
+
+    // Jumps and calls
+    JP(JumpTest),
+    JPHL,
+    JR(JumpTest),
+    CALL(JumpTest),
+    RET(JumpTest),
+    RETI,
+    RST(u8),
+
+    // Loads and stack
+    LD(LoadType),
+    PUSH(StackTarget),
+    POP(StackTarget),
+
+    // Control
+    NOP,
+    HALT,
+    STOP,
+    DI,
+    EI,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ArthimeticTarget {
-    A, B, C, D, E, H, L,
+    A, B, C, D, E, H, L, HLI, D8,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ADDHLTarget {
     BC, DE, HL, SP
 }
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IncDecTarget {
+    A, B, C, D, E, H, L, HLI, BC, DE, HL, SP
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PrefixTarget {
+    A, B, C, D, E, H, L, HLI
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitPosition {
+    B0, B1, B2, B3, B4, B5, B6, B7
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JumpTest {
+    NotZero, Zero, NotCarry, Carry, Always
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StackTarget {
+    AF, BC, DE, HL
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoadByteTarget {
+    A, B, C, D, E, H, L, HLI
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoadByteSource {
+    A, B, C, D, E, H, L, D8, HLI
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoadWordTarget {
+    BC, DE, HL, SP
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Indirect {
+    BCIndirect,
+    DEIndirect,
+    HLIndirectPlus,
+    HLIndirectMinus,
+    WordIndirect,
+    LastByteIndirect,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoadType {
+    Byte(LoadByteTarget, LoadByteSource),
+    Word(LoadWordTarget),
+    AFromIndirect(Indirect),
+    IndirectFromA(Indirect),
+    AFromByteAddress,
+    ByteAddressFromA,
+    SPFromHL,
+    HLFromSPN,
+    IndirectFromSP,
+}
+
+impl std::convert::From<BitPosition> for u8 {
+    fn from(position: BitPosition) -> u8 {
+        match position {
+            BitPosition::B0 => 0,
+            BitPosition::B1 => 1,
+            BitPosition::B2 => 2,
+            BitPosition::B3 => 3,
+            BitPosition::B4 => 4,
+            BitPosition::B5 => 5,
+            BitPosition::B6 => 6,
+            BitPosition::B7 => 7,
+        }
+    }
+}
+
+impl BitPosition {
+    fn from_index(index: u8) -> BitPosition {
+        match index & 0b111 {
+            0 => BitPosition::B0,
+            1 => BitPosition::B1,
+            2 => BitPosition::B2,
+            3 => BitPosition::B3,
+            4 => BitPosition::B4,
+            5 => BitPosition::B5,
+            6 => BitPosition::B6,
+            _ => BitPosition::B7,
+        }
+    }
+}
+
+// Opcodes encode their 8 bit register operand in three bits, ordered
+// B, C, D, E, H, L, (HL), A
+impl PrefixTarget {
+    fn from_index(index: u8) -> PrefixTarget {
+        match index & 0b111 {
+            0 => PrefixTarget::B,
+            1 => PrefixTarget::C,
+            2 => PrefixTarget::D,
+            3 => PrefixTarget::E,
+            4 => PrefixTarget::H,
+            5 => PrefixTarget::L,
+            6 => PrefixTarget::HLI,
+            _ => PrefixTarget::A,
+        }
+    }
+}
+
+impl ArthimeticTarget {
+    fn from_index(index: u8) -> ArthimeticTarget {
+        match index & 0b111 {
+            0 => ArthimeticTarget::B,
+            1 => ArthimeticTarget::C,
+            2 => ArthimeticTarget::D,
+            3 => ArthimeticTarget::E,
+            4 => ArthimeticTarget::H,
+            5 => ArthimeticTarget::L,
+            6 => ArthimeticTarget::HLI,
+            _ => ArthimeticTarget::A,
+        }
+    }
+}
+
+impl LoadByteTarget {
+    fn from_index(index: u8) -> LoadByteTarget {
+        match index & 0b111 {
+            0 => LoadByteTarget::B,
+            1 => LoadByteTarget::C,
+            2 => LoadByteTarget::D,
+            3 => LoadByteTarget::E,
+            4 => LoadByteTarget::H,
+            5 => LoadByteTarget::L,
+            6 => LoadByteTarget::HLI,
+            _ => LoadByteTarget::A,
+        }
+    }
+}
+
+impl LoadByteSource {
+    fn from_index(index: u8) -> LoadByteSource {
+        match index & 0b111 {
+            0 => LoadByteSource::B,
+            1 => LoadByteSource::C,
+            2 => LoadByteSource::D,
+            3 => LoadByteSource::E,
+            4 => LoadByteSource::H,
+            5 => LoadByteSource::L,
+            6 => LoadByteSource::HLI,
+            _ => LoadByteSource::A,
+        }
+    }
+}
+
+impl Instruction {
+    pub fn from_byte(byte: u8, prefixed: bool) -> Option<Instruction> {
+        if prefixed {
+            Some(Instruction::from_byte_prefixed(byte))
+        } else {
+            Instruction::from_byte_not_prefixed(byte)
+        }
+    }
+
+    // Bytes taken up including the 0xCB prefix and any immediate
+    pub fn length(self) -> u16 {
+        match self {
+            Instruction::RLC(_)
+            | Instruction::RRC(_)
+            | Instruction::RL(_)
+            | Instruction::RR(_)
+            | Instruction::SLA(_)
+            | Instruction::SRA(_)
+            | Instruction::SWAP(_)
+            | Instruction::SRL(_)
+            | Instruction::BIT(..)
+            | Instruction::RES(..)
+            | Instruction::SET(..) => 2,
+            Instruction::ADD(ArthimeticTarget::D8)
+            | Instruction::ADC(ArthimeticTarget::D8)
+            | Instruction::SUB(ArthimeticTarget::D8)
+            | Instruction::SBC(ArthimeticTarget::D8)
+            | Instruction::AND(ArthimeticTarget::D8)
+            | Instruction::XOR(ArthimeticTarget::D8)
+            | Instruction::OR(ArthimeticTarget::D8)
+            | Instruction::CP(ArthimeticTarget::D8) => 2,
+            Instruction::ADDSP | Instruction::JR(_) | Instruction::STOP => 2,
+            Instruction::JP(_) | Instruction::CALL(_) => 3,
+            Instruction::LD(load_type) => match load_type {
+                LoadType::Byte(_, LoadByteSource::D8) => 2,
+                LoadType::AFromByteAddress | LoadType::ByteAddressFromA | LoadType::HLFromSPN => 2,
+                LoadType::Word(_) | LoadType::IndirectFromSP => 3,
+                LoadType::AFromIndirect(Indirect::WordIndirect)
+                | LoadType::IndirectFromA(Indirect::WordIndirect) => 3,
+                _ => 1,
+            },
+            _ => 1,
+        }
+    }
+
+    fn from_byte_prefixed(byte: u8) -> Instruction {
+        let target = PrefixTarget::from_index(byte);
+        let bit = BitPosition::from_index(byte >> 3);
+        match byte >> 3 {
+            0x00 => Instruction::RLC(target),
+            0x01 => Instruction::RRC(target),
+            0x02 => Instruction::RL(target),
+            0x03 => Instruction::RR(target),
+            0x04 => Instruction::SLA(target),
+            0x05 => Instruction::SRA(target),
+            0x06 => Instruction::SWAP(target),
+            0x07 => Instruction::SRL(target),
+            0x08..=0x0F => Instruction::BIT(target, bit),
+            0x10..=0x17 => Instruction::RES(target, bit),
+            _ => Instruction::SET(target, bit),
+        }
+    }
+
+    fn from_byte_not_prefixed(byte: u8) -> Option<Instruction> {
+        let instruction = match byte {
+            0x00 => Instruction::NOP,
+            0x10 => Instruction::STOP,
+            0x76 => Instruction::HALT,
+            0xF3 => Instruction::DI,
+            0xFB => Instruction::EI,
+
+            0x01 => Instruction::LD(LoadType::Word(LoadWordTarget::BC)),
+            0x11 => Instruction::LD(LoadType::Word(LoadWordTarget::DE)),
+            0x21 => Instruction::LD(LoadType::Word(LoadWordTarget::HL)),
+            0x31 => Instruction::LD(LoadType::Word(LoadWordTarget::SP)),
+
+            0x02 => Instruction::LD(LoadType::IndirectFromA(Indirect::BCIndirect)),
+            0x12 => Instruction::LD(LoadType::IndirectFromA(Indirect::DEIndirect)),
+            0x22 => Instruction::LD(LoadType::IndirectFromA(Indirect::HLIndirectPlus)),
+            0x32 => Instruction::LD(LoadType::IndirectFromA(Indirect::HLIndirectMinus)),
+            0xEA => Instruction::LD(LoadType::IndirectFromA(Indirect::WordIndirect)),
+            0xE2 => Instruction::LD(LoadType::IndirectFromA(Indirect::LastByteIndirect)),
+
+            0x0A => Instruction::LD(LoadType::AFromIndirect(Indirect::BCIndirect)),
+            0x1A => Instruction::LD(LoadType::AFromIndirect(Indirect::DEIndirect)),
+            0x2A => Instruction::LD(LoadType::AFromIndirect(Indirect::HLIndirectPlus)),
+            0x3A => Instruction::LD(LoadType::AFromIndirect(Indirect::HLIndirectMinus)),
+            0xFA => Instruction::LD(LoadType::AFromIndirect(Indirect::WordIndirect)),
+            0xF2 => Instruction::LD(LoadType::AFromIndirect(Indirect::LastByteIndirect)),
+
+            0xE0 => Instruction::LD(LoadType::ByteAddressFromA),
+            0xF0 => Instruction::LD(LoadType::AFromByteAddress),
+            0x08 => Instruction::LD(LoadType::IndirectFromSP),
+            0xF8 => Instruction::LD(LoadType::HLFromSPN),
+            0xF9 => Instruction::LD(LoadType::SPFromHL),
+
+            0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E => Instruction::LD(
+                LoadType::Byte(LoadByteTarget::from_index(byte >> 3), LoadByteSource::D8),
+            ),
+            0x40..=0x7F => Instruction::LD(LoadType::Byte(
+                LoadByteTarget::from_index(byte >> 3),
+                LoadByteSource::from_index(byte),
+            )),
+
+            0x03 => Instruction::INC(IncDecTarget::BC),
+            0x13 => Instruction::INC(IncDecTarget::DE),
+            0x23 => Instruction::INC(IncDecTarget::HL),
+            0x33 => Instruction::INC(IncDecTarget::SP),
+            0x04 => Instruction::INC(IncDecTarget::B),
+            0x0C => Instruction::INC(IncDecTarget::C),
+            0x14 => Instruction::INC(IncDecTarget::D),
+            0x1C => Instruction::INC(IncDecTarget::E),
+            0x24 => Instruction::INC(IncDecTarget::H),
+            0x2C => Instruction::INC(IncDecTarget::L),
+            0x34 => Instruction::INC(IncDecTarget::HLI),
+            0x3C => Instruction::INC(IncDecTarget::A),
+
+            0x0B => Instruction::DEC(IncDecTarget::BC),
+            0x1B => Instruction::DEC(IncDecTarget::DE),
+            0x2B => Instruction::DEC(IncDecTarget::HL),
+            0x3B => Instruction::DEC(IncDecTarget::SP),
+            0x05 => Instruction::DEC(IncDecTarget::B),
+            0x0D => Instruction::DEC(IncDecTarget::C),
+            0x15 => Instruction::DEC(IncDecTarget::D),
+            0x1D => Instruction::DEC(IncDecTarget::E),
+            0x25 => Instruction::DEC(IncDecTarget::H),
+            0x2D => Instruction::DEC(IncDecTarget::L),
+            0x35 => Instruction::DEC(IncDecTarget::HLI),
+            0x3D => Instruction::DEC(IncDecTarget::A),
+
+            0x09 => Instruction::ADDHL(ADDHLTarget::BC),
+            0x19 => Instruction::ADDHL(ADDHLTarget::DE),
+            0x29 => Instruction::ADDHL(ADDHLTarget::HL),
+            0x39 => Instruction::ADDHL(ADDHLTarget::SP),
+            0xE8 => Instruction::ADDSP,
+
+            0x07 => Instruction::RLCA,
+            0x0F => Instruction::RRCA,
+            0x17 => Instruction::RLA,
+            0x1F => Instruction::RRA,
+            0x27 => Instruction::DAA,
+            0x2F => Instruction::CPL,
+            0x37 => Instruction::SCF,
+            0x3F => Instruction::CCF,
+
+            0x80..=0x87 => Instruction::ADD(ArthimeticTarget::from_index(byte)),
+            0x88..=0x8F => Instruction::ADC(ArthimeticTarget::from_index(byte)),
+            0x90..=0x97 => Instruction::SUB(ArthimeticTarget::from_index(byte)),
+            0x98..=0x9F => Instruction::SBC(ArthimeticTarget::from_index(byte)),
+            0xA0..=0xA7 => Instruction::AND(ArthimeticTarget::from_index(byte)),
+            0xA8..=0xAF => Instruction::XOR(ArthimeticTarget::from_index(byte)),
+            0xB0..=0xB7 => Instruction::OR(ArthimeticTarget::from_index(byte)),
+            0xB8..=0xBF => Instruction::CP(ArthimeticTarget::from_index(byte)),
+            0xC6 => Instruction::ADD(ArthimeticTarget::D8),
+            0xCE => Instruction::ADC(ArthimeticTarget::D8),
+            0xD6 => Instruction::SUB(ArthimeticTarget::D8),
+            0xDE => Instruction::SBC(ArthimeticTarget::D8),
+            0xE6 => Instruction::AND(ArthimeticTarget::D8),
+            0xEE => Instruction::XOR(ArthimeticTarget::D8),
+            0xF6 => Instruction::OR(ArthimeticTarget::D8),
+            0xFE => Instruction::CP(ArthimeticTarget::D8),
+
+            0xC3 => Instruction::JP(JumpTest::Always),
+            0xC2 => Instruction::JP(JumpTest::NotZero),
+            0xCA => Instruction::JP(JumpTest::Zero),
+            0xD2 => Instruction::JP(JumpTest::NotCarry),
+            0xDA => Instruction::JP(JumpTest::Carry),
+            0xE9 => Instruction::JPHL,
+
+            0x18 => Instruction::JR(JumpTest::Always),
+            0x20 => Instruction::JR(JumpTest::NotZero),
+            0x28 => Instruction::JR(JumpTest::Zero),
+            0x30 => Instruction::JR(JumpTest::NotCarry),
+            0x38 => Instruction::JR(JumpTest::Carry),
+
+            0xCD => Instruction::CALL(JumpTest::Always),
+            0xC4 => Instruction::CALL(JumpTest::NotZero),
+            0xCC => Instruction::CALL(JumpTest::Zero),
+            0xD4 => Instruction::CALL(JumpTest::NotCarry),
+            0xDC => Instruction::CALL(JumpTest::Carry),
+
+            0xC9 => Instruction::RET(JumpTest::Always),
+            0xC0 => Instruction::RET(JumpTest::NotZero),
+            0xC8 => Instruction::RET(JumpTest::Zero),
+            0xD0 => Instruction::RET(JumpTest::NotCarry),
+            0xD8 => Instruction::RET(JumpTest::Carry),
+            0xD9 => Instruction::RETI,
+
+            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => Instruction::RST(byte & 0x38),
+
+            0xC5 => Instruction::PUSH(StackTarget::BC),
+            0xD5 => Instruction::PUSH(StackTarget::DE),
+            0xE5 => Instruction::PUSH(StackTarget::HL),
+            0xF5 => Instruction::PUSH(StackTarget::AF),
+            0xC1 => Instruction::POP(StackTarget::BC),
+            0xD1 => Instruction::POP(StackTarget::DE),
+            0xE1 => Instruction::POP(StackTarget::HL),
+            0xF1 => Instruction::POP(StackTarget::AF),
+
+            // 0xCB is the prefix byte and the remaining opcodes are unused
+            // on the SM83, they lock up the CPU on real hardware
+            _ => return None,
+        };
+        Some(instruction)
+    }
+}
+
+#[cfg(test)]
+mod instruction_decode_tests {
+    use super::*;
+    #[test]
+    fn decode_load_block() {
+        assert_eq!(
+            Instruction::from_byte(0x78, false),
+            Some(Instruction::LD(LoadType::Byte(LoadByteTarget::A, LoadByteSource::B)))
+        );
+        assert_eq!(
+            Instruction::from_byte(0x36, false),
+            Some(Instruction::LD(LoadType::Byte(LoadByteTarget::HLI, LoadByteSource::D8)))
+        );
+        assert_eq!(Instruction::from_byte(0x76, false), Some(Instruction::HALT));
+    }
+    #[test]
+    fn decode_alu_block() {
+        assert_eq!(Instruction::from_byte(0x86, false), Some(Instruction::ADD(ArthimeticTarget::HLI)));
+        assert_eq!(Instruction::from_byte(0x9B, false), Some(Instruction::SBC(ArthimeticTarget::E)));
+        assert_eq!(Instruction::from_byte(0xFE, false), Some(Instruction::CP(ArthimeticTarget::D8)));
+    }
+    #[test]
+    fn decode_prefixed() {
+        assert_eq!(Instruction::from_byte(0x37, true), Some(Instruction::SWAP(PrefixTarget::A)));
+        assert_eq!(
+            Instruction::from_byte(0x7E, true),
+            Some(Instruction::BIT(PrefixTarget::HLI, BitPosition::B7))
+        );
+        assert_eq!(
+            Instruction::from_byte(0xC1, true),
+            Some(Instruction::SET(PrefixTarget::C, BitPosition::B0))
+        );
+    }
+    #[test]
+    fn instruction_lengths() {
+        let length = |byte, prefixed| Instruction::from_byte(byte, prefixed).unwrap().length();
+        assert_eq!(length(0x00, false), 1);
+        assert_eq!(length(0x10, false), 2);
+        assert_eq!(length(0x3E, false), 2);
+        assert_eq!(length(0xE0, false), 2);
+        assert_eq!(length(0xE2, false), 1);
+        assert_eq!(length(0xFA, false), 3);
+        assert_eq!(length(0x08, false), 3);
+        assert_eq!(length(0xCD, false), 3);
+        assert_eq!(length(0x7C, true), 2);
+    }
+    #[test]
+    fn decode_unused_opcodes() {
+        for byte in [0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD].iter() {
+            assert_eq!(Instruction::from_byte(*byte, false), None);
+        }
+    }
+}
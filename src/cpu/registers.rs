@@ -1,5 +1,6 @@
 use super::FlagsRegister;
 
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct Registers {
     pub a: u8,
     pub b: u8,
@@ -24,15 +25,15 @@ impl Registers {
             l: 0
         }
     }
-    // fn get_af(&self) -> u16 {
-    // (self.a as u16) << 8
-    // | self.f as u16
-    // }
+    pub fn get_af(&self) -> u16 {
+    (self.a as u16) << 8
+    | u8::from(self.f) as u16
+    }
 
-    // fn set_af(&mut self, value: u16) {
-    // self.a = ((value & 0xFF00) >> 8) as u8;
-    // self.f = (value & 0xFF) as u8;
-    // }
+    pub fn set_af(&mut self, value: u16) {
+    self.a = ((value & 0xFF00) >> 8) as u8;
+    self.f = FlagsRegister::from((value & 0xFF) as u8);
+    }
     
     pub fn get_bc(&self) -> u16 {
     (self.b as u16) << 8
@@ -0,0 +1,436 @@
+// Runs the SingleStepTests SM83 vectors, a JSON file per opcode named
+// like "3e.json" or "cb 37.json", each a list of single instruction
+// tests with the state before and after and the bus activity of every
+// cycle. They are not kept in the repository. Point SM83_TESTS at the
+// directory of JSON files, or put them in tests/sm83/v1, then run
+// `cargo test single_step_tests -- --ignored`.
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::*;
+use crate::memory_bus::{Access, AccessKind};
+
+#[derive(Debug, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn number(&self) -> Option<u32> {
+        match self {
+            Json::Number(number) => Some(*number as u32),
+            _ => None,
+        }
+    }
+
+    fn array(&self) -> &[Json] {
+        match self {
+            Json::Array(items) => items,
+            _ => &[],
+        }
+    }
+}
+
+// Just enough JSON for the test vectors: no escapes past the simple
+// ones and numbers as f64
+struct Parser<'a> {
+    text: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn parse(text: &'a str) -> Result<Json, String> {
+        let mut parser = Parser { text: text.as_bytes(), position: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.position != parser.text.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("{} at byte {}", message, self.position)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.text.get(self.position).is_some_and(u8::is_ascii_whitespace) {
+            self.position += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.text.get(self.position) != Some(&byte) {
+            return Err(self.error(&format!("expected {:?}", byte as char)));
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        if !self.text[self.position..].starts_with(word.as_bytes()) {
+            return Err(self.error("unknown literal"));
+        }
+        self.position += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.text.get(self.position) {
+            Some(b'{') => {
+                self.position += 1;
+                let mut fields = Vec::new();
+                self.skip_whitespace();
+                if self.text.get(self.position) == Some(&b'}') {
+                    self.position += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let name = self.string()?;
+                    self.expect(b':')?;
+                    fields.push((name, self.value()?));
+                    self.skip_whitespace();
+                    match self.text.get(self.position) {
+                        Some(b',') => self.position += 1,
+                        Some(b'}') => {
+                            self.position += 1;
+                            return Ok(Json::Object(fields));
+                        }
+                        _ => return Err(self.error("expected , or }")),
+                    }
+                }
+            }
+            Some(b'[') => {
+                self.position += 1;
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.text.get(self.position) == Some(&b']') {
+                    self.position += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.text.get(self.position) {
+                        Some(b',') => self.position += 1,
+                        Some(b']') => {
+                            self.position += 1;
+                            return Ok(Json::Array(items));
+                        }
+                        _ => return Err(self.error("expected , or ]")),
+                    }
+                }
+            }
+            Some(b'"') => self.string().map(Json::String),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(_) => {
+                let start = self.position;
+                while self.text.get(self.position).is_some_and(|&byte| b"+-.eE0123456789".contains(&byte)) {
+                    self.position += 1;
+                }
+                let number = std::str::from_utf8(&self.text[start..self.position]).unwrap_or("");
+                number.parse().map(Json::Number).map_err(|_| self.error("expected a value"))
+            }
+            None => Err(self.error("unexpected end")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut string = String::new();
+        loop {
+            match self.text.get(self.position) {
+                Some(b'"') => {
+                    self.position += 1;
+                    return Ok(string);
+                }
+                Some(b'\\') => {
+                    let escaped = self.text.get(self.position + 1).ok_or_else(|| self.error("unexpected end"))?;
+                    string.push(match escaped {
+                        b'n' => '\n',
+                        b't' => '\t',
+                        other => *other as char,
+                    });
+                    self.position += 2;
+                }
+                Some(&byte) => {
+                    string.push(byte as char);
+                    self.position += 1;
+                }
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+}
+
+fn register(state: &Json, name: &str) -> u32 {
+    state.get(name).and_then(Json::number).unwrap_or(0)
+}
+
+fn load(cpu: &mut CPU, state: &Json) {
+    cpu.registers.a = register(state, "a") as u8;
+    cpu.registers.f = FlagsRegister::from(register(state, "f") as u8);
+    cpu.registers.b = register(state, "b") as u8;
+    cpu.registers.c = register(state, "c") as u8;
+    cpu.registers.d = register(state, "d") as u8;
+    cpu.registers.e = register(state, "e") as u8;
+    cpu.registers.h = register(state, "h") as u8;
+    cpu.registers.l = register(state, "l") as u8;
+    cpu.pc = register(state, "pc") as u16;
+    cpu.sp = register(state, "sp") as u16;
+    cpu.ime = register(state, "ime") != 0;
+    cpu.bus.interrupt_enable = register(state, "ie") as u8;
+    let memory = cpu.bus.flat_memory.as_mut().unwrap();
+    for entry in state.get("ram").map(Json::array).unwrap_or(&[]) {
+        let entry = entry.array();
+        if let (Some(address), Some(value)) = (entry[0].number(), entry[1].number()) {
+            memory[address as usize] = value as u8;
+        }
+    }
+}
+
+// Differences from the expected state, empty when it matches. PC is
+// compared less the prefetch offset.
+fn compare(cpu: &CPU, state: &Json, prefetch: u16) -> Vec<String> {
+    let registers = &cpu.registers;
+    let actual = [
+        ("a", registers.a as u32),
+        ("f", u8::from(registers.f) as u32),
+        ("b", registers.b as u32),
+        ("c", registers.c as u32),
+        ("d", registers.d as u32),
+        ("e", registers.e as u32),
+        ("h", registers.h as u32),
+        ("l", registers.l as u32),
+        ("pc", cpu.pc as u32),
+        ("sp", cpu.sp as u32),
+        // EI's one instruction delay isn't in the vectors
+        ("ime", (cpu.ime || cpu.ime_scheduled) as u32),
+        ("ie", cpu.bus.interrupt_enable as u32),
+    ];
+    let mut differences = Vec::new();
+    for (name, value) in actual.iter() {
+        let expected = match state.get(name).and_then(Json::number) {
+            Some(expected) if *name == "pc" => expected.wrapping_sub(prefetch as u32) & 0xFFFF,
+            Some(expected) => expected,
+            None => continue,
+        };
+        if *value != expected {
+            differences.push(format!("{} is ${:X}, expected ${:X}", name, value, expected));
+        }
+    }
+    let memory = cpu.bus.flat_memory.as_ref().unwrap();
+    for entry in state.get("ram").map(Json::array).unwrap_or(&[]) {
+        let entry = entry.array();
+        if let (Some(address), Some(expected)) = (entry[0].number(), entry[1].number()) {
+            let value = memory[address as usize] as u32;
+            if value != expected {
+                differences.push(format!("${:04X} is ${:02X}, expected ${:02X}", address, value, expected));
+            }
+        }
+    }
+    differences
+}
+
+// The reads and writes of each cycle, leaving out internal cycles
+fn bus_activity(cycles: &[Json]) -> Vec<Access> {
+    cycles
+        .iter()
+        .filter_map(|cycle| {
+            let cycle = cycle.array();
+            let kind = match cycle.get(2) {
+                Some(Json::String(pins)) if pins.starts_with('r') => AccessKind::Read,
+                Some(Json::String(pins)) if pins.get(1..2) == Some("w") => AccessKind::Write,
+                _ => return None,
+            };
+            Some(Access { kind, address: cycle[0].number()? as u16, value: cycle[1].number()? as u8 })
+        })
+        .collect()
+}
+
+// Runs one test, describing what went wrong
+fn run(opcode: &[u8], test: &Json) -> Result<(), String> {
+    let initial = test.get("initial").ok_or("no initial state")?;
+    let expected = test.get("final").ok_or("no final state")?;
+    let cycles = test.get("cycles").map(Json::array).unwrap_or(&[]);
+    let mut cpu = CPU::new();
+    cpu.bus.flat_memory = Some(vec![0; 0x10000]);
+    load(&mut cpu, initial);
+
+    // The SM83 fetches the next opcode during the last cycle of an
+    // instruction. Vectors that model it start with PC past the opcode
+    // and end with it past the next one, with that fetch as the last
+    // cycle.
+    let memory = cpu.bus.flat_memory.as_ref().unwrap();
+    let at = |pc: u16| (0..opcode.len() as u16).map(move |offset| memory[pc.wrapping_add(offset) as usize]);
+    let prefetch = if at(cpu.pc).eq(opcode.iter().copied()) {
+        0
+    } else if at(cpu.pc.wrapping_sub(1)).eq(opcode.iter().copied()) {
+        1
+    } else {
+        return Err("opcode is not at PC".to_string());
+    };
+    cpu.pc = cpu.pc.wrapping_sub(prefetch);
+    let start = cpu.pc;
+
+    // Decoded straight from the file name and executed, so a pending
+    // interrupt or HALT can't get in the way of the opcode under test
+    let prefixed = opcode[0] == 0xCB;
+    let instruction = Instruction::from_byte(opcode[opcode.len() - 1], prefixed).ok_or("unused opcode")?;
+    cpu.bus.access_log = Some(RefCell::new(Vec::new()));
+    let (next_pc, taken) = cpu.execute(instruction);
+    cpu.pc = next_pc;
+    let taken = taken as usize;
+    let mut differences = compare(&cpu, expected, prefetch);
+
+    // The opcode was never fetched, so its fetches are left out of the
+    // expected activity. The rest has to match in order.
+    let fetched = |access: &Access| {
+        access.kind == AccessKind::Read && access.address.wrapping_sub(start) < opcode.len() as u16
+    };
+    let actual = cpu.bus.take_accesses();
+    let mut wanted = bus_activity(cycles);
+    let skip = wanted.iter().take(opcode.len()).take_while(|access| fetched(access)).count();
+    wanted.drain(..skip);
+    if prefetch == 1 {
+        wanted.pop();
+    }
+    if actual != wanted {
+        differences.push(format!("bus activity {:?}, expected {:?}", actual, wanted));
+    }
+    if taken != cycles.len() * 4 {
+        differences.push(format!("took {} cycles, expected {}", taken, cycles.len() * 4));
+    }
+    if differences.is_empty() {
+        Ok(())
+    } else {
+        Err(differences.join(", "))
+    }
+}
+
+// "cb 37.json" holds the tests for 0xCB 0x37
+fn opcode_of(path: &Path) -> Option<Vec<u8>> {
+    let stem = path.file_stem()?.to_str()?;
+    stem.split_whitespace().map(|byte| u8::from_str_radix(byte, 16).ok()).collect()
+}
+
+#[test]
+#[ignore]
+fn single_step_tests() {
+    let directory = match env::var_os("SM83_TESTS") {
+        Some(directory) => PathBuf::from(directory),
+        None => PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/sm83/v1"),
+    };
+    let entries = fs::read_dir(&directory).unwrap_or_else(|error| panic!("{}: {}", directory.display(), error));
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no test vectors in {}", directory.display());
+
+    // Failures by opcode, with the first one described
+    let mut failures: BTreeMap<String, (usize, usize, String)> = BTreeMap::new();
+    let mut total = 0;
+    for path in &paths {
+        let name = path.file_stem().unwrap().to_string_lossy().to_string();
+        let opcode = match opcode_of(path) {
+            Some(opcode) => opcode,
+            None => continue,
+        };
+        let text = fs::read_to_string(path).unwrap_or_else(|error| panic!("{}: {}", path.display(), error));
+        let tests = Parser::parse(&text).unwrap_or_else(|error| panic!("{}: {}", path.display(), error));
+        for test in tests.array() {
+            total += 1;
+            if let Err(message) = run(&opcode, test) {
+                let entry = failures.entry(name.clone()).or_insert((0, tests.array().len(), String::new()));
+                if entry.0 == 0 {
+                    let test_name = match test.get("name") {
+                        Some(Json::String(test_name)) => test_name.clone(),
+                        _ => String::new(),
+                    };
+                    entry.2 = format!("{}: {}", test_name, message);
+                }
+                entry.0 += 1;
+            }
+        }
+    }
+
+    let report: Vec<String> = failures
+        .iter()
+        .map(|(name, (failed, tests, first))| format!("{}: {} of {} failed, first {}", name, failed, tests, first))
+        .collect();
+    assert!(report.is_empty(), "{} of {} opcodes failed\n{}", report.len(), paths.len(), report.join("\n"));
+    eprintln!("{} SingleStepTests passed across {} opcodes", total, paths.len());
+}
+
+#[test]
+fn parses_test_vectors() {
+    let text = r#"[{"name": "3e 0000", "initial": {"a": 1, "ram": [[256, 62]]}, "cycles": [[256, 62, "r-m"], null]}]"#;
+    let tests = Parser::parse(text).unwrap();
+    let test = &tests.array()[0];
+    assert_eq!(test.get("name"), Some(&Json::String("3e 0000".to_string())));
+    assert_eq!(register(test.get("initial").unwrap(), "a"), 1);
+    let cycles = test.get("cycles").unwrap().array();
+    assert_eq!(bus_activity(cycles), vec![Access { kind: AccessKind::Read, address: 256, value: 62 }]);
+    assert_eq!(opcode_of(Path::new("v1/cb 37.json")), Some(vec![0xCB, 0x37]));
+    assert!(Parser::parse("[1, 2").is_err());
+}
+#[test]
+fn runs_a_vector() {
+    // ld a, $42 at $C000, modelled both with and without the prefetch
+    let plain = r#"{"name": "3e 0001",
+        "initial": {"pc": 49152, "sp": 0, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0,
+                    "ime": 0, "ie": 0, "ram": [[49152, 62], [49153, 66]]},
+        "final": {"pc": 49154, "sp": 0, "a": 66, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0,
+                  "ime": 0, "ie": 0, "ram": [[49152, 62], [49153, 66]]},
+        "cycles": [[49152, 62, "r-m"], [49153, 66, "r-m"]]}"#;
+    assert_eq!(run(&[0x3E], &Parser::parse(plain).unwrap()), Ok(()));
+    let prefetched = plain.replace("\"pc\": 49152", "\"pc\": 49153").replace("\"pc\": 49154", "\"pc\": 49155");
+    let prefetched = prefetched.replace(
+        "[[49152, 62, \"r-m\"], [49153, 66, \"r-m\"]]",
+        "[[49153, 66, \"r-m\"], [49154, 0, \"r-m\"]]",
+    );
+    assert_eq!(run(&[0x3E], &Parser::parse(&prefetched).unwrap()), Ok(()));
+    let wrong = plain.replace("\"a\": 66", "\"a\": 67");
+    assert_eq!(run(&[0x3E], &Parser::parse(&wrong).unwrap()), Err("a is $42, expected $43".to_string()));
+    // call $1234, the target is read before the return address is pushed
+    let call = r#"{"name": "cd 0000",
+        "initial": {"pc": 49152, "sp": 53248, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0,
+                    "ime": 0, "ie": 0, "ram": [[49152, 205], [49153, 52], [49154, 18]]},
+        "final": {"pc": 4660, "sp": 53246, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0,
+                  "ime": 0, "ie": 0, "ram": [[49152, 205], [49153, 52], [49154, 18], [53247, 192], [53246, 3]]},
+        "cycles": [[49152, 205, "r-m"], [49153, 52, "r-m"], [49154, 18, "r-m"], [null, null, "---"],
+                   [53247, 192, "-wm"], [53246, 3, "-wm"]]}"#;
+    assert_eq!(run(&[0xCD], &Parser::parse(call).unwrap()), Ok(()));
+    // call nz, $1234 with Z set still reads the target
+    let not_taken = call
+        .replace("cd 0000", "c4 0000")
+        .replace("[49152, 205]", "[49152, 196]")
+        .replace("[49152, 205, \"r-m\"]", "[49152, 196, \"r-m\"]")
+        .replace("\"f\": 0", "\"f\": 128")
+        .replace("\"pc\": 4660, \"sp\": 53246", "\"pc\": 49155, \"sp\": 53248")
+        .replace(", [53247, 192], [53246, 3]]", "]")
+        .replace(", [null, null, \"---\"],\n                   [53247, 192, \"-wm\"], [53246, 3, \"-wm\"]]", "]");
+    assert_eq!(run(&[0xC4], &Parser::parse(&not_taken).unwrap()), Ok(()));
+}
@@ -0,0 +1,892 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::File;
+use std::io::BufWriter;
+
+use crate::call_stack::{is_call, is_return, CallStack, FrameKind, Imbalance};
+use crate::cpu::instruction::Instruction;
+use crate::cpu::{CPU, CYCLES_PER_FRAME};
+use crate::disassembler::{self, Line};
+use crate::heatmap::Heatmap;
+use crate::memory_bus::{Access, AccessKind, MemoryBus};
+use crate::symbols::Symbols;
+
+// How long continue runs before giving control back
+const CONTINUE_LIMIT_FRAMES: u64 = 3600;
+
+// Stack imbalances shown after a run, the rest are only counted
+const WARNINGS_SHOWN: usize = 5;
+
+// Lines shown before and after PC by disassemble
+const CONTEXT_BEFORE: usize = 4;
+const CONTEXT_AFTER: usize = 6;
+
+const HELP: &str = "commands:
+  step [N], s          run N instructions
+  next, n              step over calls and rsts
+  continue, c          run until a breakpoint or watchpoint
+  finish               run until the current function returns
+  break ADDR [if REG OP VALUE], b
+                       stop at ADDR, optionally when a register compares true
+                       a label stops only while its bank is mapped
+                       REG is a b c d e f h l af bc de hl sp pc, OP is == != < > <= >=
+  watch ADDR           stop after ADDR is written
+  rwatch ADDR          stop after ADDR is read
+  awatch ADDR          stop after ADDR is read or written
+  delete N, d          remove breakpoint or watchpoint N
+  info, i              list breakpoints and watchpoints
+  backtrace, bt        show the calls, rsts and interrupts that led here
+  registers, r         show registers and flags
+  x ADDR [LEN]         hexdump LEN bytes of memory
+  who ADDR             count accesses to ADDR and show what last wrote it
+  heatmap FILE         save accesses so far as a PNG, reads in green and writes in red
+  heatmap clear        forget the accesses so far
+  disassemble [ADDR], l
+                       disassemble around PC or from ADDR
+  quit, q
+numbers are decimal or hex with a 0x or $ prefix, addresses can also be labels from
+a .sym file, an empty line repeats the last command";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Register {
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+    H,
+    L,
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+}
+
+impl Register {
+    pub const ALL: [Register; 14] = [
+        Register::A,
+        Register::B,
+        Register::C,
+        Register::D,
+        Register::E,
+        Register::F,
+        Register::H,
+        Register::L,
+        Register::AF,
+        Register::BC,
+        Register::DE,
+        Register::HL,
+        Register::SP,
+        Register::PC,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Register> {
+        let name = name.to_ascii_lowercase();
+        Register::ALL.iter().copied().find(|register| register.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Register::A => "a",
+            Register::B => "b",
+            Register::C => "c",
+            Register::D => "d",
+            Register::E => "e",
+            Register::F => "f",
+            Register::H => "h",
+            Register::L => "l",
+            Register::AF => "af",
+            Register::BC => "bc",
+            Register::DE => "de",
+            Register::HL => "hl",
+            Register::SP => "sp",
+            Register::PC => "pc",
+        }
+    }
+
+    pub fn read(self, cpu: &CPU) -> u16 {
+        let registers = &cpu.registers;
+        match self {
+            Register::A => registers.a as u16,
+            Register::B => registers.b as u16,
+            Register::C => registers.c as u16,
+            Register::D => registers.d as u16,
+            Register::E => registers.e as u16,
+            Register::F => u8::from(registers.f) as u16,
+            Register::H => registers.h as u16,
+            Register::L => registers.l as u16,
+            Register::AF => registers.get_af(),
+            Register::BC => registers.get_bc(),
+            Register::DE => registers.get_de(),
+            Register::HL => registers.get_hl(),
+            Register::SP => cpu.sp,
+            Register::PC => cpu.pc,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessOrEqual,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    pub const ALL: [Comparison; 6] = [
+        Comparison::Equal,
+        Comparison::NotEqual,
+        Comparison::Less,
+        Comparison::Greater,
+        Comparison::LessOrEqual,
+        Comparison::GreaterOrEqual,
+    ];
+
+    pub fn from_symbol(symbol: &str) -> Option<Comparison> {
+        Comparison::ALL.iter().copied().find(|comparison| comparison.symbol() == symbol)
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Comparison::Equal => "==",
+            Comparison::NotEqual => "!=",
+            Comparison::Less => "<",
+            Comparison::Greater => ">",
+            Comparison::LessOrEqual => "<=",
+            Comparison::GreaterOrEqual => ">=",
+        }
+    }
+
+    pub fn holds(self, left: u16, right: u16) -> bool {
+        match self {
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+            Comparison::Less => left < right,
+            Comparison::Greater => left > right,
+            Comparison::LessOrEqual => left <= right,
+            Comparison::GreaterOrEqual => left >= right,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Condition {
+    pub register: Register,
+    pub comparison: Comparison,
+    pub value: u16,
+}
+
+impl Condition {
+    pub fn holds(&self, cpu: &CPU) -> bool {
+        self.comparison.holds(self.register.read(cpu), self.value)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} ${:X}", self.register.name(), self.comparison.symbol(), self.value)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Breakpoint {
+    // A bank is only given for breakpoints set on a label
+    Pc { address: u16, bank: Option<u16>, condition: Option<Condition> },
+    Watch { address: u16, read: bool, write: bool },
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Breakpoint::Pc { address, bank, condition } => {
+                write!(f, "breakpoint at ${:04X}", address)?;
+                if let Some(bank) = bank {
+                    write!(f, " in bank {}", bank)?;
+                }
+                match condition {
+                    Some(condition) => write!(f, " if {}", condition),
+                    None => Ok(()),
+                }
+            }
+            Breakpoint::Watch { address, read, write } => {
+                let accesses = match (read, write) {
+                    (true, true) => "reads and writes of",
+                    (true, false) => "reads of",
+                    _ => "writes to",
+                };
+                write!(f, "watchpoint on {} ${:04X}", accesses, address)
+            }
+        }
+    }
+}
+
+// Why a run gave control back
+enum Stop {
+    Done,
+    Breakpoint(u32),
+    Watchpoint(u32, Access),
+    Limit,
+}
+
+// What the instruction just executed started from
+struct Executed {
+    pc: u16,
+    opcode: u8,
+}
+
+// Command interpreter for the debug subcommand. Output and errors are
+// returned as text so it does not care where the lines come from.
+pub struct Debugger {
+    pub breakpoints: Vec<(u32, Breakpoint)>,
+    pub symbols: Symbols,
+    pub call_stack: CallStack,
+    // Every access made while the debugger ran the machine
+    pub heatmap: Heatmap,
+    pub quit: bool,
+    next_id: u32,
+    last_command: String,
+    // Found during the last run, beyond the first few only counted
+    imbalances: Vec<Imbalance>,
+    hidden_imbalances: usize,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            symbols: Symbols::new(),
+            call_stack: CallStack::new(),
+            heatmap: Heatmap::new(),
+            quit: false,
+            next_id: 1,
+            last_command: String::new(),
+            imbalances: Vec::new(),
+            hidden_imbalances: 0,
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push((id, breakpoint));
+        id
+    }
+
+    pub fn command(&mut self, cpu: &mut CPU, line: &str) -> Result<String, String> {
+        let line = line.trim();
+        let line = if line.is_empty() { self.last_command.clone() } else { line.to_string() };
+        self.last_command = line.clone();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (name, arguments) = match words.split_first() {
+            Some((name, arguments)) => (*name, arguments),
+            None => return Ok(String::new()),
+        };
+        match name {
+            "step" | "s" => {
+                let count = match arguments.first() {
+                    Some(count) => parse_number(count)?,
+                    None => 1,
+                };
+                let mut stepped = 0;
+                let stop = self.run(cpu, |_, _| {
+                    stepped += 1;
+                    stepped >= count
+                });
+                Ok(self.report(cpu, stop))
+            }
+            "next" | "n" => {
+                let opcode = cpu.bus.peek(cpu.pc);
+                if is_call(opcode) {
+                    let (target, sp) = (cpu.pc.wrapping_add(instruction_length(&cpu.bus, cpu.pc)), cpu.sp);
+                    let stop = self.run(cpu, |cpu, _| cpu.pc == target && cpu.sp >= sp);
+                    Ok(self.report(cpu, stop))
+                } else {
+                    let stop = self.run(cpu, |_, _| true);
+                    Ok(self.report(cpu, stop))
+                }
+            }
+            "continue" | "c" => {
+                let stop = self.run(cpu, |_, _| false);
+                Ok(self.report(cpu, stop))
+            }
+            "finish" => {
+                let sp = cpu.sp;
+                let stop = self.run(cpu, |cpu, executed| is_return(executed.opcode) && cpu.sp > sp);
+                Ok(self.report(cpu, stop))
+            }
+            "break" | "b" => {
+                let (address, bank) = self.location(arguments.first().ok_or("break needs an address")?)?;
+                let condition = match &arguments[1..] {
+                    [] => None,
+                    ["if", register, comparison, value] => Some(Condition {
+                        register: Register::from_name(register)
+                            .ok_or_else(|| format!("unknown register {}", register))?,
+                        comparison: Comparison::from_symbol(comparison)
+                            .ok_or_else(|| format!("unknown comparison {}", comparison))?,
+                        value: self.address(value)?,
+                    }),
+                    _ => return Err("expected break ADDR [if REG OP VALUE]".to_string()),
+                };
+                let breakpoint = Breakpoint::Pc { address, bank, condition };
+                let id = self.add_breakpoint(breakpoint);
+                Ok(format!("{}: {}{}", id, breakpoint, self.label_suffix(&cpu.bus, breakpoint)))
+            }
+            "watch" | "rwatch" | "awatch" => {
+                let address = self.address(arguments.first().ok_or_else(|| format!("{} needs an address", name))?)?;
+                let breakpoint = Breakpoint::Watch {
+                    address,
+                    read: name != "watch",
+                    write: name != "rwatch",
+                };
+                let id = self.add_breakpoint(breakpoint);
+                Ok(format!("{}: {}{}", id, breakpoint, self.label_suffix(&cpu.bus, breakpoint)))
+            }
+            "delete" | "d" => {
+                let id = parse_number(arguments.first().ok_or("delete needs a breakpoint number")?)?;
+                let before = self.breakpoints.len();
+                self.breakpoints.retain(|(existing, _)| *existing != id);
+                if self.breakpoints.len() == before {
+                    return Err(format!("no breakpoint {}", id));
+                }
+                Ok(format!("deleted {}", id))
+            }
+            "info" | "i" => {
+                if self.breakpoints.is_empty() {
+                    return Ok("no breakpoints".to_string());
+                }
+                let lines: Vec<String> = self
+                    .breakpoints
+                    .iter()
+                    .map(|(id, breakpoint)| {
+                        format!("{}: {}{}", id, breakpoint, self.label_suffix(&cpu.bus, *breakpoint))
+                    })
+                    .collect();
+                Ok(lines.join("\n"))
+            }
+            "backtrace" | "bt" => Ok(self.backtrace(cpu)),
+            "registers" | "r" => Ok(registers(cpu)),
+            "x" => {
+                let address = self.address(arguments.first().ok_or("x needs an address")?)?;
+                let length = match arguments.get(1) {
+                    // Past that it would only wrap round and repeat
+                    Some(length) => parse_number(length)?.min(0x10000),
+                    None => 64,
+                };
+                Ok(hexdump(&cpu.bus, address, length))
+            }
+            "who" => {
+                let address = self.address(arguments.first().ok_or("who needs an address")?)?;
+                Ok(self.who(&cpu.bus, address))
+            }
+            "heatmap" => {
+                let path = arguments.first().ok_or("heatmap needs a file name")?;
+                if *path == "clear" {
+                    self.heatmap.clear();
+                    return Ok("cleared".to_string());
+                }
+                let file = File::create(path).map_err(|error| format!("{}: {}", path, error))?;
+                self.heatmap.write_png(&mut BufWriter::new(file)).map_err(|error| format!("{}: {}", path, error))?;
+                Ok(format!("wrote {}", path))
+            }
+            "disassemble" | "l" => {
+                let lines = match arguments.first() {
+                    Some(address) => listing(&cpu.bus, self.address(address)?, CONTEXT_BEFORE + CONTEXT_AFTER),
+                    None => around(&cpu.bus, cpu.pc),
+                };
+                let lines: Vec<String> = lines.iter().map(|line| self.format_line(&cpu.bus, line, cpu.pc)).collect();
+                Ok(lines.join("\n"))
+            }
+            "help" | "h" => Ok(HELP.to_string()),
+            "quit" | "q" => {
+                self.quit = true;
+                Ok(String::new())
+            }
+            _ => Err(format!("unknown command {}, try help", name)),
+        }
+    }
+
+    // Steps until `done` says so or something stops the machine
+    fn run<F: FnMut(&CPU, &Executed) -> bool>(&mut self, cpu: &mut CPU, mut done: F) -> Stop {
+        cpu.bus.access_log = Some(RefCell::new(Vec::new()));
+        self.call_stack.imbalances.clear();
+        self.imbalances.clear();
+        self.hidden_imbalances = 0;
+        let mut cycles = 0;
+        let stop = loop {
+            let executed = Executed { pc: cpu.pc, opcode: cpu.bus.peek(cpu.pc) };
+            let bank = cpu.bus.bank_at(cpu.pc);
+            let length = instruction_length(&cpu.bus, cpu.pc);
+            cycles += self.call_stack.step(cpu) as u64;
+            let accesses = cpu.bus.take_accesses();
+            self.heatmap.record(executed.pc, bank, &accesses);
+            for imbalance in self.call_stack.imbalances.drain(..) {
+                if self.imbalances.len() < WARNINGS_SHOWN {
+                    self.imbalances.push(imbalance);
+                } else {
+                    self.hidden_imbalances += 1;
+                }
+            }
+            if let Some(stop) = self.check_watchpoints(&accesses, executed.pc, length) {
+                break stop;
+            }
+            if let Some(id) = self.breakpoint_at(cpu) {
+                break Stop::Breakpoint(id);
+            }
+            if done(cpu, &executed) {
+                break Stop::Done;
+            }
+            if cycles >= CONTINUE_LIMIT_FRAMES * CYCLES_PER_FRAME as u64 {
+                break Stop::Limit;
+            }
+        };
+        cpu.bus.access_log = None;
+        stop
+    }
+
+    fn breakpoint_at(&self, cpu: &CPU) -> Option<u32> {
+        self.breakpoints.iter().find_map(|(id, breakpoint)| match breakpoint {
+            Breakpoint::Pc { address, bank, condition } if *address == cpu.pc => {
+                let mapped = bank.is_none_or(|bank| cpu.bus.bank_at(cpu.pc) == bank);
+                match condition {
+                    Some(condition) if !condition.holds(cpu) => None,
+                    _ if mapped => Some(*id),
+                    _ => None,
+                }
+            }
+            _ => None,
+        })
+    }
+
+    // Reads of the instruction's own bytes are fetches, not data reads
+    fn check_watchpoints(&self, accesses: &[Access], pc: u16, length: u16) -> Option<Stop> {
+        for &access in accesses {
+            if access.kind == AccessKind::Read && access.address.wrapping_sub(pc) < length {
+                continue;
+            }
+            for (id, breakpoint) in &self.breakpoints {
+                if let Breakpoint::Watch { address, read, write } = *breakpoint {
+                    let matches = match access.kind {
+                        AccessKind::Read => read,
+                        AccessKind::Write => write,
+                    };
+                    if matches && access.address == address {
+                        return Some(Stop::Watchpoint(*id, access));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn report(&self, cpu: &CPU, stop: Stop) -> String {
+        let line = disassembler::decode(|address| cpu.bus.peek(address), cpu.pc);
+        let mut current = String::new();
+        for imbalance in &self.imbalances {
+            current.push_str(&format!("warning: {}\n", imbalance));
+        }
+        if self.hidden_imbalances > 0 {
+            current.push_str(&format!("warning: {} more stack imbalances\n", self.hidden_imbalances));
+        }
+        current.push_str(&self.format_line(&cpu.bus, &line, cpu.pc));
+        match stop {
+            Stop::Done => current,
+            Stop::Breakpoint(id) => format!("breakpoint {}\n{}", id, current),
+            Stop::Watchpoint(id, access) => {
+                let kind = match access.kind {
+                    AccessKind::Read => "read",
+                    AccessKind::Write => "write",
+                };
+                let label = match self.symbols.describe_mapped(&cpu.bus, access.address) {
+                    Some(label) => format!(" ({})", label),
+                    None => String::new(),
+                };
+                format!(
+                    "watchpoint {}: {} ${:02X} at ${:04X}{}\n{}",
+                    id, kind, access.value, access.address, label, current
+                )
+            }
+            Stop::Limit => format!("still running after {} frames\n{}", CONTINUE_LIMIT_FRAMES, current),
+        }
+    }
+
+    fn who(&self, bus: &MemoryBus, address: u16) -> String {
+        let label = |bank: u16, address: u16| match self.symbols.describe(bank, address) {
+            Some(label) => format!(" ({})", label),
+            None => String::new(),
+        };
+        let mut text = format!(
+            "${:04X}{}: {} reads, {} writes",
+            address,
+            label(bus.bank_at(address), address),
+            self.heatmap.reads[address as usize],
+            self.heatmap.writes[address as usize]
+        );
+        match self.heatmap.last_writer(address) {
+            Some(writer) => text.push_str(&format!(
+                ", last wrote ${:02X} from ${:04X} in bank {}{}",
+                writer.value,
+                writer.pc,
+                writer.bank,
+                label(writer.bank, writer.pc)
+            )),
+            None => text.push_str(", never written"),
+        }
+        text
+    }
+
+    // The innermost frame first, each line giving where it was entered
+    fn backtrace(&self, cpu: &CPU) -> String {
+        let location = |address: u16, bank: u16| match self.symbols.describe(bank, address) {
+            Some(label) => format!("${:04X} in bank {} ({})", address, bank, label),
+            None => format!("${:04X} in bank {}", address, bank),
+        };
+        let mut lines = vec![format!("#0  {}", location(cpu.pc, cpu.bus.bank_at(cpu.pc)))];
+        for (depth, frame) in self.call_stack.frames.iter().rev().enumerate() {
+            let entry = match frame.kind {
+                FrameKind::Call => "called",
+                FrameKind::Rst => "rst to",
+                FrameKind::Interrupt => "interrupted by",
+            };
+            lines.push(format!(
+                "#{:<2} {}, {} {}",
+                depth + 1,
+                location(frame.call_site, frame.call_bank),
+                entry,
+                location(frame.target, frame.target_bank)
+            ));
+        }
+        lines.join("\n")
+    }
+
+    // A label's address and bank, or a plain number with no bank
+    fn location(&self, text: &str) -> Result<(u16, Option<u16>), String> {
+        match self.symbols.find(text) {
+            Some(symbol) => Ok((symbol.address, Some(symbol.bank))),
+            None => parse_u16(text).map(|address| (address, None)),
+        }
+    }
+
+    fn address(&self, text: &str) -> Result<u16, String> {
+        self.location(text).map(|(address, _)| address)
+    }
+
+    fn label_suffix(&self, bus: &MemoryBus, breakpoint: Breakpoint) -> String {
+        let label = match breakpoint {
+            Breakpoint::Pc { address, bank: Some(bank), .. } => self.symbols.describe(bank, address),
+            Breakpoint::Pc { address, .. } | Breakpoint::Watch { address, .. } => {
+                self.symbols.describe_mapped(bus, address)
+            }
+        };
+        label.map(|label| format!(" ({})", label)).unwrap_or_default()
+    }
+
+    // Labels placed at the line go above it, the label a jump or call
+    // goes to after it
+    fn format_line(&self, bus: &MemoryBus, line: &Line, pc: u16) -> String {
+        let bytes: Vec<String> = line.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        let marker = if line.address == pc { "=>" } else { "  " };
+        let mut text = String::new();
+        for symbol in self.symbols.at(bus.bank_at(line.address), line.address) {
+            text.push_str(&format!("{}:\n", symbol.name));
+        }
+        text.push_str(&format!("{} ${:04X}: {:<9} {}", marker, line.address, bytes.join(" "), line.text));
+        if let Some(label) = line.target().and_then(|target| self.symbols.describe_mapped(bus, target)) {
+            text.push_str(&format!("  ; {}", label));
+        }
+        text
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Accepts decimal, or hex with a 0x or $ prefix. The command line
+// parses its options with these too.
+pub fn parse_number(text: &str) -> Result<u32, String> {
+    let parsed = if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix('$')) {
+        u32::from_str_radix(hex, 16)
+    } else {
+        text.parse()
+    };
+    parsed.map_err(|_| format!("invalid number: {}", text))
+}
+
+// Addresses and ports
+pub fn parse_u16(text: &str) -> Result<u16, String> {
+    let value = parse_number(text)?;
+    if value > 0xFFFF {
+        return Err(format!("{} does not fit in 16 bits", text));
+    }
+    Ok(value as u16)
+}
+
+// Unused opcodes count as one byte
+pub fn instruction_length(bus: &MemoryBus, address: u16) -> u16 {
+    let opcode = bus.peek(address);
+    let instruction = if opcode == 0xCB {
+        Instruction::from_byte(bus.peek(address.wrapping_add(1)), true)
+    } else {
+        Instruction::from_byte(opcode, false)
+    };
+    instruction.map_or(1, Instruction::length)
+}
+
+pub fn registers(cpu: &CPU) -> String {
+    let flags = cpu.registers.f;
+    let flag = |set: bool, letter: char| if set { letter } else { '-' };
+    format!(
+        "AF ${:04X}  BC ${:04X}  DE ${:04X}  HL ${:04X}\nSP ${:04X}  PC ${:04X}  flags {}{}{}{}  IME {}{}",
+        cpu.registers.get_af(),
+        cpu.registers.get_bc(),
+        cpu.registers.get_de(),
+        cpu.registers.get_hl(),
+        cpu.sp,
+        cpu.pc,
+        flag(flags.zero, 'Z'),
+        flag(flags.subtract, 'N'),
+        flag(flags.half_carry, 'H'),
+        flag(flags.carry, 'C'),
+        if cpu.ime { "on" } else { "off" },
+        if cpu.is_halted { "  halted" } else { "" },
+    )
+}
+
+// Sixteen bytes a row with the printable ones on the right
+pub fn hexdump(bus: &MemoryBus, start: u16, length: u32) -> String {
+    let bytes: Vec<u8> = (0..length).map(|offset| bus.peek(start.wrapping_add(offset as u16))).collect();
+    let rows: Vec<String> = bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(row, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+            let text: String = chunk
+                .iter()
+                .map(|&byte| if (0x20..0x7F).contains(&byte) { byte as char } else { '.' })
+                .collect();
+            format!("${:04X}: {:<47}  {}", start.wrapping_add(row as u16 * 16), hex.join(" "), text)
+        })
+        .collect();
+    rows.join("\n")
+}
+
+fn listing(bus: &MemoryBus, start: u16, count: usize) -> Vec<Line> {
+    let mut lines: Vec<Line> = Vec::new();
+    let mut address = start;
+    for _ in 0..count {
+        let line = disassembler::decode(|address| bus.peek(address), address);
+        address = address.wrapping_add(line.bytes.len() as u16);
+        lines.push(line);
+    }
+    lines
+}
+
+// Instructions can't be decoded backwards, so start a little before PC
+// at the furthest point whose instructions land exactly on it
+fn around(bus: &MemoryBus, pc: u16) -> Vec<Line> {
+    let mut before = Vec::new();
+    for back in (1..=(CONTEXT_BEFORE as u16 * 3)).rev() {
+        let start = pc.wrapping_sub(back);
+        let mut address = start;
+        let mut lines = Vec::new();
+        while address.wrapping_sub(start) < back {
+            let line = disassembler::decode(|address| bus.peek(address), address);
+            address = address.wrapping_add(line.bytes.len() as u16);
+            lines.push(line);
+        }
+        if address == pc {
+            before = lines;
+            break;
+        }
+    }
+    let skip = before.len().saturating_sub(CONTEXT_BEFORE);
+    before.drain(..skip);
+    before.extend(listing(bus, pc, CONTEXT_AFTER + 1));
+    before
+}
+
+#[cfg(test)]
+mod debugger_tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+
+    // Calls a function that loads and increments a, then counts up in
+    // WRAM at 0xC000 forever
+    fn machine() -> CPU {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x10C].copy_from_slice(&[
+            0x31, 0xFE, 0xFF, // ld sp, $FFFE
+            0xCD, 0x10, 0x01, // call $0110
+            0x21, 0x00, 0xC0, // ld hl, $C000
+            0x34, // inc [hl]
+            0x18, 0xFD, // jr $0109
+        ]);
+        rom[0x110..0x114].copy_from_slice(&[0x3E, 0x05, 0x3C, 0xC9]);
+        CPU::with_cartridge(Cartridge::new(rom).unwrap())
+    }
+
+    #[test]
+    fn steps_and_repeats() {
+        let mut cpu = machine();
+        let mut debugger = Debugger::new();
+        let output = debugger.command(&mut cpu, "step").unwrap();
+        assert_eq!(output, "=> $0103: CD 10 01  call $0110");
+        debugger.command(&mut cpu, "").unwrap();
+        assert_eq!(cpu.pc, 0x0110);
+        debugger.command(&mut cpu, "s 2").unwrap();
+        assert_eq!(cpu.pc, 0x0113);
+        assert_eq!(cpu.registers.a, 6);
+    }
+    #[test]
+    fn next_steps_over_calls() {
+        let mut cpu = machine();
+        let mut debugger = Debugger::new();
+        debugger.command(&mut cpu, "n").unwrap();
+        debugger.command(&mut cpu, "n").unwrap();
+        assert_eq!(cpu.pc, 0x0106);
+        assert_eq!(cpu.registers.a, 6);
+    }
+    #[test]
+    fn finish_runs_to_the_return() {
+        let mut cpu = machine();
+        let mut debugger = Debugger::new();
+        debugger.command(&mut cpu, "s 3").unwrap();
+        assert_eq!(cpu.pc, 0x0112);
+        debugger.command(&mut cpu, "finish").unwrap();
+        assert_eq!(cpu.pc, 0x0106);
+        assert_eq!(cpu.sp, 0xFFFE);
+    }
+    #[test]
+    fn stops_at_breakpoints() {
+        let mut cpu = machine();
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.command(&mut cpu, "break $0112").unwrap(), "1: breakpoint at $0112");
+        let output = debugger.command(&mut cpu, "c").unwrap();
+        assert!(output.starts_with("breakpoint 1\n=> $0112"), "{}", output);
+        debugger.command(&mut cpu, "delete 1").unwrap();
+        debugger.command(&mut cpu, "b 0x109 if a != 6").unwrap();
+        debugger.command(&mut cpu, "b 0x109 if hl >= $C000").unwrap();
+        let output = debugger.command(&mut cpu, "c").unwrap();
+        assert!(output.starts_with("breakpoint 3\n"), "{}", output);
+        assert_eq!(cpu.pc, 0x0109);
+        assert!(debugger.command(&mut cpu, "b 0x109 if q == 1").is_err());
+        assert!(debugger.command(&mut cpu, "delete 7").is_err());
+    }
+    #[test]
+    fn stops_on_watched_accesses() {
+        let mut cpu = machine();
+        let mut debugger = Debugger::new();
+        debugger.command(&mut cpu, "watch $C000").unwrap();
+        let output = debugger.command(&mut cpu, "c").unwrap();
+        assert!(output.starts_with("watchpoint 1: write $01 at $C000\n=> $010A"), "{}", output);
+        debugger.command(&mut cpu, "d 1").unwrap();
+        // Fetching the operand of jr is not a read of $010B
+        debugger.command(&mut cpu, "rwatch $010B").unwrap();
+        debugger.command(&mut cpu, "rwatch $C000").unwrap();
+        let output = debugger.command(&mut cpu, "c").unwrap();
+        assert!(output.starts_with("watchpoint 3: read $01 at $C000\n"), "{}", output);
+        assert!(cpu.bus.access_log.is_none());
+    }
+    #[test]
+    fn shows_registers_and_memory() {
+        let mut cpu = machine();
+        let mut debugger = Debugger::new();
+        cpu.registers.set_af(0x12A0);
+        cpu.registers.set_hl(0xC000);
+        let output = debugger.command(&mut cpu, "r").unwrap();
+        assert!(output.contains("AF $12A0"), "{}", output);
+        assert!(output.contains("HL $C000"), "{}", output);
+        assert!(output.contains("flags Z-H-"), "{}", output);
+        cpu.bus.wram[..3].copy_from_slice(b"GB!");
+        let output = debugger.command(&mut cpu, "x $C000 20").unwrap();
+        let rows: Vec<&str> = output.lines().collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].starts_with("$C000: 47 42 21 00"), "{}", rows[0]);
+        assert!(rows[0].ends_with("  GB!............."), "{}", rows[0]);
+        assert!(rows[1].starts_with("$C010: 00 00 00 00"), "{}", rows[1]);
+        let output = debugger.command(&mut cpu, "x 0 0xFFFFFFFF").unwrap();
+        assert_eq!(output.lines().count(), 0x1000);
+    }
+    #[test]
+    fn uses_symbols() {
+        let mut cpu = machine();
+        let mut debugger = Debugger::new();
+        debugger.symbols = Symbols::parse("00:0100 Start\n00:0110 Increment\n00:c000 wCounter\n").unwrap();
+        let output = debugger.command(&mut cpu, "break Increment").unwrap();
+        assert_eq!(output, "1: breakpoint at $0110 in bank 0 (Increment)");
+        let output = debugger.command(&mut cpu, "c").unwrap();
+        assert_eq!(output, "breakpoint 1\nIncrement:\n=> $0110: 3E 05     ld a, $05");
+        debugger.command(&mut cpu, "watch wCounter").unwrap();
+        let output = debugger.command(&mut cpu, "c").unwrap();
+        assert!(output.starts_with("watchpoint 2: write $01 at $C000 (wCounter)\n"), "{}", output);
+        let output = debugger.command(&mut cpu, "l Start").unwrap();
+        assert!(output.starts_with("Start:\n   $0100: 31 FE FF  ld sp, $FFFE\n"), "{}", output);
+        assert!(output.contains("call $0110  ; Increment\n"), "{}", output);
+        assert!(debugger.command(&mut cpu, "break Nowhere").is_err());
+        // A label in another bank does not stop in this one
+        debugger.symbols = Symbols::parse("01:0109 Elsewhere\n").unwrap();
+        debugger.breakpoints.clear();
+        debugger.command(&mut cpu, "break Elsewhere").unwrap();
+        debugger.command(&mut cpu, "break $010A").unwrap();
+        let output = debugger.command(&mut cpu, "c").unwrap();
+        assert!(output.starts_with("breakpoint 4\n"), "{}", output);
+    }
+    #[test]
+    fn shows_backtraces_and_imbalances() {
+        let mut cpu = machine();
+        let mut debugger = Debugger::new();
+        debugger.symbols = Symbols::parse("00:0100 Start\n00:0110 Increment\n").unwrap();
+        debugger.command(&mut cpu, "s 3").unwrap();
+        let output = debugger.command(&mut cpu, "bt").unwrap();
+        assert_eq!(
+            output,
+            "#0  $0112 in bank 0 (Increment+$2)\n\
+             #1  $0103 in bank 0 (Start+$3), called $0110 in bank 0 (Increment)"
+        );
+        // Dropping the return address without returning
+        cpu.sp = 0xFFFE;
+        let output = debugger.command(&mut cpu, "s").unwrap();
+        let warning = "warning: SP moved to $FFFE at $0112 without returning from the call at $0103 in bank 0\n";
+        assert!(output.starts_with(warning), "{}", output);
+        assert_eq!(debugger.command(&mut cpu, "bt").unwrap(), "#0  $0113 in bank 0 (Increment+$3)");
+    }
+    #[test]
+    fn tracks_who_wrote_memory() {
+        let mut cpu = machine();
+        let mut debugger = Debugger::new();
+        debugger.symbols = Symbols::parse("00:0100 Start\n00:0110 Increment\n00:c000 wCounter\n").unwrap();
+        let output = debugger.command(&mut cpu, "who wCounter").unwrap();
+        assert_eq!(output, "$C000 (wCounter): 0 reads, 0 writes, never written");
+        debugger.command(&mut cpu, "s 9").unwrap();
+        assert_eq!(
+            debugger.command(&mut cpu, "who wCounter").unwrap(),
+            "$C000 (wCounter): 2 reads, 2 writes, last wrote $02 from $0109 in bank 0 (Start+$9)"
+        );
+        // The call pushed its return address, the ret read it back
+        assert_eq!(
+            debugger.command(&mut cpu, "who $FFFD").unwrap(),
+            "$FFFD: 1 reads, 1 writes, last wrote $01 from $0103 in bank 0 (Start+$3)"
+        );
+        assert!(debugger.heatmap.reads[0x0100] > 0);
+        debugger.command(&mut cpu, "heatmap clear").unwrap();
+        assert_eq!(debugger.heatmap.reads[0x0100], 0);
+        assert!(debugger.command(&mut cpu, "who").is_err());
+    }
+    #[test]
+    fn disassembles_around_pc() {
+        let mut cpu = machine();
+        let mut debugger = Debugger::new();
+        debugger.command(&mut cpu, "n").unwrap();
+        debugger.command(&mut cpu, "n").unwrap();
+        let output = debugger.command(&mut cpu, "l").unwrap();
+        assert!(output.contains("   $0103: CD 10 01  call $0110\n=> $0106: 21 00 C0  ld hl, $C000\n"), "{}", output);
+    }
+}
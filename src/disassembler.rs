@@ -0,0 +1,408 @@
+use std::fmt;
+
+use crate::cpu::instruction::{
+    ADDHLTarget, ArthimeticTarget, IncDecTarget, Indirect, Instruction, JumpTest, LoadByteSource,
+    LoadByteTarget, LoadType, LoadWordTarget, PrefixTarget, StackTarget,
+};
+use crate::memory_bus::MemoryBus;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+
+// One decoded instruction in RGBDS syntax
+#[derive(Clone, Debug, PartialEq)]
+pub struct Line {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+impl Line {
+    // Where a jump, call or rst goes. None for everything else, jp hl
+    // included since its target is only known at run time.
+    pub fn target(&self) -> Option<u16> {
+        let byte = |index: usize| self.bytes.get(index).copied();
+        match byte(0)? {
+            0xC3 | 0xC2 | 0xCA | 0xD2 | 0xDA | 0xCD | 0xC4 | 0xCC | 0xD4 | 0xDC => {
+                Some(u16::from_le_bytes([byte(1)?, byte(2)?]))
+            }
+            0x18 | 0x20 | 0x28 | 0x30 | 0x38 => Some(self.address.wrapping_add(2).wrapping_add(byte(1)? as i8 as u16)),
+            opcode if opcode & 0xC7 == 0xC7 => Some((opcode & 0x38) as u16),
+            _ => None,
+        }
+    }
+}
+
+// Assembles as is, the address and encoding go in a comment
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        write!(f, "    {:<24}; ${:04X}: {}", self.text, self.address, bytes.join(" "))
+    }
+}
+
+// Decodes the instruction at `address`, `read` supplies the bytes.
+// Unused opcodes come out as a `db`.
+pub fn decode<F: Fn(u16) -> u8>(read: F, address: u16) -> Line {
+    let opcode = read(address);
+    let prefixed = opcode == 0xCB;
+    let byte = if prefixed { read(address.wrapping_add(1)) } else { opcode };
+    let (text, length) = match Instruction::from_byte(byte, prefixed) {
+        Some(instruction) => {
+            let operand = |offset| read(address.wrapping_add(offset));
+            let operands = Operands {
+                address,
+                byte: operand(1),
+                word: operand(1) as u16 | (operand(2) as u16) << 8,
+            };
+            (text(instruction, &operands), instruction.length())
+        }
+        None => (format!("db ${:02X}", opcode), 1),
+    };
+    Line {
+        address,
+        bytes: (0..length).map(|offset| read(address.wrapping_add(offset))).collect(),
+        text,
+    }
+}
+
+// Instructions starting in `start..end` as the CPU would see them now
+pub fn disassemble(bus: &MemoryBus, start: u16, end: u16) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut address = start as u32;
+    while address < end as u32 {
+        let line = decode(|address| bus.peek(address), address as u16);
+        address += line.bytes.len() as u32;
+        lines.push(line);
+    }
+    lines
+}
+
+// A whole ROM bank at the address it is mapped to, 0x0000 for bank 0
+// and 0x4000 for the others
+pub fn disassemble_bank(rom: &[u8], bank: usize) -> Vec<Line> {
+    let base = if bank == 0 { 0x0000 } else { ROM_BANK_SIZE };
+    let read = |address: u16| {
+        let offset = (address as usize).wrapping_sub(base);
+        if offset < ROM_BANK_SIZE {
+            rom.get(bank * ROM_BANK_SIZE + offset).copied().unwrap_or(0xFF)
+        } else {
+            0xFF
+        }
+    };
+    let mut lines = Vec::new();
+    let mut address = base;
+    while address < base + ROM_BANK_SIZE {
+        let line = decode(read, address as u16);
+        address += line.bytes.len();
+        lines.push(line);
+    }
+    lines
+}
+
+struct Operands {
+    address: u16,
+    byte: u8,
+    word: u16,
+}
+
+fn text(instruction: Instruction, operands: &Operands) -> String {
+    let n8 = format!("${:02X}", operands.byte);
+    let n16 = format!("${:04X}", operands.word);
+    let alu = |mnemonic, target| match target {
+        ArthimeticTarget::D8 => format!("{} a, {}", mnemonic, n8),
+        _ => format!("{} a, {}", mnemonic, arithmetic(target)),
+    };
+    match instruction {
+        Instruction::ADD(target) => alu("add", target),
+        Instruction::ADC(target) => alu("adc", target),
+        Instruction::SUB(target) => alu("sub", target),
+        Instruction::SBC(target) => alu("sbc", target),
+        Instruction::AND(target) => alu("and", target),
+        Instruction::XOR(target) => alu("xor", target),
+        Instruction::OR(target) => alu("or", target),
+        Instruction::CP(target) => alu("cp", target),
+        Instruction::ADDHL(target) => format!("add hl, {}", add_hl(target)),
+        Instruction::ADDSP => format!("add sp, {}", signed(operands.byte)),
+        Instruction::INC(target) => format!("inc {}", inc_dec(target)),
+        Instruction::DEC(target) => format!("dec {}", inc_dec(target)),
+        Instruction::DAA => "daa".to_string(),
+        Instruction::CPL => "cpl".to_string(),
+        Instruction::SCF => "scf".to_string(),
+        Instruction::CCF => "ccf".to_string(),
+        Instruction::RLCA => "rlca".to_string(),
+        Instruction::RRCA => "rrca".to_string(),
+        Instruction::RLA => "rla".to_string(),
+        Instruction::RRA => "rra".to_string(),
+
+        Instruction::RLC(target) => format!("rlc {}", prefix(target)),
+        Instruction::RRC(target) => format!("rrc {}", prefix(target)),
+        Instruction::RL(target) => format!("rl {}", prefix(target)),
+        Instruction::RR(target) => format!("rr {}", prefix(target)),
+        Instruction::SLA(target) => format!("sla {}", prefix(target)),
+        Instruction::SRA(target) => format!("sra {}", prefix(target)),
+        Instruction::SWAP(target) => format!("swap {}", prefix(target)),
+        Instruction::SRL(target) => format!("srl {}", prefix(target)),
+        Instruction::BIT(target, bit) => format!("bit {}, {}", u8::from(bit), prefix(target)),
+        Instruction::RES(target, bit) => format!("res {}, {}", u8::from(bit), prefix(target)),
+        Instruction::SET(target, bit) => format!("set {}, {}", u8::from(bit), prefix(target)),
+
+        Instruction::JP(test) => format!("jp {}{}", condition(test), n16),
+        Instruction::JPHL => "jp hl".to_string(),
+        Instruction::JR(test) => {
+            let target = operands.address.wrapping_add(2).wrapping_add(operands.byte as i8 as u16);
+            format!("jr {}${:04X}", condition(test), target)
+        }
+        Instruction::CALL(test) => format!("call {}{}", condition(test), n16),
+        Instruction::RET(JumpTest::Always) => "ret".to_string(),
+        Instruction::RET(test) => format!("ret {}", condition(test).trim_end_matches(", ")),
+        Instruction::RETI => "reti".to_string(),
+        Instruction::RST(vector) => format!("rst ${:02X}", vector),
+
+        Instruction::LD(load_type) => load(load_type, &n8, &n16, operands.byte),
+        Instruction::PUSH(target) => format!("push {}", stack(target)),
+        Instruction::POP(target) => format!("pop {}", stack(target)),
+
+        Instruction::NOP => "nop".to_string(),
+        Instruction::HALT => "halt".to_string(),
+        Instruction::STOP => "stop".to_string(),
+        Instruction::DI => "di".to_string(),
+        Instruction::EI => "ei".to_string(),
+    }
+}
+
+fn load(load_type: LoadType, n8: &str, n16: &str, byte: u8) -> String {
+    match load_type {
+        LoadType::Byte(target, source) => format!("ld {}, {}", load_target(target), load_source(source, n8)),
+        LoadType::Word(target) => format!("ld {}, {}", word(target), n16),
+        LoadType::AFromIndirect(indirect) => {
+            format!("{} a, {}", indirect_mnemonic(indirect), indirect_operand(indirect, n16))
+        }
+        LoadType::IndirectFromA(indirect) => {
+            format!("{} {}, a", indirect_mnemonic(indirect), indirect_operand(indirect, n16))
+        }
+        LoadType::AFromByteAddress => format!("ldh a, [$FF{:02X}]", byte),
+        LoadType::ByteAddressFromA => format!("ldh [$FF{:02X}], a", byte),
+        LoadType::SPFromHL => "ld sp, hl".to_string(),
+        LoadType::HLFromSPN => {
+            let offset = byte as i8;
+            let sign = if offset < 0 { '-' } else { '+' };
+            format!("ld hl, sp {} ${:02X}", sign, offset.unsigned_abs())
+        }
+        LoadType::IndirectFromSP => format!("ld [{}], sp", n16),
+    }
+}
+
+// [c] is only valid with ldh
+fn indirect_mnemonic(indirect: Indirect) -> &'static str {
+    match indirect {
+        Indirect::LastByteIndirect => "ldh",
+        _ => "ld",
+    }
+}
+
+fn indirect_operand(indirect: Indirect, n16: &str) -> String {
+    match indirect {
+        Indirect::BCIndirect => "[bc]".to_string(),
+        Indirect::DEIndirect => "[de]".to_string(),
+        Indirect::HLIndirectPlus => "[hl+]".to_string(),
+        Indirect::HLIndirectMinus => "[hl-]".to_string(),
+        Indirect::WordIndirect => format!("[{}]", n16),
+        Indirect::LastByteIndirect => "[c]".to_string(),
+    }
+}
+
+fn signed(byte: u8) -> String {
+    let offset = byte as i8;
+    if offset < 0 {
+        format!("-${:02X}", offset.unsigned_abs())
+    } else {
+        format!("${:02X}", offset)
+    }
+}
+
+fn condition(test: JumpTest) -> &'static str {
+    match test {
+        JumpTest::NotZero => "nz, ",
+        JumpTest::Zero => "z, ",
+        JumpTest::NotCarry => "nc, ",
+        JumpTest::Carry => "c, ",
+        JumpTest::Always => "",
+    }
+}
+
+fn arithmetic(target: ArthimeticTarget) -> &'static str {
+    match target {
+        ArthimeticTarget::A => "a",
+        ArthimeticTarget::B => "b",
+        ArthimeticTarget::C => "c",
+        ArthimeticTarget::D => "d",
+        ArthimeticTarget::E => "e",
+        ArthimeticTarget::H => "h",
+        ArthimeticTarget::L => "l",
+        ArthimeticTarget::HLI => "[hl]",
+        ArthimeticTarget::D8 => "n8",
+    }
+}
+
+fn add_hl(target: ADDHLTarget) -> &'static str {
+    match target {
+        ADDHLTarget::BC => "bc",
+        ADDHLTarget::DE => "de",
+        ADDHLTarget::HL => "hl",
+        ADDHLTarget::SP => "sp",
+    }
+}
+
+fn inc_dec(target: IncDecTarget) -> &'static str {
+    match target {
+        IncDecTarget::A => "a",
+        IncDecTarget::B => "b",
+        IncDecTarget::C => "c",
+        IncDecTarget::D => "d",
+        IncDecTarget::E => "e",
+        IncDecTarget::H => "h",
+        IncDecTarget::L => "l",
+        IncDecTarget::HLI => "[hl]",
+        IncDecTarget::BC => "bc",
+        IncDecTarget::DE => "de",
+        IncDecTarget::HL => "hl",
+        IncDecTarget::SP => "sp",
+    }
+}
+
+fn prefix(target: PrefixTarget) -> &'static str {
+    match target {
+        PrefixTarget::A => "a",
+        PrefixTarget::B => "b",
+        PrefixTarget::C => "c",
+        PrefixTarget::D => "d",
+        PrefixTarget::E => "e",
+        PrefixTarget::H => "h",
+        PrefixTarget::L => "l",
+        PrefixTarget::HLI => "[hl]",
+    }
+}
+
+fn load_target(target: LoadByteTarget) -> &'static str {
+    match target {
+        LoadByteTarget::A => "a",
+        LoadByteTarget::B => "b",
+        LoadByteTarget::C => "c",
+        LoadByteTarget::D => "d",
+        LoadByteTarget::E => "e",
+        LoadByteTarget::H => "h",
+        LoadByteTarget::L => "l",
+        LoadByteTarget::HLI => "[hl]",
+    }
+}
+
+fn load_source(source: LoadByteSource, n8: &str) -> String {
+    match source {
+        LoadByteSource::A => "a",
+        LoadByteSource::B => "b",
+        LoadByteSource::C => "c",
+        LoadByteSource::D => "d",
+        LoadByteSource::E => "e",
+        LoadByteSource::H => "h",
+        LoadByteSource::L => "l",
+        LoadByteSource::HLI => "[hl]",
+        LoadByteSource::D8 => n8,
+    }
+    .to_string()
+}
+
+fn word(target: LoadWordTarget) -> &'static str {
+    match target {
+        LoadWordTarget::BC => "bc",
+        LoadWordTarget::DE => "de",
+        LoadWordTarget::HL => "hl",
+        LoadWordTarget::SP => "sp",
+    }
+}
+
+fn stack(target: StackTarget) -> &'static str {
+    match target {
+        StackTarget::AF => "af",
+        StackTarget::BC => "bc",
+        StackTarget::DE => "de",
+        StackTarget::HL => "hl",
+    }
+}
+
+#[cfg(test)]
+mod disassembler_tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+
+    fn text_of(bytes: &[u8], address: u16) -> String {
+        decode(|at| bytes.get(at.wrapping_sub(address) as usize).copied().unwrap_or(0), address).text
+    }
+
+    #[test]
+    fn immediates() {
+        assert_eq!(text_of(&[0x21, 0x00, 0xC0], 0), "ld hl, $C000");
+        assert_eq!(text_of(&[0x3E, 0x7F], 0), "ld a, $7F");
+        assert_eq!(text_of(&[0xFE, 0x90], 0), "cp a, $90");
+        assert_eq!(text_of(&[0xEA, 0x34, 0x12], 0), "ld [$1234], a");
+        assert_eq!(text_of(&[0x08, 0x00, 0xD0], 0), "ld [$D000], sp");
+        assert_eq!(text_of(&[0xC4, 0x50, 0x01], 0), "call nz, $0150");
+    }
+    #[test]
+    fn relative_and_signed_operands() {
+        assert_eq!(text_of(&[0x18, 0xFE], 0x0150), "jr $0150");
+        assert_eq!(text_of(&[0x38, 0x05], 0x0150), "jr c, $0157");
+        assert_eq!(text_of(&[0xE8, 0xFD], 0), "add sp, -$03");
+        assert_eq!(text_of(&[0xF8, 0x02], 0), "ld hl, sp + $02");
+        assert_eq!(text_of(&[0xF8, 0x80], 0), "ld hl, sp - $80");
+    }
+    #[test]
+    fn high_ram_and_indirect_loads() {
+        assert_eq!(text_of(&[0xE0, 0x44], 0), "ldh [$FF44], a");
+        assert_eq!(text_of(&[0xF2], 0), "ldh a, [c]");
+        assert_eq!(text_of(&[0x22], 0), "ld [hl+], a");
+        assert_eq!(text_of(&[0x3A], 0), "ld a, [hl-]");
+        assert_eq!(text_of(&[0x36, 0x00], 0), "ld [hl], $00");
+    }
+    #[test]
+    fn prefixed_and_control() {
+        assert_eq!(text_of(&[0xCB, 0x7E], 0), "bit 7, [hl]");
+        assert_eq!(text_of(&[0xCB, 0x37], 0), "swap a");
+        assert_eq!(text_of(&[0xC8], 0), "ret z");
+        assert_eq!(text_of(&[0xFF], 0), "rst $38");
+        assert_eq!(text_of(&[0xD3], 0), "db $D3");
+    }
+    #[test]
+    fn branch_targets() {
+        let target = |bytes: &[u8], address: u16| {
+            decode(|at| bytes.get(at.wrapping_sub(address) as usize).copied().unwrap_or(0), address).target()
+        };
+        assert_eq!(target(&[0xCD, 0x50, 0x01], 0), Some(0x0150));
+        assert_eq!(target(&[0xDA, 0x00, 0x40], 0), Some(0x4000));
+        assert_eq!(target(&[0x20, 0xFE], 0x0200), Some(0x0200));
+        assert_eq!(target(&[0xEF], 0), Some(0x0028));
+        assert_eq!(target(&[0xE9], 0), None);
+        assert_eq!(target(&[0xC9], 0), None);
+        assert_eq!(target(&[0xD3], 0), None);
+    }
+    #[test]
+    fn disassembles_from_the_bus() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x106].copy_from_slice(&[0x21, 0x00, 0xC0, 0xCB, 0x37, 0x00]);
+        let bus = MemoryBus::new(Cartridge::new(rom).unwrap());
+        let lines = disassemble(&bus, 0x0100, 0x0106);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1].address, 0x0103);
+        assert_eq!(lines[1].to_string(), "    swap a                  ; $0103: CB 37");
+    }
+    #[test]
+    fn disassembles_a_bank() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x4000] = 0xC3;
+        rom[0x7FFF] = 0x3E;
+        let lines = disassemble_bank(&rom, 1);
+        assert_eq!(lines[0].text, "jp $0000");
+        assert_eq!(lines[0].address, 0x4000);
+        // The immediate past the end of the bank reads as 0xFF
+        assert_eq!(lines.last().unwrap().text, "ld a, $FF");
+    }
+}
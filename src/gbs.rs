@@ -0,0 +1,280 @@
+use std::fmt;
+
+use crate::apu::CPU_CLOCK_HZ;
+use crate::cartridge::{Cartridge, MbcKind, RAM_BANK_SIZE, ROM_BANK_SIZE};
+use crate::cpu::registers::Registers;
+use crate::cpu::{CPU, CYCLES_PER_FRAME};
+
+pub const HEADER_SIZE: usize = 0x70;
+
+// Routines return here. Nothing can execute from the unusable OAM area,
+// so reaching it means the routine has returned to the player.
+const RETURN_ADDRESS: u16 = 0xFEA0;
+
+// Give up on init/play routines that never return
+const MAX_CALL_CYCLES: u32 = CPU_CLOCK_HZ * 10;
+
+#[derive(Debug, PartialEq)]
+pub enum GbsError {
+    TooSmall(usize),
+    BadMagic,
+    LoadAddressOutOfRange(u16),
+    NoSuchSong(u8),
+}
+
+impl fmt::Display for GbsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GbsError::TooSmall(size) => write!(f, "GBS file is {} bytes, too small for a header", size),
+            GbsError::BadMagic => write!(f, "missing GBS signature"),
+            GbsError::LoadAddressOutOfRange(address) => {
+                write!(f, "load address 0x{:04X} is outside of ROM", address)
+            }
+            GbsError::NoSuchSong(song) => write!(f, "song {} does not exist", song),
+        }
+    }
+}
+
+impl std::error::Error for GbsError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GbsHeader {
+    pub version: u8,
+    pub song_count: u8,
+    // 1 based, like the song numbers players show
+    pub first_song: u8,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+}
+
+fn read_word(data: &[u8], offset: usize) -> u16 {
+    data[offset] as u16 | (data[offset + 1] as u16) << 8
+}
+
+fn read_string(data: &[u8]) -> String {
+    data.iter().take_while(|&&byte| byte != 0).map(|&byte| byte as char).collect()
+}
+
+impl GbsHeader {
+    pub fn parse(data: &[u8]) -> Result<GbsHeader, GbsError> {
+        if data.len() < HEADER_SIZE {
+            return Err(GbsError::TooSmall(data.len()));
+        }
+        if &data[0..3] != b"GBS" {
+            return Err(GbsError::BadMagic);
+        }
+        Ok(GbsHeader {
+            version: data[0x03],
+            song_count: data[0x04],
+            first_song: data[0x05],
+            load_address: read_word(data, 0x06),
+            init_address: read_word(data, 0x08),
+            play_address: read_word(data, 0x0A),
+            stack_pointer: read_word(data, 0x0C),
+            timer_modulo: data[0x0E],
+            timer_control: data[0x0F],
+            title: read_string(&data[0x10..0x30]),
+            author: read_string(&data[0x30..0x50]),
+            copyright: read_string(&data[0x50..0x70]),
+        })
+    }
+
+    // Cycles between calls to the play routine. Rips either use the
+    // timer interrupt, with the rate set by TMA/TAC, or VBlank.
+    pub fn play_period(&self) -> u32 {
+        if self.timer_control & 0x04 == 0 {
+            return CYCLES_PER_FRAME;
+        }
+        let divider = match self.timer_control & 0x03 {
+            0b00 => 1024,
+            0b01 => 16,
+            0b10 => 64,
+            _ => 256,
+        };
+        let period = divider * (256 - self.timer_modulo as u32);
+        // Bit 7 asks for CGB double speed, where the timer runs twice as fast
+        if self.timer_control & 0x80 != 0 {
+            period / 2
+        } else {
+            period
+        }
+    }
+}
+
+// Plays a GBS rip by mapping its data into a banked ROM and calling the
+// rip's init and play routines on the CPU
+pub struct GbsPlayer {
+    pub header: GbsHeader,
+    pub cpu: CPU,
+    image: Vec<u8>,
+    pub song: u8,
+    pub cycles: u64,
+}
+
+impl GbsPlayer {
+    pub fn new(data: &[u8]) -> Result<GbsPlayer, GbsError> {
+        let header = GbsHeader::parse(data)?;
+        let load_address = header.load_address as usize;
+        if !(0x0400..0x8000).contains(&load_address) {
+            return Err(GbsError::LoadAddressOutOfRange(header.load_address));
+        }
+
+        let body = &data[HEADER_SIZE..];
+        let size = (load_address + body.len()).max(0x8000);
+        let mut image = vec![0; size.div_ceil(ROM_BANK_SIZE) * ROM_BANK_SIZE];
+        image[load_address..load_address + body.len()].copy_from_slice(body);
+        // RST vectors are relocated to the load address
+        for vector in (0x00..0x40).step_by(8) {
+            let target = (load_address + vector) as u16;
+            image[vector..vector + 3].copy_from_slice(&[0xC3, target as u8, (target >> 8) as u8]);
+        }
+
+        let song = header.first_song.saturating_sub(1);
+        let mut player = GbsPlayer {
+            header,
+            cpu: CPU::new(),
+            image,
+            song,
+            cycles: 0,
+        };
+        player.start_song(song)?;
+        Ok(player)
+    }
+
+    // Resets the machine and runs the init routine for a 0 based song
+    pub fn start_song(&mut self, song: u8) -> Result<(), GbsError> {
+        if song >= self.header.song_count {
+            return Err(GbsError::NoSuchSong(song));
+        }
+        let mut cartridge = Cartridge::with_mapper(self.image.clone(), MbcKind::Mbc5, RAM_BANK_SIZE);
+        cartridge.ram_enabled = true;
+        let sample_rate = self.cpu.bus.apu.sample_rate;
+        self.cpu = CPU::with_cartridge(cartridge);
+        self.cpu.bus.apu.sample_rate = sample_rate;
+        self.cpu.registers = Registers::new();
+        self.cpu.sp = self.header.stack_pointer;
+        self.cpu.bus.write_byte(0xFF40, 0x00);
+        self.cpu.bus.write_byte(0xFF06, self.header.timer_modulo);
+        self.cpu.bus.write_byte(0xFF07, self.header.timer_control);
+        self.song = song;
+        self.cycles = 0;
+
+        self.cpu.registers.a = song;
+        let init_address = self.header.init_address;
+        self.call(init_address);
+        Ok(())
+    }
+
+    // Pushes the sentinel return address and runs until the routine
+    // returns to it. Interrupts stay disabled, the player drives timing.
+    fn call(&mut self, address: u16) -> u32 {
+        self.cpu.sp = self.cpu.sp.wrapping_sub(2);
+        self.cpu.bus.write_word(self.cpu.sp, RETURN_ADDRESS);
+        self.cpu.pc = address;
+        self.cpu.is_halted = false;
+        self.cpu.ime = false;
+        self.cpu.bus.interrupt_enable = 0;
+
+        let mut cycles = 0;
+        while self.cpu.pc != RETURN_ADDRESS && cycles < MAX_CALL_CYCLES {
+            cycles += self.cpu.step();
+        }
+        self.cycles += cycles as u64;
+        cycles
+    }
+
+    // Calls play once and then idles until the next call is due
+    pub fn step(&mut self) {
+        let period = self.header.play_period();
+        let play_address = self.header.play_address;
+        let mut cycles = self.call(play_address);
+        self.cpu.is_halted = true;
+        while cycles < period {
+            let idle = self.cpu.step();
+            cycles += idle;
+            self.cycles += idle as u64;
+        }
+    }
+
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        self.cpu.bus.apu.take_samples()
+    }
+
+    // Renders the current song for the given number of seconds
+    pub fn render(&mut self, seconds: u32) -> Vec<i16> {
+        let target = self.cycles + seconds as u64 * CPU_CLOCK_HZ as u64;
+        let mut samples = self.take_samples();
+        while self.cycles < target {
+            self.step();
+            samples.extend(self.take_samples());
+        }
+        samples
+    }
+}
+
+#[cfg(test)]
+mod gbs_tests {
+    use super::*;
+
+    // init stores A at 0xC000, play increments 0xC001
+    fn test_rip(timer_modulo: u8, timer_control: u8) -> Vec<u8> {
+        let mut data = vec![0; HEADER_SIZE];
+        data[0..3].copy_from_slice(b"GBS");
+        data[0x03] = 1;
+        data[0x04] = 3;
+        data[0x05] = 2;
+        data[0x06..0x08].copy_from_slice(&[0x00, 0x04]);
+        data[0x08..0x0A].copy_from_slice(&[0x00, 0x04]);
+        data[0x0A..0x0C].copy_from_slice(&[0x04, 0x04]);
+        data[0x0C..0x0E].copy_from_slice(&[0xFE, 0xFF]);
+        data[0x0E] = timer_modulo;
+        data[0x0F] = timer_control;
+        data[0x10..0x14].copy_from_slice(b"Test");
+        data.extend_from_slice(&[0xEA, 0x00, 0xC0, 0xC9, 0x21, 0x01, 0xC0, 0x34, 0xC9]);
+        data
+    }
+
+    #[test]
+    fn parses_header() {
+        let header = GbsHeader::parse(&test_rip(0, 0)).unwrap();
+        assert_eq!(header.song_count, 3);
+        assert_eq!(header.load_address, 0x0400);
+        assert_eq!(header.play_address, 0x0404);
+        assert_eq!(header.title, "Test");
+        assert_eq!(header.play_period(), CYCLES_PER_FRAME);
+    }
+    #[test]
+    fn rejects_bad_magic() {
+        let mut data = test_rip(0, 0);
+        data[0] = b'X';
+        assert_eq!(GbsPlayer::new(&data).err(), Some(GbsError::BadMagic));
+    }
+    #[test]
+    fn timer_play_rate() {
+        let header = GbsHeader::parse(&test_rip(0xC0, 0x04)).unwrap();
+        assert_eq!(header.play_period(), 1024 * 64);
+    }
+    #[test]
+    fn init_gets_song_number() {
+        let mut player = GbsPlayer::new(&test_rip(0, 0)).unwrap();
+        assert_eq!(player.song, 1);
+        assert_eq!(player.cpu.bus.read_byte(0xC000), 1);
+        player.start_song(2).unwrap();
+        assert_eq!(player.cpu.bus.read_byte(0xC000), 2);
+        assert_eq!(player.start_song(3), Err(GbsError::NoSuchSong(3)));
+    }
+    #[test]
+    fn play_called_once_per_frame() {
+        let mut player = GbsPlayer::new(&test_rip(0, 0)).unwrap();
+        let samples = player.render(1);
+        assert_eq!(player.cpu.bus.read_byte(0xC001), 60);
+        assert!(!samples.is_empty());
+    }
+}
@@ -0,0 +1,672 @@
+use std::cell::RefCell;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+
+use crate::cpu::{CPU, CYCLES_PER_FRAME};
+use crate::debugger::instruction_length;
+use crate::memory_bus::{Access, AccessKind};
+
+// GDB has no SM83 target, so the register layout is ours and is also
+// described to clients through target.xml: a f b c d e h l as bytes,
+// then sp and pc as little endian words
+const REGISTER_COUNT: usize = 10;
+
+const TARGET_XML: &str = "<?xml version=\"1.0\"?>
+<!DOCTYPE target SYSTEM \"gdb-target.dtd\">
+<target version=\"1.0\">
+<feature name=\"org.gnu.gdb.sm83.core\">
+<reg name=\"a\" bitsize=\"8\" regnum=\"0\"/>
+<reg name=\"f\" bitsize=\"8\"/>
+<reg name=\"b\" bitsize=\"8\"/>
+<reg name=\"c\" bitsize=\"8\"/>
+<reg name=\"d\" bitsize=\"8\"/>
+<reg name=\"e\" bitsize=\"8\"/>
+<reg name=\"h\" bitsize=\"8\"/>
+<reg name=\"l\" bitsize=\"8\"/>
+<reg name=\"sp\" bitsize=\"16\" type=\"data_ptr\"/>
+<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>
+</feature>
+</target>
+";
+
+// Sent by the client to stop a running target
+const INTERRUPT: u8 = 0x03;
+
+// The largest packet we accept or send, as told to the client
+const PACKET_SIZE: usize = 0x4000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+impl WatchKind {
+    // The Z packet type and the name used in stop replies
+    fn from_type(kind: u8) -> Option<WatchKind> {
+        match kind {
+            2 => Some(WatchKind::Write),
+            3 => Some(WatchKind::Read),
+            4 => Some(WatchKind::Access),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            WatchKind::Write => "watch",
+            WatchKind::Read => "rwatch",
+            WatchKind::Access => "awatch",
+        }
+    }
+
+    fn matches(self, kind: AccessKind) -> bool {
+        match self {
+            WatchKind::Write => kind == AccessKind::Write,
+            WatchKind::Read => kind == AccessKind::Read,
+            WatchKind::Access => true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub address: u16,
+    pub length: u16,
+}
+
+impl Watchpoint {
+    fn covers(&self, address: u16) -> bool {
+        address.wrapping_sub(self.address) < self.length.max(1)
+    }
+}
+
+// Answers packets against the machine. The transport is left to serve
+// so the protocol can be driven directly.
+pub struct GdbStub {
+    pub breakpoints: Vec<u16>,
+    pub watchpoints: Vec<Watchpoint>,
+    pub detached: bool,
+}
+
+impl GdbStub {
+    pub fn new() -> GdbStub {
+        GdbStub {
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            detached: false,
+        }
+    }
+
+    // The reply to one packet, without framing. None means no reply is
+    // sent. `interrupted` is polled while the machine runs.
+    pub fn handle(&mut self, cpu: &mut CPU, packet: &[u8], interrupted: &mut dyn FnMut() -> bool) -> Option<Vec<u8>> {
+        let (&command, arguments) = match packet.split_first() {
+            Some(split) => split,
+            None => return Some(Vec::new()),
+        };
+        let text = String::from_utf8_lossy(arguments);
+        let reply = match command {
+            b'?' => "S05".to_string(),
+            b'g' => encode_hex(&read_registers(cpu)),
+            b'G' => match decode_hex(&text) {
+                Some(bytes) if bytes.len() == read_registers(cpu).len() => {
+                    write_registers(cpu, &bytes);
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            b'p' => match usize::from_str_radix(&text, 16).ok().and_then(|register| read_register(cpu, register)) {
+                Some(bytes) => encode_hex(&bytes),
+                None => "E01".to_string(),
+            },
+            b'P' => {
+                let parsed = text.split_once('=').and_then(|(register, value)| {
+                    Some((usize::from_str_radix(register, 16).ok()?, decode_hex(value)?))
+                });
+                match parsed {
+                    Some((register, value)) if write_register(cpu, register, &value) => "OK".to_string(),
+                    _ => "E01".to_string(),
+                }
+            }
+            b'm' => match parse_range(&text) {
+                // Each byte takes two hex digits in the reply
+                Some((address, length)) if length <= PACKET_SIZE / 2 => {
+                    let bytes: Vec<u8> =
+                        (0..length).map(|offset| cpu.bus.peek(address.wrapping_add(offset as u16))).collect();
+                    encode_hex(&bytes)
+                }
+                _ => "E01".to_string(),
+            },
+            b'M' => {
+                let parsed =
+                    text.split_once(':').and_then(|(range, data)| Some((parse_range(range)?, decode_hex(data)?)));
+                match parsed {
+                    Some(((address, length), data)) if data.len() == length => {
+                        write_memory(cpu, address, &data);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            b'X' => {
+                let colon = arguments.iter().position(|&byte| byte == b':');
+                let parsed = colon.and_then(|colon| {
+                    let range = parse_range(&String::from_utf8_lossy(&arguments[..colon]))?;
+                    Some((range, unescape(&arguments[colon + 1..])))
+                });
+                match parsed {
+                    Some(((address, length), data)) if data.len() == length => {
+                        write_memory(cpu, address, &data);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            b'Z' | b'z' => self.set_point(command == b'Z', &text),
+            b's' => {
+                if let Some(address) = parse_resume_address(&text) {
+                    cpu.pc = address;
+                }
+                self.resume(cpu, true, interrupted)
+            }
+            b'c' => {
+                if let Some(address) = parse_resume_address(&text) {
+                    cpu.pc = address;
+                }
+                self.resume(cpu, false, interrupted)
+            }
+            b'v' if text == "Cont?" => "vCont;c;C;s;S".to_string(),
+            // One thread, so the first action applies to it
+            b'v' if text.starts_with("Cont;") => match text[5..].chars().next() {
+                Some('s') | Some('S') => self.resume(cpu, true, interrupted),
+                Some('c') | Some('C') => self.resume(cpu, false, interrupted),
+                _ => "E01".to_string(),
+            },
+            b'H' | b'T' => "OK".to_string(),
+            b'q' => query(&text),
+            b'D' => {
+                self.detached = true;
+                "OK".to_string()
+            }
+            b'k' => {
+                self.detached = true;
+                return None;
+            }
+            _ => String::new(),
+        };
+        Some(reply.into_bytes())
+    }
+
+    fn set_point(&mut self, insert: bool, arguments: &str) -> String {
+        let fields: Vec<&str> = arguments.split(',').collect();
+        let (kind, address, length) = match fields[..] {
+            [kind, address, length] => match (
+                kind.parse::<u8>(),
+                u16::from_str_radix(address, 16),
+                u16::from_str_radix(length.split(';').next().unwrap_or(""), 16),
+            ) {
+                (Ok(kind), Ok(address), Ok(length)) => (kind, address, length),
+                _ => return "E01".to_string(),
+            },
+            _ => return "E01".to_string(),
+        };
+        // Software and hardware breakpoints are the same thing here
+        if kind <= 1 {
+            self.breakpoints.retain(|&existing| existing != address);
+            if insert {
+                self.breakpoints.push(address);
+            }
+            return "OK".to_string();
+        }
+        let kind = match WatchKind::from_type(kind) {
+            Some(kind) => kind,
+            None => return String::new(),
+        };
+        let watchpoint = Watchpoint { kind, address, length };
+        self.watchpoints.retain(|existing| *existing != watchpoint);
+        if insert {
+            self.watchpoints.push(watchpoint);
+        }
+        "OK".to_string()
+    }
+
+    // Runs one instruction or until something stops the machine, and
+    // returns the stop reply
+    fn resume(&mut self, cpu: &mut CPU, single_step: bool, interrupted: &mut dyn FnMut() -> bool) -> String {
+        if !self.watchpoints.is_empty() {
+            cpu.bus.access_log = Some(RefCell::new(Vec::new()));
+        }
+        let mut cycles = 0;
+        let reply = loop {
+            let pc = cpu.pc;
+            let length = instruction_length(&cpu.bus, pc);
+            cycles += cpu.step();
+            if let Some((kind, access)) = self.check_watchpoints(cpu, pc, length) {
+                break format!("T05{}:{:04x};", kind.name(), access.address);
+            }
+            if single_step || self.breakpoints.contains(&cpu.pc) {
+                break "S05".to_string();
+            }
+            if cycles >= CYCLES_PER_FRAME {
+                cycles = 0;
+                if interrupted() {
+                    break "S02".to_string();
+                }
+            }
+        };
+        cpu.bus.access_log = None;
+        reply
+    }
+
+    // Reads of the instruction's own bytes are fetches, not data reads
+    fn check_watchpoints(&self, cpu: &CPU, pc: u16, length: u16) -> Option<(WatchKind, Access)> {
+        let log = cpu.bus.access_log.as_ref()?;
+        let accesses: Vec<Access> = log.borrow_mut().drain(..).collect();
+        accesses
+            .into_iter()
+            .filter(|access| access.kind == AccessKind::Write || access.address.wrapping_sub(pc) >= length)
+            .find_map(|access| {
+                self.watchpoints
+                    .iter()
+                    .find(|watchpoint| watchpoint.kind.matches(access.kind) && watchpoint.covers(access.address))
+                    .map(|watchpoint| (watchpoint.kind, access))
+            })
+    }
+}
+
+impl Default for GdbStub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn query(text: &str) -> String {
+    if text.starts_with("Supported") {
+        return format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE);
+    }
+    if let Some(range) = text.strip_prefix("Xfer:features:read:target.xml:") {
+        return match parse_range(range) {
+            Some((offset, length)) => {
+                let xml = TARGET_XML.as_bytes();
+                let start = (offset as usize).min(xml.len());
+                let end = (start + length).min(xml.len());
+                let more = if end < xml.len() { "m" } else { "l" };
+                format!("{}{}", more, String::from_utf8_lossy(&xml[start..end]))
+            }
+            None => "E01".to_string(),
+        };
+    }
+    match text {
+        "Attached" => "1".to_string(),
+        "C" => "QC1".to_string(),
+        "fThreadInfo" => "m1".to_string(),
+        "sThreadInfo" => "l".to_string(),
+        _ => String::new(),
+    }
+}
+
+fn read_registers(cpu: &CPU) -> Vec<u8> {
+    (0..REGISTER_COUNT).flat_map(|register| read_register(cpu, register).unwrap_or_default()).collect()
+}
+
+fn write_registers(cpu: &mut CPU, bytes: &[u8]) {
+    for (register, value) in bytes[..8].iter().enumerate() {
+        write_register(cpu, register, &[*value]);
+    }
+    write_register(cpu, 8, &bytes[8..10]);
+    write_register(cpu, 9, &bytes[10..12]);
+}
+
+fn read_register(cpu: &CPU, register: usize) -> Option<Vec<u8>> {
+    let registers = &cpu.registers;
+    let value = match register {
+        0 => registers.a,
+        1 => u8::from(registers.f),
+        2 => registers.b,
+        3 => registers.c,
+        4 => registers.d,
+        5 => registers.e,
+        6 => registers.h,
+        7 => registers.l,
+        8 => return Some(cpu.sp.to_le_bytes().to_vec()),
+        9 => return Some(cpu.pc.to_le_bytes().to_vec()),
+        _ => return None,
+    };
+    Some(vec![value])
+}
+
+fn write_register(cpu: &mut CPU, register: usize, value: &[u8]) -> bool {
+    let registers = &mut cpu.registers;
+    match (register, value) {
+        (0, &[value]) => registers.a = value,
+        (1, &[value]) => registers.f = value.into(),
+        (2, &[value]) => registers.b = value,
+        (3, &[value]) => registers.c = value,
+        (4, &[value]) => registers.d = value,
+        (5, &[value]) => registers.e = value,
+        (6, &[value]) => registers.h = value,
+        (7, &[value]) => registers.l = value,
+        (8, &[low, high]) => cpu.sp = u16::from_le_bytes([low, high]),
+        (9, &[low, high]) => cpu.pc = u16::from_le_bytes([low, high]),
+        _ => return false,
+    }
+    true
+}
+
+fn write_memory(cpu: &mut CPU, address: u16, data: &[u8]) {
+    for (offset, &byte) in data.iter().enumerate() {
+        cpu.bus.write_byte(address.wrapping_add(offset as u16), byte);
+    }
+}
+
+// "addr,length" in hex
+fn parse_range(text: &str) -> Option<(u16, usize)> {
+    let (address, length) = text.split_once(',')?;
+    Some((u16::from_str_radix(address, 16).ok()?, usize::from_str_radix(length, 16).ok()?))
+}
+
+fn parse_resume_address(text: &str) -> Option<u16> {
+    u16::from_str_radix(text, 16).ok()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok()).collect()
+}
+
+// Binary data escapes } # $ and * as } followed by the byte XOR 0x20
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len());
+    let mut escaped = false;
+    for &byte in data {
+        if escaped {
+            bytes.push(byte ^ 0x20);
+            escaped = false;
+        } else if byte == b'}' {
+            escaped = true;
+        } else {
+            bytes.push(byte);
+        }
+    }
+    bytes
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum: u8, &byte| sum.wrapping_add(byte))
+}
+
+// $data#checksum, with the characters that would break the framing escaped
+fn frame(data: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(data.len());
+    for &byte in data {
+        if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+            body.push(b'}');
+            body.push(byte ^ 0x20);
+        } else {
+            body.push(byte);
+        }
+    }
+    let mut framed = vec![b'$'];
+    framed.extend_from_slice(&body);
+    framed.extend_from_slice(format!("#{:02x}", checksum(&body)).as_bytes());
+    framed
+}
+
+struct Connection {
+    stream: TcpStream,
+    pending: Vec<u8>,
+}
+
+impl Connection {
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut buffer = [0; 1024];
+        let count = self.stream.read(&mut buffer)?;
+        self.pending.extend_from_slice(&buffer[..count]);
+        Ok(count > 0)
+    }
+
+    // The next packet with a good checksum, None once the client hangs
+    // up. Acks and stray interrupts between packets are dropped.
+    fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(start) = self.pending.iter().position(|&byte| byte == b'$') {
+                if let Some(hash) = self.pending[start..].iter().position(|&byte| byte == b'#') {
+                    let end = start + hash;
+                    if self.pending.len() >= end + 3 {
+                        let body = self.pending[start + 1..end].to_vec();
+                        let sent = std::str::from_utf8(&self.pending[end + 1..end + 3])
+                            .ok()
+                            .and_then(|text| u8::from_str_radix(text, 16).ok());
+                        self.pending.drain(..end + 3);
+                        if sent == Some(checksum(&body)) {
+                            self.stream.write_all(b"+")?;
+                            return Ok(Some(body));
+                        }
+                        self.stream.write_all(b"-")?;
+                        continue;
+                    }
+                }
+            } else {
+                self.pending.clear();
+            }
+            if !self.fill()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn send_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stream.write_all(&frame(data))
+    }
+
+    // Checks for a Ctrl-C from the client without waiting. A client that
+    // has hung up stops the machine too, otherwise it would run forever.
+    fn interrupted(&mut self) -> bool {
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let mut buffer = [0; 64];
+        let read = self.stream.read(&mut buffer);
+        let _ = self.stream.set_nonblocking(false);
+        match read {
+            // The client closed the connection
+            Ok(0) => true,
+            Ok(count) => {
+                self.pending.extend_from_slice(&buffer[..count]);
+                match self.pending.iter().position(|&byte| byte == INTERRUPT) {
+                    Some(position) => {
+                        self.pending.remove(position);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            Err(error) if error.kind() == ErrorKind::WouldBlock => false,
+            // Reset or otherwise broken
+            Err(_) => true,
+        }
+    }
+}
+
+// Waits for a debugger on the localhost port and serves it until it
+// detaches or disconnects
+pub fn serve(cpu: &mut CPU, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+    let (stream, _) = listener.accept()?;
+    serve_stream(cpu, stream)
+}
+
+pub fn serve_stream(cpu: &mut CPU, stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut connection = Connection { stream, pending: Vec::new() };
+    let mut stub = GdbStub::new();
+    while !stub.detached {
+        let packet = match connection.read_packet()? {
+            Some(packet) => packet,
+            None => break,
+        };
+        let reply = stub.handle(cpu, &packet, &mut || connection.interrupted());
+        if let Some(reply) = reply {
+            connection.send_packet(&reply)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod gdb_tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use std::thread;
+
+    // Counts up in WRAM at 0xC000 forever
+    fn machine() -> CPU {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x106].copy_from_slice(&[0x21, 0x00, 0xC0, 0x34, 0x18, 0xFD]);
+        CPU::with_cartridge(Cartridge::new(rom).unwrap())
+    }
+
+    fn send(stub: &mut GdbStub, cpu: &mut CPU, packet: &str) -> String {
+        String::from_utf8(stub.handle(cpu, packet.as_bytes(), &mut || false).unwrap()).unwrap()
+    }
+
+    // Sends a packet and returns everything up to the end of the reply
+    fn exchange(client: &mut TcpStream, packet: &str) -> String {
+        client.write_all(&frame(packet.as_bytes())).unwrap();
+        let mut received = Vec::new();
+        let mut buffer = [0; 256];
+        loop {
+            if let Some(hash) = received.iter().position(|&byte| byte == b'#') {
+                if received.len() >= hash + 3 {
+                    return String::from_utf8(received).unwrap();
+                }
+            }
+            let count = client.read(&mut buffer).unwrap();
+            received.extend_from_slice(&buffer[..count]);
+        }
+    }
+
+    #[test]
+    fn reads_and_writes_registers() {
+        let mut cpu = machine();
+        let mut stub = GdbStub::new();
+        cpu.registers.set_af(0x01B0);
+        cpu.registers.set_bc(0x0013);
+        cpu.sp = 0xFFFE;
+        assert_eq!(send(&mut stub, &mut cpu, "g"), "01b0001300d8014dfeff0001");
+        assert_eq!(send(&mut stub, &mut cpu, "p9"), "0001");
+        assert_eq!(send(&mut stub, &mut cpu, "P6=c0"), "OK");
+        assert_eq!(cpu.registers.h, 0xC0);
+        assert_eq!(send(&mut stub, &mut cpu, "P9=5001"), "OK");
+        assert_eq!(cpu.pc, 0x0150);
+        assert_eq!(send(&mut stub, &mut cpu, "G112233445566778800d00002"), "OK");
+        assert_eq!(cpu.registers.get_de(), 0x5566);
+        assert_eq!(cpu.sp, 0xD000);
+        assert_eq!(cpu.pc, 0x0200);
+        assert_eq!(send(&mut stub, &mut cpu, "pa"), "E01");
+    }
+    #[test]
+    fn reads_and_writes_memory() {
+        let mut cpu = machine();
+        let mut stub = GdbStub::new();
+        assert_eq!(send(&mut stub, &mut cpu, "m100,3"), "2100c0");
+        assert_eq!(send(&mut stub, &mut cpu, "Mc000,2:abcd"), "OK");
+        assert_eq!(&cpu.bus.wram[..2], &[0xAB, 0xCD]);
+        let reply = stub.handle(&mut cpu, b"Xc002,2:}]\x01", &mut || false).unwrap();
+        assert_eq!(reply, b"OK");
+        assert_eq!(&cpu.bus.wram[2..4], &[0x7D, 0x01]);
+        assert_eq!(send(&mut stub, &mut cpu, "Mc000,2:ab"), "E01");
+        assert_eq!(send(&mut stub, &mut cpu, "m0,2000").len(), 0x4000);
+        assert_eq!(send(&mut stub, &mut cpu, "m0,2001"), "E01");
+        assert_eq!(send(&mut stub, &mut cpu, "m0,ffffffffffff"), "E01");
+    }
+    #[test]
+    fn steps_and_stops_at_breakpoints() {
+        let mut cpu = machine();
+        let mut stub = GdbStub::new();
+        assert_eq!(send(&mut stub, &mut cpu, "s"), "S05");
+        assert_eq!(cpu.pc, 0x0103);
+        assert_eq!(send(&mut stub, &mut cpu, "Z0,104,1"), "OK");
+        assert_eq!(send(&mut stub, &mut cpu, "c"), "S05");
+        assert_eq!(cpu.pc, 0x0104);
+        assert_eq!(send(&mut stub, &mut cpu, "c"), "S05");
+        assert_eq!(cpu.pc, 0x0104);
+        assert_eq!(cpu.bus.wram[0], 2);
+        assert_eq!(send(&mut stub, &mut cpu, "z0,104,1"), "OK");
+        assert_eq!(send(&mut stub, &mut cpu, "Z2,c000,1"), "OK");
+        assert_eq!(send(&mut stub, &mut cpu, "c"), "T05watch:c000;");
+        assert_eq!(cpu.bus.wram[0], 3);
+        let reply = stub.handle(&mut cpu, b"z2,c000,1", &mut || false).unwrap();
+        assert_eq!(reply, b"OK");
+        assert_eq!(stub.handle(&mut cpu, b"c", &mut || true).unwrap(), b"S02");
+    }
+    #[test]
+    fn describes_the_target() {
+        let mut cpu = machine();
+        let mut stub = GdbStub::new();
+        assert!(send(&mut stub, &mut cpu, "qSupported:swbreak+").contains("qXfer:features:read+"));
+        let start = send(&mut stub, &mut cpu, "qXfer:features:read:target.xml:0,a");
+        assert_eq!(start, "m<?xml vers");
+        let rest = send(&mut stub, &mut cpu, "qXfer:features:read:target.xml:a,1000");
+        assert!(rest.starts_with('l') && rest.contains("name=\"pc\""), "{}", rest);
+        assert_eq!(send(&mut stub, &mut cpu, "?"), "S05");
+        assert_eq!(send(&mut stub, &mut cpu, "qUnknown"), "");
+    }
+    #[test]
+    fn frames_packets() {
+        assert_eq!(frame(b"OK"), b"$OK#9a");
+        assert_eq!(frame(b"a#b"), b"$a}\x03b#43");
+        assert_eq!(unescape(b"a}\x03b"), b"a#b");
+    }
+    #[test]
+    fn serves_over_localhost() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut cpu = machine();
+            serve_stream(&mut cpu, stream).unwrap();
+            cpu.pc
+        });
+        let mut client = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        assert_eq!(exchange(&mut client, "m100,1"), "+$21#63");
+        // A bad checksum is nacked and the packet resent
+        client.write_all(b"$s#00").unwrap();
+        let mut nack = [0; 1];
+        client.read_exact(&mut nack).unwrap();
+        assert_eq!(&nack, b"-");
+        assert_eq!(exchange(&mut client, "s"), "+$S05#b8");
+        assert_eq!(exchange(&mut client, "D"), "+$OK#9a");
+        assert_eq!(handle.join().unwrap(), 0x0103);
+    }
+    #[test]
+    fn stops_when_the_client_hangs_up() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut cpu = machine();
+            // The stop reply has nowhere to go, so this may fail either way
+            let _ = serve_stream(&mut cpu, stream);
+            cpu.bus.wram[0]
+        });
+        let mut client = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        client.write_all(&frame(b"c")).unwrap();
+        let mut ack = [0; 1];
+        client.read_exact(&mut ack).unwrap();
+        assert_eq!(&ack, b"+");
+        drop(client);
+        // The counter loop never ends by itself, returning at all means
+        // the stub noticed
+        assert!(handle.join().unwrap() > 0);
+    }
+}
@@ -0,0 +1,116 @@
+// CGB VRAM DMA through HDMA1-HDMA5. The memory bus does the copying,
+// this keeps the transfer state and the register interface.
+pub struct Hdma {
+    pub source: u16,
+    // Offset into VRAM
+    pub destination: u16,
+    // 16 byte blocks left to copy
+    pub blocks: u8,
+    // An HBlank transfer copies one block at the start of each HBlank
+    pub hblank_active: bool,
+}
+
+impl Default for Hdma {
+    fn default() -> Self {
+        Hdma::new()
+    }
+}
+
+impl Hdma {
+    pub fn new() -> Hdma {
+        Hdma {
+            source: 0,
+            destination: 0,
+            blocks: 0,
+            hblank_active: false,
+        }
+    }
+
+    // HDMA5 reads back the remaining length minus one, with bit 7 set
+    // once the transfer has finished or was cancelled
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0xFF55 => {
+                let remaining = self.blocks.wrapping_sub(1) & 0x7F;
+                if self.hblank_active { remaining } else { 0x80 | remaining }
+            }
+            _ => 0xFF,
+        }
+    }
+
+    // Returns the number of blocks a general purpose transfer wants
+    // copied right away
+    pub fn write(&mut self, address: u16, value: u8) -> u8 {
+        match address {
+            0xFF51 => self.source = (self.source & 0x00FF) | (value as u16) << 8,
+            0xFF52 => self.source = (self.source & 0xFF00) | (value & 0xF0) as u16,
+            0xFF53 => self.destination = (self.destination & 0x00FF) | ((value & 0x1F) as u16) << 8,
+            0xFF54 => self.destination = (self.destination & 0xFF00) | (value & 0xF0) as u16,
+            0xFF55 => {
+                let blocks = (value & 0x7F) + 1;
+                if value & 0x80 != 0 {
+                    self.blocks = blocks;
+                    self.hblank_active = true;
+                } else if self.hblank_active {
+                    // Clearing bit 7 during an HBlank transfer cancels it
+                    self.hblank_active = false;
+                } else {
+                    self.blocks = blocks;
+                    return blocks;
+                }
+            }
+            _ => {}
+        }
+        0
+    }
+
+    // Moves the addresses on after a block has been copied
+    pub fn advance(&mut self) {
+        self.source = self.source.wrapping_add(0x10);
+        self.destination = (self.destination + 0x10) & 0x1FF0;
+        self.blocks -= 1;
+        if self.blocks == 0 {
+            self.hblank_active = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod hdma_tests {
+    use super::*;
+    #[test]
+    fn registers_mask_addresses() {
+        let mut hdma = Hdma::new();
+        hdma.write(0xFF51, 0xC1);
+        hdma.write(0xFF52, 0x2F);
+        hdma.write(0xFF53, 0xFF);
+        hdma.write(0xFF54, 0xFF);
+        assert_eq!(hdma.source, 0xC120);
+        assert_eq!(hdma.destination, 0x1FF0);
+        assert_eq!(hdma.read(0xFF51), 0xFF);
+    }
+    #[test]
+    fn general_purpose_returns_length() {
+        let mut hdma = Hdma::new();
+        assert_eq!(hdma.write(0xFF55, 0x03), 4);
+        assert!(!hdma.hblank_active);
+    }
+    #[test]
+    fn hblank_transfer_can_be_cancelled() {
+        let mut hdma = Hdma::new();
+        assert_eq!(hdma.write(0xFF55, 0x82), 0);
+        assert_eq!(hdma.read(0xFF55), 0x02);
+        hdma.advance();
+        assert_eq!(hdma.read(0xFF55), 0x01);
+        hdma.write(0xFF55, 0x00);
+        assert_eq!(hdma.read(0xFF55), 0x81);
+    }
+    #[test]
+    fn finished_transfer_reads_ff() {
+        let mut hdma = Hdma::new();
+        hdma.write(0xFF55, 0x80);
+        hdma.advance();
+        assert!(!hdma.hblank_active);
+        assert_eq!(hdma.read(0xFF55), 0xFF);
+    }
+}
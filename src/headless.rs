@@ -0,0 +1,71 @@
+use crate::cpu::CPU;
+
+// Audio captured from a headless run, as interleaved left/right samples
+pub struct Recording {
+    pub samples: Vec<i16>,
+    pub sample_rate: u32,
+    pub frames: u32,
+}
+
+// Runs the machine without a frontend for at most `max_frames` frames,
+// stopping early once `until` returns true. `until` is checked after
+// every instruction.
+pub fn run_until<F: FnMut(&CPU) -> bool>(cpu: &mut CPU, max_frames: u32, mut until: F) -> Recording {
+    let mut recording = Recording {
+        samples: Vec::new(),
+        sample_rate: cpu.bus.apu.sample_rate,
+        frames: 0,
+    };
+    while recording.frames < max_frames {
+        let stopped = cpu.run_frame_until(&mut until);
+        recording.samples.extend(cpu.bus.apu.take_samples());
+        recording.frames += 1;
+        if stopped {
+            break;
+        }
+    }
+    recording
+}
+
+pub fn run_frames(cpu: &mut CPU, frames: u32) -> Recording {
+    run_until(cpu, frames, |_| false)
+}
+
+#[cfg(test)]
+mod headless_tests {
+    use super::*;
+    use crate::apu::CPU_CLOCK_HZ;
+    use crate::cartridge::Cartridge;
+    use crate::cpu::CYCLES_PER_FRAME;
+
+    // JR -2 at the entry point, spins forever
+    fn spinning_cartridge() -> Cartridge {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100] = 0x18;
+        rom[0x101] = 0xFE;
+        Cartridge::new(rom).unwrap()
+    }
+
+    #[test]
+    fn records_a_second_of_audio() {
+        let mut cpu = CPU::with_cartridge(spinning_cartridge());
+        let frames = CPU_CLOCK_HZ / CYCLES_PER_FRAME;
+        let recording = run_frames(&mut cpu, frames);
+        assert_eq!(recording.frames, frames);
+        // The first frame is cut short since the PPU starts at line 0
+        let samples_per_frame =
+            (recording.sample_rate as u64 * CYCLES_PER_FRAME as u64 / CPU_CLOCK_HZ as u64) as usize * 2;
+        assert!(recording.samples.len() <= samples_per_frame * frames as usize + 2);
+        assert!(recording.samples.len() >= samples_per_frame * (frames - 1) as usize);
+    }
+    #[test]
+    fn stops_on_condition() {
+        let mut cpu = CPU::with_cartridge(spinning_cartridge());
+        let mut steps = 0;
+        let recording = run_until(&mut cpu, 100, |_| {
+            steps += 1;
+            steps == 10
+        });
+        assert_eq!(recording.frames, 1);
+    }
+}
@@ -0,0 +1,135 @@
+use std::io::{self, Write};
+
+use crate::memory_bus::{Access, AccessKind};
+use crate::png::{self, ColorType};
+
+// The image has a pixel per address, a row per high byte
+const IMAGE_SIZE: u32 = 256;
+
+// The instruction behind a write. Pushes made while dispatching an
+// interrupt are put down to the instruction it came in before.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Writer {
+    pub pc: u16,
+    pub bank: u16,
+    pub value: u8,
+}
+
+// Read and write counts for every address the CPU can see, and the
+// instruction that last wrote each one. Banked memory is counted by
+// address, whichever bank was mapped.
+pub struct Heatmap {
+    pub reads: Vec<u32>,
+    pub writes: Vec<u32>,
+    last_writers: Vec<Option<Writer>>,
+}
+
+impl Heatmap {
+    pub fn new() -> Heatmap {
+        Heatmap {
+            reads: vec![0; 0x10000],
+            writes: vec![0; 0x10000],
+            last_writers: vec![None; 0x10000],
+        }
+    }
+
+    // Adds the accesses made by one step, which started at `pc` with
+    // `bank` mapped there. Instruction fetches count as reads.
+    pub fn record(&mut self, pc: u16, bank: u16, accesses: &[Access]) {
+        for access in accesses {
+            let address = access.address as usize;
+            match access.kind {
+                AccessKind::Read => self.reads[address] = self.reads[address].saturating_add(1),
+                AccessKind::Write => {
+                    self.writes[address] = self.writes[address].saturating_add(1);
+                    self.last_writers[address] = Some(Writer { pc, bank, value: access.value });
+                }
+            }
+        }
+    }
+
+    pub fn last_writer(&self, address: u16) -> Option<Writer> {
+        self.last_writers[address as usize]
+    }
+
+    pub fn clear(&mut self) {
+        self.reads.iter_mut().for_each(|count| *count = 0);
+        self.writes.iter_mut().for_each(|count| *count = 0);
+        self.last_writers.iter_mut().for_each(|writer| *writer = None);
+    }
+
+    // Reads in green and writes in red, brighter the more often on a log
+    // scale so a few hot loops don't wash out everything else
+    pub fn image(&self) -> Vec<u8> {
+        let max_reads = self.reads.iter().copied().max().unwrap_or(0);
+        let max_writes = self.writes.iter().copied().max().unwrap_or(0);
+        let mut pixels = Vec::with_capacity(0x10000 * 3);
+        for address in 0..0x10000 {
+            pixels.push(brightness(self.writes[address], max_writes));
+            pixels.push(brightness(self.reads[address], max_reads));
+            pixels.push(0);
+        }
+        pixels
+    }
+
+    pub fn write_png<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        png::write_png(writer, IMAGE_SIZE, IMAGE_SIZE, ColorType::Rgb, &self.image())
+    }
+}
+
+impl Default for Heatmap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Anything accessed at all is at least dimly lit
+fn brightness(count: u32, max: u32) -> u8 {
+    if count == 0 {
+        return 0;
+    }
+    let scale = (count as f64).ln_1p() / (max as f64).ln_1p();
+    (64.0 + scale * 191.0).round() as u8
+}
+
+#[cfg(test)]
+mod heatmap_tests {
+    use super::*;
+
+    fn access(kind: AccessKind, address: u16, value: u8) -> Access {
+        Access { kind, address, value }
+    }
+
+    #[test]
+    fn counts_accesses_and_remembers_writers() {
+        let mut heatmap = Heatmap::new();
+        heatmap.record(0x0150, 0, &[access(AccessKind::Read, 0xC123, 1), access(AccessKind::Write, 0xC123, 2)]);
+        heatmap.record(0x4010, 3, &[access(AccessKind::Write, 0xC123, 7)]);
+        heatmap.record(0x0160, 0, &[access(AccessKind::Read, 0xC123, 7)]);
+        assert_eq!(heatmap.reads[0xC123], 2);
+        assert_eq!(heatmap.writes[0xC123], 2);
+        assert_eq!(heatmap.last_writer(0xC123), Some(Writer { pc: 0x4010, bank: 3, value: 7 }));
+        assert_eq!(heatmap.last_writer(0xC124), None);
+        heatmap.clear();
+        assert_eq!(heatmap.writes[0xC123], 0);
+        assert_eq!(heatmap.last_writer(0xC123), None);
+    }
+    #[test]
+    fn draws_reads_and_writes() {
+        let mut heatmap = Heatmap::new();
+        for _ in 0..100 {
+            heatmap.record(0x0150, 0, &[access(AccessKind::Read, 0x0150, 0)]);
+        }
+        heatmap.record(0x0150, 0, &[access(AccessKind::Read, 0x0151, 0), access(AccessKind::Write, 0xFF80, 1)]);
+        let image = heatmap.image();
+        assert_eq!(image.len(), 256 * 256 * 3);
+        assert_eq!(&image[0x0150 * 3..0x0150 * 3 + 3], &[0, 255, 0]);
+        let dim = image[0x0151 * 3 + 1];
+        assert!((64..255).contains(&dim), "{}", dim);
+        assert_eq!(&image[0xFF80 * 3..0xFF80 * 3 + 3], &[255, 0, 0]);
+        assert_eq!(&image[0xC000 * 3..0xC000 * 3 + 3], &[0, 0, 0]);
+        let mut png = Vec::new();
+        heatmap.write_png(&mut png).unwrap();
+        assert!(png.starts_with(b"\x89PNG"));
+    }
+}
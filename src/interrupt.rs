@@ -0,0 +1,11 @@
+// Bits of IF (0xFF0F) and IE (0xFFFF), in priority order
+pub const VBLANK: u8 = 0x01;
+pub const STAT: u8 = 0x02;
+pub const TIMER: u8 = 0x04;
+pub const SERIAL: u8 = 0x08;
+pub const JOYPAD: u8 = 0x10;
+
+// Address the CPU jumps to when servicing the given interrupt bit
+pub fn vector(interrupt: u8) -> u16 {
+    0x40 + interrupt.trailing_zeros() as u16 * 8
+}
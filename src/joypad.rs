@@ -0,0 +1,85 @@
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+// P1/JOYP at 0xFF00. Bits 4 and 5 select the direction keys and the
+// action buttons, the low nibble reads back the selected keys active low.
+pub struct Joypad {
+    pub select: u8,
+    pub directions: u8,
+    pub actions: u8,
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Joypad::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            select: 0x30,
+            directions: 0x0F,
+            actions: 0x0F,
+        }
+    }
+
+    fn mask(button: Button) -> (bool, u8) {
+        match button {
+            Button::Right => (true, 0b0001),
+            Button::Left => (true, 0b0010),
+            Button::Up => (true, 0b0100),
+            Button::Down => (true, 0b1000),
+            Button::A => (false, 0b0001),
+            Button::B => (false, 0b0010),
+            Button::Select => (false, 0b0100),
+            Button::Start => (false, 0b1000),
+        }
+    }
+
+    // Returns true when the press should raise the joypad interrupt
+    pub fn press(&mut self, button: Button) -> bool {
+        let before = self.read();
+        let (direction, mask) = Joypad::mask(button);
+        if direction {
+            self.directions &= !mask;
+        } else {
+            self.actions &= !mask;
+        }
+        // The interrupt fires on a high to low transition of an input line
+        before & !self.read() & 0x0F != 0
+    }
+
+    pub fn release(&mut self, button: Button) {
+        let (direction, mask) = Joypad::mask(button);
+        if direction {
+            self.directions |= mask;
+        } else {
+            self.actions |= mask;
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        let mut lines = 0x0F;
+        if self.select & 0x10 == 0 {
+            lines &= self.directions;
+        }
+        if self.select & 0x20 == 0 {
+            lines &= self.actions;
+        }
+        0xC0 | self.select | lines
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.select = value & 0x30;
+    }
+}
@@ -0,0 +1,31 @@
+pub mod apu;
+pub mod boot_rom;
+pub mod call_stack;
+pub mod cartridge;
+pub mod compat_palette;
+pub mod coverage;
+pub mod cpu;
+pub mod debugger;
+pub mod disassembler;
+pub mod gdb;
+pub mod gbs;
+pub mod hdma;
+pub mod heatmap;
+pub mod headless;
+pub mod interrupt;
+pub mod joypad;
+pub mod link;
+pub mod memory_bus;
+pub mod model;
+pub mod png;
+pub mod ppu;
+pub mod printer;
+pub mod profiler;
+pub mod rewind;
+pub mod save_state;
+pub mod serial;
+pub mod sgb;
+pub mod symbols;
+pub mod timer;
+pub mod trace;
+pub mod wav;
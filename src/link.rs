@@ -0,0 +1,144 @@
+use std::sync::{Arc, Mutex};
+
+use crate::cpu::CPU;
+use crate::interrupt;
+use crate::serial::LinkTransport;
+
+// State of the cable between two instances driven by a LinkedPair
+#[derive(Default)]
+struct Wire {
+    // Each side's SB and whether it is waiting on an external clock, as
+    // of its last step
+    published: [(u8, bool); 2],
+    // Bytes clocked into a side, applied before its next step
+    delivered: [Option<u8>; 2],
+}
+
+struct PairedTransport {
+    wire: Arc<Mutex<Wire>>,
+    side: usize,
+}
+
+impl LinkTransport for PairedTransport {
+    fn exchange(&mut self, outgoing: u8) -> u8 {
+        let mut wire = self.wire.lock().unwrap();
+        let peer = 1 - self.side;
+        let (data, ready) = wire.published[peer];
+        if !ready {
+            return 0xFF;
+        }
+        wire.published[peer].1 = false;
+        wire.delivered[peer] = Some(outgoing);
+        data
+    }
+
+    // The pair hands incoming bytes over directly, there is nothing to poll
+    fn poll(&mut self, _ready: bool, _outgoing: u8) -> Option<u8> {
+        None
+    }
+}
+
+// Two instances connected by a link cable and run in lockstep on one
+// thread. The instance that is behind always runs next, so transfers
+// land on the same cycle on every run.
+pub struct LinkedPair {
+    pub left: CPU,
+    pub right: CPU,
+    pub left_cycles: u64,
+    pub right_cycles: u64,
+    wire: Arc<Mutex<Wire>>,
+}
+
+impl LinkedPair {
+    pub fn new(mut left: CPU, mut right: CPU) -> LinkedPair {
+        let wire = Arc::new(Mutex::new(Wire::default()));
+        left.bus.serial.connect(Box::new(PairedTransport {
+            wire: Arc::clone(&wire),
+            side: 0,
+        }));
+        right.bus.serial.connect(Box::new(PairedTransport {
+            wire: Arc::clone(&wire),
+            side: 1,
+        }));
+        let mut pair = LinkedPair {
+            left,
+            right,
+            left_cycles: 0,
+            right_cycles: 0,
+            wire,
+        };
+        pair.sync();
+        pair
+    }
+
+    // Runs one instruction on whichever instance is behind
+    pub fn step(&mut self) {
+        if self.left_cycles <= self.right_cycles {
+            self.left_cycles += self.left.step() as u64;
+        } else {
+            self.right_cycles += self.right.step() as u64;
+        }
+        self.sync();
+    }
+
+    pub fn run_cycles(&mut self, cycles: u64) {
+        let target = self.left_cycles.min(self.right_cycles) + cycles;
+        while self.left_cycles < target || self.right_cycles < target {
+            self.step();
+        }
+    }
+
+    fn sync(&mut self) {
+        let mut wire = self.wire.lock().unwrap();
+        for (side, cpu) in [&mut self.left, &mut self.right].iter_mut().enumerate() {
+            if let Some(incoming) = wire.delivered[side].take() {
+                if cpu.bus.serial.receive(incoming) {
+                    cpu.bus.interrupt_flag |= interrupt::SERIAL;
+                }
+            }
+            wire.published[side] = (cpu.bus.serial.data, cpu.bus.serial.waiting_for_clock());
+        }
+    }
+}
+
+#[cfg(test)]
+mod linked_pair_tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::cpu::CYCLES_PER_FRAME;
+
+    // Loads SB and SC, waits for the transfer to finish and stores the
+    // received byte at 0xC000
+    fn transfer_program(data: u8, control: u8) -> CPU {
+        let program = [
+            0x3E, data, 0xE0, 0x01, // LD A,data; LDH (SB),A
+            0x3E, control, 0xE0, 0x02, // LD A,control; LDH (SC),A
+            0xF0, 0x02, 0xCB, 0x7F, 0x20, 0xFA, // wait: LDH A,(SC); BIT 7,A; JR NZ,wait
+            0xF0, 0x01, 0xEA, 0x00, 0xC0, // LDH A,(SB); LD (0xC000),A
+            0x18, 0xFE, // JR -2
+        ];
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+        CPU::with_cartridge(Cartridge::new(rom).unwrap())
+    }
+
+    #[test]
+    fn bytes_swap_between_instances() {
+        let master = transfer_program(0x42, 0x81);
+        let slave = transfer_program(0x99, 0x80);
+        let mut pair = LinkedPair::new(master, slave);
+        pair.run_cycles(CYCLES_PER_FRAME as u64);
+        assert_eq!(pair.left.bus.read_byte(0xC000), 0x99);
+        assert_eq!(pair.right.bus.read_byte(0xC000), 0x42);
+        assert_eq!(pair.right.bus.interrupt_flag & interrupt::SERIAL, interrupt::SERIAL);
+    }
+    #[test]
+    fn runs_are_deterministic() {
+        let run = || {
+            let mut pair = LinkedPair::new(transfer_program(0x42, 0x81), transfer_program(0x99, 0x80));
+            pair.run_cycles(CYCLES_PER_FRAME as u64);
+            (pair.left_cycles, pair.right_cycles, pair.left.pc, pair.right.pc)
+        };
+        assert_eq!(run(), run());
+    }
+}
@@ -1,4 +1,490 @@
-pub mod cpu;
+use std::cell::RefCell;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter, Write};
+use std::process;
+use std::sync::{Arc, Mutex};
+
+use emulator::boot_rom::BootRom;
+use emulator::cartridge::Cartridge;
+use emulator::compat_palette::{ButtonCombo, CompatPalette};
+use emulator::coverage::Coverage;
+use emulator::cpu::CPU;
+use emulator::debugger::{parse_number, parse_u16, Debugger};
+use emulator::disassembler;
+use emulator::gbs::GbsPlayer;
+use emulator::gdb;
+use emulator::headless;
+use emulator::heatmap::Heatmap;
+use emulator::model::Model;
+use emulator::png::{self, ColorType};
+use emulator::ppu::{self, SCREEN_HEIGHT, SCREEN_WIDTH};
+use emulator::printer::{PrintedImage, Printer};
+use emulator::profiler::Profiler;
+use emulator::serial::tcp::TcpTransport;
+use emulator::serial::{LinkTransport, LoopbackTransport};
+use emulator::sgb::{SGB_HEIGHT, SGB_WIDTH};
+use emulator::symbols::Symbols;
+use emulator::trace::{DoctorTrace, DOCTOR_LY};
+use emulator::wav;
+
+const USAGE: &str = "usage:
+  emulator record <rom> <output.wav> [--frames N] [--until-pc ADDR] [--sample-rate HZ]
+                  [--link loopback|listen:PORT|connect:PORT|printer:DIR]
+                  [--serial-log FILE] [--compat-palette auto|up|up+a|...|right+b]
+                  [--model dmg0|dmg|mgb|sgb|sgb2|cgb|agb] [--boot-rom FILE]
+                  [--screenshot FILE.png] [--load-state FILE] [--save-state FILE]
+                  [--export-bess FILE] [--trace FILE] [--coverage FILE[.json]]
+                  [--heatmap FILE.png]
+  emulator gbs <file.gbs> <output.wav> [--song N] [--seconds S] [--sample-rate HZ]
+  emulator disassemble <rom> [--bank N] [--sym FILE]
+  emulator debug <rom> [--model dmg0|dmg|mgb|sgb|sgb2|cgb|agb] [--boot-rom FILE] [--load-state FILE]
+                 [--sym FILE] [--gdb PORT]
+  emulator profile <rom> [--frames N] [--model dmg0|dmg|mgb|sgb|sgb2|cgb|agb] [--boot-rom FILE]
+                   [--load-state FILE] [--sym FILE] [--report FILE] [--folded FILE]";
+
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("record") => record(&args[1..]),
+        Some("gbs") => gbs(&args[1..]),
+        Some("disassemble") => disassemble(&args[1..]),
+        Some("debug") => debug(&args[1..]),
+        Some("profile") => profile(&args[1..]),
+        _ => Err(USAGE.to_string()),
+    };
+    if let Err(message) = result {
+        eprintln!("{}", message);
+        process::exit(1);
+    }
+}
+
+// Anything outside the usual audio rates is a mistake, and the APU
+// emits at most one sample every four clocks
+fn parse_sample_rate(text: &str) -> Result<u32, String> {
+    match parse_number(text)? {
+        rate @ 8000..=192000 => Ok(rate),
+        _ => Err(format!("sample rate {} is out of range, 8000 to 192000", text)),
+    }
+}
+
+fn load_cartridge(path: &str) -> Result<Cartridge, String> {
+    let rom = fs::read(path).map_err(|error| format!("{}: {}", path, error))?;
+    Cartridge::new(rom).map_err(|error| format!("{}: {}", path, error))
+}
+
+// Printed pages go into the directory named by a printer:DIR link
+struct PrinterOutput {
+    directory: String,
+    printed: Arc<Mutex<Vec<PrintedImage>>>,
+}
+
+fn load_boot_rom(path: &str) -> Result<BootRom, String> {
+    let data = fs::read(path).map_err(|error| format!("{}: {}", path, error))?;
+    BootRom::new(data).map_err(|error| format!("{}: {}", path, error))
+}
+
+// The machine for a ROM, optionally starting from a save state
+fn machine(path: &str, model: Option<Model>, boot_rom: Option<BootRom>, state: Option<&String>) -> Result<CPU, String> {
+    let cartridge = load_cartridge(path)?;
+    let model = model.unwrap_or_else(|| Model::for_cartridge(&cartridge.header));
+    let mut cpu = match boot_rom {
+        Some(boot_rom) => CPU::with_boot_rom(cartridge, model, boot_rom),
+        None => CPU::with_model(cartridge, model),
+    };
+    if let Some(path) = state {
+        let state = fs::read(path).map_err(|error| format!("{}: {}", path, error))?;
+        cpu.load_state(&state).map_err(|error| format!("{}: {}", path, error))?;
+    }
+    Ok(cpu)
+}
+
+fn load_symbols(path: &str) -> Result<Symbols, String> {
+    let text = fs::read_to_string(path).map_err(|error| format!("{}: {}", path, error))?;
+    Symbols::parse(&text).map_err(|error| format!("{}: {}", path, error))
+}
+
+fn parse_model(name: &str) -> Result<Model, String> {
+    Model::from_name(name).ok_or_else(|| {
+        let names: Vec<&str> = Model::ALL.iter().map(|model| model.name()).collect();
+        format!("unknown model {}, expected one of {}", name, names.join(", "))
+    })
+}
+
+fn parse_link(spec: &str) -> Result<(Box<dyn LinkTransport>, Option<PrinterOutput>), String> {
+    let transport: Box<dyn LinkTransport> = if spec == "loopback" {
+        Box::new(LoopbackTransport)
+    } else if let Some(directory) = spec.strip_prefix("printer:") {
+        let printer = Printer::new();
+        let output = PrinterOutput {
+            directory: directory.to_string(),
+            printed: printer.printed(),
+        };
+        return Ok((Box::new(printer), Some(output)));
+    } else if let Some(port) = spec.strip_prefix("listen:") {
+        let port = parse_u16(port)?;
+        Box::new(TcpTransport::listen(port).map_err(|error| format!("{}: {}", spec, error))?)
+    } else if let Some(port) = spec.strip_prefix("connect:") {
+        let port = parse_u16(port)?;
+        Box::new(TcpTransport::connect(port).map_err(|error| format!("{}: {}", spec, error))?)
+    } else {
+        return Err(format!("unknown link {}", spec));
+    };
+    Ok((transport, None))
+}
+
+fn write_printed(output: &PrinterOutput) -> Result<usize, String> {
+    let images = output.printed.lock().unwrap();
+    fs::create_dir_all(&output.directory).map_err(|error| format!("{}: {}", output.directory, error))?;
+    for (index, image) in images.iter().enumerate() {
+        let path = format!("{}/print_{:04}.png", output.directory, index + 1);
+        let file = File::create(&path).map_err(|error| format!("{}: {}", path, error))?;
+        image
+            .write_png(&mut BufWriter::new(file))
+            .map_err(|error| format!("{}: {}", path, error))?;
+    }
+    Ok(images.len())
+}
+
+// Colorizes a DMG cartridge the way CGB hardware would
+fn parse_compat_palette(name: &str, rom: &[u8]) -> Result<CompatPalette, String> {
+    if name == "auto" {
+        return Ok(CompatPalette::for_rom(rom));
+    }
+    let combo = ButtonCombo::from_name(name).ok_or_else(|| {
+        let names: Vec<&str> = ButtonCombo::ALL.iter().map(|combo| combo.name()).collect();
+        format!("unknown palette {}, expected auto or one of {}", name, names.join(", "))
+    })?;
+    Ok(CompatPalette::for_combo(combo))
+}
+
+// The last frame, with the border on SGB models
+fn write_screenshot(path: &str, cpu: &CPU) -> Result<(), String> {
+    let (width, height, colors) = match &cpu.bus.sgb {
+        Some(sgb) => (SGB_WIDTH, SGB_HEIGHT, sgb.render(&cpu.bus.ppu.shades)),
+        None => (SCREEN_WIDTH, SCREEN_HEIGHT, cpu.bus.ppu.framebuffer.clone()),
+    };
+    let pixels: Vec<u8> = colors.iter().flat_map(|&color| ppu::rgb888(color)).collect();
+    let file = File::create(path).map_err(|error| format!("{}: {}", path, error))?;
+    png::write_png(&mut BufWriter::new(file), width as u32, height as u32, ColorType::Rgb, &pixels)
+        .map_err(|error| format!("{}: {}", path, error))
+}
+
+fn write_recording(path: &str, sample_rate: u32, samples: &[i16]) -> Result<(), String> {
+    let file = File::create(path).map_err(|error| format!("{}: {}", path, error))?;
+    wav::write_wav(&mut BufWriter::new(file), sample_rate, samples)
+        .map_err(|error| format!("{}: {}", path, error))
+}
+
+fn record(args: &[String]) -> Result<(), String> {
+    if args.len() < 2 {
+        return Err(USAGE.to_string());
+    }
+    let mut frames = 600;
+    let mut until_pc = None;
+    let mut sample_rate = None;
+    let mut link = None;
+    let mut serial_log = None;
+    let mut compat_palette = None;
+    let mut model = None;
+    let mut boot_rom = None;
+    let mut screenshot = None;
+    let mut load_state = None;
+    let mut save_state = None;
+    let mut export_bess = None;
+    let mut trace = None;
+    let mut coverage = None;
+    let mut heatmap = None;
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or(format!("{} needs a value", option))?;
+        match option.as_str() {
+            "--frames" => frames = parse_number(value)?,
+            "--until-pc" => until_pc = Some(parse_u16(value)?),
+            "--sample-rate" => sample_rate = Some(parse_sample_rate(value)?),
+            "--link" => link = Some(parse_link(value)?),
+            "--serial-log" => serial_log = Some(value),
+            "--compat-palette" => compat_palette = Some(value),
+            "--model" => model = Some(parse_model(value)?),
+            "--boot-rom" => boot_rom = Some(load_boot_rom(value)?),
+            "--screenshot" => screenshot = Some(value),
+            "--load-state" => load_state = Some(value),
+            "--save-state" => save_state = Some(value),
+            "--export-bess" => export_bess = Some(value),
+            "--trace" => trace = Some(value),
+            "--coverage" => coverage = Some(value),
+            "--heatmap" => heatmap = Some(value),
+            _ => return Err(format!("unknown option {}\n{}", option, USAGE)),
+        }
+    }
+
+    // The state is loaded first, it would replace the palette
+    let mut cpu = machine(&args[0], model, boot_rom, load_state)?;
+    if let Some(sample_rate) = sample_rate {
+        cpu.bus.apu.sample_rate = sample_rate;
+    }
+    if let Some(name) = compat_palette {
+        // Only a CGB colorizes, and only cartridges without CGB support
+        if !cpu.bus.model.is_cgb() || cpu.bus.cgb_mode {
+            return Err(format!("{}: --compat-palette only applies to DMG cartridges on a CGB", args[0]));
+        }
+        let palette = parse_compat_palette(name, cpu.bus.cartridge.rom())?;
+        cpu.bus.ppu.load_compat_palette(&palette);
+    }
+    let mut printer = None;
+    if let Some((link, output)) = link {
+        cpu.bus.serial.connect(link);
+        printer = output;
+    }
+    // A Gameboy Doctor log, which needs LY stubbed to match the
+    // reference logs
+    let mut trace = match trace {
+        Some(path) => {
+            let file = File::create(path).map_err(|error| format!("{}: {}", path, error))?;
+            cpu.bus.ly_override = Some(DOCTOR_LY);
+            let mut trace = DoctorTrace::new(BufWriter::new(file));
+            trace.log(&cpu);
+            Some((path, trace))
+        }
+        None => None,
+    };
+    let mut coverage = coverage.map(|path| (path, Coverage::attach(&mut cpu)));
+    let mut heatmap = heatmap.map(|path| (path, Heatmap::new()));
+    if heatmap.is_some() {
+        cpu.bus.access_log = Some(RefCell::new(Vec::new()));
+    }
+    // Where the step about to run starts, for the heatmap's writers
+    let mut origin = (cpu.pc, cpu.bus.bank_at(cpu.pc));
+    let recording = headless::run_until(&mut cpu, frames, |cpu| {
+        if let Some((_, trace)) = trace.as_mut() {
+            trace.log(cpu);
+        }
+        let accesses = cpu.bus.take_accesses();
+        if let Some((_, coverage)) = coverage.as_mut() {
+            coverage.record(cpu, &accesses);
+        }
+        if let Some((_, heatmap)) = heatmap.as_mut() {
+            heatmap.record(origin.0, origin.1, &accesses);
+            origin = (cpu.pc, cpu.bus.bank_at(cpu.pc));
+        }
+        Some(cpu.pc) == until_pc
+    });
+    if let Some((path, trace)) = trace {
+        trace.finish().map_err(|error| format!("{}: {}", path, error))?;
+    }
+    // JSON when the file name asks for it, the text map otherwise
+    if let Some((path, coverage)) = coverage {
+        let map = if path.ends_with(".json") { coverage.json() } else { coverage.text() };
+        fs::write(path, map).map_err(|error| format!("{}: {}", path, error))?;
+    }
+    if let Some((path, heatmap)) = heatmap {
+        let file = File::create(path).map_err(|error| format!("{}: {}", path, error))?;
+        heatmap.write_png(&mut BufWriter::new(file)).map_err(|error| format!("{}: {}", path, error))?;
+    }
+    if let Some(path) = serial_log {
+        fs::write(path, cpu.bus.serial.take_output()).map_err(|error| format!("{}: {}", path, error))?;
+    }
+
+    if let Some(path) = screenshot {
+        write_screenshot(path, &cpu)?;
+    }
+    if let Some(path) = save_state {
+        fs::write(path, cpu.save_state()).map_err(|error| format!("{}: {}", path, error))?;
+    }
+    if let Some(path) = export_bess {
+        fs::write(path, cpu.export_bess()).map_err(|error| format!("{}: {}", path, error))?;
+    }
+    if let Some(printer) = printer {
+        let pages = write_printed(&printer)?;
+        println!("printed {} pages to {}", pages, printer.directory);
+    }
+
+    write_recording(&args[1], recording.sample_rate, &recording.samples)?;
+    println!(
+        "recorded {} frames, {} samples to {}",
+        recording.frames,
+        recording.samples.len() / 2,
+        args[1]
+    );
+    Ok(())
+}
+
+fn gbs(args: &[String]) -> Result<(), String> {
+    if args.len() < 2 {
+        return Err(USAGE.to_string());
+    }
+    let mut song = None;
+    let mut seconds = 60;
+    let mut sample_rate = None;
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or(format!("{} needs a value", option))?;
+        match option.as_str() {
+            "--song" => song = Some(parse_number(value)?),
+            "--seconds" => seconds = parse_number(value)?,
+            "--sample-rate" => sample_rate = Some(parse_sample_rate(value)?),
+            _ => return Err(format!("unknown option {}\n{}", option, USAGE)),
+        }
+    }
+
+    let data = fs::read(&args[0]).map_err(|error| format!("{}: {}", args[0], error))?;
+    let mut player = GbsPlayer::new(&data).map_err(|error| format!("{}: {}", args[0], error))?;
+    if let Some(sample_rate) = sample_rate {
+        player.cpu.bus.apu.sample_rate = sample_rate;
+    }
+    // Song numbers on the command line are 1 based like the header's
+    if let Some(song) = song {
+        let count = player.header.song_count;
+        if song == 0 || song > count as u32 {
+            return Err(format!("{}: song {} is out of range, 1 to {}", args[0], song, count));
+        }
+        player.start_song(song as u8 - 1).map_err(|error| format!("{}: {}", args[0], error))?;
+    }
+    let samples = player.render(seconds);
+    write_recording(&args[1], player.cpu.bus.apu.sample_rate, &samples)?;
+    println!(
+        "rendered song {} of {} ({}) to {}",
+        player.song + 1,
+        player.header.song_count,
+        player.header.title,
+        args[1]
+    );
+    Ok(())
+}
+
+fn disassemble(args: &[String]) -> Result<(), String> {
+    if args.is_empty() {
+        return Err(USAGE.to_string());
+    }
+    let mut bank = 0;
+    let mut symbols = Symbols::new();
+    let mut options = args[1..].iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or(format!("{} needs a value", option))?;
+        match option.as_str() {
+            "--bank" => bank = parse_number(value)? as usize,
+            "--sym" => symbols = load_symbols(value)?,
+            _ => return Err(format!("unknown option {}\n{}", option, USAGE)),
+        }
+    }
+
+    let cartridge = load_cartridge(&args[0])?;
+    if bank >= cartridge.rom_bank_count() {
+        return Err(format!("{}: no bank {}, the ROM has {}", args[0], bank, cartridge.rom_bank_count()));
+    }
+    // A SECTION header so the listing assembles with RGBDS
+    if bank == 0 {
+        println!("SECTION \"ROM Bank $000\", ROM0[$0000]");
+    } else {
+        println!("SECTION \"ROM Bank ${:03X}\", ROMX[$4000], BANK[${:X}]", bank, bank);
+    }
+    // Jumps out of the bank only have a known bank when they go to bank
+    // 0, or to bank 1 of a ROM without a mapper
+    let bank_of = |address: u16| match address {
+        0x0000..=0x3FFF => Some(0),
+        0x4000..=0x7FFF if bank != 0 => Some(bank as u16),
+        0x4000..=0x7FFF if cartridge.rom_bank_count() == 2 => Some(1),
+        _ => None,
+    };
+    for line in disassembler::disassemble_bank(cartridge.rom(), bank) {
+        for symbol in symbols.at(bank_of(line.address).unwrap_or(0), line.address) {
+            println!("{}:", symbol.name);
+        }
+        let target = line.target().and_then(|target| symbols.describe(bank_of(target)?, target));
+        match target {
+            Some(label) => println!("{} -> {}", line, label),
+            None => println!("{}", line),
+        }
+    }
+    Ok(())
+}
+
+fn debug(args: &[String]) -> Result<(), String> {
+    if args.is_empty() {
+        return Err(USAGE.to_string());
+    }
+    let mut model = None;
+    let mut boot_rom = None;
+    let mut load_state = None;
+    let mut symbols = Symbols::new();
+    let mut gdb_port = None;
+    let mut options = args[1..].iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or(format!("{} needs a value", option))?;
+        match option.as_str() {
+            "--model" => model = Some(parse_model(value)?),
+            "--boot-rom" => boot_rom = Some(load_boot_rom(value)?),
+            "--load-state" => load_state = Some(value),
+            "--sym" => symbols = load_symbols(value)?,
+            "--gdb" => gdb_port = Some(parse_u16(value)?),
+            _ => return Err(format!("unknown option {}\n{}", option, USAGE)),
+        }
+    }
+
+    let mut cpu = machine(&args[0], model, boot_rom, load_state)?;
+    if let Some(port) = gdb_port {
+        println!("waiting for a debugger on localhost:{}", port);
+        return gdb::serve(&mut cpu, port).map_err(|error| format!("gdb: {}", error));
+    }
+
+    let mut debugger = Debugger::new();
+    debugger.symbols = symbols;
+    println!("{}", debugger.command(&mut cpu, "disassemble")?);
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    while !debugger.quit {
+        print!("(gb) ");
+        io::stdout().flush().map_err(|error| error.to_string())?;
+        let line = match lines.next() {
+            Some(line) => line.map_err(|error| error.to_string())?,
+            None => break,
+        };
+        match debugger.command(&mut cpu, &line) {
+            Ok(output) if output.is_empty() => {}
+            Ok(output) => println!("{}", output),
+            Err(message) => println!("{}", message),
+        }
+    }
+    Ok(())
+}
+
+fn profile(args: &[String]) -> Result<(), String> {
+    if args.is_empty() {
+        return Err(USAGE.to_string());
+    }
+    let mut frames = 600;
+    let mut model = None;
+    let mut boot_rom = None;
+    let mut load_state = None;
+    let mut symbols = Symbols::new();
+    let mut report = None;
+    let mut folded = None;
+    let mut options = args[1..].iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or(format!("{} needs a value", option))?;
+        match option.as_str() {
+            "--frames" => frames = parse_number(value)?,
+            "--model" => model = Some(parse_model(value)?),
+            "--boot-rom" => boot_rom = Some(load_boot_rom(value)?),
+            "--load-state" => load_state = Some(value),
+            "--sym" => symbols = load_symbols(value)?,
+            "--report" => report = Some(value),
+            "--folded" => folded = Some(value),
+            _ => return Err(format!("unknown option {}\n{}", option, USAGE)),
+        }
+    }
+
+    let mut cpu = machine(&args[0], model, boot_rom, load_state)?;
+    let mut profiler = Profiler::new();
+    for _ in 0..frames {
+        profiler.run_frame(&mut cpu);
+    }
+    if let Some(path) = folded {
+        fs::write(path, profiler.folded(&symbols)).map_err(|error| format!("{}: {}", path, error))?;
+    }
+    match report {
+        Some(path) => fs::write(path, profiler.report(&symbols)).map_err(|error| format!("{}: {}", path, error))?,
+        None => print!("{}", profiler.report(&symbols)),
+    }
+    Ok(())
 }
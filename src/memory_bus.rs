@@ -0,0 +1,581 @@
+use std::cell::RefCell;
+
+use crate::apu::Apu;
+use crate::boot_rom::BootRom;
+use crate::cartridge::{Cartridge, ROM_BANK_SIZE};
+use crate::compat_palette::CompatPalette;
+use crate::hdma::Hdma;
+use crate::interrupt;
+use crate::joypad::{Button, Joypad};
+use crate::model::Model;
+use crate::ppu::Ppu;
+use crate::serial::Serial;
+use crate::sgb::Sgb;
+use crate::timer::Timer;
+
+pub const WRAM_BANK_SIZE: usize = 0x1000;
+
+// The CPU sits stopped for 2050 M-cycles while the clock switches
+pub const SPEED_SWITCH_CYCLES: u32 = 8200;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Access {
+    pub kind: AccessKind,
+    pub address: u16,
+    pub value: u8,
+}
+
+pub struct MemoryBus {
+    pub cartridge: Cartridge,
+    // Mapped over the cartridge until 0xFF50 is written
+    pub boot_rom: Option<BootRom>,
+    // KEY0, the CGB boot ROM sets bit 2 to drop into DMG mode
+    pub key0: u8,
+    pub model: Model,
+    // CGB hardware running a cartridge with the CGB flag set
+    pub cgb_mode: bool,
+    // Eight 4 KiB banks, 0xD000-0xDFFF maps bank 1-7 in CGB mode
+    pub wram: Vec<u8>,
+    pub wram_bank: u8,
+    pub hram: [u8; 0x7F],
+    pub ppu: Ppu,
+    pub apu: Apu,
+    pub timer: Timer,
+    pub joypad: Joypad,
+    pub serial: Serial,
+    pub hdma: Hdma,
+    // Present on SGB models
+    pub sgb: Option<Sgb>,
+    pub interrupt_flag: u8,
+    pub interrupt_enable: u8,
+    // CGB double speed. The CPU, timer and serial clock run twice as
+    // fast while the PPU, APU and RTC keep their normal rate.
+    pub double_speed: bool,
+    // KEY1 bit 0, the next STOP switches speed
+    pub speed_switch_armed: bool,
+    // CPU cycles the CPU is held for by a speed switch or DMA
+    pub stall_cycles: u32,
+    // The divider does not run during a speed switch
+    pub speed_switch_cycles: u32,
+    // CPU cycles not yet passed on to the normal speed components
+    pub double_speed_carry: u32,
+    // Every read and write while set, for debugging tools
+    pub access_log: Option<RefCell<Vec<Access>>>,
+    // Read back as LY in place of the PPU's line while set, for matching
+    // traces from emulators that stub it
+    pub ly_override: Option<u8>,
+    // 64KB of plain RAM standing in for the whole memory map while set,
+    // for CPU tests that don't model the hardware behind it
+    #[cfg(test)]
+    pub flat_memory: Option<Vec<u8>>,
+}
+
+impl MemoryBus {
+    pub fn new(cartridge: Cartridge) -> MemoryBus {
+        let model = Model::for_cartridge(&cartridge.header);
+        MemoryBus::with_model(cartridge, model)
+    }
+
+    // Sets up the I/O state the model's boot ROM leaves behind
+    pub fn with_model(cartridge: Cartridge, model: Model) -> MemoryBus {
+        let cgb_mode = model.is_cgb() && cartridge.header.supports_cgb();
+        let mut bus = MemoryBus {
+            cartridge,
+            boot_rom: None,
+            key0: 0,
+            model,
+            cgb_mode,
+            wram: vec![0; WRAM_BANK_SIZE * 8],
+            wram_bank: 1,
+            hram: [0; 0x7F],
+            ppu: if cgb_mode { Ppu::new_cgb() } else { Ppu::new() },
+            apu: Apu::new(),
+            timer: Timer::new(),
+            joypad: Joypad::new(),
+            serial: Serial::new(),
+            hdma: Hdma::new(),
+            sgb: if model.is_sgb() { Some(Sgb::new()) } else { None },
+            interrupt_flag: 0xE1,
+            interrupt_enable: 0x00,
+            double_speed: false,
+            speed_switch_armed: false,
+            stall_cycles: 0,
+            speed_switch_cycles: 0,
+            double_speed_carry: 0,
+            access_log: None,
+            ly_override: None,
+            #[cfg(test)]
+            flat_memory: None,
+        };
+        bus.timer.divider = model.initial_divider();
+        if model.is_cgb() {
+            bus.serial.control = 0x7F;
+            if !cgb_mode {
+                let palette = CompatPalette::for_rom(bus.cartridge.rom());
+                bus.ppu.load_compat_palette(&palette);
+            }
+        }
+        bus
+    }
+
+    // Power on state for running a real boot ROM, which sets up the
+    // I/O registers itself
+    pub fn with_boot_rom(cartridge: Cartridge, model: Model, boot_rom: BootRom) -> MemoryBus {
+        let mut bus = MemoryBus::new_powered_on(cartridge, model);
+        bus.boot_rom = Some(boot_rom);
+        bus
+    }
+
+    fn new_powered_on(cartridge: Cartridge, model: Model) -> MemoryBus {
+        let mut bus = MemoryBus::with_model(cartridge, model);
+        // The CGB boot ROM always starts in CGB mode
+        bus.cgb_mode = model.is_cgb();
+        bus.ppu = if bus.cgb_mode { Ppu::new_cgb() } else { Ppu::new() };
+        bus.ppu.lcdc = 0x00;
+        bus.ppu.bgp = 0x00;
+        bus.apu.write(0xFF26, 0x00);
+        bus.timer.divider = 0;
+        bus.serial.control = 0x7E;
+        bus
+    }
+
+    fn unmap_boot_rom(&mut self) {
+        if self.boot_rom.take().is_none() || !self.model.is_cgb() {
+            return;
+        }
+        // Hand over to DMG compatibility mode with the palettes the boot
+        // ROM picked
+        if self.key0 & 0x04 != 0 {
+            self.cgb_mode = false;
+            self.ppu.cgb_mode = false;
+            self.ppu.compat_mode = true;
+        }
+    }
+
+    pub fn read_byte(&self, address: u16) -> u8 {
+        let value = self.peek(address);
+        if let Some(log) = &self.access_log {
+            log.borrow_mut().push(Access {
+                kind: AccessKind::Read,
+                address,
+                value,
+            });
+        }
+        value
+    }
+
+    // Everything logged since the last call, nothing when logging is off
+    pub fn take_accesses(&self) -> Vec<Access> {
+        self.access_log.as_ref().map(|log| log.borrow_mut().drain(..).collect()).unwrap_or_default()
+    }
+
+    // Reads without going into the access log
+    pub fn peek(&self, address: u16) -> u8 {
+        #[cfg(test)]
+        if let Some(memory) = &self.flat_memory {
+            return memory[address as usize];
+        }
+        match address {
+            0x0000..=0x08FF => match self.boot_rom.as_ref().and_then(|boot_rom| boot_rom.read(address)) {
+                Some(value) => value,
+                None => self.cartridge.read_rom(address),
+            },
+            0x0900..=0x7FFF => self.cartridge.read_rom(address),
+            0x8000..=0x9FFF => self.ppu.read_vram(address),
+            0xA000..=0xBFFF => self.cartridge.read_ram(address),
+            0xC000..=0xFDFF => self.wram[self.wram_index(address)],
+            0xFE00..=0xFE9F => self.ppu.oam[(address - 0xFE00) as usize],
+            0xFEA0..=0xFEFF => 0xFF,
+            0xFF00..=0xFF7F => self.read_io(address),
+            0xFF80..=0xFFFE => self.hram[(address - 0xFF80) as usize],
+            0xFFFF => self.interrupt_enable,
+        }
+    }
+
+    pub fn write_byte(&mut self, address: u16, value: u8) {
+        if let Some(log) = &self.access_log {
+            log.borrow_mut().push(Access {
+                kind: AccessKind::Write,
+                address,
+                value,
+            });
+        }
+        #[cfg(test)]
+        if let Some(memory) = self.flat_memory.as_mut() {
+            memory[address as usize] = value;
+            return;
+        }
+        match address {
+            0x0000..=0x7FFF => self.cartridge.write_rom(address, value),
+            0x8000..=0x9FFF => self.ppu.write_vram(address, value),
+            0xA000..=0xBFFF => self.cartridge.write_ram(address, value),
+            0xC000..=0xFDFF => {
+                let index = self.wram_index(address);
+                self.wram[index] = value;
+            }
+            0xFE00..=0xFE9F => self.ppu.oam[(address - 0xFE00) as usize] = value,
+            0xFEA0..=0xFEFF => {}
+            0xFF00..=0xFF7F => self.write_io(address, value),
+            0xFF80..=0xFFFE => self.hram[(address - 0xFF80) as usize] = value,
+            0xFFFF => self.interrupt_enable = value,
+        }
+    }
+
+    // The bank mapped at an address, numbered the way RGBDS numbers
+    // sections so symbols can be matched against it
+    pub fn bank_at(&self, address: u16) -> u16 {
+        match address {
+            0x0000..=0x7FFF => self.cartridge.rom_bank_at(address) as u16,
+            0x8000..=0x9FFF => self.ppu.vram_bank as u16,
+            0xA000..=0xBFFF => self.cartridge.current_ram_bank() as u16,
+            0xD000..=0xDFFF => self.wram_bank as u16,
+            _ => 0,
+        }
+    }
+
+    // Where in the cartridge ROM a read of the address lands, None
+    // outside ROM and where the boot ROM is mapped over it
+    pub fn rom_offset(&self, address: u16) -> Option<usize> {
+        if address >= 0x8000 || self.boot_rom.as_ref().and_then(|boot_rom| boot_rom.read(address)).is_some() {
+            return None;
+        }
+        let offset = self.cartridge.rom_bank_at(address) * ROM_BANK_SIZE + (address as usize & (ROM_BANK_SIZE - 1));
+        if offset < self.cartridge.rom().len() {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    // Echo RAM at 0xE000-0xFDFF mirrors 0xC000-0xDDFF
+    fn wram_index(&self, address: u16) -> usize {
+        let offset = (address as usize - 0xC000) & 0x1FFF;
+        if offset < WRAM_BANK_SIZE {
+            offset
+        } else {
+            self.wram_bank as usize * WRAM_BANK_SIZE + offset - WRAM_BANK_SIZE
+        }
+    }
+
+    pub fn read_word(&self, address: u16) -> u16 {
+        self.read_byte(address) as u16 | (self.read_byte(address.wrapping_add(1)) as u16) << 8
+    }
+
+    pub fn write_word(&mut self, address: u16, value: u16) {
+        self.write_byte(address, value as u8);
+        self.write_byte(address.wrapping_add(1), (value >> 8) as u8);
+    }
+
+    fn read_io(&self, address: u16) -> u8 {
+        match address {
+            0xFF00 => match &self.sgb {
+                Some(sgb) => sgb.read_joypad(&self.joypad),
+                None => self.joypad.read(),
+            },
+            0xFF01 | 0xFF02 => self.serial.read(address),
+            0xFF04..=0xFF07 => self.timer.read(address),
+            0xFF0F => self.interrupt_flag | 0xE0,
+            0xFF10..=0xFF3F => self.apu.read(address),
+            0xFF44 => self.ly_override.unwrap_or(self.ppu.ly),
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B | 0xFF4F | 0xFF68..=0xFF6B => {
+                self.ppu.read_register(address)
+            }
+            0xFF51..=0xFF55 if self.cgb_mode => self.hdma.read(address),
+            0xFF4D if self.cgb_mode => {
+                0x7E | (self.double_speed as u8) << 7 | self.speed_switch_armed as u8
+            }
+            0xFF70 if self.cgb_mode => 0xF8 | self.wram_bank,
+            _ => 0xFF,
+        }
+    }
+
+    fn write_io(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF00 => {
+                self.joypad.write(value);
+                if let Some(sgb) = &mut self.sgb {
+                    sgb.write_joypad(value);
+                }
+            }
+            0xFF01 | 0xFF02 => self.serial.write(address, value),
+            0xFF04..=0xFF07 => {
+                let overflow = self.timer.write(address, value);
+                if overflow {
+                    self.interrupt_flag |= interrupt::TIMER;
+                }
+            }
+            0xFF0F => self.interrupt_flag = value & 0x1F,
+            0xFF10..=0xFF3F => self.apu.write(address, value),
+            0xFF46 => self.oam_dma(value),
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B | 0xFF4F | 0xFF68..=0xFF6B => {
+                self.interrupt_flag |= self.ppu.write_register(address, value);
+            }
+            0xFF4C if self.boot_rom.is_some() => self.key0 = value,
+            0xFF4D if self.cgb_mode => self.speed_switch_armed = value & 0x01 != 0,
+            0xFF50 => self.unmap_boot_rom(),
+            0xFF51..=0xFF55 if self.cgb_mode => {
+                let blocks = self.hdma.write(address, value);
+                self.vram_dma(blocks);
+            }
+            // Bank 0 selects bank 1
+            0xFF70 if self.cgb_mode => self.wram_bank = (value & 0x07).max(1),
+            _ => {}
+        }
+    }
+
+    // OAM DMA copies 160 bytes from XX00 into OAM. The transfer is done
+    // in one go rather than spread over 640 cycles.
+    fn oam_dma(&mut self, source: u8) {
+        let base = (source as u16) << 8;
+        for offset in 0..0xA0 {
+            self.ppu.oam[offset as usize] = self.read_byte(base + offset);
+        }
+    }
+
+    // Copies 16 byte blocks into VRAM, holding the CPU for the same time
+    // a block in either speed: 32 CPU cycles, or 64 in double speed
+    fn vram_dma(&mut self, blocks: u8) {
+        for _ in 0..blocks {
+            for offset in 0..0x10 {
+                let value = self.read_byte(self.hdma.source.wrapping_add(offset));
+                self.ppu.write_vram(0x8000 | (self.hdma.destination + offset), value);
+            }
+            self.hdma.advance();
+            self.stall_cycles += if self.double_speed { 64 } else { 32 };
+        }
+    }
+
+    pub fn press(&mut self, button: Button) {
+        if self.joypad.press(button) {
+            self.interrupt_flag |= interrupt::JOYPAD;
+        }
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.joypad.release(button);
+    }
+
+    // Called for STOP. Returns true if it switched speed instead of
+    // stopping the system.
+    pub fn stop(&mut self) -> bool {
+        self.timer.divider = 0;
+        if !self.cgb_mode || !self.speed_switch_armed {
+            return false;
+        }
+        self.double_speed = !self.double_speed;
+        self.speed_switch_armed = false;
+        self.stall_cycles += SPEED_SWITCH_CYCLES;
+        self.speed_switch_cycles = SPEED_SWITCH_CYCLES;
+        true
+    }
+
+    // Advances the devices by a number of CPU cycles. Returns the time
+    // that passed in normal speed cycles.
+    pub fn step(&mut self, cycles: u32) -> u32 {
+        let paused = cycles.min(self.speed_switch_cycles);
+        self.speed_switch_cycles -= paused;
+        if self.timer.step(cycles - paused) {
+            self.interrupt_flag |= interrupt::TIMER;
+        }
+        if self.serial.step(cycles) {
+            self.interrupt_flag |= interrupt::SERIAL;
+        }
+
+        // Passed on in multiples of 4 so the APU never drops cycles
+        let elapsed = if self.double_speed {
+            self.double_speed_carry += cycles;
+            let elapsed = self.double_speed_carry / 8 * 4;
+            self.double_speed_carry %= 8;
+            elapsed
+        } else {
+            cycles
+        };
+        self.cartridge.step(elapsed);
+        let interrupts = self.ppu.step(elapsed);
+        if interrupts & interrupt::VBLANK != 0 {
+            if let Some(sgb) = &mut self.sgb {
+                sgb.frame_done(&self.ppu);
+            }
+        }
+        self.interrupt_flag |= interrupts;
+        if self.ppu.hblank_started {
+            self.ppu.hblank_started = false;
+            if self.hdma.hblank_active {
+                self.vram_dma(1);
+            }
+        }
+        self.apu.step(elapsed);
+        elapsed
+    }
+}
+
+#[cfg(test)]
+mod memory_bus_tests {
+    use super::*;
+    #[test]
+    fn echo_ram_mirrors_wram() {
+        let mut bus = MemoryBus::new(Cartridge::empty());
+        bus.write_byte(0xC123, 0x42);
+        assert_eq!(bus.read_byte(0xE123), 0x42);
+    }
+    #[test]
+    fn oam_dma_copies_from_wram() {
+        let mut bus = MemoryBus::new(Cartridge::empty());
+        bus.write_byte(0xC000, 0x12);
+        bus.write_byte(0xC09F, 0x34);
+        bus.write_byte(0xFF46, 0xC0);
+        assert_eq!(bus.read_byte(0xFE00), 0x12);
+        assert_eq!(bus.read_byte(0xFE9F), 0x34);
+    }
+    #[test]
+    fn svbk_switches_upper_wram() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        let mut bus = MemoryBus::new(Cartridge::new(rom).unwrap());
+        assert!(bus.cgb_mode);
+        bus.write_byte(0xD000, 0x11);
+        bus.write_byte(0xFF70, 0x03);
+        bus.write_byte(0xD000, 0x33);
+        assert_eq!(bus.read_byte(0xFF70), 0xFB);
+        bus.write_byte(0xFF70, 0x00);
+        assert_eq!(bus.read_byte(0xD000), 0x11);
+        assert_eq!(bus.wram[3 * WRAM_BANK_SIZE], 0x33);
+    }
+    #[test]
+    fn dmg_ignores_svbk() {
+        let mut bus = MemoryBus::new(Cartridge::empty());
+        bus.write_byte(0xFF70, 0x03);
+        bus.write_byte(0xD000, 0x44);
+        assert_eq!(bus.wram[WRAM_BANK_SIZE], 0x44);
+        assert_eq!(bus.read_byte(0xFF70), 0xFF);
+    }
+    #[test]
+    fn armed_stop_switches_speed() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0xC0;
+        let mut bus = MemoryBus::new(Cartridge::new(rom).unwrap());
+        assert!(!bus.stop());
+        bus.write_byte(0xFF4D, 0x01);
+        assert_eq!(bus.read_byte(0xFF4D), 0x7F);
+        assert!(bus.stop());
+        assert_eq!(bus.read_byte(0xFF4D), 0xFE);
+        assert_eq!(bus.stall_cycles, SPEED_SWITCH_CYCLES);
+
+        // The timer is paused for the switch, then runs at CPU speed
+        bus.timer.divider = 0;
+        assert_eq!(bus.step(SPEED_SWITCH_CYCLES), SPEED_SWITCH_CYCLES / 2);
+        assert_eq!(bus.timer.divider, 0);
+        assert_eq!(bus.step(4), 0);
+        assert_eq!(bus.step(4), 4);
+        assert_eq!(bus.timer.divider, 8);
+    }
+    fn cgb_bus() -> MemoryBus {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        MemoryBus::new(Cartridge::new(rom).unwrap())
+    }
+
+    #[test]
+    fn general_purpose_dma_copies_and_stalls() {
+        let mut bus = cgb_bus();
+        for offset in 0..0x20 {
+            bus.write_byte(0xC000 + offset, offset as u8);
+        }
+        bus.write_byte(0xFF51, 0xC0);
+        bus.write_byte(0xFF52, 0x00);
+        bus.write_byte(0xFF53, 0x01);
+        bus.write_byte(0xFF54, 0x00);
+        bus.write_byte(0xFF55, 0x01);
+        assert_eq!(bus.read_byte(0x8100), 0x00);
+        assert_eq!(bus.read_byte(0x811F), 0x1F);
+        assert_eq!(bus.read_byte(0xFF55), 0xFF);
+        assert_eq!(bus.stall_cycles, 64);
+    }
+    #[test]
+    fn hblank_dma_copies_a_block_per_line() {
+        let mut bus = cgb_bus();
+        bus.write_byte(0xC010, 0xAB);
+        bus.write_byte(0xFF51, 0xC0);
+        bus.write_byte(0xFF52, 0x00);
+        bus.write_byte(0xFF55, 0x81);
+        assert_eq!(bus.read_byte(0x8010), 0x00);
+        // Into HBlank of line 0, then line 1
+        bus.step(252);
+        assert_eq!(bus.read_byte(0xFF55), 0x00);
+        bus.step(456);
+        assert_eq!(bus.read_byte(0x8010), 0xAB);
+        assert_eq!(bus.read_byte(0xFF55), 0xFF);
+    }
+    #[test]
+    fn cgb_model_colorizes_dmg_cartridges() {
+        let bus = MemoryBus::with_model(Cartridge::empty(), Model::Cgb);
+        assert!(!bus.cgb_mode);
+        assert!(bus.ppu.compat_mode);
+        assert_eq!(bus.read_byte(0xFF02), 0x7F);
+        let bus = MemoryBus::with_model(Cartridge::empty(), Model::Dmg0);
+        assert!(!bus.ppu.compat_mode);
+        assert_eq!(bus.read_byte(0xFF04), 0x18);
+    }
+    #[test]
+    fn cgb_boot_rom_hands_over_to_dmg_mode() {
+        let boot_rom = BootRom::new(vec![0; crate::boot_rom::CGB_BOOT_ROM_SIZE]).unwrap();
+        let mut bus = MemoryBus::with_boot_rom(Cartridge::empty(), Model::Cgb, boot_rom);
+        assert!(bus.cgb_mode);
+        bus.write_byte(0xFF68, 0x80);
+        bus.write_byte(0xFF69, 0x1F);
+        bus.write_byte(0xFF4C, 0x04);
+        bus.write_byte(0xFF50, 0x01);
+        assert!(!bus.cgb_mode);
+        assert!(bus.ppu.compat_mode);
+        assert_eq!(bus.ppu.bg_palettes.data[0], 0x1F);
+        // KEY0 is locked once the boot ROM is gone
+        bus.write_byte(0xFF4C, 0x00);
+        assert_eq!(bus.key0, 0x04);
+    }
+    #[test]
+    fn words_are_little_endian() {
+        let mut bus = MemoryBus::new(Cartridge::empty());
+        bus.write_word(0xFF80, 0xBEEF);
+        assert_eq!(bus.read_byte(0xFF80), 0xEF);
+        assert_eq!(bus.read_word(0xFF80), 0xBEEF);
+    }
+    #[test]
+    fn reports_mapped_banks() {
+        let mut bus = MemoryBus::new(Cartridge::with_mapper(vec![0; 0x10000], crate::cartridge::MbcKind::Mbc5, 0x8000));
+        bus.write_byte(0x2000, 0x03);
+        bus.write_byte(0x4000, 0x02);
+        assert_eq!(bus.bank_at(0x0150), 0);
+        assert_eq!(bus.bank_at(0x4000), 3);
+        assert_eq!(bus.bank_at(0xA000), 2);
+        assert_eq!(bus.bank_at(0xC000), 0);
+        assert_eq!(bus.bank_at(0xD000), 1);
+        assert_eq!(bus.bank_at(0xFF80), 0);
+        assert_eq!(bus.rom_offset(0x4001), Some(3 * ROM_BANK_SIZE + 1));
+        assert_eq!(bus.rom_offset(0x0150), Some(0x0150));
+        assert_eq!(bus.rom_offset(0xC000), None);
+    }
+    #[test]
+    fn logs_accesses_when_asked() {
+        let mut bus = MemoryBus::new(Cartridge::empty());
+        bus.write_byte(0xC000, 0x12);
+        assert!(bus.access_log.is_none());
+        bus.access_log = Some(RefCell::new(Vec::new()));
+        bus.write_byte(0xC001, 0x34);
+        assert_eq!(bus.read_byte(0xC000), 0x12);
+        assert_eq!(bus.peek(0xC001), 0x34);
+        let log = bus.access_log.take().unwrap().into_inner();
+        assert_eq!(
+            log,
+            vec![
+                Access { kind: AccessKind::Write, address: 0xC001, value: 0x34 },
+                Access { kind: AccessKind::Read, address: 0xC000, value: 0x12 },
+            ]
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use std::fmt;
+
+use crate::cartridge::header::CartridgeHeader;
+use crate::compat_palette::nintendo_title_checksum;
+use crate::cpu::flags_register::FlagsRegister;
+use crate::cpu::registers::Registers;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Model {
+    Dmg0,
+    Dmg,
+    Mgb,
+    Sgb,
+    Sgb2,
+    Cgb,
+    Agb,
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl Model {
+    pub const ALL: [Model; 7] = [
+        Model::Dmg0,
+        Model::Dmg,
+        Model::Mgb,
+        Model::Sgb,
+        Model::Sgb2,
+        Model::Cgb,
+        Model::Agb,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Model> {
+        Model::ALL.iter().copied().find(|model| model.name() == name.to_lowercase())
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Model::Dmg0 => "dmg0",
+            Model::Dmg => "dmg",
+            Model::Mgb => "mgb",
+            Model::Sgb => "sgb",
+            Model::Sgb2 => "sgb2",
+            Model::Cgb => "cgb",
+            Model::Agb => "agb",
+        }
+    }
+
+    // The hardware a cartridge was made for
+    pub fn for_cartridge(header: &CartridgeHeader) -> Model {
+        if header.supports_cgb() {
+            Model::Cgb
+        } else {
+            Model::Dmg
+        }
+    }
+
+    pub fn is_cgb(self) -> bool {
+        self == Model::Cgb || self == Model::Agb
+    }
+
+    pub fn is_sgb(self) -> bool {
+        self == Model::Sgb || self == Model::Sgb2
+    }
+
+    // CPU registers as the boot ROM leaves them at 0x0100. `rom` is the
+    // cartridge, some values depend on its header.
+    pub fn initial_registers(self, rom: &[u8]) -> Registers {
+        let header_checksum = rom[0x14D];
+        // The DMG boot ROM's header check leaves H and C set unless the
+        // checksum byte is zero
+        let dmg_flags = if header_checksum == 0 { 0x80 } else { 0xB0 };
+        let (a, f, b, c, d, e, h, l) = match self {
+            Model::Dmg0 => (0x01, 0x00, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03),
+            Model::Dmg => (0x01, dmg_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+            Model::Mgb => (0xFF, dmg_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+            Model::Sgb => (0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60),
+            Model::Sgb2 => (0xFF, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60),
+            Model::Cgb | Model::Agb if rom[0x143] & 0x80 != 0 => {
+                (0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D)
+            }
+            // In DMG compatibility mode B keeps the title checksum used
+            // for the palette lookup
+            Model::Cgb | Model::Agb => {
+                let checksum = nintendo_title_checksum(rom).unwrap_or(0);
+                (0x11, 0x80, checksum, 0x00, 0x00, 0x08, 0x00, 0x7C)
+            }
+        };
+        // The AGB boot ROM finishes with an INC B, which sets Z and H
+        // from the result like any other and leaves C alone
+        let (b, f) = if self == Model::Agb {
+            let zero = if b == 0xFF { 0x80 } else { 0x00 };
+            let half_carry = if b & 0x0F == 0x0F { 0x20 } else { 0x00 };
+            (b.wrapping_add(1), zero | half_carry | (f & 0x10))
+        } else {
+            (b, f)
+        };
+        Registers {
+            a,
+            b,
+            c,
+            d,
+            e,
+            f: FlagsRegister::from(f),
+            h,
+            l,
+        }
+    }
+
+    // Internal divider counter at handover, DIV is its upper byte. The
+    // SGB and CGB boot ROMs run for a cartridge dependent time, these
+    // are typical values.
+    pub fn initial_divider(self) -> u16 {
+        match self {
+            Model::Dmg0 => 0x1830,
+            Model::Dmg | Model::Mgb => 0xABCC,
+            Model::Sgb | Model::Sgb2 => 0xD85C,
+            Model::Cgb | Model::Agb => 0x1EA0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod model_tests {
+    use super::*;
+
+    fn rom(cgb_flag: u8, header_checksum: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = cgb_flag;
+        rom[0x14D] = header_checksum;
+        rom
+    }
+
+    #[test]
+    fn dmg_flags_follow_header_checksum() {
+        assert_eq!(Model::Dmg.initial_registers(&rom(0, 0x12)).get_af(), 0x01B0);
+        assert_eq!(Model::Dmg.initial_registers(&rom(0, 0x00)).get_af(), 0x0180);
+    }
+    #[test]
+    fn cgb_registers() {
+        let registers = Model::Cgb.initial_registers(&rom(0x80, 0x12));
+        assert_eq!(registers.get_af(), 0x1180);
+        assert_eq!(registers.get_de(), 0xFF56);
+        assert_eq!(Model::Agb.initial_registers(&rom(0x80, 0x12)).get_bc(), 0x0100);
+        assert_eq!(Model::Agb.initial_registers(&rom(0x80, 0x12)).get_af(), 0x1100);
+    }
+    #[test]
+    fn agb_increments_title_checksum() {
+        let mut dmg_rom = rom(0, 0x12);
+        dmg_rom[0x14B] = 0x01;
+        dmg_rom[0x134] = 0x4F;
+        let registers = Model::Agb.initial_registers(&dmg_rom);
+        assert_eq!(registers.b, 0x50);
+        assert_eq!(registers.get_af(), 0x1120);
+        dmg_rom[0x134] = 0xFF;
+        let registers = Model::Agb.initial_registers(&dmg_rom);
+        assert_eq!(registers.b, 0x00);
+        assert_eq!(registers.get_af(), 0x11A0);
+        dmg_rom[0x134] = 0x42;
+        assert_eq!(Model::Agb.initial_registers(&dmg_rom).get_af(), 0x1100);
+    }
+    #[test]
+    fn names_round_trip() {
+        for &model in Model::ALL.iter() {
+            assert_eq!(Model::from_name(model.name()), Some(model));
+        }
+        assert_eq!(Model::from_name("SGB2"), Some(Model::Sgb2));
+        assert_eq!(Model::from_name("gba"), None);
+    }
+}
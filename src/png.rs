@@ -0,0 +1,128 @@
+use std::io::{self, Write};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorType {
+    Grayscale,
+    Rgb,
+}
+
+impl ColorType {
+    fn channels(self) -> usize {
+        match self {
+            ColorType::Grayscale => 1,
+            ColorType::Rgb => 3,
+        }
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            ColorType::Grayscale => 0,
+            ColorType::Rgb => 2,
+        }
+    }
+}
+
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn write_chunk<W: Write>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    let mut crc_input = kind.to_vec();
+    crc_input.extend_from_slice(data);
+    writer.write_all(&crc32(&crc_input).to_be_bytes())
+}
+
+// zlib stream made of stored deflate blocks. The images are small
+// enough that skipping compression keeps this simple.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut output = vec![0x78, 0x01];
+    let mut chunks = data.chunks(0xFFFF).peekable();
+    if chunks.peek().is_none() {
+        output.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none();
+        output.push(if last { 0x01 } else { 0x00 });
+        let length = chunk.len() as u16;
+        output.extend_from_slice(&length.to_le_bytes());
+        output.extend_from_slice(&(!length).to_le_bytes());
+        output.extend_from_slice(chunk);
+    }
+    output.extend_from_slice(&adler32(data).to_be_bytes());
+    output
+}
+
+// Writes 8 bit per channel pixels, row major
+pub fn write_png<W: Write>(
+    writer: &mut W,
+    width: u32,
+    height: u32,
+    color_type: ColorType,
+    pixels: &[u8],
+) -> io::Result<()> {
+    let stride = width as usize * color_type.channels();
+    if pixels.len() != stride * height as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "pixel buffer does not match image size"));
+    }
+
+    writer.write_all(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A])?;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    header.extend_from_slice(&[8, color_type.code(), 0, 0, 0]);
+    write_chunk(writer, b"IHDR", &header)?;
+
+    // Every scanline starts with filter type 0 (none)
+    let mut raw = Vec::with_capacity((stride + 1) * height as usize);
+    for row in pixels.chunks(stride.max(1)).take(height as usize) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    write_chunk(writer, b"IDAT", &zlib_stored(&raw))?;
+    write_chunk(writer, b"IEND", &[])
+}
+
+#[cfg(test)]
+mod png_tests {
+    use super::*;
+    #[test]
+    fn crc_matches_reference() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+    #[test]
+    fn writes_signature_and_header() {
+        let mut output = Vec::new();
+        write_png(&mut output, 2, 1, ColorType::Grayscale, &[0x00, 0xFF]).unwrap();
+        assert_eq!(&output[1..4], b"PNG");
+        assert_eq!(&output[12..16], b"IHDR");
+        assert_eq!(&output[16..20], &2u32.to_be_bytes());
+        assert_eq!(&output[output.len() - 8..output.len() - 4], b"IEND");
+    }
+    #[test]
+    fn rejects_wrong_buffer_size() {
+        let mut output = Vec::new();
+        assert!(write_png(&mut output, 2, 2, ColorType::Rgb, &[0; 3]).is_err());
+    }
+}
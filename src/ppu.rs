@@ -0,0 +1,573 @@
+use crate::compat_palette::CompatPalette;
+use crate::interrupt::{STAT as STAT_INTERRUPT, VBLANK as VBLANK_INTERRUPT};
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+pub(crate) const OAM_SCAN_CYCLES: u32 = 80;
+pub(crate) const DRAWING_CYCLES: u32 = 172;
+const LINE_CYCLES: u32 = 456;
+
+pub const VRAM_BANK_SIZE: usize = 0x2000;
+
+// DMG shades 0 (white) to 3 (black) as RGB555
+pub const DMG_SHADES: [u16; 4] = [0x7FFF, 0x56B5, 0x294A, 0x0000];
+
+// Expands a 15 bit color (red in the low bits) to 8 bits per channel
+pub fn rgb888(color: u16) -> [u8; 3] {
+    let expand = |channel: u16| ((channel & 0x1F) << 3 | (channel & 0x1F) >> 2) as u8;
+    [expand(color), expand(color >> 5), expand(color >> 10)]
+}
+
+// 64 bytes of CGB palette RAM behind an index register (BCPS/OCPS) and
+// a data register (BCPD/OCPD)
+#[derive(Clone)]
+pub struct PaletteRam {
+    pub data: [u8; 64],
+    pub index: u8,
+    pub auto_increment: bool,
+}
+
+impl Default for PaletteRam {
+    fn default() -> Self {
+        PaletteRam::new()
+    }
+}
+
+impl PaletteRam {
+    pub fn new() -> PaletteRam {
+        PaletteRam {
+            data: [0xFF; 64],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+
+    pub fn read_index(&self) -> u8 {
+        0x40 | (self.auto_increment as u8) << 7 | self.index
+    }
+
+    pub fn write_index(&mut self, value: u8) {
+        self.index = value & 0x3F;
+        self.auto_increment = value & 0x80 != 0;
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    pub fn write_data(&mut self, value: u8) {
+        self.data[self.index as usize] = value;
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3F;
+        }
+    }
+
+    pub fn set_colors(&mut self, palette: u8, colors: &[u16; 4]) {
+        for (color, &value) in colors.iter().enumerate() {
+            let index = (palette as usize & 0x07) * 8 + color * 2;
+            self.data[index] = value as u8;
+            self.data[index + 1] = (value >> 8) as u8;
+        }
+    }
+
+    pub fn color(&self, palette: u8, color: u8) -> u16 {
+        let index = (palette as usize & 0x07) * 8 + color as usize * 2;
+        (self.data[index] as u16 | (self.data[index + 1] as u16) << 8) & 0x7FFF
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    HBlank,
+    VBlank,
+    OamScan,
+    Drawing,
+}
+
+impl std::convert::From<Mode> for u8 {
+    fn from(mode: Mode) -> u8 {
+        match mode {
+            Mode::HBlank => 0,
+            Mode::VBlank => 1,
+            Mode::OamScan => 2,
+            Mode::Drawing => 3,
+        }
+    }
+}
+
+pub struct Ppu {
+    // Two banks in CGB mode, bank 1 holds tiles and BG map attributes
+    pub vram: Vec<u8>,
+    pub vram_bank: u8,
+    pub cgb_mode: bool,
+    // A DMG cartridge on CGB hardware. BGP, OBP0 and OBP1 pick colors
+    // from the first CGB palettes, set up by the boot ROM.
+    pub compat_mode: bool,
+    pub bg_palettes: PaletteRam,
+    pub obj_palettes: PaletteRam,
+    pub oam: [u8; 0xA0],
+    pub lcdc: u8,
+    pub stat: u8,
+    pub scy: u8,
+    pub scx: u8,
+    pub ly: u8,
+    pub lyc: u8,
+    pub bgp: u8,
+    pub obp0: u8,
+    pub obp1: u8,
+    pub wy: u8,
+    pub wx: u8,
+    pub mode: Mode,
+    pub line_cycles: u32,
+    pub window_line: u8,
+    pub stat_line: bool,
+    // RGB555 colors with red in the low bits, row major
+    pub framebuffer: Vec<u16>,
+    // DMG shades 0-3 behind each pixel, what the SGB colors
+    pub shades: Vec<u8>,
+    pub frame_ready: bool,
+    // Set when a visible line enters HBlank, for HBlank DMA
+    pub hblank_started: bool,
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Ppu::new()
+    }
+}
+
+impl Ppu {
+    pub fn new() -> Ppu {
+        Ppu {
+            vram: vec![0; VRAM_BANK_SIZE * 2],
+            vram_bank: 0,
+            cgb_mode: false,
+            compat_mode: false,
+            bg_palettes: PaletteRam::new(),
+            obj_palettes: PaletteRam::new(),
+            oam: [0; 0xA0],
+            lcdc: 0x91,
+            stat: 0x00,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0xFC,
+            obp0: 0xFF,
+            obp1: 0xFF,
+            wy: 0,
+            wx: 0,
+            mode: Mode::OamScan,
+            line_cycles: 0,
+            window_line: 0,
+            stat_line: false,
+            framebuffer: vec![DMG_SHADES[0]; SCREEN_WIDTH * SCREEN_HEIGHT],
+            shades: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frame_ready: false,
+            hblank_started: false,
+        }
+    }
+
+    pub fn new_cgb() -> Ppu {
+        Ppu {
+            cgb_mode: true,
+            ..Ppu::new()
+        }
+    }
+
+    // What the CGB boot ROM does before starting a DMG cartridge
+    pub fn load_compat_palette(&mut self, palette: &CompatPalette) {
+        self.bg_palettes.set_colors(0, &palette.bg);
+        self.obj_palettes.set_colors(0, &palette.obj0);
+        self.obj_palettes.set_colors(1, &palette.obj1);
+        self.compat_mode = true;
+    }
+
+    pub fn read_vram(&self, address: u16) -> u8 {
+        self.vram[self.vram_bank as usize * VRAM_BANK_SIZE + (address as usize & 0x1FFF)]
+    }
+
+    pub fn write_vram(&mut self, address: u16, value: u8) {
+        self.vram[self.vram_bank as usize * VRAM_BANK_SIZE + (address as usize & 0x1FFF)] = value;
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        self.lcdc & 0x80 != 0
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            0xFF40 => self.lcdc,
+            0xFF41 => {
+                let coincidence = if self.ly == self.lyc { 0x04 } else { 0 };
+                let mode = if self.lcd_enabled() { u8::from(self.mode) } else { 0 };
+                0x80 | (self.stat & 0x78) | coincidence | mode
+            }
+            0xFF42 => self.scy,
+            0xFF43 => self.scx,
+            0xFF44 => self.ly,
+            0xFF45 => self.lyc,
+            0xFF47 => self.bgp,
+            0xFF48 => self.obp0,
+            0xFF49 => self.obp1,
+            0xFF4A => self.wy,
+            0xFF4B => self.wx,
+            0xFF4F if self.cgb_mode => 0xFE | self.vram_bank,
+            0xFF68 if self.cgb_mode => self.bg_palettes.read_index(),
+            0xFF69 if self.cgb_mode => self.bg_palettes.read_data(),
+            0xFF6A if self.cgb_mode => self.obj_palettes.read_index(),
+            0xFF6B if self.cgb_mode => self.obj_palettes.read_data(),
+            _ => 0xFF,
+        }
+    }
+
+    // Returns any interrupts raised by the write
+    pub fn write_register(&mut self, address: u16, value: u8) -> u8 {
+        match address {
+            0xFF40 => {
+                let was_enabled = self.lcd_enabled();
+                self.lcdc = value;
+                if was_enabled && !self.lcd_enabled() {
+                    self.ly = 0;
+                    self.line_cycles = 0;
+                    self.window_line = 0;
+                    self.mode = Mode::HBlank;
+                } else if !was_enabled && self.lcd_enabled() {
+                    self.mode = Mode::OamScan;
+                }
+            }
+            0xFF41 => self.stat = value & 0x78,
+            0xFF42 => self.scy = value,
+            0xFF43 => self.scx = value,
+            0xFF45 => self.lyc = value,
+            0xFF47 => self.bgp = value,
+            0xFF48 => self.obp0 = value,
+            0xFF49 => self.obp1 = value,
+            0xFF4A => self.wy = value,
+            0xFF4B => self.wx = value,
+            0xFF4F if self.cgb_mode => self.vram_bank = value & 0x01,
+            0xFF68 if self.cgb_mode => self.bg_palettes.write_index(value),
+            0xFF69 if self.cgb_mode => self.bg_palettes.write_data(value),
+            0xFF6A if self.cgb_mode => self.obj_palettes.write_index(value),
+            0xFF6B if self.cgb_mode => self.obj_palettes.write_data(value),
+            _ => {}
+        }
+        self.update_stat_line()
+    }
+
+    // The STAT interrupt fires on the rising edge of the OR of all
+    // enabled STAT sources
+    pub(crate) fn update_stat_line(&mut self) -> u8 {
+        if !self.lcd_enabled() {
+            self.stat_line = false;
+            return 0;
+        }
+        let line = (self.stat & 0x40 != 0 && self.ly == self.lyc)
+            || (self.stat & 0x08 != 0 && self.mode == Mode::HBlank)
+            || (self.stat & 0x10 != 0 && self.mode == Mode::VBlank)
+            || (self.stat & 0x20 != 0 && self.mode == Mode::OamScan);
+        let rising = line && !self.stat_line;
+        self.stat_line = line;
+        if rising { STAT_INTERRUPT } else { 0 }
+    }
+
+    pub fn step(&mut self, cycles: u32) -> u8 {
+        if !self.lcd_enabled() {
+            return 0;
+        }
+        let mut interrupts = 0;
+        self.line_cycles += cycles;
+        loop {
+            match self.mode {
+                Mode::OamScan if self.line_cycles >= OAM_SCAN_CYCLES => {
+                    self.mode = Mode::Drawing;
+                }
+                Mode::Drawing if self.line_cycles >= OAM_SCAN_CYCLES + DRAWING_CYCLES => {
+                    self.render_scanline();
+                    self.mode = Mode::HBlank;
+                    self.hblank_started = true;
+                }
+                Mode::HBlank if self.line_cycles >= LINE_CYCLES => {
+                    self.line_cycles -= LINE_CYCLES;
+                    self.ly += 1;
+                    if self.ly == SCREEN_HEIGHT as u8 {
+                        self.mode = Mode::VBlank;
+                        self.frame_ready = true;
+                        interrupts |= VBLANK_INTERRUPT;
+                    } else {
+                        self.mode = Mode::OamScan;
+                    }
+                }
+                Mode::VBlank if self.line_cycles >= LINE_CYCLES => {
+                    self.line_cycles -= LINE_CYCLES;
+                    self.ly += 1;
+                    if self.ly > 153 {
+                        self.ly = 0;
+                        self.window_line = 0;
+                        self.mode = Mode::OamScan;
+                    }
+                }
+                _ => break,
+            }
+            interrupts |= self.update_stat_line();
+        }
+        interrupts | self.update_stat_line()
+    }
+
+    fn tile_row(&self, tile_address: usize, row: usize) -> (u8, u8) {
+        let address = tile_address + row * 2;
+        (self.vram[address], self.vram[address + 1])
+    }
+
+    pub(crate) fn bg_tile_address(&self, tile_number: u8) -> usize {
+        if self.lcdc & 0x10 != 0 {
+            tile_number as usize * 16
+        } else {
+            (0x1000 + (tile_number as i8 as i32) * 16) as usize
+        }
+    }
+
+    fn apply_palette(palette: u8, color: u8) -> u8 {
+        (palette >> (color * 2)) & 0b11
+    }
+
+    // Returns the DMG shade and the displayed color
+    fn bg_color(&self, palette: u8, color: u8) -> (u8, u16) {
+        if self.cgb_mode {
+            return (color, self.bg_palettes.color(palette, color));
+        }
+        let shade = Ppu::apply_palette(self.bgp, color);
+        if self.compat_mode {
+            (shade, self.bg_palettes.color(0, shade))
+        } else {
+            (shade, DMG_SHADES[shade as usize])
+        }
+    }
+
+    fn obj_color(&self, attributes: u8, color: u8) -> (u8, u16) {
+        if self.cgb_mode {
+            return (color, self.obj_palettes.color(attributes & 0x07, color));
+        }
+        let (number, palette) = if attributes & 0x10 != 0 { (1, self.obp1) } else { (0, self.obp0) };
+        let shade = Ppu::apply_palette(palette, color);
+        if self.compat_mode {
+            (shade, self.obj_palettes.color(number, shade))
+        } else {
+            (shade, DMG_SHADES[shade as usize])
+        }
+    }
+
+    fn render_scanline(&mut self) {
+        let ly = self.ly as usize;
+        let mut bg_colors = [0u8; SCREEN_WIDTH];
+        // BG map attribute bit 7, BG over sprites in CGB mode
+        let mut bg_priority = [false; SCREEN_WIDTH];
+        let mut line = [(0, DMG_SHADES[0]); SCREEN_WIDTH];
+
+        // In CGB mode LCDC bit 0 only takes away the BG's priority
+        if self.cgb_mode || self.lcdc & 0x01 != 0 {
+            let window_visible = self.lcdc & 0x20 != 0 && self.ly >= self.wy && self.wx <= 166;
+            let mut window_drawn = false;
+            for x in 0..SCREEN_WIDTH {
+                let in_window = window_visible && x + 7 >= self.wx as usize;
+                let (map_base, map_x, map_y) = if in_window {
+                    window_drawn = true;
+                    let map_base = if self.lcdc & 0x40 != 0 { 0x1C00 } else { 0x1800 };
+                    (map_base, x + 7 - self.wx as usize, self.window_line as usize)
+                } else {
+                    let map_base = if self.lcdc & 0x08 != 0 { 0x1C00 } else { 0x1800 };
+                    (
+                        map_base,
+                        (x + self.scx as usize) & 0xFF,
+                        (ly + self.scy as usize) & 0xFF,
+                    )
+                };
+                let map_address = map_base + (map_y / 8) * 32 + map_x / 8;
+                let tile_number = self.vram[map_address];
+                let attributes = if self.cgb_mode {
+                    self.vram[VRAM_BANK_SIZE + map_address]
+                } else {
+                    0
+                };
+                let bank = if attributes & 0x08 != 0 { VRAM_BANK_SIZE } else { 0 };
+                let row = if attributes & 0x40 != 0 { 7 - map_y % 8 } else { map_y % 8 };
+                let (low, high) = self.tile_row(bank + self.bg_tile_address(tile_number), row);
+                let bit = if attributes & 0x20 != 0 { map_x % 8 } else { 7 - (map_x % 8) };
+                let color = ((high >> bit) & 1) << 1 | ((low >> bit) & 1);
+                bg_colors[x] = color;
+                bg_priority[x] = attributes & 0x80 != 0;
+                line[x] = self.bg_color(attributes & 0x07, color);
+            }
+            if window_drawn {
+                self.window_line += 1;
+            }
+        }
+
+        if self.lcdc & 0x02 != 0 {
+            self.render_sprites(&bg_colors, &bg_priority, &mut line);
+        }
+        for (x, &(shade, color)) in line.iter().enumerate() {
+            self.shades[ly * SCREEN_WIDTH + x] = shade;
+            self.framebuffer[ly * SCREEN_WIDTH + x] = color;
+        }
+    }
+
+    fn render_sprites(
+        &self,
+        bg_colors: &[u8; SCREEN_WIDTH],
+        bg_priority: &[bool; SCREEN_WIDTH],
+        line: &mut [(u8, u16); SCREEN_WIDTH],
+    ) {
+        let ly = self.ly as i32;
+        let height = if self.lcdc & 0x04 != 0 { 16 } else { 8 };
+
+        // Only the first ten sprites in OAM order that overlap the line
+        // are drawn
+        let mut sprites: Vec<usize> = (0..40)
+            .filter(|&index| {
+                let y = self.oam[index * 4] as i32 - 16;
+                ly >= y && ly < y + height
+            })
+            .take(10)
+            .collect();
+        // On DMG lower X wins, ties go to the lower OAM index. CGB only
+        // looks at the OAM index. Draw the losers first so the winners
+        // end up on top.
+        if !self.cgb_mode {
+            sprites.sort_by_key(|&index| (self.oam[index * 4 + 1], index));
+        }
+
+        for &index in sprites.iter().rev() {
+            let y = self.oam[index * 4] as i32 - 16;
+            let x = self.oam[index * 4 + 1] as i32 - 8;
+            let mut tile = self.oam[index * 4 + 2];
+            let attributes = self.oam[index * 4 + 3];
+
+            let mut row = ly - y;
+            if attributes & 0x40 != 0 {
+                row = height - 1 - row;
+            }
+            if height == 16 {
+                tile &= 0xFE;
+            }
+            let bank = if self.cgb_mode && attributes & 0x08 != 0 { VRAM_BANK_SIZE } else { 0 };
+            let (low, high) = self.tile_row(bank + tile as usize * 16, row as usize);
+
+            for column in 0..8 {
+                let screen_x = x + column;
+                if screen_x < 0 || screen_x >= SCREEN_WIDTH as i32 {
+                    continue;
+                }
+                let screen_x = screen_x as usize;
+                let bit = if attributes & 0x20 != 0 { column } else { 7 - column };
+                let color = ((high >> bit) & 1) << 1 | ((low >> bit) & 1);
+                if color == 0 {
+                    continue;
+                }
+                let behind_bg = if self.cgb_mode {
+                    self.lcdc & 0x01 != 0 && (attributes & 0x80 != 0 || bg_priority[screen_x])
+                } else {
+                    attributes & 0x80 != 0
+                };
+                if behind_bg && bg_colors[screen_x] != 0 {
+                    continue;
+                }
+                line[screen_x] = self.obj_color(attributes, color);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod ppu_tests {
+    use super::*;
+    #[test]
+    fn vblank_after_144_lines() {
+        let mut ppu = Ppu::new();
+        let mut interrupts = 0;
+        for _ in 0..144 * LINE_CYCLES / 4 {
+            interrupts |= ppu.step(4);
+        }
+        assert_eq!(ppu.ly, 144);
+        assert_eq!(ppu.mode, Mode::VBlank);
+        assert_eq!(interrupts & VBLANK_INTERRUPT, VBLANK_INTERRUPT);
+        assert!(ppu.frame_ready);
+    }
+    #[test]
+    fn lyc_coincidence_raises_stat() {
+        let mut ppu = Ppu::new();
+        ppu.write_register(0xFF45, 2);
+        ppu.write_register(0xFF41, 0x40);
+        let mut interrupts = 0;
+        for _ in 0..2 * LINE_CYCLES / 4 {
+            interrupts |= ppu.step(4);
+        }
+        assert_eq!(interrupts & STAT_INTERRUPT, STAT_INTERRUPT);
+        assert_eq!(ppu.read_register(0xFF41) & 0x04, 0x04);
+    }
+    #[test]
+    fn renders_background_tile() {
+        let mut ppu = Ppu::new();
+        // Tile 0 row 0 is solid color 3
+        ppu.vram[0] = 0xFF;
+        ppu.vram[1] = 0xFF;
+        ppu.bgp = 0b1110_0100;
+        for _ in 0..LINE_CYCLES / 4 {
+            ppu.step(4);
+        }
+        assert_eq!(ppu.framebuffer[0], DMG_SHADES[3]);
+        assert_eq!(ppu.framebuffer[SCREEN_WIDTH], DMG_SHADES[0]);
+    }
+    #[test]
+    fn palette_ram_auto_increments() {
+        let mut ppu = Ppu::new_cgb();
+        ppu.write_register(0xFF68, 0x82);
+        ppu.write_register(0xFF69, 0x1F);
+        ppu.write_register(0xFF69, 0x00);
+        assert_eq!(ppu.read_register(0xFF68), 0xC4);
+        assert_eq!(ppu.bg_palettes.color(0, 1), 0x001F);
+    }
+    #[test]
+    fn cgb_attributes_select_bank_palette_and_flip() {
+        let mut ppu = Ppu::new_cgb();
+        // Tile 0 in bank 1, row 7 has only the leftmost pixel set
+        ppu.vram[VRAM_BANK_SIZE + 14] = 0x80;
+        ppu.vram[VRAM_BANK_SIZE + 15] = 0x80;
+        // Map entry 0: palette 2, bank 1, X and Y flip
+        ppu.vram[VRAM_BANK_SIZE + 0x1800] = 0x6A;
+        ppu.bg_palettes.data[2 * 8 + 6] = 0x00;
+        ppu.bg_palettes.data[2 * 8 + 7] = 0x7C;
+        for _ in 0..LINE_CYCLES / 4 {
+            ppu.step(4);
+        }
+        assert_eq!(ppu.framebuffer[7], 0x7C00);
+        assert_eq!(ppu.framebuffer[0], ppu.bg_palettes.color(2, 0));
+    }
+    #[test]
+    fn compat_mode_colors_dmg_palettes() {
+        let mut ppu = Ppu::new();
+        let palette = CompatPalette::for_combo(crate::compat_palette::ButtonCombo::RightA);
+        ppu.load_compat_palette(&palette);
+        ppu.vram[0] = 0xFF;
+        ppu.vram[1] = 0xFF;
+        ppu.bgp = 0b1110_0100;
+        ppu.framebuffer[SCREEN_WIDTH] = 0;
+        for _ in 0..2 * LINE_CYCLES / 4 {
+            ppu.step(4);
+        }
+        assert_eq!(ppu.framebuffer[0], palette.bg[3]);
+        assert_eq!(ppu.framebuffer[SCREEN_WIDTH], palette.bg[0]);
+        // The palette registers stay hidden from DMG software
+        assert_eq!(ppu.read_register(0xFF69), 0xFF);
+    }
+    #[test]
+    fn vram_bank_register() {
+        let mut ppu = Ppu::new_cgb();
+        ppu.write_register(0xFF4F, 0x01);
+        ppu.write_vram(0x8000, 0x42);
+        assert_eq!(ppu.vram[VRAM_BANK_SIZE], 0x42);
+        assert_eq!(ppu.read_register(0xFF4F), 0xFF);
+        assert_eq!(Ppu::new().read_register(0xFF4F), 0xFF);
+    }
+}
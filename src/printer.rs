@@ -0,0 +1,341 @@
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::png::{self, ColorType};
+use crate::serial::LinkTransport;
+
+pub const PRINT_WIDTH: usize = 160;
+
+// Each DATA packet carries two rows of 20 tiles
+const TILES_PER_ROW: usize = PRINT_WIDTH / 8;
+const BUFFER_SIZE: usize = 0x2000;
+// Each margin unit feeds one band of paper the height of two tile rows
+const MARGIN_UNIT_HEIGHT: usize = 16;
+// STATUS polls answered with the busy bit after a PRINT command
+const BUSY_POLLS: u8 = 4;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_UNPROCESSED_DATA: u8 = 0x08;
+
+const GRAYS: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrintedImage {
+    pub width: usize,
+    pub height: usize,
+    // 8 bit grayscale, row major
+    pub pixels: Vec<u8>,
+    pub palette: u8,
+    pub exposure: u8,
+}
+
+impl PrintedImage {
+    pub fn write_png<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        png::write_png(writer, self.width as u32, self.height as u32, ColorType::Grayscale, &self.pixels)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    KeepAlive,
+    Status,
+}
+
+// Game Boy Printer on the other end of the link cable. The game drives
+// the clock and sends packets of the form
+// 0x88 0x33 command compression length(2) data checksum(2) 0x00 0x00,
+// the printer answers the last two bytes with 0x81 and its status.
+pub struct Printer {
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    buffer: Vec<u8>,
+    status: u8,
+    busy_polls: u8,
+    printed: Arc<Mutex<Vec<PrintedImage>>>,
+}
+
+impl Default for Printer {
+    fn default() -> Self {
+        Printer::new()
+    }
+}
+
+impl Printer {
+    pub fn new() -> Printer {
+        Printer {
+            state: State::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            buffer: Vec::new(),
+            status: 0,
+            busy_polls: 0,
+            printed: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    // Handle to the printed strips that stays valid after the printer is
+    // plugged into a serial port
+    pub fn printed(&self) -> Arc<Mutex<Vec<PrintedImage>>> {
+        Arc::clone(&self.printed)
+    }
+
+    fn status(&self) -> u8 {
+        self.status | if self.busy_polls > 0 { STATUS_PRINTING } else { 0 }
+    }
+
+    fn receive(&mut self, byte: u8) -> u8 {
+        let mut response = 0x00;
+        self.state = match self.state {
+            State::Magic1 if byte == 0x88 => State::Magic2,
+            State::Magic1 => State::Magic1,
+            State::Magic2 if byte == 0x33 => State::Command,
+            State::Magic2 => State::Magic1,
+            State::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                self.data.clear();
+                State::Compression
+            }
+            State::Compression => {
+                self.compressed = byte & 0x01 != 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::LengthLow
+            }
+            State::LengthLow => {
+                self.length = byte as u16;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::LengthHigh
+            }
+            State::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.length == 0 { State::ChecksumLow } else { State::Data }
+            }
+            State::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.data.len() == self.length as usize { State::ChecksumLow } else { State::Data }
+            }
+            State::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                State::ChecksumHigh
+            }
+            State::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                State::KeepAlive
+            }
+            State::KeepAlive => {
+                response = 0x81;
+                State::Status
+            }
+            State::Status => {
+                self.process_packet();
+                response = self.status();
+                State::Magic1
+            }
+        };
+        response
+    }
+
+    fn process_packet(&mut self) {
+        if self.received_checksum != self.checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+        match self.command {
+            COMMAND_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.busy_polls = 0;
+            }
+            COMMAND_DATA if !self.data.is_empty() => {
+                let data = if self.compressed {
+                    decompress(&self.data)
+                } else {
+                    self.data.clone()
+                };
+                let room = BUFFER_SIZE - self.buffer.len();
+                self.buffer.extend_from_slice(&data[..data.len().min(room)]);
+                self.status |= STATUS_UNPROCESSED_DATA;
+            }
+            COMMAND_PRINT if self.data.len() >= 4 => {
+                let image = self.render(self.data[1], self.data[2], self.data[3]);
+                // Zero sheets only feeds the paper
+                if self.data[0] > 0 {
+                    self.printed.lock().unwrap().push(image);
+                }
+                self.buffer.clear();
+                self.status &= !STATUS_UNPROCESSED_DATA;
+                self.busy_polls = BUSY_POLLS;
+            }
+            COMMAND_STATUS => {
+                self.busy_polls = self.busy_polls.saturating_sub(1);
+            }
+            _ => {}
+        }
+    }
+
+    fn render(&self, margins: u8, palette: u8, exposure: u8) -> PrintedImage {
+        // A zero palette means the default 0xE4 mapping
+        let palette = if palette == 0 { 0xE4 } else { palette };
+        let top = (margins >> 4) as usize * MARGIN_UNIT_HEIGHT;
+        let bottom = (margins & 0x0F) as usize * MARGIN_UNIT_HEIGHT;
+        let tile_rows = self.buffer.len() / (TILES_PER_ROW * 16);
+        let height = top + tile_rows * 8 + bottom;
+        let mut pixels = vec![0xFF; PRINT_WIDTH * height];
+
+        // Exposure 0x40 is neutral, higher values print darker
+        let darken = (exposure & 0x7F) as i32 - 0x40;
+        for (tile, bytes) in self.buffer.chunks_exact(16).enumerate() {
+            let tile_x = (tile % TILES_PER_ROW) * 8;
+            let tile_y = top + (tile / TILES_PER_ROW) * 8;
+            for row in 0..8 {
+                let (low, high) = (bytes[row * 2], bytes[row * 2 + 1]);
+                for column in 0..8 {
+                    let bit = 7 - column;
+                    let color = ((high >> bit) & 1) << 1 | ((low >> bit) & 1);
+                    let shade = (palette >> (color * 2)) & 0b11;
+                    let gray = (GRAYS[shade as usize] as i32 - darken).clamp(0, 0xFF);
+                    pixels[(tile_y + row) * PRINT_WIDTH + tile_x + column] = gray as u8;
+                }
+            }
+        }
+
+        PrintedImage {
+            width: PRINT_WIDTH,
+            height,
+            pixels,
+            palette,
+            exposure,
+        }
+    }
+}
+
+// Printer RLE: a control byte with bit 7 set repeats the next byte
+// (control & 0x7F) + 2 times, otherwise (control + 1) literal bytes follow
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut index = 0;
+    while index < data.len() {
+        let control = data[index];
+        index += 1;
+        if control & 0x80 != 0 {
+            let count = (control & 0x7F) as usize + 2;
+            if let Some(&byte) = data.get(index) {
+                output.extend(std::iter::repeat_n(byte, count));
+            }
+            index += 1;
+        } else {
+            let count = control as usize + 1;
+            let end = (index + count).min(data.len());
+            output.extend_from_slice(&data[index..end]);
+            index = end;
+        }
+    }
+    output
+}
+
+impl LinkTransport for Printer {
+    fn exchange(&mut self, outgoing: u8) -> u8 {
+        self.receive(outgoing)
+    }
+
+    // The printer never drives the clock
+    fn poll(&mut self, _ready: bool, _outgoing: u8) -> Option<u8> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod printer_tests {
+    use super::*;
+
+    fn send_packet(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> (u8, u8) {
+        let mut bytes = vec![0x88, 0x33, command, compressed as u8, data.len() as u8, (data.len() >> 8) as u8];
+        bytes.extend_from_slice(data);
+        let checksum = bytes[2..].iter().fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        for byte in bytes {
+            assert_eq!(printer.exchange(byte), 0x00);
+        }
+        (printer.exchange(0x00), printer.exchange(0x00))
+    }
+
+    #[test]
+    fn answers_keep_alive_and_status() {
+        let mut printer = Printer::new();
+        assert_eq!(send_packet(&mut printer, COMMAND_INIT, false, &[]), (0x81, 0x00));
+    }
+    #[test]
+    fn data_marks_unprocessed() {
+        let mut printer = Printer::new();
+        send_packet(&mut printer, COMMAND_INIT, false, &[]);
+        let (_, status) = send_packet(&mut printer, COMMAND_DATA, false, &[0xFF; 640]);
+        assert_eq!(status, STATUS_UNPROCESSED_DATA);
+    }
+    #[test]
+    fn bad_checksum_sets_error() {
+        let mut printer = Printer::new();
+        for byte in [0x88, 0x33, COMMAND_STATUS, 0x00, 0x00, 0x00, 0x00, 0x00].iter() {
+            printer.exchange(*byte);
+        }
+        assert_eq!(printer.exchange(0x00), 0x81);
+        assert_eq!(printer.exchange(0x00), STATUS_CHECKSUM_ERROR);
+    }
+    #[test]
+    fn prints_buffered_tiles() {
+        let mut printer = Printer::new();
+        let printed = printer.printed();
+        send_packet(&mut printer, COMMAND_INIT, false, &[]);
+        // One tile row: a solid color 3 tile followed by 19 blank tiles
+        let data = [0x8E, 0xFF, 0xFF, 0x00, 0xFF, 0x00, 0xAC, 0x00];
+        send_packet(&mut printer, COMMAND_DATA, true, &data);
+        let (_, status) = send_packet(&mut printer, COMMAND_PRINT, false, &[0x01, 0x00, 0xE4, 0x40]);
+        assert_eq!(status & STATUS_PRINTING, STATUS_PRINTING);
+
+        let images = printed.lock().unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].height, 8);
+        assert_eq!(images[0].pixels[0], 0x00);
+        assert_eq!(images[0].pixels[8], 0xFF);
+    }
+    #[test]
+    fn busy_clears_after_status_polls() {
+        let mut printer = Printer::new();
+        send_packet(&mut printer, COMMAND_PRINT, false, &[0x01, 0x00, 0xE4, 0x40]);
+        let mut status = STATUS_PRINTING;
+        for _ in 0..BUSY_POLLS {
+            status = send_packet(&mut printer, COMMAND_STATUS, false, &[]).1;
+        }
+        assert_eq!(status & STATUS_PRINTING, 0);
+    }
+    #[test]
+    fn decompresses_runs_and_literals() {
+        assert_eq!(decompress(&[0x81, 0xAA, 0x01, 0x01, 0x02]), vec![0xAA, 0xAA, 0xAA, 0x01, 0x02]);
+    }
+}
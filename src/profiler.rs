@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+
+use crate::call_stack::CallStack;
+use crate::cpu::{CPU, CYCLES_PER_FRAME};
+use crate::symbols::Symbols;
+
+// Rows in each table of the report
+const REPORT_ROWS: usize = 30;
+
+// A bank qualified address, as bank then address
+type Location = (u16, u16);
+
+// Adds up the cycles spent at each instruction along with the chain of
+// calls that led there. Functions are the entry points of calls, or the
+// labels around the code when there are symbols.
+pub struct Profiler {
+    pub call_stack: CallStack,
+    pub frames: u64,
+    pub total_cycles: u64,
+    // Where the outermost call in progress was made from, then the entry
+    // points of every call in progress. Interned so samples can refer to
+    // them by index.
+    stacks: Vec<Vec<Location>>,
+    stack_ids: HashMap<Vec<Location>, usize>,
+    current_stack: usize,
+    // Cycles by stack and the instruction they were spent on
+    samples: HashMap<(usize, Location), u64>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        let mut stack_ids = HashMap::new();
+        stack_ids.insert(Vec::new(), 0);
+        Profiler {
+            call_stack: CallStack::new(),
+            frames: 0,
+            total_cycles: 0,
+            stacks: vec![Vec::new()],
+            stack_ids,
+            current_stack: 0,
+            samples: HashMap::new(),
+        }
+    }
+
+    // Steps the machine like CPU::step, charging the cycles to the
+    // instruction at PC
+    pub fn step(&mut self, cpu: &mut CPU) -> u32 {
+        let location = (cpu.bus.bank_at(cpu.pc), cpu.pc);
+        let cycles = self.call_stack.step(cpu);
+        // Imbalances are the debugger's business
+        self.call_stack.imbalances.clear();
+        *self.samples.entry((self.current_stack, location)).or_insert(0) += cycles as u64;
+        self.total_cycles += cycles as u64;
+        let frames = &self.call_stack.frames;
+        let root = frames.first().map(|frame| (frame.call_bank, frame.call_site));
+        let entries = root.into_iter().chain(frames.iter().map(|frame| (frame.target_bank, frame.target)));
+        if !self.stacks[self.current_stack].iter().copied().eq(entries.clone()) {
+            let stack: Vec<Location> = entries.collect();
+            self.current_stack = match self.stack_ids.get(&stack) {
+                Some(&id) => id,
+                None => {
+                    self.stacks.push(stack.clone());
+                    self.stack_ids.insert(stack, self.stacks.len() - 1);
+                    self.stacks.len() - 1
+                }
+            };
+        }
+        cycles
+    }
+
+    // Runs a frame the way CPU::run_frame does. Audio is thrown away.
+    pub fn run_frame(&mut self, cpu: &mut CPU) {
+        let mut cycles = 0;
+        cpu.bus.ppu.frame_ready = false;
+        while cycles < CYCLES_PER_FRAME && !cpu.bus.ppu.frame_ready {
+            cycles += self.step(cpu);
+        }
+        cpu.bus.apu.take_samples();
+        self.frames += 1;
+    }
+
+    // Cycles per bank qualified address
+    pub fn cycles_by_address(&self) -> HashMap<Location, u64> {
+        let mut totals = HashMap::new();
+        for (&(_, location), &cycles) in &self.samples {
+            *totals.entry(location).or_insert(0) += cycles;
+        }
+        totals
+    }
+
+    // The names of the functions from the outermost in, ending with the
+    // one the instruction is in
+    fn function_stack(&self, symbols: &Symbols, stack: usize, location: Location) -> Vec<String> {
+        let entries = &self.stacks[stack];
+        let root = entries.first().copied().unwrap_or(location);
+        let mut names = vec![symbols.function_at(root.0, root.1).unwrap_or("(root)").to_string()];
+        for &(bank, address) in entries.iter().skip(1) {
+            names.push(match symbols.function_at(bank, address) {
+                Some(name) => name.to_string(),
+                None => format!("{:02X}:{:04X}", bank, address),
+            });
+        }
+        // Code reached by jumping rather than calling is only told apart
+        // by its label
+        if let Some(function) = symbols.function_at(location.0, location.1) {
+            if names.last().map(String::as_str) != Some(function) {
+                names.push(function.to_string());
+            }
+        }
+        names
+    }
+
+    // One line per distinct stack, "outer;inner;innermost cycles", the
+    // input flamegraph.pl and speedscope take
+    pub fn folded(&self, symbols: &Symbols) -> String {
+        let mut totals: HashMap<String, u64> = HashMap::new();
+        for (&(stack, location), &cycles) in &self.samples {
+            let names = self.function_stack(symbols, stack, location).join(";");
+            *totals.entry(names).or_insert(0) += cycles;
+        }
+        let mut lines: Vec<(String, u64)> = totals.into_iter().collect();
+        lines.sort();
+        lines.iter().map(|(names, cycles)| format!("{} {}\n", names, cycles)).collect()
+    }
+
+    pub fn report(&self, symbols: &Symbols) -> String {
+        let total = self.total_cycles.max(1);
+        let share = |cycles: u64| format!("{:>10} {:>5.1}%", cycles, cycles as f64 * 100.0 / total as f64);
+        let mut out = String::new();
+        if let Some(per_frame) = self.total_cycles.checked_div(self.frames) {
+            out.push_str(&format!(
+                "{} cycles over {} frames, {} per frame ({:.1}% of {})\n",
+                self.total_cycles,
+                self.frames,
+                per_frame,
+                per_frame as f64 * 100.0 / CYCLES_PER_FRAME as f64,
+                CYCLES_PER_FRAME
+            ));
+        } else {
+            out.push_str(&format!("{} cycles\n", self.total_cycles));
+        }
+
+        // Self time goes to the innermost function, total time to every
+        // function on the stack once
+        let mut functions: HashMap<String, (u64, u64)> = HashMap::new();
+        for (&(stack, location), &cycles) in &self.samples {
+            let names = self.function_stack(symbols, stack, location);
+            for (index, name) in names.iter().enumerate() {
+                if names[..index].contains(name) {
+                    continue;
+                }
+                let entry = functions.entry(name.clone()).or_insert((0, 0));
+                entry.1 += cycles;
+                if index == names.len() - 1 {
+                    entry.0 += cycles;
+                }
+            }
+        }
+        let mut functions: Vec<(String, (u64, u64))> = functions.into_iter().collect();
+        functions.sort_by(|a, b| (b.1).0.cmp(&(a.1).0).then_with(|| a.0.cmp(&b.0)));
+        out.push_str(&format!("\n{:<32} {:>17} {:>17}\n", "function", "self", "total"));
+        for (name, (own, inclusive)) in functions.iter().take(REPORT_ROWS) {
+            out.push_str(&format!("{:<32} {} {}\n", name, share(*own), share(*inclusive)));
+        }
+
+        let mut addresses: Vec<(Location, u64)> = self.cycles_by_address().into_iter().collect();
+        addresses.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        out.push_str(&format!("\n{:<32} {:>17}\n", "address", "cycles"));
+        for ((bank, address), cycles) in addresses.iter().take(REPORT_ROWS) {
+            let label = symbols.describe(*bank, *address).unwrap_or_default();
+            let location = format!("{:02X}:{:04X} {}", bank, address, label);
+            out.push_str(&format!("{:<32} {}\n", location, share(*cycles)));
+        }
+        out
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod profiler_tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+
+    // Main calls Work forever, Work calls Inner once
+    fn machine() -> CPU {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x106].copy_from_slice(&[
+            0xCD, 0x00, 0x02, // call $0200
+            0x18, 0xFB, // jr $0100
+            0x00,
+        ]);
+        rom[0x200..0x205].copy_from_slice(&[0x00, 0xCD, 0x00, 0x03, 0xC9]); // nop, call $0300, ret
+        rom[0x300] = 0xC9; // ret
+        CPU::with_cartridge(Cartridge::new(rom).unwrap())
+    }
+
+    #[test]
+    fn charges_cycles_to_addresses_and_stacks() {
+        let mut cpu = machine();
+        let mut profiler = Profiler::new();
+        // One full pass of the loop
+        for _ in 0..6 {
+            profiler.step(&mut cpu);
+        }
+        assert_eq!(cpu.pc, 0x0100);
+        let by_address = profiler.cycles_by_address();
+        assert_eq!(by_address[&(0, 0x0100)], 24);
+        assert_eq!(by_address[&(0, 0x0300)], 16);
+        assert_eq!(profiler.total_cycles, 24 + 4 + 24 + 16 + 16 + 12);
+        let folded = profiler.folded(&Symbols::new());
+        assert_eq!(folded, "(root) 36\n(root);00:0200 44\n(root);00:0200;00:0300 16\n");
+        let symbols = Symbols::parse("00:0100 Main\n00:0200 Work\n00:0300 Inner\n").unwrap();
+        let folded = profiler.folded(&symbols);
+        assert_eq!(folded, "Main 36\nMain;Work 44\nMain;Work;Inner 16\n");
+    }
+    #[test]
+    fn reports_functions_and_frame_budget() {
+        let mut cpu = machine();
+        let mut profiler = Profiler::new();
+        profiler.run_frame(&mut cpu);
+        profiler.run_frame(&mut cpu);
+        assert_eq!(profiler.frames, 2);
+        let symbols = Symbols::parse("00:0100 Main\n00:0200 Work\n00:0300 Inner\n").unwrap();
+        let report = profiler.report(&symbols);
+        assert!(report.contains(" over 2 frames, "), "{}", report);
+        let work = report.lines().find(|line| line.starts_with("Work ")).unwrap();
+        let columns: Vec<&str> = work.split_whitespace().collect();
+        // Work's total includes the time in Inner
+        assert!(columns[3].parse::<u64>().unwrap() > columns[1].parse::<u64>().unwrap(), "{}", work);
+        assert!(report.contains("00:0100 Main"), "{}", report);
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::VecDeque;
+
+use crate::boot_rom::BootRom;
+use crate::cpu::CPU;
+use crate::save_state::SaveStateError;
+
+// Every this many snapshots a full state is kept, the ones in between
+// are stored as the difference to it
+const DELTAS_PER_KEYFRAME: usize = 15;
+
+// XOR against the keyframe, then run length coded. Most of the machine
+// does not change between snapshots so the XOR is mostly zeroes. The
+// delta is the state length followed by pairs of a zero run and a run
+// of literal bytes.
+fn encode_delta(keyframe: &[u8], state: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    write_varint(&mut delta, state.len());
+    let xor: Vec<u8> = state
+        .iter()
+        .enumerate()
+        .map(|(index, &byte)| byte ^ keyframe.get(index).copied().unwrap_or(0))
+        .collect();
+    let mut position = 0;
+    while position < xor.len() {
+        let zeroes = xor[position..].iter().take_while(|&&byte| byte == 0).count();
+        position += zeroes;
+        let literals = xor[position..].iter().take_while(|&&byte| byte != 0).count();
+        write_varint(&mut delta, zeroes);
+        write_varint(&mut delta, literals);
+        delta.extend_from_slice(&xor[position..position + literals]);
+        position += literals;
+    }
+    delta
+}
+
+fn decode_delta(keyframe: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut position = 0;
+    let length = read_varint(delta, &mut position);
+    let mut state: Vec<u8> = (0..length).map(|index| keyframe.get(index).copied().unwrap_or(0)).collect();
+    let mut offset = 0;
+    while position < delta.len() {
+        offset += read_varint(delta, &mut position);
+        let literals = read_varint(delta, &mut position);
+        for &byte in &delta[position..position + literals] {
+            state[offset] ^= byte;
+            offset += 1;
+        }
+        position += literals;
+    }
+    state
+}
+
+// Seven bits at a time, low bits first
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*position];
+        *position += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+// A keyframe and the deltas that depend on it, dropped together
+struct Group {
+    frame: u64,
+    keyframe: Vec<u8>,
+    deltas: Vec<(u64, Vec<u8>)>,
+}
+
+impl Group {
+    fn size(&self) -> usize {
+        self.keyframe.len() + self.deltas.iter().map(|(_, delta)| delta.len()).sum::<usize>()
+    }
+}
+
+// Snapshots of the machine taken every `interval` frames. Once they
+// take up more than `budget` bytes the oldest are dropped, though the
+// newest keyframe is always kept.
+pub struct Rewind {
+    pub interval: u32,
+    pub budget: usize,
+    groups: VecDeque<Group>,
+    frame: u64,
+    frames_since_snapshot: u32,
+    // States don't carry the boot ROM image and the machine drops it at
+    // the handover, snapshots taken before then need it put back
+    boot_rom: Option<BootRom>,
+}
+
+impl Rewind {
+    pub fn new(interval: u32, budget: usize) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            groups: VecDeque::new(),
+            frame: 0,
+            frames_since_snapshot: 0,
+            boot_rom: None,
+        }
+    }
+
+    // Call once per emulated frame
+    pub fn record(&mut self, cpu: &CPU) {
+        if self.groups.is_empty() || self.frames_since_snapshot >= self.interval {
+            self.snapshot(cpu);
+        }
+        self.frame += 1;
+        self.frames_since_snapshot += 1;
+    }
+
+    fn snapshot(&mut self, cpu: &CPU) {
+        if self.boot_rom.is_none() {
+            self.boot_rom = cpu.bus.boot_rom.clone();
+        }
+        let state = cpu.save_state();
+        match self.groups.back_mut() {
+            Some(group) if group.deltas.len() < DELTAS_PER_KEYFRAME => {
+                let delta = encode_delta(&group.keyframe, &state);
+                group.deltas.push((self.frame, delta));
+            }
+            _ => self.groups.push_back(Group {
+                frame: self.frame,
+                keyframe: state,
+                deltas: Vec::new(),
+            }),
+        }
+        self.frames_since_snapshot = 0;
+        while self.groups.len() > 1 && self.memory_used() > self.budget {
+            self.groups.pop_front();
+        }
+    }
+
+    // Bytes held by the snapshots
+    pub fn memory_used(&self) -> usize {
+        self.groups.iter().map(Group::size).sum()
+    }
+
+    pub fn snapshot_count(&self) -> usize {
+        self.groups.iter().map(|group| 1 + group.deltas.len()).sum()
+    }
+
+    // Goes back to the newest snapshot at least `frames` frames old, or
+    // the oldest one there is, and forgets everything after it. Returns
+    // how many frames were undone. If the snapshot can't be loaded the
+    // machine and the history are left as they were.
+    pub fn rewind(&mut self, cpu: &mut CPU, frames: u32) -> Result<u64, SaveStateError> {
+        let target = self.frame.saturating_sub(frames as u64);
+        if self.groups.is_empty() {
+            return Ok(0);
+        }
+        let index = self.groups.iter().rposition(|group| group.frame <= target).unwrap_or(0);
+        let group = &self.groups[index];
+        let kept = group.deltas.iter().take_while(|(frame, _)| *frame <= target).count();
+        let (frame, state) = match kept {
+            0 => (group.frame, group.keyframe.clone()),
+            _ => {
+                let (frame, delta) = &group.deltas[kept - 1];
+                (*frame, decode_delta(&group.keyframe, delta))
+            }
+        };
+        // A state from after the handover drops the boot ROM again
+        let mapped = cpu.bus.boot_rom.is_some();
+        if !mapped {
+            cpu.bus.boot_rom = self.boot_rom.clone();
+        }
+        if let Err(error) = cpu.load_state(&state) {
+            if !mapped {
+                cpu.bus.boot_rom = None;
+            }
+            return Err(error);
+        }
+        self.groups.truncate(index + 1);
+        self.groups[index].deltas.truncate(kept);
+        let rewound = self.frame - frame;
+        self.frame = frame;
+        self.frames_since_snapshot = 0;
+        Ok(rewound)
+    }
+}
+
+#[cfg(test)]
+mod rewind_tests {
+    use super::*;
+    use crate::boot_rom::DMG_BOOT_ROM_SIZE;
+    use crate::cartridge::Cartridge;
+    use crate::model::Model;
+
+    // Counts up in WRAM at 0xC000 forever
+    fn machine() -> CPU {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x106].copy_from_slice(&[0x21, 0x00, 0xC0, 0x34, 0x18, 0xFD]);
+        CPU::with_cartridge(Cartridge::new(rom).unwrap())
+    }
+
+    #[test]
+    fn delta_round_trips() {
+        let keyframe = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        let state = vec![1, 2, 9, 4, 5, 6, 0, 8, 10, 11];
+        let delta = encode_delta(&keyframe, &state);
+        assert_eq!(decode_delta(&keyframe, &delta), state);
+        assert_eq!(decode_delta(&keyframe, &encode_delta(&keyframe, &keyframe[..4])), &keyframe[..4]);
+        let mut long = vec![0; 300];
+        long[200] = 1;
+        assert_eq!(decode_delta(&[], &encode_delta(&[], &long)), long);
+    }
+    #[test]
+    fn rewinds_to_an_earlier_frame() {
+        let mut cpu = machine();
+        let mut rewind = Rewind::new(2, usize::MAX);
+        let mut counters = Vec::new();
+        for _ in 0..40 {
+            rewind.record(&cpu);
+            counters.push(cpu.bus.wram[0]);
+            cpu.run_frame();
+        }
+        assert_eq!(rewind.snapshot_count(), 20);
+        // Frame 40 now, 10 back lands exactly on the snapshot of frame 30
+        assert_eq!(rewind.rewind(&mut cpu, 10), Ok(10));
+        assert_eq!(cpu.bus.wram[0], counters[30]);
+        // Frame 30, the newest snapshot at or before frame 27 is 26
+        assert_eq!(rewind.rewind(&mut cpu, 3), Ok(4));
+        assert_eq!(cpu.bus.wram[0], counters[26]);
+        assert_eq!(rewind.rewind(&mut cpu, 1000), Ok(26));
+        assert_eq!(cpu.bus.wram[0], counters[0]);
+    }
+    #[test]
+    fn stays_within_budget() {
+        let mut cpu = machine();
+        let state_size = cpu.save_state().len();
+        let mut rewind = Rewind::new(1, state_size * 3);
+        for _ in 0..100 {
+            rewind.record(&cpu);
+            cpu.run_frame();
+            assert!(rewind.memory_used() <= state_size * 3);
+        }
+        assert!(rewind.snapshot_count() > DELTAS_PER_KEYFRAME);
+        let rewound = rewind.rewind(&mut cpu, 1000).unwrap();
+        assert!(rewound > 0 && rewound < 100);
+    }
+    #[test]
+    fn rewinds_into_the_boot_rom() {
+        // Hands over to the cartridge straight away
+        let mut boot = vec![0; DMG_BOOT_ROM_SIZE];
+        boot[..4].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+        let cartridge = machine().bus.cartridge;
+        let mut cpu = CPU::with_boot_rom(cartridge, Model::Dmg, BootRom::new(boot).unwrap());
+        let mut rewind = Rewind::new(1, usize::MAX);
+        for _ in 0..3 {
+            rewind.record(&cpu);
+            cpu.run_frame();
+        }
+        assert!(cpu.bus.boot_rom.is_none());
+        assert_eq!(rewind.rewind(&mut cpu, 1000), Ok(3));
+        assert!(cpu.bus.boot_rom.is_some());
+        assert_eq!(cpu.pc, 0x0000);
+        assert_eq!(rewind.rewind(&mut cpu, 0), Ok(0));
+        cpu.run_frame();
+        assert!(cpu.bus.boot_rom.is_none());
+    }
+    #[test]
+    fn failed_rewind_keeps_history() {
+        let mut boot = vec![0; DMG_BOOT_ROM_SIZE];
+        boot[..4].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+        let cartridge = machine().bus.cartridge;
+        let mut cpu = CPU::with_boot_rom(cartridge, Model::Dmg, BootRom::new(boot).unwrap());
+        let mut rewind = Rewind::new(1, usize::MAX);
+        for _ in 0..3 {
+            rewind.record(&cpu);
+            cpu.run_frame();
+        }
+        // Another cartridge, whose machine the snapshots don't fit
+        let mut rom = vec![0; 0x8000];
+        rom[0x134] = b'X';
+        let mut other = CPU::with_cartridge(Cartridge::new(rom).unwrap());
+        assert!(rewind.rewind(&mut other, 1000).is_err());
+        assert!(other.bus.boot_rom.is_none());
+        assert_eq!(rewind.snapshot_count(), 3);
+        assert_eq!(rewind.rewind(&mut cpu, 1), Ok(1));
+        assert_eq!(rewind.snapshot_count(), 3);
+    }
+}
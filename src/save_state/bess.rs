@@ -0,0 +1,577 @@
+// Best Effort Save State, the block format SameBoy and other emulators
+// append to their own state files. A footer at the very end points at
+// the first block, so whatever comes before the blocks is skipped.
+//
+// BESS describes the machine the way the game sees it: registers, memory
+// and IO registers. Timing inside a scanline, APU sequencer phase and
+// SGB state are not carried over in either direction.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{SaveStateError, StateReader, StateWriter};
+use crate::apu::Apu;
+use crate::cartridge::rtc::RtcRegisters;
+use crate::cartridge::{Cartridge, MbcKind};
+use crate::cpu::CPU;
+use crate::model::Model;
+use crate::ppu::{Mode, DRAWING_CYCLES, OAM_SCAN_CYCLES};
+
+const FOOTER: &[u8; 4] = b"BESS";
+const MAJOR_VERSION: u16 = 1;
+const MINOR_VERSION: u16 = 1;
+const RTC_SIZE: usize = 0x30;
+
+pub fn is_bess(data: &[u8]) -> bool {
+    data.len() >= 8 && data.ends_with(FOOTER)
+}
+
+// Family, model and revision, padded with spaces
+fn model_code(model: Model) -> &'static [u8; 4] {
+    match model {
+        Model::Dmg0 => b"GD0 ",
+        Model::Dmg => b"GDB ",
+        Model::Mgb => b"GM  ",
+        Model::Sgb => b"SN  ",
+        Model::Sgb2 => b"S2  ",
+        Model::Cgb => b"CCE ",
+        Model::Agb => b"CA  ",
+    }
+}
+
+fn model_for_code(code: &[u8]) -> Option<Model> {
+    match (code[0], code[1]) {
+        (b'G', b'D') if code[2] == b'0' => Some(Model::Dmg0),
+        (b'G', b'D') => Some(Model::Dmg),
+        (b'G', b'M') => Some(Model::Mgb),
+        (b'S', b'N') | (b'S', b'P') => Some(Model::Sgb),
+        (b'S', b'2') => Some(Model::Sgb2),
+        (b'C', b'A') => Some(Model::Agb),
+        (b'C', _) => Some(Model::Cgb),
+        _ => None,
+    }
+}
+
+// Appends a memory buffer and returns the size and offset the core
+// block refers to it by
+fn buffer(writer: &mut StateWriter, bytes: &[u8]) -> (u32, u32) {
+    let offset = writer.data.len() as u32;
+    writer.data.extend_from_slice(bytes);
+    (bytes.len() as u32, offset)
+}
+
+pub fn export(cpu: &CPU) -> Vec<u8> {
+    let bus = &cpu.bus;
+    let cgb = bus.model.is_cgb();
+    let (ram_size, vram_size, palette_size) = if cgb {
+        (0x8000, 0x4000, 0x40)
+    } else {
+        (0x2000, 0x2000, 0)
+    };
+
+    // Memory goes first, the core block points into it
+    let mut writer = StateWriter { data: Vec::new() };
+    let buffers = [
+        buffer(&mut writer, &bus.wram[..ram_size]),
+        buffer(&mut writer, &bus.ppu.vram[..vram_size]),
+        buffer(&mut writer, &bus.cartridge.ram),
+        buffer(&mut writer, &bus.ppu.oam),
+        buffer(&mut writer, &bus.hram),
+        buffer(&mut writer, &bus.ppu.bg_palettes.data[..palette_size]),
+        buffer(&mut writer, &bus.ppu.obj_palettes.data[..palette_size]),
+    ];
+    let first_block = writer.data.len() as u32;
+
+    writer.section(b"NAME", |w| {
+        w.data
+            .extend_from_slice(concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")).as_bytes());
+    });
+    writer.section(b"INFO", |w| {
+        let rom = bus.cartridge.rom();
+        w.data.extend_from_slice(&rom[0x134..0x144]);
+        w.data.extend_from_slice(&rom[0x14E..0x150]);
+    });
+    writer.section(b"CORE", |w| {
+        w.u16(MAJOR_VERSION);
+        w.u16(MINOR_VERSION);
+        w.data.extend_from_slice(model_code(bus.model));
+        w.u16(cpu.pc);
+        w.u16(cpu.registers.get_af());
+        w.u16(cpu.registers.get_bc());
+        w.u16(cpu.registers.get_de());
+        w.u16(cpu.registers.get_hl());
+        w.u16(cpu.sp);
+        w.bool(cpu.ime);
+        w.u8(bus.interrupt_enable);
+        w.bool(cpu.is_halted);
+        w.u8(0);
+        w.data.extend_from_slice(&io_registers(cpu));
+        for &(size, offset) in buffers.iter() {
+            w.u32(size);
+            w.u32(offset);
+        }
+    });
+    let mbc_writes = mbc_writes(&bus.cartridge);
+    if !mbc_writes.is_empty() {
+        writer.section(b"MBC ", |w| {
+            for &(address, value) in mbc_writes.iter() {
+                w.u16(address);
+                w.u8(value);
+            }
+        });
+    }
+    if let Some(rtc) = &bus.cartridge.rtc {
+        writer.section(b"RTC ", |w| {
+            save_rtc_registers(w, &rtc.live);
+            save_rtc_registers(w, &rtc.latched);
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs());
+            w.data.extend_from_slice(&now.to_le_bytes());
+        });
+    }
+    writer.section(b"END ", |_| {});
+
+    writer.u32(first_block);
+    writer.data.extend_from_slice(FOOTER);
+    writer.data
+}
+
+// 0xFF00-0xFF7F as the game would want them written back
+fn io_registers(cpu: &CPU) -> [u8; 0x80] {
+    let bus = &cpu.bus;
+    let mut io = [0xFF; 0x80];
+    for (offset, value) in io.iter_mut().enumerate() {
+        *value = bus.read_byte(0xFF00 + offset as u16);
+    }
+    sound_registers(&bus.apu, &mut io);
+    if bus.cgb_mode {
+        io[0x51] = (bus.hdma.source >> 8) as u8;
+        io[0x52] = bus.hdma.source as u8;
+        io[0x53] = (bus.hdma.destination >> 8) as u8;
+        io[0x54] = bus.hdma.destination as u8;
+    }
+    io
+}
+
+// Lengths and frequencies are write-only and read back as 1s, so they
+// come from the channels instead
+fn sound_registers(apu: &Apu, io: &mut [u8; 0x80]) {
+    for &(base, channel) in [(0x10, &apu.channel1), (0x15, &apu.channel2)].iter() {
+        io[base + 1] = channel.duty << 6 | (64u16.wrapping_sub(channel.length.counter) as u8 & 0x3F);
+        io[base + 3] = channel.frequency as u8;
+        io[base + 4] = (channel.length.enabled as u8) << 6 | (channel.frequency >> 8) as u8 & 0x07;
+    }
+    let wave = &apu.channel3;
+    io[0x1B] = 256u16.wrapping_sub(wave.length.counter) as u8;
+    io[0x1D] = wave.frequency as u8;
+    io[0x1E] = (wave.length.enabled as u8) << 6 | (wave.frequency >> 8) as u8 & 0x07;
+    let noise = &apu.channel4;
+    io[0x20] = 64u16.wrapping_sub(noise.length.counter) as u8 & 0x3F;
+    io[0x23] = (noise.length.enabled as u8) << 6;
+}
+
+// Register writes that put the mapper back in its current state
+fn mbc_writes(cartridge: &Cartridge) -> Vec<(u16, u8)> {
+    let ram_enable = if cartridge.ram_enabled { 0x0A } else { 0x00 };
+    let rom_bank = cartridge.rom_bank;
+    match cartridge.kind {
+        MbcKind::None => Vec::new(),
+        MbcKind::Mbc1 => vec![
+            (0x0000, ram_enable),
+            (0x2000, rom_bank as u8 & 0x1F),
+            (0x4000, cartridge.ram_bank),
+            (0x6000, cartridge.banking_mode as u8),
+        ],
+        MbcKind::Mbc2 => vec![(0x0000, ram_enable), (0x0100, rom_bank as u8)],
+        MbcKind::Mbc3 => vec![
+            (0x0000, ram_enable),
+            (0x2000, rom_bank as u8),
+            (0x4000, cartridge.ram_bank),
+        ],
+        MbcKind::Mbc5 => vec![
+            (0x0000, ram_enable),
+            (0x2000, rom_bank as u8),
+            (0x3000, (rom_bank >> 8) as u8),
+            (0x4000, cartridge.ram_bank),
+        ],
+    }
+}
+
+// Each register takes four bytes
+fn save_rtc_registers(w: &mut StateWriter, registers: &RtcRegisters) {
+    let day_high =
+        (registers.days >> 8) as u8 & 0x01 | (registers.halted as u8) << 6 | (registers.day_carry as u8) << 7;
+    for &value in [
+        registers.seconds,
+        registers.minutes,
+        registers.hours,
+        registers.days as u8,
+        day_high,
+    ]
+    .iter()
+    {
+        w.u32(value as u32);
+    }
+}
+
+fn load_rtc_registers(bytes: &[u8], registers: &mut RtcRegisters) {
+    registers.seconds = bytes[0];
+    registers.minutes = bytes[4];
+    registers.hours = bytes[8];
+    let day_high = bytes[16];
+    registers.days = bytes[12] as u16 | (day_high as u16 & 0x01) << 8;
+    registers.halted = day_high & 0x40 != 0;
+    registers.day_carry = day_high & 0x80 != 0;
+}
+
+// Everything the blocks say, checked before the machine is touched
+struct Core<'a> {
+    model: Model,
+    pc: u16,
+    af: u16,
+    bc: u16,
+    de: u16,
+    hl: u16,
+    sp: u16,
+    ime: bool,
+    interrupt_enable: u8,
+    halted: bool,
+    io: &'a [u8],
+    ram: &'a [u8],
+    vram: &'a [u8],
+    mbc_ram: &'a [u8],
+    oam: &'a [u8],
+    hram: &'a [u8],
+    bg_palettes: &'a [u8],
+    obj_palettes: &'a [u8],
+}
+
+fn buffer_slice<'a>(data: &'a [u8], r: &mut StateReader) -> Result<&'a [u8], SaveStateError> {
+    let size = r.u32()? as usize;
+    let offset = r.u32()? as usize;
+    let end = offset.checked_add(size).ok_or(SaveStateError::Truncated)?;
+    data.get(offset..end).ok_or(SaveStateError::Truncated)
+}
+
+fn read_core<'a>(data: &'a [u8], block: &'a [u8]) -> Result<Core<'a>, SaveStateError> {
+    let mut r = StateReader::new(block);
+    let major = r.u16()?;
+    if major != MAJOR_VERSION {
+        return Err(SaveStateError::UnsupportedVersion(major));
+    }
+    let _minor = r.u16()?;
+    let model = model_for_code(r.take(4)?).ok_or(SaveStateError::Invalid("model"))?;
+    let registers = [r.u16()?, r.u16()?, r.u16()?, r.u16()?, r.u16()?, r.u16()?];
+    let ime = r.bool()?;
+    let interrupt_enable = r.u8()?;
+    // Running, halted or stopped. STOP is treated as HALT.
+    let halted = r.u8()? != 0;
+    let _reserved = r.u8()?;
+    Ok(Core {
+        model,
+        pc: registers[0],
+        af: registers[1],
+        bc: registers[2],
+        de: registers[3],
+        hl: registers[4],
+        sp: registers[5],
+        ime,
+        interrupt_enable,
+        halted,
+        io: r.take(0x80)?,
+        ram: buffer_slice(data, &mut r)?,
+        vram: buffer_slice(data, &mut r)?,
+        mbc_ram: buffer_slice(data, &mut r)?,
+        oam: buffer_slice(data, &mut r)?,
+        hram: buffer_slice(data, &mut r)?,
+        bg_palettes: buffer_slice(data, &mut r)?,
+        obj_palettes: buffer_slice(data, &mut r)?,
+    })
+}
+
+pub fn import(cpu: &mut CPU, data: &[u8]) -> Result<(), SaveStateError> {
+    if !is_bess(data) {
+        return Err(SaveStateError::NotASaveState);
+    }
+    let footer = data.len() - 8;
+    let first_block = StateReader::new(&data[footer..]).u32()? as usize;
+    let mut r = StateReader::new(data.get(first_block..footer).ok_or(SaveStateError::Truncated)?);
+    let mut blocks = Vec::new();
+    while !r.is_empty() {
+        let tag = r.take(4)?;
+        let block = r.bytes()?;
+        if tag == b"END " {
+            break;
+        }
+        blocks.push((tag, block));
+    }
+    let block = |tag: &[u8; 4]| {
+        blocks
+            .iter()
+            .find(|(found, _)| *found == &tag[..])
+            .map(|&(_, block)| block)
+    };
+
+    let core = read_core(
+        data,
+        block(b"CORE").ok_or_else(|| SaveStateError::MissingSection("CORE".to_string()))?,
+    )?;
+    if let Some(info) = block(b"INFO") {
+        let rom = cpu.bus.cartridge.rom();
+        if info.len() < 0x12 || info[..0x10] != rom[0x134..0x144] || info[0x10..0x12] != rom[0x14E..0x150] {
+            let title = info.get(..0x10).unwrap_or(info);
+            return Err(SaveStateError::RomMismatch {
+                state: String::from_utf8_lossy(title).trim_end_matches('\0').to_string(),
+                loaded: cpu.bus.cartridge.header.title.clone(),
+            });
+        }
+    }
+    let model = cpu.bus.model;
+    if core.model.is_cgb() != model.is_cgb() || core.model.is_sgb() != model.is_sgb() {
+        return Err(SaveStateError::ModelMismatch {
+            state: core.model,
+            loaded: model,
+        });
+    }
+    let mbc = block(b"MBC ").unwrap_or(&[]);
+    if mbc.len() % 3 != 0 {
+        return Err(SaveStateError::Invalid("MBC block"));
+    }
+    let rtc = block(b"RTC ");
+    if rtc.is_some_and(|rtc| rtc.len() != RTC_SIZE) {
+        return Err(SaveStateError::Invalid("RTC block"));
+    }
+
+    apply_core(cpu, &core);
+    for write in mbc.chunks(3) {
+        let address = write[0] as u16 | (write[1] as u16) << 8;
+        match address {
+            0x0000..=0x7FFF => cpu.bus.cartridge.write_rom(address, write[2]),
+            0xA000..=0xBFFF => cpu.bus.cartridge.write_ram(address, write[2]),
+            _ => {}
+        }
+    }
+    // The timestamp is ignored, the clock runs on emulated time
+    if let (Some(bytes), Some(rtc)) = (rtc, cpu.bus.cartridge.rtc.as_mut()) {
+        load_rtc_registers(&bytes[..20], &mut rtc.live);
+        load_rtc_registers(&bytes[20..40], &mut rtc.latched);
+    }
+    Ok(())
+}
+
+// Buffers of another size are copied as far as both go
+fn copy_prefix(destination: &mut [u8], source: &[u8]) {
+    let length = destination.len().min(source.len());
+    destination[..length].copy_from_slice(&source[..length]);
+}
+
+fn apply_core(cpu: &mut CPU, core: &Core) {
+    cpu.registers.set_af(core.af);
+    cpu.registers.set_bc(core.bc);
+    cpu.registers.set_de(core.de);
+    cpu.registers.set_hl(core.hl);
+    cpu.pc = core.pc;
+    cpu.sp = core.sp;
+    cpu.ime = core.ime;
+    cpu.ime_scheduled = false;
+    cpu.is_halted = core.halted;
+
+    let bus = &mut cpu.bus;
+    let io = core.io;
+    // BESS has no boot ROM state, an imported state runs the cartridge
+    bus.boot_rom = None;
+    bus.interrupt_enable = core.interrupt_enable;
+    bus.interrupt_flag = io[0x0F] & 0x1F;
+    bus.stall_cycles = 0;
+    bus.speed_switch_cycles = 0;
+    bus.double_speed_carry = 0;
+    copy_prefix(&mut bus.wram, core.ram);
+    copy_prefix(&mut bus.ppu.vram, core.vram);
+    copy_prefix(&mut bus.cartridge.ram, core.mbc_ram);
+    copy_prefix(&mut bus.ppu.oam, core.oam);
+    copy_prefix(&mut bus.hram, core.hram);
+
+    bus.joypad.write(io[0x00]);
+    bus.serial.data = io[0x01];
+    bus.serial.control = io[0x02] | 0x7E;
+    bus.serial.cycles = 0;
+    bus.timer.divider = (io[0x04] as u16) << 8;
+    bus.timer.tima = io[0x05];
+    bus.timer.tma = io[0x06];
+    bus.timer.tac = io[0x07] | 0xF8;
+
+    apply_sound_registers(&mut bus.apu, io);
+
+    let ppu = &mut bus.ppu;
+    ppu.lcdc = io[0x40];
+    ppu.stat = io[0x41] & 0x78;
+    ppu.scy = io[0x42];
+    ppu.scx = io[0x43];
+    ppu.ly = io[0x44];
+    ppu.lyc = io[0x45];
+    ppu.bgp = io[0x47];
+    ppu.obp0 = io[0x48];
+    ppu.obp1 = io[0x49];
+    ppu.wy = io[0x4A];
+    ppu.wx = io[0x4B];
+    // Only the mode is known, each one resumes from its start
+    let (mode, line_cycles) = match io[0x41] & 0x03 {
+        _ if !ppu.lcd_enabled() => (Mode::HBlank, 0),
+        0 => (Mode::HBlank, OAM_SCAN_CYCLES + DRAWING_CYCLES),
+        1 => (Mode::VBlank, 0),
+        2 => (Mode::OamScan, 0),
+        _ => (Mode::Drawing, OAM_SCAN_CYCLES),
+    };
+    ppu.mode = mode;
+    ppu.line_cycles = line_cycles;
+    ppu.window_line = 0;
+    ppu.frame_ready = false;
+    ppu.hblank_started = false;
+    ppu.update_stat_line();
+
+    if bus.model.is_cgb() {
+        copy_prefix(&mut ppu.bg_palettes.data, core.bg_palettes);
+        copy_prefix(&mut ppu.obj_palettes.data, core.obj_palettes);
+        ppu.bg_palettes.write_index(io[0x68]);
+        ppu.obj_palettes.write_index(io[0x6A]);
+    }
+    if bus.cgb_mode {
+        ppu.vram_bank = io[0x4F] & 0x01;
+        bus.double_speed = io[0x4D] & 0x80 != 0;
+        bus.speed_switch_armed = io[0x4D] & 0x01 != 0;
+        bus.wram_bank = (io[0x70] & 0x07).max(1);
+        bus.hdma.source = (io[0x51] as u16) << 8 | (io[0x52] & 0xF0) as u16;
+        bus.hdma.destination = ((io[0x53] & 0x1F) as u16) << 8 | (io[0x54] & 0xF0) as u16;
+        // HDMA5 reads back with bit 7 clear while an HBlank transfer runs
+        bus.hdma.blocks = (io[0x55] & 0x7F) + 1;
+        bus.hdma.hblank_active = io[0x55] & 0x80 == 0;
+    }
+}
+
+// Goes through the registers with the trigger bits cleared, then sets
+// what only a trigger would have
+fn apply_sound_registers(apu: &mut Apu, io: &[u8]) {
+    apu.write(0xFF26, 0x00);
+    apu.write(0xFF26, io[0x26] & 0x80);
+    for address in 0xFF10..=0xFF25u16 {
+        let value = io[address as usize - 0xFF00];
+        let value = match address {
+            0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => value & 0x7F,
+            _ => value,
+        };
+        apu.write(address, value);
+    }
+    apu.channel3.wave_ram.copy_from_slice(&io[0x30..0x40]);
+    let status = io[0x26];
+    apu.channel1.enabled = status & 0x01 != 0;
+    apu.channel2.enabled = status & 0x02 != 0;
+    apu.channel3.enabled = status & 0x04 != 0;
+    apu.channel4.enabled = status & 0x08 != 0;
+    apu.channel1.envelope.volume = apu.channel1.envelope.register >> 4;
+    apu.channel2.envelope.volume = apu.channel2.envelope.register >> 4;
+    apu.channel4.envelope.volume = apu.channel4.envelope.register >> 4;
+}
+
+#[cfg(test)]
+mod bess_tests {
+    use super::*;
+    use crate::cartridge::MbcKind;
+
+    fn machine(title: &[u8], model: Model) -> CPU {
+        let mut rom = vec![0; 0x10000];
+        rom[0x100..0x106].copy_from_slice(&[0x21, 0x00, 0xC0, 0x34, 0x18, 0xFD]);
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        CPU::with_model(Cartridge::with_mapper(rom, MbcKind::Mbc5, 0x2000), model)
+    }
+
+    #[test]
+    fn round_trip_keeps_registers_and_memory() {
+        let mut cpu = machine(b"COUNTER", Model::Cgb);
+        cpu.run_frame();
+        cpu.bus.write_byte(0x2000, 0x02);
+        cpu.bus.cartridge.ram[0x10] = 0x42;
+        cpu.bus.write_byte(0xFF12, 0xA3);
+        cpu.bus.write_byte(0xFF13, 0x34);
+        cpu.bus.write_byte(0xFF14, 0x45);
+        cpu.bus.wram[0x5000] = 0x99;
+        cpu.bus.ppu.bg_palettes.data[3] = 0x12;
+        let state = export(&cpu);
+        assert!(is_bess(&state));
+
+        let mut restored = machine(b"COUNTER", Model::Cgb);
+        import(&mut restored, &state).unwrap();
+        assert_eq!(restored.pc, cpu.pc);
+        assert_eq!(restored.registers.get_hl(), 0xC000);
+        assert_eq!(restored.bus.wram[..], cpu.bus.wram[..]);
+        assert_eq!(restored.bus.wram[0x5000], 0x99);
+        assert_eq!(restored.bus.cartridge.rom_bank, 2);
+        assert_eq!(restored.bus.cartridge.ram[0x10], 0x42);
+        assert_eq!(restored.bus.ppu.bg_palettes.data[3], 0x12);
+        assert_eq!(restored.bus.ppu.ly, cpu.bus.ppu.ly);
+        assert_eq!(restored.bus.apu.channel1.frequency, 0x534);
+        assert_eq!(restored.bus.apu.channel1.envelope.register, 0xA3);
+        assert!(restored.bus.apu.channel1.length.enabled);
+        assert_eq!(restored.bus.timer.divider >> 8, cpu.bus.timer.divider >> 8);
+    }
+    #[test]
+    fn skips_data_before_the_blocks() {
+        let cpu = machine(b"COUNTER", Model::Dmg);
+        let mut state = vec![0xEE; 100];
+        let bess = export(&cpu);
+        // Offsets are from the start of the file
+        let footer = bess.len() - 8;
+        let first_block = u32::from_le_bytes([bess[footer], bess[footer + 1], bess[footer + 2], bess[footer + 3]]);
+        let mut core = bess.clone();
+        let core_start = core.windows(4).position(|tag| tag == b"CORE").unwrap() + 8;
+        for index in 0..7 {
+            let position = core_start + 0x98 + index * 8 + 4;
+            let offset = u32::from_le_bytes([
+                core[position],
+                core[position + 1],
+                core[position + 2],
+                core[position + 3],
+            ]);
+            core[position..position + 4].copy_from_slice(&(offset + 100).to_le_bytes());
+        }
+        core[footer..footer + 4].copy_from_slice(&(first_block + 100).to_le_bytes());
+        state.extend_from_slice(&core);
+
+        let mut restored = machine(b"COUNTER", Model::Dmg);
+        restored.pc = 0;
+        import(&mut restored, &state).unwrap();
+        assert_eq!(restored.pc, 0x0100);
+    }
+    #[test]
+    fn refuses_other_rom() {
+        let state = export(&machine(b"COUNTER", Model::Dmg));
+        let error = import(&mut machine(b"OTHER", Model::Dmg), &state).unwrap_err();
+        assert_eq!(
+            error,
+            SaveStateError::RomMismatch {
+                state: "COUNTER".to_string(),
+                loaded: "OTHER".to_string()
+            }
+        );
+    }
+    #[test]
+    fn refuses_other_family() {
+        let state = export(&machine(b"COUNTER", Model::Dmg));
+        assert!(import(&mut machine(b"COUNTER", Model::Mgb), &state).is_ok());
+        assert_eq!(
+            import(&mut machine(b"COUNTER", Model::Cgb), &state),
+            Err(SaveStateError::ModelMismatch {
+                state: Model::Dmg,
+                loaded: Model::Cgb
+            })
+        );
+    }
+    #[test]
+    fn load_state_accepts_bess() {
+        let mut cpu = machine(b"COUNTER", Model::Dmg);
+        cpu.pc = 0x0123;
+        let state = export(&cpu);
+        let mut restored = machine(b"COUNTER", Model::Dmg);
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.pc, 0x0123);
+    }
+}
@@ -0,0 +1,965 @@
+pub mod bess;
+
+use std::fmt;
+
+use crate::apu::envelope::{Envelope, LengthCounter};
+use crate::apu::noise_channel::NoiseChannel;
+use crate::apu::square_channel::SquareChannel;
+use crate::apu::wave_channel::WaveChannel;
+use crate::apu::Apu;
+use crate::cartridge::rtc::RtcRegisters;
+use crate::cartridge::Cartridge;
+use crate::cpu::flags_register::FlagsRegister;
+use crate::cpu::CPU;
+use crate::model::Model;
+use crate::png::crc32;
+use crate::ppu::{Mode, PaletteRam, Ppu};
+use crate::sgb::{Mask, Sgb, Transfer};
+
+// A state file is a header followed by sections, each a four byte tag
+// and a length. Readers skip sections they do not know and ignore bytes
+// past the fields they read, so later versions can add to the format.
+const MAGIC: &[u8; 4] = b"GBSS";
+pub const VERSION: u16 = 1;
+
+#[derive(Debug, PartialEq)]
+pub enum SaveStateError {
+    NotASaveState,
+    UnsupportedVersion(u16),
+    RomMismatch { state: String, loaded: String },
+    ModelMismatch { state: Model, loaded: Model },
+    BootRomMissing,
+    MissingSection(String),
+    Truncated,
+    Invalid(&'static str),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveStateError::NotASaveState => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(version) => write!(
+                f,
+                "save state version {} is newer than the supported version {}",
+                version, VERSION
+            ),
+            SaveStateError::RomMismatch { state, loaded } => write!(
+                f,
+                "save state belongs to ROM \"{}\", but \"{}\" is loaded",
+                state, loaded
+            ),
+            SaveStateError::ModelMismatch { state, loaded } => write!(
+                f,
+                "save state was made on model {}, but the machine is {}",
+                state, loaded
+            ),
+            SaveStateError::BootRomMissing => {
+                write!(f, "save state was made inside the boot ROM, but none is loaded")
+            }
+            SaveStateError::MissingSection(tag) => write!(f, "save state has no {} section", tag),
+            SaveStateError::Truncated => write!(f, "save state is truncated"),
+            SaveStateError::Invalid(what) => write!(f, "save state has an invalid {}", what),
+        }
+    }
+}
+
+impl std::error::Error for SaveStateError {}
+
+struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn i32(&mut self, value: i32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Length prefixed
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+
+    fn words(&mut self, words: &[u16]) {
+        self.u32(words.len() as u32);
+        for &word in words {
+            self.u16(word);
+        }
+    }
+
+    fn section<F: FnOnce(&mut StateWriter)>(&mut self, tag: &[u8; 4], write: F) {
+        let mut section = StateWriter { data: Vec::new() };
+        write(&mut section);
+        self.data.extend_from_slice(tag);
+        self.bytes(&section.data);
+    }
+}
+
+struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, position: 0 }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], SaveStateError> {
+        let end = self.position.checked_add(length).ok_or(SaveStateError::Truncated)?;
+        let bytes = self.data.get(self.position..end).ok_or(SaveStateError::Truncated)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position == self.data.len()
+    }
+
+    fn u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, SaveStateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    fn u16(&mut self) -> Result<u16, SaveStateError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, SaveStateError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn i32(&mut self) -> Result<i32, SaveStateError> {
+        Ok(self.u32()? as i32)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], SaveStateError> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+
+    // Fills a buffer whose size the machine fixes, `what` names it in
+    // the error when the sizes differ
+    fn bytes_into(&mut self, buffer: &mut [u8], what: &'static str) -> Result<(), SaveStateError> {
+        let bytes = self.bytes()?;
+        if bytes.len() != buffer.len() {
+            return Err(SaveStateError::Invalid(what));
+        }
+        buffer.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn words_into(&mut self, buffer: &mut [u16], what: &'static str) -> Result<(), SaveStateError> {
+        let length = self.u32()? as usize;
+        if length != buffer.len() {
+            return Err(SaveStateError::Invalid(what));
+        }
+        for word in buffer.iter_mut() {
+            *word = self.u16()?;
+        }
+        Ok(())
+    }
+}
+
+// Identifies the ROM a state belongs to
+struct RomId {
+    title: String,
+    crc: u32,
+}
+
+impl RomId {
+    fn of(cartridge: &Cartridge) -> RomId {
+        RomId {
+            title: cartridge.header.title.clone(),
+            crc: crc32(cartridge.rom()),
+        }
+    }
+}
+
+pub fn save_state(cpu: &CPU) -> Vec<u8> {
+    let mut writer = StateWriter { data: Vec::new() };
+    let rom = RomId::of(&cpu.bus.cartridge);
+    writer.data.extend_from_slice(MAGIC);
+    writer.u16(VERSION);
+    writer.u8(model_index(cpu.bus.model));
+    writer.bytes(rom.title.as_bytes());
+    writer.u32(rom.crc);
+
+    writer.section(b"CPU ", |w| save_cpu(w, cpu));
+    writer.section(b"BUS ", |w| save_bus(w, cpu));
+    writer.section(b"CART", |w| save_cartridge(w, &cpu.bus.cartridge));
+    writer.section(b"PPU ", |w| save_ppu(w, &cpu.bus.ppu));
+    writer.section(b"APU ", |w| save_apu(w, &cpu.bus.apu));
+    if let Some(sgb) = &cpu.bus.sgb {
+        writer.section(b"SGB ", |w| save_sgb(w, sgb));
+    }
+    writer.data
+}
+
+// Checks the header before touching the machine. If a section turns
+// out to be damaged the machine is put back the way it was. BESS files
+// from other emulators are recognised by their footer.
+pub fn load_state(cpu: &mut CPU, data: &[u8]) -> Result<(), SaveStateError> {
+    if !data.starts_with(MAGIC) && bess::is_bess(data) {
+        return bess::import(cpu, data);
+    }
+    let sections = read_header(cpu, data)?;
+    let backup = save_state(cpu);
+    // Loading the bus drops the boot ROM, which a backup made inside it
+    // needs back
+    let boot_rom = cpu.bus.boot_rom.clone();
+    let result = load_sections(cpu, &sections);
+    if result.is_err() {
+        cpu.bus.boot_rom = boot_rom;
+        restore(cpu, &backup);
+    }
+    result
+}
+
+// The backup is the machine's own state with its own boot ROM in place,
+// which passes every check the loaders make
+fn restore(cpu: &mut CPU, backup: &[u8]) {
+    if let Ok(sections) = read_header(cpu, backup) {
+        let _ = load_sections(cpu, &sections);
+    }
+}
+
+type Sections<'a> = Vec<([u8; 4], &'a [u8])>;
+
+fn read_header<'a>(cpu: &CPU, data: &'a [u8]) -> Result<Sections<'a>, SaveStateError> {
+    let mut reader = StateReader::new(data);
+    if reader.take(4).ok() != Some(&MAGIC[..]) {
+        return Err(SaveStateError::NotASaveState);
+    }
+    let version = reader.u16()?;
+    if version > VERSION {
+        return Err(SaveStateError::UnsupportedVersion(version));
+    }
+    let model = *Model::ALL
+        .get(reader.u8()? as usize)
+        .ok_or(SaveStateError::Invalid("model"))?;
+    let title = String::from_utf8_lossy(reader.bytes()?).into_owned();
+    let crc = reader.u32()?;
+
+    let rom = RomId::of(&cpu.bus.cartridge);
+    if crc != rom.crc {
+        return Err(SaveStateError::RomMismatch {
+            state: title,
+            loaded: rom.title,
+        });
+    }
+    if model != cpu.bus.model {
+        return Err(SaveStateError::ModelMismatch {
+            state: model,
+            loaded: cpu.bus.model,
+        });
+    }
+
+    let mut sections = Vec::new();
+    while !reader.is_empty() {
+        let mut tag = [0; 4];
+        tag.copy_from_slice(reader.take(4)?);
+        sections.push((tag, reader.bytes()?));
+    }
+    Ok(sections)
+}
+
+fn section<'a>(sections: &Sections<'a>, tag: &[u8; 4]) -> Result<StateReader<'a>, SaveStateError> {
+    sections
+        .iter()
+        .find(|(found, _)| found == tag)
+        .map(|(_, data)| StateReader::new(data))
+        .ok_or_else(|| SaveStateError::MissingSection(String::from_utf8_lossy(tag).trim().to_string()))
+}
+
+fn load_sections(cpu: &mut CPU, sections: &Sections) -> Result<(), SaveStateError> {
+    load_cpu(&mut section(sections, b"CPU ")?, cpu)?;
+    load_bus(&mut section(sections, b"BUS ")?, cpu)?;
+    load_cartridge(&mut section(sections, b"CART")?, &mut cpu.bus.cartridge)?;
+    load_ppu(&mut section(sections, b"PPU ")?, &mut cpu.bus.ppu)?;
+    load_apu(&mut section(sections, b"APU ")?, &mut cpu.bus.apu)?;
+    if let Some(sgb) = &mut cpu.bus.sgb {
+        load_sgb(&mut section(sections, b"SGB ")?, sgb)?;
+    }
+    Ok(())
+}
+
+fn model_index(model: Model) -> u8 {
+    Model::ALL.iter().position(|&m| m == model).unwrap() as u8
+}
+
+fn save_cpu(w: &mut StateWriter, cpu: &CPU) {
+    let registers = &cpu.registers;
+    for &register in [registers.a, registers.b, registers.c, registers.d, registers.e].iter() {
+        w.u8(register);
+    }
+    w.u8(u8::from(registers.f));
+    w.u8(registers.h);
+    w.u8(registers.l);
+    w.u16(cpu.pc);
+    w.u16(cpu.sp);
+    w.bool(cpu.ime);
+    w.bool(cpu.ime_scheduled);
+    w.bool(cpu.is_halted);
+}
+
+fn load_cpu(r: &mut StateReader, cpu: &mut CPU) -> Result<(), SaveStateError> {
+    let registers = &mut cpu.registers;
+    registers.a = r.u8()?;
+    registers.b = r.u8()?;
+    registers.c = r.u8()?;
+    registers.d = r.u8()?;
+    registers.e = r.u8()?;
+    registers.f = FlagsRegister::from(r.u8()?);
+    registers.h = r.u8()?;
+    registers.l = r.u8()?;
+    cpu.pc = r.u16()?;
+    cpu.sp = r.u16()?;
+    cpu.ime = r.bool()?;
+    cpu.ime_scheduled = r.bool()?;
+    cpu.is_halted = r.bool()?;
+    Ok(())
+}
+
+// Everything on the bus short of the larger components, which get
+// sections of their own
+fn save_bus(w: &mut StateWriter, cpu: &CPU) {
+    let bus = &cpu.bus;
+    w.bool(bus.boot_rom.is_some());
+    w.u8(bus.key0);
+    w.bool(bus.cgb_mode);
+    w.bytes(&bus.wram);
+    w.u8(bus.wram_bank);
+    w.bytes(&bus.hram);
+    w.u8(bus.interrupt_flag);
+    w.u8(bus.interrupt_enable);
+    w.bool(bus.double_speed);
+    w.bool(bus.speed_switch_armed);
+    w.u32(bus.stall_cycles);
+    w.u32(bus.speed_switch_cycles);
+    w.u32(bus.double_speed_carry);
+
+    w.u16(bus.timer.divider);
+    w.u8(bus.timer.tima);
+    w.u8(bus.timer.tma);
+    w.u8(bus.timer.tac);
+
+    w.u8(bus.joypad.select);
+    w.u8(bus.joypad.directions);
+    w.u8(bus.joypad.actions);
+
+    w.u8(bus.serial.data);
+    w.u8(bus.serial.control);
+    w.u32(bus.serial.cycles);
+
+    w.u16(bus.hdma.source);
+    w.u16(bus.hdma.destination);
+    w.u8(bus.hdma.blocks);
+    w.bool(bus.hdma.hblank_active);
+}
+
+fn load_bus(r: &mut StateReader, cpu: &mut CPU) -> Result<(), SaveStateError> {
+    let bus = &mut cpu.bus;
+    // The boot ROM image is not part of the state, a state made after
+    // the handover simply drops it
+    if r.bool()? {
+        if bus.boot_rom.is_none() {
+            return Err(SaveStateError::BootRomMissing);
+        }
+    } else {
+        bus.boot_rom = None;
+    }
+    bus.key0 = r.u8()?;
+    bus.cgb_mode = r.bool()?;
+    r.bytes_into(&mut bus.wram, "WRAM size")?;
+    bus.wram_bank = r.u8()?;
+    if bus.wram_bank > 7 {
+        return Err(SaveStateError::Invalid("WRAM bank"));
+    }
+    r.bytes_into(&mut bus.hram, "HRAM size")?;
+    bus.interrupt_flag = r.u8()?;
+    bus.interrupt_enable = r.u8()?;
+    bus.double_speed = r.bool()?;
+    bus.speed_switch_armed = r.bool()?;
+    bus.stall_cycles = r.u32()?;
+    bus.speed_switch_cycles = r.u32()?;
+    bus.double_speed_carry = r.u32()?;
+
+    bus.timer.divider = r.u16()?;
+    bus.timer.tima = r.u8()?;
+    bus.timer.tma = r.u8()?;
+    bus.timer.tac = r.u8()?;
+
+    bus.joypad.select = r.u8()?;
+    bus.joypad.directions = r.u8()?;
+    bus.joypad.actions = r.u8()?;
+
+    bus.serial.data = r.u8()?;
+    bus.serial.control = r.u8()?;
+    bus.serial.cycles = r.u32()?;
+
+    bus.hdma.source = r.u16()?;
+    bus.hdma.destination = r.u16()?;
+    bus.hdma.blocks = r.u8()?;
+    bus.hdma.hblank_active = r.bool()?;
+    Ok(())
+}
+
+fn save_cartridge(w: &mut StateWriter, cartridge: &Cartridge) {
+    w.bytes(&cartridge.ram);
+    w.bool(cartridge.ram_enabled);
+    w.u16(cartridge.rom_bank);
+    w.u8(cartridge.ram_bank);
+    w.bool(cartridge.banking_mode);
+    w.bool(cartridge.rtc.is_some());
+    if let Some(rtc) = &cartridge.rtc {
+        save_rtc_registers(w, &rtc.live);
+        save_rtc_registers(w, &rtc.latched);
+        w.u32(rtc.cycles);
+        w.bool(rtc.latch_armed);
+    }
+}
+
+fn load_cartridge(r: &mut StateReader, cartridge: &mut Cartridge) -> Result<(), SaveStateError> {
+    r.bytes_into(&mut cartridge.ram, "cartridge RAM size")?;
+    cartridge.ram_enabled = r.bool()?;
+    // Banks past the end of the ROM or RAM are legal, reads wrap or
+    // come back open, but no MBC register holds more than these
+    cartridge.rom_bank = r.u16()?;
+    if cartridge.rom_bank > 0x1FF {
+        return Err(SaveStateError::Invalid("ROM bank"));
+    }
+    cartridge.ram_bank = r.u8()?;
+    if cartridge.ram_bank > 0x0F {
+        return Err(SaveStateError::Invalid("RAM bank"));
+    }
+    cartridge.banking_mode = r.bool()?;
+    let has_rtc = r.bool()?;
+    match &mut cartridge.rtc {
+        Some(rtc) if has_rtc => {
+            load_rtc_registers(r, &mut rtc.live)?;
+            load_rtc_registers(r, &mut rtc.latched)?;
+            rtc.cycles = r.u32()?;
+            rtc.latch_armed = r.bool()?;
+        }
+        None if !has_rtc => {}
+        _ => return Err(SaveStateError::Invalid("RTC")),
+    }
+    Ok(())
+}
+
+fn save_rtc_registers(w: &mut StateWriter, registers: &RtcRegisters) {
+    w.u8(registers.seconds);
+    w.u8(registers.minutes);
+    w.u8(registers.hours);
+    w.u16(registers.days);
+    w.bool(registers.halted);
+    w.bool(registers.day_carry);
+}
+
+fn load_rtc_registers(r: &mut StateReader, registers: &mut RtcRegisters) -> Result<(), SaveStateError> {
+    registers.seconds = r.u8()?;
+    registers.minutes = r.u8()?;
+    registers.hours = r.u8()?;
+    registers.days = r.u16()?;
+    registers.halted = r.bool()?;
+    registers.day_carry = r.bool()?;
+    Ok(())
+}
+
+fn save_ppu(w: &mut StateWriter, ppu: &Ppu) {
+    w.bytes(&ppu.vram);
+    w.u8(ppu.vram_bank);
+    w.bool(ppu.cgb_mode);
+    w.bool(ppu.compat_mode);
+    save_palette_ram(w, &ppu.bg_palettes);
+    save_palette_ram(w, &ppu.obj_palettes);
+    w.bytes(&ppu.oam);
+    for &register in [
+        ppu.lcdc, ppu.stat, ppu.scy, ppu.scx, ppu.ly, ppu.lyc, ppu.bgp, ppu.obp0, ppu.obp1, ppu.wy, ppu.wx,
+    ]
+    .iter()
+    {
+        w.u8(register);
+    }
+    w.u8(match ppu.mode {
+        Mode::HBlank => 0,
+        Mode::VBlank => 1,
+        Mode::OamScan => 2,
+        Mode::Drawing => 3,
+    });
+    w.u32(ppu.line_cycles);
+    w.u8(ppu.window_line);
+    w.bool(ppu.stat_line);
+    // The last frame, so a screenshot right after loading is not blank
+    w.words(&ppu.framebuffer);
+    w.bytes(&ppu.shades);
+    w.bool(ppu.frame_ready);
+    w.bool(ppu.hblank_started);
+}
+
+fn load_ppu(r: &mut StateReader, ppu: &mut Ppu) -> Result<(), SaveStateError> {
+    r.bytes_into(&mut ppu.vram, "VRAM size")?;
+    ppu.vram_bank = r.u8()?;
+    if ppu.vram_bank > 1 {
+        return Err(SaveStateError::Invalid("VRAM bank"));
+    }
+    ppu.cgb_mode = r.bool()?;
+    ppu.compat_mode = r.bool()?;
+    load_palette_ram(r, &mut ppu.bg_palettes)?;
+    load_palette_ram(r, &mut ppu.obj_palettes)?;
+    r.bytes_into(&mut ppu.oam, "OAM size")?;
+    for register in [
+        &mut ppu.lcdc,
+        &mut ppu.stat,
+        &mut ppu.scy,
+        &mut ppu.scx,
+        &mut ppu.ly,
+        &mut ppu.lyc,
+        &mut ppu.bgp,
+        &mut ppu.obp0,
+        &mut ppu.obp1,
+        &mut ppu.wy,
+        &mut ppu.wx,
+    ]
+    .iter_mut()
+    {
+        **register = r.u8()?;
+    }
+    ppu.mode = match r.u8()? {
+        0 => Mode::HBlank,
+        1 => Mode::VBlank,
+        2 => Mode::OamScan,
+        3 => Mode::Drawing,
+        _ => return Err(SaveStateError::Invalid("PPU mode")),
+    };
+    ppu.line_cycles = r.u32()?;
+    ppu.window_line = r.u8()?;
+    ppu.stat_line = r.bool()?;
+    r.words_into(&mut ppu.framebuffer, "framebuffer size")?;
+    r.bytes_into(&mut ppu.shades, "framebuffer size")?;
+    ppu.frame_ready = r.bool()?;
+    ppu.hblank_started = r.bool()?;
+    Ok(())
+}
+
+fn save_palette_ram(w: &mut StateWriter, palettes: &PaletteRam) {
+    w.bytes(&palettes.data);
+    w.u8(palettes.index);
+    w.bool(palettes.auto_increment);
+}
+
+fn load_palette_ram(r: &mut StateReader, palettes: &mut PaletteRam) -> Result<(), SaveStateError> {
+    r.bytes_into(&mut palettes.data, "palette RAM size")?;
+    palettes.index = r.u8()?;
+    palettes.auto_increment = r.bool()?;
+    Ok(())
+}
+
+// The sample rate and the resampler belong to the host, not the
+// machine, and are left alone
+fn save_apu(w: &mut StateWriter, apu: &Apu) {
+    w.bool(apu.powered);
+    save_square_channel(w, &apu.channel1);
+    save_square_channel(w, &apu.channel2);
+    save_wave_channel(w, &apu.channel3);
+    save_noise_channel(w, &apu.channel4);
+    w.u8(apu.nr50);
+    w.u8(apu.nr51);
+    w.u32(apu.frame_sequencer);
+    w.u8(apu.frame_sequencer_step);
+}
+
+fn load_apu(r: &mut StateReader, apu: &mut Apu) -> Result<(), SaveStateError> {
+    apu.powered = r.bool()?;
+    load_square_channel(r, &mut apu.channel1)?;
+    load_square_channel(r, &mut apu.channel2)?;
+    load_wave_channel(r, &mut apu.channel3)?;
+    load_noise_channel(r, &mut apu.channel4)?;
+    apu.nr50 = r.u8()?;
+    apu.nr51 = r.u8()?;
+    apu.frame_sequencer = r.u32()?;
+    apu.frame_sequencer_step = r.u8()?;
+    // Samples from before the load would play out of place
+    apu.samples.clear();
+    Ok(())
+}
+
+fn save_square_channel(w: &mut StateWriter, channel: &SquareChannel) {
+    w.bool(channel.enabled);
+    w.u8(channel.sweep);
+    w.u8(channel.duty);
+    w.u16(channel.frequency);
+    w.i32(channel.timer);
+    w.u8(channel.duty_step);
+    save_length(w, &channel.length);
+    save_envelope(w, &channel.envelope);
+    w.u8(channel.sweep_timer);
+    w.bool(channel.sweep_enabled);
+    w.u16(channel.shadow_frequency);
+}
+
+fn load_square_channel(r: &mut StateReader, channel: &mut SquareChannel) -> Result<(), SaveStateError> {
+    channel.enabled = r.bool()?;
+    channel.sweep = r.u8()?;
+    channel.duty = r.u8()?;
+    channel.frequency = r.u16()?;
+    channel.timer = r.i32()?;
+    channel.duty_step = r.u8()?;
+    load_length(r, &mut channel.length)?;
+    load_envelope(r, &mut channel.envelope)?;
+    channel.sweep_timer = r.u8()?;
+    channel.sweep_enabled = r.bool()?;
+    channel.shadow_frequency = r.u16()?;
+    Ok(())
+}
+
+fn save_wave_channel(w: &mut StateWriter, channel: &WaveChannel) {
+    w.bool(channel.enabled);
+    w.bool(channel.dac_enabled);
+    w.u8(channel.volume_code);
+    w.u16(channel.frequency);
+    w.i32(channel.timer);
+    w.u8(channel.position);
+    w.u8(channel.sample_buffer);
+    save_length(w, &channel.length);
+    w.bytes(&channel.wave_ram);
+}
+
+fn load_wave_channel(r: &mut StateReader, channel: &mut WaveChannel) -> Result<(), SaveStateError> {
+    channel.enabled = r.bool()?;
+    channel.dac_enabled = r.bool()?;
+    channel.volume_code = r.u8()?;
+    channel.frequency = r.u16()?;
+    channel.timer = r.i32()?;
+    channel.position = r.u8()?;
+    channel.sample_buffer = r.u8()?;
+    load_length(r, &mut channel.length)?;
+    r.bytes_into(&mut channel.wave_ram, "wave RAM size")?;
+    Ok(())
+}
+
+fn save_noise_channel(w: &mut StateWriter, channel: &NoiseChannel) {
+    w.bool(channel.enabled);
+    w.u8(channel.polynomial);
+    w.i32(channel.timer);
+    w.u16(channel.lfsr);
+    save_length(w, &channel.length);
+    save_envelope(w, &channel.envelope);
+}
+
+fn load_noise_channel(r: &mut StateReader, channel: &mut NoiseChannel) -> Result<(), SaveStateError> {
+    channel.enabled = r.bool()?;
+    channel.polynomial = r.u8()?;
+    channel.timer = r.i32()?;
+    channel.lfsr = r.u16()?;
+    load_length(r, &mut channel.length)?;
+    load_envelope(r, &mut channel.envelope)?;
+    Ok(())
+}
+
+fn save_length(w: &mut StateWriter, length: &LengthCounter) {
+    w.u16(length.counter);
+    w.bool(length.enabled);
+}
+
+fn load_length(r: &mut StateReader, length: &mut LengthCounter) -> Result<(), SaveStateError> {
+    length.counter = r.u16()?;
+    length.enabled = r.bool()?;
+    Ok(())
+}
+
+fn save_envelope(w: &mut StateWriter, envelope: &Envelope) {
+    w.u8(envelope.register);
+    w.u8(envelope.volume);
+    w.u8(envelope.timer);
+}
+
+fn load_envelope(r: &mut StateReader, envelope: &mut Envelope) -> Result<(), SaveStateError> {
+    envelope.register = r.u8()?;
+    envelope.volume = r.u8()?;
+    envelope.timer = r.u8()?;
+    Ok(())
+}
+
+fn save_sgb(w: &mut StateWriter, sgb: &Sgb) {
+    w.bool(sgb.receiving);
+    w.u32(sgb.bit_index as u32);
+    w.bytes(&sgb.packet);
+    w.bytes(&sgb.command);
+    w.u8(sgb.joypad_lines);
+    w.u8(sgb.player_count);
+    w.u8(sgb.current_player);
+    w.words(&sgb.palettes.concat());
+    w.words(&sgb.system_palettes);
+    w.bytes(&sgb.attributes);
+    w.bytes(&sgb.attribute_files);
+    w.u8(match sgb.mask {
+        Mask::None => 0,
+        Mask::Freeze => 1,
+        Mask::Black => 2,
+        Mask::Color0 => 3,
+    });
+    w.bool(sgb.frozen.is_some());
+    if let Some(frozen) = &sgb.frozen {
+        w.bytes(frozen);
+    }
+    w.bytes(&sgb.border_tiles);
+    w.bytes(&sgb.border_map);
+    w.words(&sgb.border_palettes.concat());
+    w.u8(match sgb.pending_transfer {
+        None => 0,
+        Some(Transfer::Palettes) => 1,
+        Some(Transfer::Tiles { upper: false }) => 2,
+        Some(Transfer::Tiles { upper: true }) => 3,
+        Some(Transfer::Border) => 4,
+        Some(Transfer::Attributes) => 5,
+    });
+}
+
+fn load_sgb(r: &mut StateReader, sgb: &mut Sgb) -> Result<(), SaveStateError> {
+    sgb.receiving = r.bool()?;
+    sgb.bit_index = r.u32()? as usize;
+    r.bytes_into(&mut sgb.packet, "SGB packet size")?;
+    // A finished packet leaves the position just past the end
+    let packet_bits = sgb.packet.len() * 8;
+    if sgb.bit_index > packet_bits || (sgb.receiving && sgb.bit_index == packet_bits) {
+        return Err(SaveStateError::Invalid("SGB packet position"));
+    }
+    sgb.command = r.bytes()?.to_vec();
+    sgb.joypad_lines = r.u8()?;
+    sgb.player_count = r.u8()?;
+    if ![1, 2, 4].contains(&sgb.player_count) {
+        return Err(SaveStateError::Invalid("SGB player count"));
+    }
+    sgb.current_player = r.u8()?;
+    if sgb.current_player >= sgb.player_count {
+        return Err(SaveStateError::Invalid("SGB player"));
+    }
+    let mut palettes = [0; 16];
+    r.words_into(&mut palettes, "SGB palette size")?;
+    for (palette, colors) in sgb.palettes.iter_mut().zip(palettes.chunks(4)) {
+        palette.copy_from_slice(colors);
+    }
+    r.words_into(&mut sgb.system_palettes, "SGB system palette size")?;
+    r.bytes_into(&mut sgb.attributes, "SGB attribute size")?;
+    r.bytes_into(&mut sgb.attribute_files, "SGB attribute file size")?;
+    sgb.mask = match r.u8()? {
+        0 => Mask::None,
+        1 => Mask::Freeze,
+        2 => Mask::Black,
+        3 => Mask::Color0,
+        _ => return Err(SaveStateError::Invalid("SGB mask")),
+    };
+    sgb.frozen = if r.bool()? { Some(r.bytes()?.to_vec()) } else { None };
+    r.bytes_into(&mut sgb.border_tiles, "SGB border tile size")?;
+    r.bytes_into(&mut sgb.border_map, "SGB border map size")?;
+    let mut border_palettes = [0; 64];
+    r.words_into(&mut border_palettes, "SGB border palette size")?;
+    for (palette, colors) in sgb.border_palettes.iter_mut().zip(border_palettes.chunks(16)) {
+        palette.copy_from_slice(colors);
+    }
+    sgb.pending_transfer = match r.u8()? {
+        0 => None,
+        1 => Some(Transfer::Palettes),
+        2 => Some(Transfer::Tiles { upper: false }),
+        3 => Some(Transfer::Tiles { upper: true }),
+        4 => Some(Transfer::Border),
+        5 => Some(Transfer::Attributes),
+        _ => return Err(SaveStateError::Invalid("SGB transfer")),
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod save_state_tests {
+    use super::*;
+    use crate::boot_rom::BootRom;
+    use crate::cartridge::MbcKind;
+
+    // Counts up in WRAM at 0xC000 forever
+    fn counter_rom(title: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x106].copy_from_slice(&[0x21, 0x00, 0xC0, 0x34, 0x18, 0xFD]);
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        rom
+    }
+
+    fn machine(title: &[u8], model: Model) -> CPU {
+        let cartridge = Cartridge::with_mapper(counter_rom(title), MbcKind::Mbc5, 0x2000);
+        CPU::with_model(cartridge, model)
+    }
+
+    // Where the data of a section starts
+    fn section_start(state: &[u8], tag: &[u8; 4]) -> usize {
+        state.windows(4).position(|found| found == tag).unwrap() + 8
+    }
+
+    #[test]
+    fn round_trip_resumes_identically() {
+        let mut cpu = machine(b"COUNTER", Model::Dmg);
+        cpu.bus.cartridge.ram[5] = 0x42;
+        cpu.run_frame();
+        let state = save_state(&cpu);
+        cpu.run_frame();
+        let expected = (cpu.pc, cpu.bus.wram[0], cpu.bus.ppu.ly, cpu.bus.timer.divider);
+
+        let mut restored = machine(b"COUNTER", Model::Dmg);
+        load_state(&mut restored, &state).unwrap();
+        assert_eq!(restored.bus.cartridge.ram[5], 0x42);
+        restored.run_frame();
+        assert_eq!(
+            (restored.pc, restored.bus.wram[0], restored.bus.ppu.ly, restored.bus.timer.divider),
+            expected
+        );
+        assert_eq!(save_state(&restored), save_state(&cpu));
+    }
+    #[test]
+    fn sgb_state_round_trips() {
+        let mut cpu = machine(b"COUNTER", Model::Sgb);
+        cpu.bus.sgb.as_mut().unwrap().palettes[2][3] = 0x1234;
+        let state = save_state(&cpu);
+        let mut restored = machine(b"COUNTER", Model::Sgb);
+        load_state(&mut restored, &state).unwrap();
+        assert_eq!(restored.bus.sgb.unwrap().palettes[2][3], 0x1234);
+    }
+    #[test]
+    fn refuses_other_rom() {
+        let state = save_state(&machine(b"COUNTER", Model::Dmg));
+        let mut other = machine(b"OTHER", Model::Dmg);
+        let error = load_state(&mut other, &state).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "save state belongs to ROM \"COUNTER\", but \"OTHER\" is loaded"
+        );
+    }
+    #[test]
+    fn refuses_other_model() {
+        let state = save_state(&machine(b"COUNTER", Model::Dmg));
+        let mut cpu = machine(b"COUNTER", Model::Cgb);
+        assert_eq!(
+            load_state(&mut cpu, &state),
+            Err(SaveStateError::ModelMismatch {
+                state: Model::Dmg,
+                loaded: Model::Cgb
+            })
+        );
+    }
+    #[test]
+    fn refuses_newer_version() {
+        let mut state = save_state(&machine(b"COUNTER", Model::Dmg));
+        state[4] = 0xFF;
+        let mut cpu = machine(b"COUNTER", Model::Dmg);
+        assert_eq!(load_state(&mut cpu, &state), Err(SaveStateError::UnsupportedVersion(0x00FF)));
+        assert_eq!(load_state(&mut cpu, b"nope"), Err(SaveStateError::NotASaveState));
+    }
+    #[test]
+    fn skips_unknown_sections() {
+        let mut cpu = machine(b"COUNTER", Model::Dmg);
+        cpu.pc = 0x1234;
+        let mut state = save_state(&cpu);
+        state.extend_from_slice(b"NEW!");
+        state.extend_from_slice(&3u32.to_le_bytes());
+        state.extend_from_slice(&[1, 2, 3]);
+        let mut restored = machine(b"COUNTER", Model::Dmg);
+        load_state(&mut restored, &state).unwrap();
+        assert_eq!(restored.pc, 0x1234);
+    }
+    #[test]
+    fn damaged_state_leaves_machine_alone() {
+        let mut cpu = machine(b"COUNTER", Model::Dmg);
+        cpu.pc = 0x1234;
+        let mut state = save_state(&cpu);
+        // Cut the APU section, the last one, short. The sections before
+        // it load fine.
+        let apu = state.windows(4).rposition(|tag| tag == b"APU ").unwrap();
+        state.truncate(apu + 4);
+        state.extend_from_slice(&2u32.to_le_bytes());
+        state.extend_from_slice(&[1, 0]);
+
+        let mut other = machine(b"COUNTER", Model::Dmg);
+        other.pc = 0x0150;
+        assert_eq!(load_state(&mut other, &state), Err(SaveStateError::Truncated));
+        assert_eq!(other.pc, 0x0150);
+        state.truncate(apu + 6);
+        assert_eq!(load_state(&mut other, &state), Err(SaveStateError::Truncated));
+    }
+    #[test]
+    fn damaged_state_inside_boot_rom_rolls_back() {
+        let state = save_state(&machine(b"COUNTER", Model::Dmg));
+        let mut state = state[..state.windows(4).rposition(|tag| tag == b"APU ").unwrap() + 4].to_vec();
+        state.extend_from_slice(&2u32.to_le_bytes());
+        state.extend_from_slice(&[1, 0]);
+        let boot_rom = BootRom::new(vec![0; crate::boot_rom::DMG_BOOT_ROM_SIZE]).unwrap();
+        let cartridge = Cartridge::with_mapper(counter_rom(b"COUNTER"), MbcKind::Mbc5, 0x2000);
+        let mut cpu = CPU::with_boot_rom(cartridge, Model::Dmg, boot_rom);
+        assert_eq!(load_state(&mut cpu, &state), Err(SaveStateError::Truncated));
+        assert!(cpu.bus.boot_rom.is_some());
+        assert_eq!(cpu.pc, 0x0000);
+    }
+    #[test]
+    fn rejects_out_of_range_fields() {
+        let corrupt = |model: Model, tag: &[u8; 4], offset: &dyn Fn(&CPU, &[u8]) -> usize, value: u8| {
+            let cpu = machine(b"COUNTER", model);
+            let mut state = save_state(&cpu);
+            let at = section_start(&state, tag) + offset(&cpu, &state);
+            state[at] = value;
+            let mut restored = machine(b"COUNTER", model);
+            let result = load_state(&mut restored, &state);
+            // Nothing was left half loaded
+            if result.is_err() {
+                assert!(save_state(&restored) == save_state(&cpu));
+            }
+            restored.bus.read_byte(0xD000);
+            restored.bus.read_byte(0x8000);
+            result
+        };
+        let wram_bank = |cpu: &CPU, _: &[u8]| 3 + 4 + cpu.bus.wram.len();
+        assert_eq!(corrupt(Model::Dmg, b"BUS ", &wram_bank, 200), Err(SaveStateError::Invalid("WRAM bank")));
+        assert_eq!(corrupt(Model::Cgb, b"BUS ", &wram_bank, 7), Ok(()));
+        let vram_bank = |cpu: &CPU, _: &[u8]| 4 + cpu.bus.ppu.vram.len();
+        assert_eq!(corrupt(Model::Cgb, b"PPU ", &vram_bank, 2), Err(SaveStateError::Invalid("VRAM bank")));
+        let rom_bank_high = |cpu: &CPU, _: &[u8]| 4 + cpu.bus.cartridge.ram.len() + 2;
+        assert_eq!(corrupt(Model::Dmg, b"CART", &rom_bank_high, 0x02), Err(SaveStateError::Invalid("ROM bank")));
+        let ram_bank = |cpu: &CPU, _: &[u8]| 4 + cpu.bus.cartridge.ram.len() + 3;
+        assert_eq!(corrupt(Model::Dmg, b"CART", &ram_bank, 0x10), Err(SaveStateError::Invalid("RAM bank")));
+
+        let bit_index = |_: &CPU, _: &[u8]| 1;
+        let position = Err(SaveStateError::Invalid("SGB packet position"));
+        assert_eq!(corrupt(Model::Sgb, b"SGB ", &bit_index, 200), position);
+        // Just past the end is where a finished packet leaves it
+        assert_eq!(corrupt(Model::Sgb, b"SGB ", &bit_index, 128), Ok(()));
+        let players = |_: &CPU, state: &[u8]| {
+            let command = section_start(state, b"SGB ") + 25;
+            let mut length = [0; 4];
+            length.copy_from_slice(&state[command..command + 4]);
+            29 + u32::from_le_bytes(length) as usize + 1
+        };
+        let count = Err(SaveStateError::Invalid("SGB player count"));
+        assert_eq!(corrupt(Model::Sgb, b"SGB ", &players, 0), count);
+        assert_eq!(corrupt(Model::Sgb, b"SGB ", &players, 3), count);
+        let player = |cpu: &CPU, state: &[u8]| players(cpu, state) + 1;
+        assert_eq!(corrupt(Model::Sgb, b"SGB ", &player, 1), Err(SaveStateError::Invalid("SGB player")));
+    }
+}
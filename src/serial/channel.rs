@@ -0,0 +1,81 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+
+use super::{exchange_over, poll_over, LinkMessage, LinkTransport, MessagePipe};
+
+// Links two emulator instances in the same process, each running on its
+// own thread
+pub struct ChannelTransport {
+    sender: Sender<LinkMessage>,
+    receiver: Receiver<LinkMessage>,
+    pub timeout: Duration,
+}
+
+impl ChannelTransport {
+    pub fn pair() -> (ChannelTransport, ChannelTransport) {
+        let (left_sender, right_receiver) = mpsc::channel();
+        let (right_sender, left_receiver) = mpsc::channel();
+        (
+            ChannelTransport {
+                sender: left_sender,
+                receiver: left_receiver,
+                timeout: Duration::from_secs(1),
+            },
+            ChannelTransport {
+                sender: right_sender,
+                receiver: right_receiver,
+                timeout: Duration::from_secs(1),
+            },
+        )
+    }
+}
+
+impl MessagePipe for ChannelTransport {
+    fn send(&mut self, message: LinkMessage) {
+        // The other end hanging up looks like an unplugged cable
+        let _ = self.sender.send(message);
+    }
+
+    fn receive(&mut self) -> Option<LinkMessage> {
+        self.receiver.recv_timeout(self.timeout).ok()
+    }
+
+    fn try_receive(&mut self) -> Option<LinkMessage> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+impl LinkTransport for ChannelTransport {
+    fn exchange(&mut self, outgoing: u8) -> u8 {
+        exchange_over(self, outgoing)
+    }
+
+    fn poll(&mut self, ready: bool, outgoing: u8) -> Option<u8> {
+        poll_over(self, ready, outgoing)
+    }
+}
+
+#[cfg(test)]
+mod channel_transport_tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn bytes_swap_between_threads() {
+        let (mut master, mut slave) = ChannelTransport::pair();
+        let handle = thread::spawn(move || loop {
+            if let Some(incoming) = slave.poll(true, 0x99) {
+                return incoming;
+            }
+        });
+        assert_eq!(master.exchange(0x42), 0x99);
+        assert_eq!(handle.join().unwrap(), 0x42);
+    }
+    #[test]
+    fn unready_peer_answers_ff() {
+        let (mut master, mut slave) = ChannelTransport::pair();
+        master.send(LinkMessage::Request(0x42));
+        assert_eq!(slave.poll(false, 0x99), None);
+        assert_eq!(master.receive(), Some(LinkMessage::Response(0xFF)));
+    }
+}
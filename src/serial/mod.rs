@@ -0,0 +1,234 @@
+pub mod channel;
+pub mod tcp;
+
+// Bit time for the 8192Hz internal clock
+const CYCLES_PER_BIT: u32 = 512;
+
+// The other end of the link cable. Transfers are modelled a byte at a
+// time: whichever side drives the clock swaps its byte for the peer's.
+pub trait LinkTransport: Send {
+    // Called when this side drives the clock and has shifted out a full
+    // byte. Returns the byte shifted in from the peer.
+    fn exchange(&mut self, outgoing: u8) -> u8;
+
+    // Called periodically so the peer can drive a transfer. `ready` is
+    // true while this side waits on an external clock transfer. Returns
+    // the peer's byte when a transfer completed, after answering with
+    // `outgoing`.
+    fn poll(&mut self, ready: bool, outgoing: u8) -> Option<u8>;
+}
+
+// Wire protocol shared by the transports that talk to another emulator
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkMessage {
+    // The sender drove the clock and shifted out this byte
+    Request(u8),
+    // The byte the receiver shifted back
+    Response(u8),
+}
+
+pub(crate) trait MessagePipe {
+    fn send(&mut self, message: LinkMessage);
+    // Blocks until a message arrives, None when the peer went away
+    fn receive(&mut self) -> Option<LinkMessage>;
+    fn try_receive(&mut self) -> Option<LinkMessage>;
+}
+
+pub(crate) fn exchange_over<P: MessagePipe>(pipe: &mut P, outgoing: u8) -> u8 {
+    pipe.send(LinkMessage::Request(outgoing));
+    loop {
+        match pipe.receive() {
+            Some(LinkMessage::Response(incoming)) => return incoming,
+            // Both sides drove the clock at once, the peer is not
+            // listening for our request either
+            Some(LinkMessage::Request(_)) => pipe.send(LinkMessage::Response(0xFF)),
+            None => return 0xFF,
+        }
+    }
+}
+
+pub(crate) fn poll_over<P: MessagePipe>(pipe: &mut P, ready: bool, outgoing: u8) -> Option<u8> {
+    match pipe.try_receive() {
+        Some(LinkMessage::Request(incoming)) if ready => {
+            pipe.send(LinkMessage::Response(outgoing));
+            Some(incoming)
+        }
+        Some(LinkMessage::Request(_)) => {
+            pipe.send(LinkMessage::Response(0xFF));
+            None
+        }
+        _ => None,
+    }
+}
+
+// No cable plugged in, the data line floats high
+pub struct NullTransport;
+
+impl LinkTransport for NullTransport {
+    fn exchange(&mut self, _outgoing: u8) -> u8 {
+        0xFF
+    }
+
+    fn poll(&mut self, _ready: bool, _outgoing: u8) -> Option<u8> {
+        None
+    }
+}
+
+// A cable with its ends wired together, every byte comes straight back
+pub struct LoopbackTransport;
+
+impl LinkTransport for LoopbackTransport {
+    fn exchange(&mut self, outgoing: u8) -> u8 {
+        outgoing
+    }
+
+    fn poll(&mut self, _ready: bool, _outgoing: u8) -> Option<u8> {
+        None
+    }
+}
+
+// SB (0xFF01) and SC (0xFF02)
+pub struct Serial {
+    pub data: u8,
+    pub control: u8,
+    pub cycles: u32,
+    pub transport: Box<dyn LinkTransport>,
+    // Every byte this side sent while driving the clock. Test ROMs
+    // print their results this way.
+    pub output: Vec<u8>,
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Serial::new()
+    }
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            data: 0x00,
+            control: 0x7E,
+            cycles: 0,
+            transport: Box::new(NullTransport),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn connect(&mut self, transport: Box<dyn LinkTransport>) {
+        self.transport = transport;
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    // True while a transfer is armed and waiting for the peer's clock
+    pub fn waiting_for_clock(&self) -> bool {
+        self.transferring() && !self.internal_clock()
+    }
+
+    // Completes an externally clocked transfer with the byte the peer
+    // shifted in. Returns true when the serial interrupt should fire.
+    pub fn receive(&mut self, incoming: u8) -> bool {
+        if !self.waiting_for_clock() {
+            return false;
+        }
+        self.data = incoming;
+        self.complete()
+    }
+
+    fn transferring(&self) -> bool {
+        self.control & 0x80 != 0
+    }
+
+    fn internal_clock(&self) -> bool {
+        self.control & 0x01 != 0
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0xFF01 => self.data,
+            0xFF02 => self.control | 0x7E,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF01 => self.data = value,
+            0xFF02 => {
+                self.control = value | 0x7E;
+                if self.transferring() {
+                    self.cycles = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Returns true when a transfer finished and the serial interrupt
+    // should be requested
+    pub fn step(&mut self, cycles: u32) -> bool {
+        self.cycles += cycles;
+        if self.transferring() && self.internal_clock() {
+            if self.cycles < CYCLES_PER_BIT * 8 {
+                return false;
+            }
+            self.output.push(self.data);
+            self.data = self.transport.exchange(self.data);
+            return self.complete();
+        }
+        // Polling every bit time keeps the transport off the hot path
+        if self.cycles < CYCLES_PER_BIT {
+            return false;
+        }
+        self.cycles = 0;
+        let ready = self.transferring();
+        match self.transport.poll(ready, self.data) {
+            Some(incoming) if ready => {
+                self.data = incoming;
+                self.complete()
+            }
+            _ => false,
+        }
+    }
+
+    fn complete(&mut self) -> bool {
+        self.control &= 0x7F;
+        self.cycles = 0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod serial_tests {
+    use super::*;
+    #[test]
+    fn internal_transfer_without_cable_reads_ff() {
+        let mut serial = Serial::new();
+        serial.write(0xFF01, 0x42);
+        serial.write(0xFF02, 0x81);
+        assert!(!serial.step(CYCLES_PER_BIT * 8 - 4));
+        assert!(serial.step(4));
+        assert_eq!(serial.read(0xFF01), 0xFF);
+        assert_eq!(serial.read(0xFF02) & 0x80, 0x00);
+        assert_eq!(serial.take_output(), vec![0x42]);
+    }
+    #[test]
+    fn loopback_returns_sent_byte() {
+        let mut serial = Serial::new();
+        serial.connect(Box::new(LoopbackTransport));
+        serial.write(0xFF01, 0x42);
+        serial.write(0xFF02, 0x81);
+        assert!(serial.step(CYCLES_PER_BIT * 8));
+        assert_eq!(serial.read(0xFF01), 0x42);
+    }
+    #[test]
+    fn external_clock_waits_for_peer() {
+        let mut serial = Serial::new();
+        serial.write(0xFF02, 0x80);
+        assert!(!serial.step(CYCLES_PER_BIT * 64));
+        assert_eq!(serial.read(0xFF02) & 0x80, 0x80);
+    }
+}
@@ -0,0 +1,129 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::time::Duration;
+
+use super::{exchange_over, poll_over, LinkMessage, LinkTransport, MessagePipe};
+
+const REQUEST: u8 = 0x01;
+const RESPONSE: u8 = 0x02;
+
+// Link cable over a TCP connection on localhost. Each message is two
+// bytes, a kind followed by the data byte.
+pub struct TcpTransport {
+    stream: TcpStream,
+    pending: Vec<u8>,
+    connected: bool,
+}
+
+impl TcpTransport {
+    // Waits for the other emulator to connect
+    pub fn listen(port: u16) -> io::Result<TcpTransport> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        let (stream, _) = listener.accept()?;
+        TcpTransport::from_stream(stream)
+    }
+
+    pub fn connect(port: u16) -> io::Result<TcpTransport> {
+        TcpTransport::from_stream(TcpStream::connect((Ipv4Addr::LOCALHOST, port))?)
+    }
+
+    pub fn from_stream(stream: TcpStream) -> io::Result<TcpTransport> {
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+        Ok(TcpTransport {
+            stream,
+            pending: Vec::new(),
+            connected: true,
+        })
+    }
+
+    fn decode(&mut self) -> Option<LinkMessage> {
+        if self.pending.len() < 2 {
+            return None;
+        }
+        let message = match self.pending[0] {
+            REQUEST => LinkMessage::Request(self.pending[1]),
+            _ => LinkMessage::Response(self.pending[1]),
+        };
+        self.pending.drain(0..2);
+        Some(message)
+    }
+
+    fn fill(&mut self, blocking: bool) {
+        if !self.connected || self.stream.set_nonblocking(!blocking).is_err() {
+            return;
+        }
+        let mut buffer = [0; 64];
+        match self.stream.read(&mut buffer) {
+            Ok(0) => self.connected = false,
+            Ok(count) => self.pending.extend_from_slice(&buffer[..count]),
+            Err(error) if error.kind() == ErrorKind::WouldBlock || error.kind() == ErrorKind::TimedOut => {}
+            Err(_) => self.connected = false,
+        }
+    }
+}
+
+impl MessagePipe for TcpTransport {
+    fn send(&mut self, message: LinkMessage) {
+        let bytes = match message {
+            LinkMessage::Request(byte) => [REQUEST, byte],
+            LinkMessage::Response(byte) => [RESPONSE, byte],
+        };
+        if self.stream.set_nonblocking(false).is_err() || self.stream.write_all(&bytes).is_err() {
+            self.connected = false;
+        }
+    }
+
+    fn receive(&mut self) -> Option<LinkMessage> {
+        while self.pending.len() < 2 && self.connected {
+            let before = self.pending.len();
+            self.fill(true);
+            // A timeout with nothing read means the peer stopped answering
+            if self.pending.len() == before {
+                return None;
+            }
+        }
+        self.decode()
+    }
+
+    fn try_receive(&mut self) -> Option<LinkMessage> {
+        if self.pending.len() < 2 {
+            self.fill(false);
+        }
+        self.decode()
+    }
+}
+
+impl LinkTransport for TcpTransport {
+    fn exchange(&mut self, outgoing: u8) -> u8 {
+        exchange_over(self, outgoing)
+    }
+
+    fn poll(&mut self, ready: bool, outgoing: u8) -> Option<u8> {
+        poll_over(self, ready, outgoing)
+    }
+}
+
+#[cfg(test)]
+mod tcp_transport_tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn bytes_swap_over_localhost() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut slave = TcpTransport::from_stream(stream).unwrap();
+            loop {
+                if let Some(incoming) = slave.poll(true, 0x99) {
+                    return incoming;
+                }
+            }
+        });
+        let mut master = TcpTransport::connect(port).unwrap();
+        assert_eq!(master.exchange(0x42), 0x99);
+        assert_eq!(handle.join().unwrap(), 0x42);
+    }
+}
@@ -0,0 +1,550 @@
+use crate::joypad::Joypad;
+use crate::ppu::{Ppu, DMG_SHADES, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+
+// Top left corner of the Game Boy screen inside the border
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+// Palettes are assigned per 8x8 cell of the Game Boy screen
+const CELLS_X: usize = SCREEN_WIDTH / 8;
+const CELLS_Y: usize = SCREEN_HEIGHT / 8;
+const ATTRIBUTE_FILE_SIZE: usize = 90;
+const ATTRIBUTE_FILES: usize = 45;
+
+const PACKET_BITS: usize = 128;
+const TRANSFER_SIZE: usize = 0x1000;
+
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const PAL_SET: u8 = 0x0A;
+const PAL_TRN: u8 = 0x0B;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const ATTR_TRN: u8 = 0x15;
+const ATTR_SET: u8 = 0x16;
+const MASK_EN: u8 = 0x17;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mask {
+    None,
+    Freeze,
+    Black,
+    Color0,
+}
+
+// VRAM transfers copy whatever the game shows on the next frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Transfer {
+    Palettes,
+    Tiles { upper: bool },
+    Border,
+    Attributes,
+}
+
+fn word(data: &[u8], offset: usize) -> u16 {
+    data[offset] as u16 | (data[offset + 1] as u16) << 8
+}
+
+// Super Game Boy. Games talk to it by bit-banging 16 byte packets over
+// P14/P15 of the joypad register.
+pub struct Sgb {
+    pub(crate) receiving: bool,
+    pub(crate) bit_index: usize,
+    pub(crate) packet: [u8; 16],
+    pub(crate) command: Vec<u8>,
+    pub(crate) joypad_lines: u8,
+    pub player_count: u8,
+    pub current_player: u8,
+    // Color 0 is shared by all four palettes
+    pub palettes: [[u16; 4]; 4],
+    // 512 palettes loaded with PAL_TRN
+    pub system_palettes: Vec<u16>,
+    // Palette number for each screen cell
+    pub attributes: [u8; CELLS_X * CELLS_Y],
+    pub attribute_files: Vec<u8>,
+    pub mask: Mask,
+    pub(crate) frozen: Option<Vec<u8>>,
+    // 256 SNES 4bpp tiles and a 32x32 map, of which 28 rows are shown
+    pub border_tiles: Vec<u8>,
+    pub border_map: Vec<u8>,
+    // Border palettes 4-7
+    pub border_palettes: [[u16; 16]; 4],
+    pub(crate) pending_transfer: Option<Transfer>,
+}
+
+impl Default for Sgb {
+    fn default() -> Self {
+        Sgb::new()
+    }
+}
+
+impl Sgb {
+    pub fn new() -> Sgb {
+        Sgb {
+            receiving: false,
+            bit_index: 0,
+            packet: [0; 16],
+            command: Vec::new(),
+            joypad_lines: 0x30,
+            player_count: 1,
+            current_player: 0,
+            palettes: [DMG_SHADES; 4],
+            system_palettes: vec![0; 512 * 4],
+            attributes: [0; CELLS_X * CELLS_Y],
+            attribute_files: vec![0; ATTRIBUTE_FILES * ATTRIBUTE_FILE_SIZE],
+            mask: Mask::None,
+            frozen: None,
+            border_tiles: vec![0; 256 * 32],
+            border_map: vec![0; 32 * 32 * 2],
+            border_palettes: [[0; 16]; 4],
+            pending_transfer: None,
+        }
+    }
+
+    // Pulling both lines low starts a packet. After that each pulse on
+    // P14 sends a 0 bit and each pulse on P15 a 1 bit, LSB first.
+    pub fn write_joypad(&mut self, value: u8) {
+        let lines = value & 0x30;
+        match lines {
+            0x00 => {
+                self.receiving = true;
+                self.bit_index = 0;
+                self.packet = [0; 16];
+            }
+            0x10 | 0x20 if self.receiving && self.joypad_lines == 0x30 => {
+                if lines == 0x10 {
+                    self.packet[self.bit_index / 8] |= 1 << (self.bit_index % 8);
+                }
+                self.bit_index += 1;
+                if self.bit_index == PACKET_BITS {
+                    // The stop bit that follows is ignored
+                    self.receiving = false;
+                    let packet = self.packet;
+                    self.receive_packet(&packet);
+                }
+            }
+            _ => {}
+        }
+        // With multiplayer on, releasing P15 moves on to the next
+        // controller
+        if !self.receiving && self.joypad_lines & 0x20 == 0 && lines & 0x20 != 0 {
+            self.current_player = (self.current_player + 1) % self.player_count;
+        }
+        self.joypad_lines = lines;
+    }
+
+    // With neither line selected P1 returns the current controller ID.
+    // Only the first controller has buttons.
+    pub fn read_joypad(&self, joypad: &Joypad) -> u8 {
+        if joypad.select == 0x30 {
+            0xF0 | (0x0F - self.current_player)
+        } else if self.current_player != 0 {
+            0xCF | joypad.select
+        } else {
+            joypad.read()
+        }
+    }
+
+    fn receive_packet(&mut self, packet: &[u8; 16]) {
+        // The first packet holds the command and the number of packets
+        if self.command.is_empty() && packet[0] & 0x07 == 0 {
+            return;
+        }
+        self.command.extend_from_slice(packet);
+        let length = (self.command[0] & 0x07) as usize;
+        if self.command.len() >= length * 16 {
+            let command = std::mem::take(&mut self.command);
+            self.execute(&command);
+        }
+    }
+
+    fn execute(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            PAL01 => self.set_palette_pair(0, 1, data),
+            PAL23 => self.set_palette_pair(2, 3, data),
+            PAL03 => self.set_palette_pair(0, 3, data),
+            PAL12 => self.set_palette_pair(1, 2, data),
+            ATTR_BLK => self.attribute_blocks(data),
+            ATTR_LIN => self.attribute_lines(data),
+            ATTR_DIV => self.attribute_divide(data),
+            ATTR_CHR => self.attribute_characters(data),
+            PAL_SET => self.set_system_palettes(data),
+            PAL_TRN => self.pending_transfer = Some(Transfer::Palettes),
+            MLT_REQ => {
+                self.player_count = match data[1] & 0x03 {
+                    0x01 => 2,
+                    0x03 => 4,
+                    _ => 1,
+                };
+                self.current_player = 0;
+            }
+            CHR_TRN => {
+                self.pending_transfer = Some(Transfer::Tiles {
+                    upper: data[1] & 0x01 != 0,
+                })
+            }
+            PCT_TRN => self.pending_transfer = Some(Transfer::Border),
+            ATTR_TRN => self.pending_transfer = Some(Transfer::Attributes),
+            ATTR_SET => self.set_attribute_file(data[1]),
+            MASK_EN => {
+                self.mask = match data[1] & 0x03 {
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    3 => Mask::Color0,
+                    _ => Mask::None,
+                }
+            }
+            // Sound, SNES code uploads and the rest are not emulated
+            _ => {}
+        }
+    }
+
+    fn set_shared_color(&mut self, color: u16) {
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color;
+        }
+    }
+
+    fn set_palette_pair(&mut self, first: usize, second: usize, data: &[u8]) {
+        self.palettes[first] = [0, word(data, 3), word(data, 5), word(data, 7)];
+        self.palettes[second] = [0, word(data, 9), word(data, 11), word(data, 13)];
+        self.set_shared_color(word(data, 1));
+    }
+
+    fn set_system_palettes(&mut self, data: &[u8]) {
+        for palette in 0..4 {
+            let id = (word(data, 1 + palette * 2) & 0x1FF) as usize;
+            self.palettes[palette].copy_from_slice(&self.system_palettes[id * 4..id * 4 + 4]);
+        }
+        let shared = self.palettes[0][0];
+        self.set_shared_color(shared);
+        if data[9] & 0x80 != 0 {
+            self.set_attribute_file(data[9]);
+        } else if data[9] & 0x40 != 0 {
+            self.mask = Mask::None;
+        }
+    }
+
+    fn set_cell(&mut self, x: usize, y: usize, palette: u8) {
+        if x < CELLS_X && y < CELLS_Y {
+            self.attributes[y * CELLS_X + x] = palette & 0x03;
+        }
+    }
+
+    fn attribute_blocks(&mut self, data: &[u8]) {
+        let count = (data[1] & 0x1F) as usize;
+        for set in data[2..].chunks_exact(6).take(count) {
+            let control = set[0] & 0x07;
+            let inside = set[1] & 0x03;
+            let outside = (set[1] >> 4) & 0x03;
+            // With only the inside or outside changed the border goes
+            // along with it
+            let border = match control {
+                0x01 => Some(inside),
+                0x04 => Some(outside),
+                _ if control & 0x02 != 0 => Some((set[1] >> 2) & 0x03),
+                _ => None,
+            };
+            let (x1, y1) = ((set[2] & 0x1F) as usize, (set[3] & 0x1F) as usize);
+            let (x2, y2) = ((set[4] & 0x1F) as usize, (set[5] & 0x1F) as usize);
+            for y in 0..CELLS_Y {
+                for x in 0..CELLS_X {
+                    let in_block = (x1..=x2).contains(&x) && (y1..=y2).contains(&y);
+                    let on_edge = in_block && (x == x1 || x == x2 || y == y1 || y == y2);
+                    let palette = if on_edge {
+                        border
+                    } else if in_block {
+                        Some(inside).filter(|_| control & 0x01 != 0)
+                    } else {
+                        Some(outside).filter(|_| control & 0x04 != 0)
+                    };
+                    if let Some(palette) = palette {
+                        self.set_cell(x, y, palette);
+                    }
+                }
+            }
+        }
+    }
+
+    fn attribute_lines(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for &line in data[2..].iter().take(count) {
+            let position = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+            if line & 0x80 != 0 {
+                for x in 0..CELLS_X {
+                    self.set_cell(x, position, palette);
+                }
+            } else {
+                for y in 0..CELLS_Y {
+                    self.set_cell(position, y, palette);
+                }
+            }
+        }
+    }
+
+    fn attribute_divide(&mut self, data: &[u8]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on_line = (data[1] >> 4) & 0x03;
+        let horizontal = data[1] & 0x40 != 0;
+        let position = (data[2] & 0x1F) as usize;
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                let coordinate = if horizontal { y } else { x };
+                let palette = match coordinate.cmp(&position) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on_line,
+                    std::cmp::Ordering::Greater => after,
+                };
+                self.set_cell(x, y, palette);
+            }
+        }
+    }
+
+    fn attribute_characters(&mut self, data: &[u8]) {
+        let (mut x, mut y) = ((data[1] & 0x1F) as usize, (data[2] & 0x1F) as usize);
+        let count = (word(data, 3) as usize).min(CELLS_X * CELLS_Y);
+        let vertical = data[5] & 0x01 != 0;
+        for index in 0..count {
+            let byte = match data.get(6 + index / 4) {
+                Some(&byte) => byte,
+                None => break,
+            };
+            if x >= CELLS_X || y >= CELLS_Y {
+                break;
+            }
+            self.set_cell(x, y, byte >> (6 - (index % 4) * 2));
+            if vertical {
+                y += 1;
+                if y == CELLS_Y {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == CELLS_X {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    fn set_attribute_file(&mut self, value: u8) {
+        let file = (value & 0x3F) as usize;
+        if file < ATTRIBUTE_FILES {
+            let start = file * ATTRIBUTE_FILE_SIZE;
+            for cell in 0..CELLS_X * CELLS_Y {
+                let byte = self.attribute_files[start + cell / 4];
+                self.attributes[cell] = (byte >> (6 - (cell % 4) * 2)) & 0x03;
+            }
+        }
+        if value & 0x40 != 0 {
+            self.mask = Mask::None;
+        }
+    }
+
+    // The 4 KiB a VRAM transfer sends are the tiles shown in the first
+    // 13 rows of the BG map, 20 tiles a row
+    fn screen_data(ppu: &Ppu) -> Vec<u8> {
+        let map_base = if ppu.lcdc & 0x08 != 0 { 0x1C00 } else { 0x1800 };
+        let mut data = Vec::with_capacity(TRANSFER_SIZE);
+        for tile in 0..TRANSFER_SIZE / 16 {
+            let tile_number = ppu.vram[map_base + (tile / 20) * 32 + tile % 20];
+            let address = ppu.bg_tile_address(tile_number);
+            data.extend_from_slice(&ppu.vram[address..address + 16]);
+        }
+        data
+    }
+
+    // Called at VBlank with the frame that was just drawn
+    pub fn frame_done(&mut self, ppu: &Ppu) {
+        if let Some(transfer) = self.pending_transfer.take() {
+            let data = Sgb::screen_data(ppu);
+            match transfer {
+                Transfer::Palettes => {
+                    for (index, color) in self.system_palettes.iter_mut().enumerate() {
+                        *color = word(&data, index * 2);
+                    }
+                }
+                Transfer::Tiles { upper } => {
+                    let offset = if upper { TRANSFER_SIZE } else { 0 };
+                    self.border_tiles[offset..offset + TRANSFER_SIZE].copy_from_slice(&data);
+                }
+                Transfer::Border => {
+                    self.border_map.copy_from_slice(&data[..0x800]);
+                    for (number, palette) in self.border_palettes.iter_mut().enumerate() {
+                        for (index, color) in palette.iter_mut().enumerate() {
+                            *color = word(&data, 0x800 + number * 32 + index * 2);
+                        }
+                    }
+                }
+                Transfer::Attributes => {
+                    let size = self.attribute_files.len();
+                    self.attribute_files.copy_from_slice(&data[..size]);
+                }
+            }
+        }
+        if self.mask == Mask::Freeze {
+            if self.frozen.is_none() {
+                self.frozen = Some(ppu.shades.clone());
+            }
+        } else {
+            self.frozen = None;
+        }
+    }
+
+    // Composes the 256x224 picture: the border with the colorized Game
+    // Boy screen in the middle
+    pub fn render(&self, shades: &[u8]) -> Vec<u16> {
+        let backdrop = self.palettes[0][0];
+        let mut output = vec![backdrop; SGB_WIDTH * SGB_HEIGHT];
+
+        for tile_y in 0..SGB_HEIGHT / 8 {
+            for tile_x in 0..SGB_WIDTH / 8 {
+                let entry = word(&self.border_map, (tile_y * 32 + tile_x) * 2);
+                let tile = &self.border_tiles[(entry & 0xFF) as usize * 32..][..32];
+                let palette = &self.border_palettes[((entry >> 10) & 0x03) as usize];
+                for row in 0..8 {
+                    let source_row = if entry & 0x8000 != 0 { 7 - row } else { row };
+                    let planes = [
+                        tile[source_row * 2],
+                        tile[source_row * 2 + 1],
+                        tile[16 + source_row * 2],
+                        tile[16 + source_row * 2 + 1],
+                    ];
+                    for column in 0..8 {
+                        let bit = if entry & 0x4000 != 0 { column } else { 7 - column };
+                        let color = planes
+                            .iter()
+                            .enumerate()
+                            .fold(0, |color, (plane, &byte)| color | ((byte >> bit) & 1) << plane);
+                        // Color 0 is transparent
+                        if color != 0 {
+                            output[(tile_y * 8 + row) * SGB_WIDTH + tile_x * 8 + column] =
+                                palette[color as usize];
+                        }
+                    }
+                }
+            }
+        }
+
+        let shades = self.frozen.as_deref().unwrap_or(shades);
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                let color = match self.mask {
+                    Mask::Black => 0x0000,
+                    Mask::Color0 => backdrop,
+                    Mask::None | Mask::Freeze => {
+                        let palette = self.attributes[(y / 8) * CELLS_X + x / 8] as usize;
+                        self.palettes[palette][shades[y * SCREEN_WIDTH + x] as usize]
+                    }
+                };
+                output[(SCREEN_Y + y) * SGB_WIDTH + SCREEN_X + x] = color;
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod sgb_tests {
+    use super::*;
+
+    fn send_packet(sgb: &mut Sgb, packet: &[u8; 16]) {
+        sgb.write_joypad(0x00);
+        sgb.write_joypad(0x30);
+        for bit in 0..PACKET_BITS {
+            let one = packet[bit / 8] & (1 << (bit % 8)) != 0;
+            sgb.write_joypad(if one { 0x10 } else { 0x20 });
+            sgb.write_joypad(0x30);
+        }
+        sgb.write_joypad(0x20);
+        sgb.write_joypad(0x30);
+    }
+
+    fn command(command: u8, data: &[u8]) -> [u8; 16] {
+        let mut packet = [0; 16];
+        packet[0] = command << 3 | 1;
+        packet[1..1 + data.len()].copy_from_slice(data);
+        packet
+    }
+
+    #[test]
+    fn pal01_sets_palettes_and_shared_color() {
+        let mut sgb = Sgb::new();
+        let data = [0x1F, 0x00, 0xE0, 0x03, 0x00, 0x7C, 0x00, 0x00, 0x11, 0x11, 0x22, 0x22, 0x33, 0x33];
+        send_packet(&mut sgb, &command(PAL01, &data));
+        assert_eq!(sgb.palettes[0], [0x001F, 0x03E0, 0x7C00, 0x0000]);
+        assert_eq!(sgb.palettes[1], [0x001F, 0x1111, 0x2222, 0x3333]);
+        assert_eq!(sgb.palettes[3][0], 0x001F);
+    }
+    #[test]
+    fn multiplayer_cycles_controller_ids() {
+        let mut sgb = Sgb::new();
+        let mut joypad = Joypad::new();
+        send_packet(&mut sgb, &command(MLT_REQ, &[0x01]));
+        assert_eq!(sgb.player_count, 2);
+        joypad.write(0x30);
+        sgb.write_joypad(0x30);
+        assert_eq!(sgb.read_joypad(&joypad) & 0x0F, 0x0F);
+        sgb.write_joypad(0x10);
+        sgb.write_joypad(0x30);
+        assert_eq!(sgb.read_joypad(&joypad) & 0x0F, 0x0E);
+    }
+    #[test]
+    fn attr_blk_colors_inside_and_border() {
+        let mut sgb = Sgb::new();
+        // Inside only, palette 2, cells (1,1) to (3,3)
+        send_packet(&mut sgb, &command(ATTR_BLK, &[0x01, 0x01, 0x02, 0x01, 0x01, 0x03, 0x03]));
+        assert_eq!(sgb.attributes[CELLS_X + 1], 2);
+        assert_eq!(sgb.attributes[2 * CELLS_X + 2], 2);
+        assert_eq!(sgb.attributes[0], 0);
+    }
+    #[test]
+    fn attr_div_splits_screen() {
+        let mut sgb = Sgb::new();
+        // Vertical split at x = 10, left palette 1, right 2, line 3
+        send_packet(&mut sgb, &command(ATTR_DIV, &[0b0011_0110, 10]));
+        assert_eq!(sgb.attributes[9], 1);
+        assert_eq!(sgb.attributes[10], 3);
+        assert_eq!(sgb.attributes[11], 2);
+    }
+    #[test]
+    fn render_places_screen_inside_border() {
+        let mut sgb = Sgb::new();
+        sgb.palettes[0] = [0x0001, 0x0002, 0x0003, 0x0004];
+        let mut shades = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT];
+        shades[0] = 3;
+        let output = sgb.render(&shades);
+        assert_eq!(output.len(), SGB_WIDTH * SGB_HEIGHT);
+        assert_eq!(output[SCREEN_Y * SGB_WIDTH + SCREEN_X], 0x0004);
+        assert_eq!(output[0], 0x0001);
+        send_packet(&mut sgb, &command(MASK_EN, &[0x02]));
+        assert_eq!(sgb.render(&shades)[SCREEN_Y * SGB_WIDTH + SCREEN_X], 0x0000);
+    }
+    #[test]
+    fn chr_trn_copies_screen_tiles() {
+        let mut sgb = Sgb::new();
+        let mut ppu = Ppu::new();
+        // Map entries 0..=255, tile data unsigned from 0x8000
+        for tile in 0..256 {
+            ppu.vram[0x1800 + (tile / 20) * 32 + tile % 20] = tile as u8;
+        }
+        ppu.vram[16 * 21] = 0xAB;
+        send_packet(&mut sgb, &command(CHR_TRN, &[0x01]));
+        sgb.frame_done(&ppu);
+        assert_eq!(sgb.border_tiles[TRANSFER_SIZE + 16 * 21], 0xAB);
+    }
+}
@@ -0,0 +1,182 @@
+use std::fmt;
+
+use crate::memory_bus::MemoryBus;
+
+#[derive(Debug, PartialEq)]
+pub enum SymbolError {
+    Syntax { line: usize, text: String },
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SymbolError::Syntax { line, text } => {
+                write!(f, "line {}: expected BANK:ADDRESS LABEL, found {:?}", line, text)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SymbolError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    pub bank: u16,
+    pub address: u16,
+    pub name: String,
+}
+
+// Labels from an RGBDS .sym file, one "BB:AAAA Label" per line with ;
+// starting a comment. Banks are numbered as MemoryBus::bank_at does.
+pub struct Symbols {
+    // Sorted by bank then address
+    symbols: Vec<Symbol>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols { symbols: Vec::new() }
+    }
+
+    pub fn parse(text: &str) -> Result<Symbols, SymbolError> {
+        let mut symbols = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let content = line.split(';').next().unwrap_or("").trim();
+            if content.is_empty() {
+                continue;
+            }
+            let syntax_error = || SymbolError::Syntax { line: index + 1, text: line.to_string() };
+            let (location, name) = content.split_once(char::is_whitespace).ok_or_else(syntax_error)?;
+            let (bank, address) = location.split_once(':').ok_or_else(syntax_error)?;
+            symbols.push(Symbol {
+                bank: u16::from_str_radix(bank, 16).map_err(|_| syntax_error())?,
+                address: u16::from_str_radix(address, 16).map_err(|_| syntax_error())?,
+                name: name.trim().to_string(),
+            });
+        }
+        symbols.sort_by_key(|symbol| (symbol.bank, symbol.address));
+        Ok(Symbols { symbols })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    // Every label placed exactly at the address
+    pub fn at(&self, bank: u16, address: u16) -> impl Iterator<Item = &Symbol> {
+        let start = self.symbols.partition_point(|symbol| (symbol.bank, symbol.address) < (bank, address));
+        self.symbols[start..].iter().take_while(move |symbol| symbol.bank == bank && symbol.address == address)
+    }
+
+    // The closest label at or before the address in the same bank and
+    // memory region, as "Label" or "Label+$12"
+    pub fn describe(&self, bank: u16, address: u16) -> Option<String> {
+        let symbol = self.closest(bank, address)?;
+        match address - symbol.address {
+            0 => Some(symbol.name.clone()),
+            offset => Some(format!("{}+${:X}", symbol.name, offset)),
+        }
+    }
+
+    // The function the address is in, the closest label with any local
+    // part like .loop left off
+    pub fn function_at(&self, bank: u16, address: u16) -> Option<&str> {
+        self.closest(bank, address)?.name.split('.').next()
+    }
+
+    fn closest(&self, bank: u16, address: u16) -> Option<&Symbol> {
+        let end = self.symbols.partition_point(|symbol| (symbol.bank, symbol.address) <= (bank, address));
+        let symbol = self.symbols[..end].last()?;
+        if symbol.bank != bank || region(symbol.address) != region(address) {
+            return None;
+        }
+        Some(symbol)
+    }
+
+    // describe for whatever bank is mapped at the address right now
+    pub fn describe_mapped(&self, bus: &MemoryBus, address: u16) -> Option<String> {
+        self.describe(bus.bank_at(address), address)
+    }
+}
+
+impl Default for Symbols {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Labels only reach as far as the region they were placed in, so the
+// last label in WRAM0 does not also name the start of WRAMX
+fn region(address: u16) -> u16 {
+    match address {
+        0x0000..=0x3FFF => 0x0000,
+        0x4000..=0x7FFF => 0x4000,
+        0x8000..=0x9FFF => 0x8000,
+        0xA000..=0xBFFF => 0xA000,
+        0xC000..=0xCFFF => 0xC000,
+        0xD000..=0xDFFF => 0xD000,
+        0xE000..=0xFF7F => 0xE000,
+        _ => 0xFF80,
+    }
+}
+
+#[cfg(test)]
+mod symbols_tests {
+    use super::*;
+
+    const SYM: &str = "; File generated by rgblink
+00:0000 RST_00
+00:0150 Main
+00:0155 Main.loop
+01:4000 BankedRoutine
+02:4000 OtherBank
+00:c000 wCounter
+00:cff0 wStack
+00:ff80 hFlag
+";
+
+    #[test]
+    fn parses_rgblink_output() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        assert_eq!(symbols.len(), 8);
+        assert_eq!(
+            symbols.find("BankedRoutine"),
+            Some(&Symbol { bank: 1, address: 0x4000, name: "BankedRoutine".to_string() })
+        );
+        assert_eq!(symbols.find("Missing"), None);
+        let names: Vec<&str> = symbols.at(2, 0x4000).map(|symbol| symbol.name.as_str()).collect();
+        assert_eq!(names, vec!["OtherBank"]);
+        assert_eq!(symbols.at(0, 0x0151).count(), 0);
+    }
+    #[test]
+    fn describes_addresses() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        assert_eq!(symbols.describe(0, 0x0150).as_deref(), Some("Main"));
+        assert_eq!(symbols.describe(0, 0x0158).as_deref(), Some("Main.loop+$3"));
+        assert_eq!(symbols.describe(1, 0x4010).as_deref(), Some("BankedRoutine+$10"));
+        assert_eq!(symbols.describe(2, 0x4001).as_deref(), Some("OtherBank+$1"));
+        assert_eq!(symbols.describe(3, 0x4001), None);
+        assert_eq!(symbols.describe(0, 0xD000), None);
+        assert_eq!(symbols.describe(0, 0xFF81).as_deref(), Some("hFlag+$1"));
+        assert_eq!(symbols.function_at(0, 0x0158), Some("Main"));
+        assert_eq!(symbols.function_at(1, 0x4010), Some("BankedRoutine"));
+        assert_eq!(symbols.function_at(3, 0x4010), None);
+    }
+    #[test]
+    fn rejects_malformed_lines() {
+        assert_eq!(
+            Symbols::parse("00:0150 Main\nnonsense\n").err(),
+            Some(SymbolError::Syntax { line: 2, text: "nonsense".to_string() })
+        );
+        assert!(Symbols::parse("0g:0150 Main").is_err());
+        assert!(Symbols::parse("\n  ; only a comment\n").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,107 @@
+// DIV is the upper byte of a 16 bit counter incremented every cycle.
+// TIMA ticks on the falling edge of the counter bit selected by TAC.
+pub struct Timer {
+    pub divider: u16,
+    pub tima: u8,
+    pub tma: u8,
+    pub tac: u8,
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Timer::new()
+    }
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            divider: 0xABCC,
+            tima: 0,
+            tma: 0,
+            tac: 0xF8,
+        }
+    }
+
+    fn selected_bit(&self) -> bool {
+        if self.tac & 0b100 == 0 {
+            return false;
+        }
+        let bit = match self.tac & 0b11 {
+            0b00 => 9,
+            0b01 => 3,
+            0b10 => 5,
+            _ => 7,
+        };
+        (self.divider >> bit) & 0b1 != 0
+    }
+
+    // Returns true when TIMA overflowed and the timer interrupt should
+    // be requested
+    pub fn step(&mut self, cycles: u32) -> bool {
+        let mut interrupt = false;
+        for _ in 0..cycles / 4 {
+            let before = self.selected_bit();
+            self.divider = self.divider.wrapping_add(4);
+            if before && !self.selected_bit() {
+                interrupt |= self.increment_tima();
+            }
+        }
+        interrupt
+    }
+
+    fn increment_tima(&mut self) -> bool {
+        let (value, overflow) = self.tima.overflowing_add(1);
+        self.tima = if overflow { self.tma } else { value };
+        overflow
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0xFF04 => (self.divider >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            0xFF07 => self.tac | 0xF8,
+            _ => 0xFF,
+        }
+    }
+
+    // Writes can produce a falling edge on the selected bit, which
+    // increments TIMA just like a regular tick would
+    pub fn write(&mut self, address: u16, value: u8) -> bool {
+        let before = self.selected_bit();
+        match address {
+            0xFF04 => self.divider = 0,
+            0xFF05 => self.tima = value,
+            0xFF06 => self.tma = value,
+            0xFF07 => self.tac = value | 0xF8,
+            _ => {}
+        }
+        if before && !self.selected_bit() {
+            return self.increment_tima();
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod timer_tests {
+    use super::*;
+    #[test]
+    fn div_counts_every_256_cycles() {
+        let mut timer = Timer::new();
+        timer.write(0xFF04, 0);
+        timer.step(256);
+        assert_eq!(timer.read(0xFF04), 1);
+    }
+    #[test]
+    fn tima_overflow_reloads_tma() {
+        let mut timer = Timer::new();
+        timer.write(0xFF04, 0);
+        timer.write(0xFF06, 0x42);
+        timer.write(0xFF05, 0xFF);
+        timer.write(0xFF07, 0b101);
+        assert!(timer.step(16));
+        assert_eq!(timer.read(0xFF05), 0x42);
+    }
+}
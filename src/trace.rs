@@ -0,0 +1,111 @@
+use std::io::{self, Write};
+
+use crate::cpu::CPU;
+
+// Gameboy Doctor's reference logs were made with LY always reading 0x90
+pub const DOCTOR_LY: u8 = 0x90;
+
+// The state before an instruction runs, as Gameboy Doctor expects it:
+// A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+pub fn doctor_line(cpu: &CPU) -> String {
+    let registers = &cpu.registers;
+    let memory = |offset: u16| cpu.bus.peek(cpu.pc.wrapping_add(offset));
+    format!(
+        "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} \
+         PCMEM:{:02X},{:02X},{:02X},{:02X}",
+        registers.a,
+        u8::from(registers.f),
+        registers.b,
+        registers.c,
+        registers.d,
+        registers.e,
+        registers.h,
+        registers.l,
+        cpu.sp,
+        cpu.pc,
+        memory(0),
+        memory(1),
+        memory(2),
+        memory(3),
+    )
+}
+
+// Writes a Gameboy Doctor line for every instruction executed. Call
+// log before each step, interrupt dispatches and halted steps are left
+// out like they are in the reference logs.
+pub struct DoctorTrace<W: Write> {
+    out: W,
+    pub lines: u64,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> DoctorTrace<W> {
+    pub fn new(out: W) -> DoctorTrace<W> {
+        DoctorTrace { out, lines: 0, error: None }
+    }
+
+    pub fn log(&mut self, cpu: &CPU) {
+        if self.error.is_some() || !cpu.executes_next() {
+            return;
+        }
+        match writeln!(self.out, "{}", doctor_line(cpu)) {
+            Ok(()) => self.lines += 1,
+            Err(error) => self.error = Some(error),
+        }
+    }
+
+    // Flushes the output, reporting the first write that failed
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod trace_tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+
+    #[test]
+    fn formats_like_gameboy_doctor() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x13, 0x02]);
+        // Half carry and carry are only set after boot with a non zero
+        // header checksum
+        rom[0x14D] = 0xE7;
+        let cpu = CPU::with_cartridge(Cartridge::new(rom).unwrap());
+        assert_eq!(
+            doctor_line(&cpu),
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02"
+        );
+    }
+    #[test]
+    fn logs_only_executed_instructions() {
+        let mut rom = vec![0; 0x8000];
+        // ei, halt, then nops. The timer interrupt vector returns with reti.
+        rom[0x50] = 0xD9;
+        rom[0x100..0x102].copy_from_slice(&[0xFB, 0x76]);
+        let mut cpu = CPU::with_cartridge(Cartridge::new(rom).unwrap());
+        cpu.bus.ly_override = Some(DOCTOR_LY);
+        cpu.bus.interrupt_enable = 0x04;
+        cpu.bus.write_byte(0xFF06, 0x00);
+        cpu.bus.write_byte(0xFF05, 0xFF);
+        cpu.bus.write_byte(0xFF07, 0x05);
+        let mut trace = DoctorTrace::new(Vec::new());
+        for _ in 0..200 {
+            trace.log(&cpu);
+            cpu.step();
+        }
+        let lines = trace.lines;
+        let log = String::from_utf8(trace.finish().unwrap()).unwrap();
+        let pcs: Vec<&str> = log.lines().map(|line| &line[51..55]).collect();
+        assert_eq!(lines as usize, pcs.len());
+        // The halt is logged once, then the handler, then execution
+        // carries on after the halt
+        assert_eq!(&pcs[..4], &["0100", "0101", "0050", "0102"]);
+        assert_eq!(cpu.bus.read_byte(0xFF44), 0x90);
+    }
+}
@@ -0,0 +1,47 @@
+use std::io::{self, Write};
+
+pub const CHANNELS: u16 = 2;
+pub const BITS_PER_SAMPLE: u16 = 16;
+
+// Writes interleaved stereo 16 bit PCM samples as a RIFF WAVE file
+pub fn write_wav<W: Write>(writer: &mut W, sample_rate: u32, samples: &[i16]) -> io::Result<()> {
+    let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+    let data_size = (samples.len() * 2) as u32;
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(36 + data_size).to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    // PCM
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&CHANNELS.to_le_bytes())?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+    writer.write_all(b"data")?;
+    writer.write_all(&data_size.to_le_bytes())?;
+    for sample in samples {
+        writer.write_all(&sample.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod wav_tests {
+    use super::*;
+    #[test]
+    fn writes_header_and_data() {
+        let mut output = Vec::new();
+        write_wav(&mut output, 44100, &[1, -1]).unwrap();
+        assert_eq!(output.len(), 44 + 4);
+        assert_eq!(&output[0..4], b"RIFF");
+        assert_eq!(&output[4..8], &40u32.to_le_bytes());
+        assert_eq!(&output[24..28], &44100u32.to_le_bytes());
+        assert_eq!(&output[40..44], &4u32.to_le_bytes());
+        assert_eq!(&output[44..48], &[0x01, 0x00, 0xFF, 0xFF]);
+    }
+}